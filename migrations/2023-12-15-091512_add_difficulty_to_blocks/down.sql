@@ -0,0 +1,2 @@
+ALTER TABLE ONLY blocks
+DROP COLUMN difficulty;
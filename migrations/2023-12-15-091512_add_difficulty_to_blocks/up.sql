@@ -0,0 +1,2 @@
+ALTER TABLE ONLY blocks
+ADD COLUMN difficulty numeric(80,0);
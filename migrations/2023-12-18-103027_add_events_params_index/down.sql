@@ -0,0 +1 @@
+DROP INDEX events_params_idx;
@@ -0,0 +1,3 @@
+CREATE INDEX events_params_idx
+  ON events
+  USING gin (params jsonb_path_ops);
@@ -0,0 +1,2 @@
+ALTER TABLE ONLY transactions
+DROP COLUMN keysets;
@@ -0,0 +1,11 @@
+CREATE TABLE account_guards (
+  account character varying NOT NULL,
+  chain_id bigint NOT NULL,
+  module character varying NOT NULL,
+  guard jsonb NOT NULL,
+  height bigint NOT NULL,
+  request_key character varying NOT NULL
+);
+
+ALTER TABLE ONLY account_guards
+    ADD CONSTRAINT account_guards_pkey PRIMARY KEY (account, chain_id, module);
@@ -0,0 +1,2 @@
+ALTER TABLE ONLY transactions
+ADD COLUMN signatures_valid boolean;
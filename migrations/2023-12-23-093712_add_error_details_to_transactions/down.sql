@@ -0,0 +1,3 @@
+ALTER TABLE ONLY transactions
+DROP COLUMN error_type,
+DROP COLUMN error_message;
@@ -0,0 +1,3 @@
+ALTER TABLE ONLY transactions
+ADD COLUMN error_type character varying,
+ADD COLUMN error_message character varying;
@@ -0,0 +1,3 @@
+ALTER TABLE ONLY transfers
+ADD COLUMN self_transfer boolean NOT NULL DEFAULT false;
+UPDATE transfers SET self_transfer = true WHERE from_account = to_account;
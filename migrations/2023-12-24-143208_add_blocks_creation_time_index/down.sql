@@ -0,0 +1 @@
+DROP INDEX blocks_creation_time_idx;
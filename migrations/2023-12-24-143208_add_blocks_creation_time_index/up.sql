@@ -0,0 +1,2 @@
+CREATE INDEX blocks_creation_time_idx
+  ON blocks (creation_time DESC);
@@ -0,0 +1,3 @@
+DROP INDEX transactions_signers_idx;
+ALTER TABLE ONLY transactions
+DROP COLUMN signers;
@@ -0,0 +1,5 @@
+ALTER TABLE ONLY transactions
+ADD COLUMN signers text[] NOT NULL DEFAULT '{}';
+CREATE INDEX transactions_signers_idx
+  ON transactions
+  USING gin (signers);
@@ -0,0 +1,2 @@
+ALTER TABLE ONLY transactions
+ADD COLUMN is_gas_station boolean NOT NULL DEFAULT false;
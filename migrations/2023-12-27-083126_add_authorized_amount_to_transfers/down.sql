@@ -0,0 +1,2 @@
+ALTER TABLE ONLY transfers
+DROP COLUMN authorized_amount;
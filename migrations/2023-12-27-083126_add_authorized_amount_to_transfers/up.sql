@@ -0,0 +1,2 @@
+ALTER TABLE ONLY transfers
+ADD COLUMN authorized_amount numeric;
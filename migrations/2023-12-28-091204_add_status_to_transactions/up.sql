@@ -0,0 +1,3 @@
+ALTER TABLE ONLY transactions
+ADD COLUMN status varchar NOT NULL DEFAULT 'success';
+UPDATE transactions SET status = 'failure' WHERE bad_result IS NOT NULL;
@@ -0,0 +1,5 @@
+DROP INDEX blocks_chainid_height_idx;
+DROP INDEX transfers_pactid_idx;
+DROP INDEX transactions_sender_height_idx;
+DROP INDEX events_module_name_height_idx;
+DROP INDEX events_qualname_height_idx;
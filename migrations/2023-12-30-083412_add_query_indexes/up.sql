@@ -0,0 +1,24 @@
+-- transactions (pact_id), transfers (from_account) and transfers (to_account) are already
+-- covered by transactions_pactid_index, transfers_from_acct_height_idx and
+-- transfers_to_acct_height_idx_idx.
+
+CREATE INDEX events_qualname_height_idx
+  ON events
+  USING btree (qual_name, height DESC);
+
+CREATE INDEX events_module_name_height_idx
+  ON events
+  USING btree (module, name, height DESC, request_key DESC, idx DESC);
+
+CREATE INDEX transactions_sender_height_idx
+  ON transactions
+  USING btree (sender, height DESC);
+
+CREATE INDEX transfers_pactid_idx
+  ON transfers
+  USING btree (pact_id)
+  WHERE pact_id IS NOT NULL;
+
+CREATE INDEX blocks_chainid_height_idx
+  ON blocks
+  USING btree (chain_id, height DESC);
@@ -0,0 +1,9 @@
+-- Last block of every chain processed by the headers stream, where it resumes from on restart
+CREATE TABLE stream_cursors (
+  chain_id bigint NOT NULL,
+  height bigint NOT NULL,
+  hash character varying NOT NULL
+);
+
+ALTER TABLE ONLY stream_cursors
+    ADD CONSTRAINT stream_cursors_pkey PRIMARY KEY (chain_id);
@@ -1,19 +1,203 @@
-use actix_web::{error, get, post, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use actix_web::body::EitherBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::{
+    error, get, middleware, post, web, App, HttpRequest, HttpResponse, HttpResponseBuilder,
+    HttpServer, Responder,
+};
+use bento::chainweb_client::{ChainId, ChainwebClient, MAINNET_CHAINS};
 use bento::db;
+use bento::gaps;
+use bento::indexer::Indexer;
+use bento::logging;
 use bento::models::*;
+use bento::pagination::Cursor;
 use bento::repository::*;
+use bento::tokens::TokenDecimals;
 use bigdecimal::BigDecimal;
+use chrono::{DateTime, NaiveDateTime, Utc};
 use dotenvy::dotenv;
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::env;
-use std::time::Instant;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 #[derive(Deserialize)]
 struct RequestKeys {
     request_keys: Vec<String>,
 }
 
+#[derive(Deserialize)]
+struct EventsSearch {
+    module: String,
+    name: String,
+    #[serde(default)]
+    params: ParamsMatch,
+    limit: Option<i64>,
+    cursor: Option<String>,
+}
+
+/// A small in-memory cache whose entries expire after `ttl`.
+/// When full, expired entries are dropped first and then the oldest entry is evicted.
+struct TtlCache<K, V> {
+    entries: Mutex<TtlEntries<K, V>>,
+    ttl: Duration,
+    capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// The entries of a [`TtlCache`] with their keys in insertion order, so that expired and
+/// oldest entries are found without scanning all of them.
+struct TtlEntries<K, V> {
+    values: HashMap<K, (Instant, V)>,
+    /// A key inserted again is queued again, the older position being skipped once reached.
+    order: VecDeque<(Instant, K)>,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> TtlCache<K, V> {
+    fn new(ttl: Duration, capacity: usize) -> Self {
+        TtlCache {
+            entries: Mutex::new(TtlEntries {
+                values: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+            ttl,
+            capacity,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn get(&self, key: &K) -> Option<V> {
+        let entries = self.entries.lock().unwrap();
+        let value = entries
+            .values
+            .get(key)
+            .filter(|(inserted_at, _)| inserted_at.elapsed() < self.ttl)
+            .map(|(_, value)| value.clone());
+        match value {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        value
+    }
+
+    fn insert(&self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        while let Some((inserted_at, _)) = entries.order.front() {
+            let expired = inserted_at.elapsed() >= self.ttl;
+            if !expired && entries.values.len() < self.capacity {
+                break;
+            }
+            let (inserted_at, oldest) = entries.order.pop_front().unwrap();
+            if entries
+                .values
+                .get(&oldest)
+                .is_some_and(|(at, _)| *at == inserted_at)
+            {
+                entries.values.remove(&oldest);
+            }
+        }
+        let inserted_at = Instant::now();
+        entries.order.push_back((inserted_at, key.clone()));
+        entries.values.insert(key, (inserted_at, value));
+    }
+
+    /// Number of lookups which found a value, and of those which didn't.
+    fn stats(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Renders the hits and misses of the given caches, with their stats, in the Prometheus text
+/// format like the metrics of the indexer.
+fn render_cache_metrics(caches: &[(&str, (u64, u64))]) -> String {
+    let mut output = String::from(
+        "# HELP bento_api_cache_hits_total Lookups of a cache which found a value.\n\
+         # TYPE bento_api_cache_hits_total counter\n",
+    );
+    for (cache, (hits, _)) in caches {
+        output.push_str(&format!(
+            "bento_api_cache_hits_total{{cache=\"{}\"}} {}\n",
+            cache, hits
+        ));
+    }
+    output.push_str(
+        "# HELP bento_api_cache_misses_total Lookups of a cache which found no value.\n\
+         # TYPE bento_api_cache_misses_total counter\n",
+    );
+    for (cache, (_, misses)) in caches {
+        output.push_str(&format!(
+            "bento_api_cache_misses_total{{cache=\"{}\"}} {}\n",
+            cache, misses
+        ));
+    }
+    output
+}
+
+type AllBalancesCache = TtlCache<String, AllBalances>;
+type BalanceCache = TtlCache<(String, String), HashMap<i64, BigDecimal>>;
+type AccountSummaryCache = TtlCache<String, serde_json::Value>;
+
+/// Starts a successful response, results truncated at `MAX_ROWS` rows are flagged
+/// with the `X-Truncated` header.
+fn ok_response(truncated: bool) -> HttpResponseBuilder {
+    let mut response = HttpResponse::Ok();
+    if truncated {
+        response.insert_header(("X-Truncated", "true"));
+    }
+    response
+}
+
+/// Reads the `limit`, 100 by default and at most 1000, and the `cursor` of a page from the
+/// query params, returning the response to send when they're invalid.
+fn page_params(params: &HashMap<String, String>) -> Result<(i64, Option<Cursor>), HttpResponse> {
+    let limit = match params.get("limit").map(|l| l.parse::<i64>()) {
+        Some(Ok(limit)) if limit > 0 && limit <= 1000 => limit,
+        Some(_) => return Err(HttpResponse::BadRequest().body("Invalid limit")),
+        None => 100,
+    };
+    let after = match params.get("cursor").map(|c| Cursor::decode(c)) {
+        Some(Some(cursor)) => Some(cursor),
+        Some(None) => return Err(HttpResponse::BadRequest().body("Invalid cursor")),
+        None => None,
+    };
+    Ok((limit, after))
+}
+
+/// A full page means there might be more items, the next page starts after the last one.
+fn page_response<T>(items: &[T], limit: i64) -> HttpResponseBuilder
+where
+    for<'a> Cursor: From<&'a T>,
+{
+    let mut response = HttpResponse::Ok();
+    if items.len() as i64 == limit {
+        if let Some(last) = items.last() {
+            response.insert_header(("X-Next-Cursor", Cursor::from(last).encode()));
+        }
+    }
+    response
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/tx/{request_key}",
+    params(("request_key" = String, Path, description = "Request key of the transaction")),
+    responses(
+        (status = 200, description = "The transaction, and all its steps if it's a multi-step transaction", body = Vec<Transaction>),
+        (status = 404, description = "Tx not found")
+    )
+))]
 #[get("/tx/{request_key}")]
 async fn tx(
     path: web::Path<String>,
@@ -22,116 +206,2131 @@ async fn tx(
     let request_key = path.into_inner();
     let req_key = request_key.clone();
     let tx: HashMap<String, Vec<Transaction>> =
-        web::block(move || transactions.find_all_related(&vec![request_key]))
+        logging::block_with_request_id(move || transactions.find_all_related(&vec![request_key]))
             .await?
-            .map_err(error::ErrorInternalServerError)?;
+            .map_err(error::ErrorInternalServerError)?
+            .rows;
     Ok(match tx.contains_key(&req_key) {
         false => HttpResponse::NotFound().body("Tx not found"),
         true => HttpResponse::Ok().json(tx.get(&req_key).unwrap()),
     })
 }
 
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/tx/{request_key}/full",
+    params(("request_key" = String, Path, description = "Request key of the transaction")),
+    responses(
+        (status = 200, description = "The transactions with their events and transfers", body = Object),
+        (status = 404, description = "Tx not found")
+    )
+))]
+#[get("/tx/{request_key}/full")]
+async fn tx_full(
+    path: web::Path<String>,
+    transactions: web::Data<TransactionsRepository>,
+    events: web::Data<EventsRepository>,
+    transfers: web::Data<TransfersRepository>,
+) -> actix_web::Result<impl Responder> {
+    let request_key = path.into_inner();
+    let req_key = request_key.clone();
+    let mut related: HashMap<String, Vec<Transaction>> =
+        logging::block_with_request_id(move || transactions.find_all_related(&vec![request_key]))
+            .await?
+            .map_err(error::ErrorInternalServerError)?
+            .rows;
+    let related_txs = match related.remove(&req_key) {
+        Some(related_txs) => related_txs,
+        None => return Ok(HttpResponse::NotFound().body("Tx not found")),
+    };
+    let request_keys = related_txs
+        .iter()
+        .map(|related_tx| related_tx.request_key.clone())
+        .collect::<Vec<String>>();
+    let (tx_events, tx_transfers) = logging::block_with_request_id(move || {
+        events
+            .find_by_request_keys(&request_keys)
+            .and_then(|events| Ok((events, transfers.find_by_request_keys(&request_keys)?)))
+    })
+    .await?
+    .map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "transactions": related_txs,
+        "events": tx_events,
+        "transfers": tx_transfers,
+    })))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/pact/{pact_id}",
+    params(("pact_id" = String, Path, description = "Id of the defpact, the request key of its first step")),
+    responses(
+        (status = 200, description = "The steps of the defpact in order with the transactions executing them, and whether each step succeeded, failed, is missing because a later step was executed, or is pending", body = Object),
+        (status = 404, description = "Pact not found")
+    )
+))]
+#[get("/pact/{pact_id}")]
+async fn pact(
+    path: web::Path<String>,
+    transactions: web::Data<TransactionsRepository>,
+) -> actix_web::Result<impl Responder> {
+    let pact_id = path.into_inner();
+    let query_pact_id = pact_id.clone();
+    let steps =
+        logging::block_with_request_id(move || transactions.find_by_pact_id(&vec![query_pact_id]))
+            .await?
+            .map_err(error::ErrorInternalServerError)?;
+    Ok(match steps.is_empty() {
+        true => HttpResponse::NotFound().body("Pact not found"),
+        false => HttpResponse::Ok().json(pact_steps(&pact_id, steps)),
+    })
+}
+
+/// Assembles the steps of a defpact from its transactions, ordered by step. A step without
+/// transactions is missing when a later step was executed, it's pending otherwise.
+fn pact_steps(pact_id: &str, transactions: Vec<Transaction>) -> serde_json::Value {
+    let last_step = transactions
+        .iter()
+        .filter_map(|transaction| transaction.step)
+        .max();
+    let step_count = transactions
+        .iter()
+        .filter_map(|transaction| transaction.continuation.as_ref()?["stepCount"].as_i64())
+        .chain(last_step.map(|step| step + 1))
+        .max()
+        .unwrap_or(0);
+    let steps = (0..step_count)
+        .map(|step| {
+            let step_transactions = transactions
+                .iter()
+                .filter(|transaction| transaction.step == Some(step))
+                .collect::<Vec<&Transaction>>();
+            // A failed step can be retried, it succeeded if any of its transactions did
+            let succeeded = step_transactions
+                .iter()
+                .any(|transaction| transaction.status == "success");
+            let step_status = match step_transactions.is_empty() {
+                false if succeeded => "success",
+                false => "failure",
+                true if last_step.is_some_and(|last_step| last_step > step) => "missing",
+                true => "pending",
+            };
+            serde_json::json!({
+                "step": step,
+                "status": step_status,
+                "transactions": step_transactions,
+            })
+        })
+        .collect::<Vec<serde_json::Value>>();
+    serde_json::json!({
+        "pact_id": pact_id,
+        "step_count": step_count,
+        "complete": steps.iter().all(|step| step["status"] == "success"),
+        "steps": steps,
+    })
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    post,
+    path = "/txs",
+    request_body = Object,
+    responses((status = 200, description = "The transactions by request key", body = Object))
+))]
 #[post("/txs")]
 async fn txs(
     body: web::Json<RequestKeys>,
     transactions: web::Data<TransactionsRepository>,
 ) -> actix_web::Result<impl Responder> {
-    let result: HashMap<String, Vec<Transaction>> =
-        web::block(move || transactions.find_all_related(&body.request_keys))
+    let result =
+        logging::block_with_request_id(move || transactions.find_all_related(&body.request_keys))
             .await?
             .map_err(error::ErrorInternalServerError)?;
-    Ok(HttpResponse::Ok().json(result))
+    Ok(ok_response(result.truncated).json(result.rows))
 }
 
+/// Maximum number of request keys of a `/transactions/batch` call.
+const MAX_BATCH_REQUEST_KEYS: usize = 1000;
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    post,
+    path = "/transactions/batch",
+    request_body = Object,
+    responses(
+        (status = 200, description = "The transactions with the given request keys", body = [Transaction]),
+        (status = 400, description = "More than 1000 request keys")
+    )
+))]
+#[post("/transactions/batch")]
+async fn transactions_batch(
+    body: web::Json<RequestKeys>,
+    transactions: web::Data<TransactionsRepository>,
+) -> actix_web::Result<impl Responder> {
+    if body.request_keys.len() > MAX_BATCH_REQUEST_KEYS {
+        return Ok(HttpResponse::BadRequest().body(format!(
+            "At most {} request keys are allowed",
+            MAX_BATCH_REQUEST_KEYS
+        )));
+    }
+    let result = logging::block_with_request_id(move || {
+        transactions.find_by_request_key(&body.request_keys)
+    })
+    .await?
+    .map_err(error::ErrorInternalServerError)?;
+    Ok(ok_response(result.truncated).json(result.rows))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/balance/{account}",
+    params(
+        ("account" = String, Path),
+        ("modules" = Option<String>, Query, description = "Comma separated modules to get the balances of, all of them by default")
+    ),
+    responses((status = 200, description = "The balances per module and chain", body = Object))
+))]
 #[get("/balance/{account}")]
 async fn all_balances(
     path: web::Path<String>,
+    request: HttpRequest,
     transfers: web::Data<TransfersRepository>,
+    cache: web::Data<AllBalancesCache>,
+    decimals: web::Data<TokenDecimals>,
 ) -> actix_web::Result<impl Responder> {
     let account = path.into_inner();
-    let all: HashMap<String, HashMap<i64, BigDecimal>> =
-        web::block(move || transfers.calculate_all_balances(&account))
-            .await?
-            .map_err(error::ErrorInternalServerError)?;
-    Ok(HttpResponse::Ok().json(all))
+    let params = web::Query::<HashMap<String, String>>::from_query(request.query_string()).unwrap();
+    // An empty list, eg. `?modules=`, is the same as no list at all
+    let modules = params
+        .get("modules")
+        .map(|modules| {
+            modules
+                .split(',')
+                .map(|module| module.trim().to_string())
+                .filter(|module| !module.is_empty())
+                .collect::<Vec<String>>()
+        })
+        .filter(|modules| !modules.is_empty());
+    if let Some(all) = cache.get(&account) {
+        let mut balances = all;
+        if let Some(modules) = &modules {
+            balances.retain(|module, _| modules.contains(module));
+        }
+        return Ok(HttpResponse::Ok().json(scale_all_balances(&balances, &decimals)));
+    }
+    let query_account = account.clone();
+    let query_modules = modules.clone();
+    let all = logging::block_with_request_id(move || {
+        transfers.calculate_all_balances(&query_account, query_modules.as_deref())
+    })
+    .await?
+    .map_err(error::ErrorInternalServerError)?;
+    let response = scale_all_balances(&all, &decimals);
+    // Only the balances of all the modules are cached, those of some modules are served from them
+    if modules.is_none() {
+        cache.insert(account, all);
+    }
+    Ok(HttpResponse::Ok().json(response))
 }
 
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/balance/{account}/{module}",
+    params(
+        ("account" = String, Path),
+        ("module" = String, Path),
+        ("height" = Option<i64>, Query, description = "Get the total balance on all chains as of this height instead")
+    ),
+    responses(
+        (status = 200, description = "The balances per chain, or the total balance as of the given height", body = Object),
+        (status = 400, description = "Invalid height")
+    )
+))]
 #[get("/balance/{account}/{module}")]
 async fn balance(
     path: web::Path<(String, String)>,
+    request: HttpRequest,
     transfers: web::Data<TransfersRepository>,
+    cache: web::Data<BalanceCache>,
+    decimals: web::Data<TokenDecimals>,
 ) -> actix_web::Result<impl Responder> {
-    let (account, module) = path.into_inner();
+    let key = path.into_inner();
+    let params = web::Query::<HashMap<String, String>>::from_query(request.query_string()).unwrap();
+    match params.get("height").map(|h| h.parse::<i64>()) {
+        Some(Ok(height)) => {
+            let (account, module) = key;
+            return balance_at_height(account, module, height, transfers, decimals).await;
+        }
+        Some(Err(_)) => return Ok(HttpResponse::BadRequest().body("Invalid height")),
+        None => {}
+    }
+    if let Some(balance) = cache.get(&key) {
+        return Ok(HttpResponse::Ok().json(scale_balances(&key.1, &balance, &decimals)));
+    }
+    let (account, module) = key.clone();
     let balance: HashMap<i64, BigDecimal> =
-        web::block(move || transfers.calculate_balance(&account, &module))
+        logging::block_with_request_id(move || transfers.calculate_balance(&account, &module))
             .await?
             .map_err(error::ErrorInternalServerError)?;
-    Ok(HttpResponse::Ok().json(balance))
+    let response = scale_balances(&key.1, &balance, &decimals);
+    cache.insert(key, balance);
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// Adds the scaled amounts to the balances of the tokens registered in `TOKEN_DECIMALS`.
+fn scale_balances(
+    module: &str,
+    balances: &HashMap<i64, BigDecimal>,
+    decimals: &TokenDecimals,
+) -> HashMap<i64, serde_json::Value> {
+    balances
+        .iter()
+        .map(|(chain, amount)| (*chain, decimals.to_json(module, amount)))
+        .collect()
+}
+
+fn scale_all_balances(
+    balances: &HashMap<String, HashMap<i64, BigDecimal>>,
+    decimals: &TokenDecimals,
+) -> HashMap<String, HashMap<i64, serde_json::Value>> {
+    balances
+        .iter()
+        .map(|(module, balances)| (module.clone(), scale_balances(module, balances, decimals)))
+        .collect()
 }
 
+/// Balances at a past height never change, so unlike the current balances they are not cached.
+async fn balance_at_height(
+    account: String,
+    module: String,
+    height: i64,
+    transfers: web::Data<TransfersRepository>,
+    decimals: web::Data<TokenDecimals>,
+) -> actix_web::Result<HttpResponse> {
+    let (query_account, query_module) = (account.clone(), module.clone());
+    let amount = logging::block_with_request_id(move || {
+        transfers.balance_at_height(&query_account, &query_module, height)
+    })
+    .await?
+    .map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "account": account,
+        "module": module,
+        "height": height,
+        "balance": decimals.to_json(&module, &amount),
+    })))
+}
+
+/// Date after which `/transfers/{account}/received` may be removed, advertised in its `Sunset`
+/// header when `API_RECEIVED_TRANSFERS_SUNSET` is set.
+struct ReceivedTransfersSunset(Option<String>);
+
 #[deprecated(note = "Use /transfers instead, this endpoint will be removed in the near future")]
 #[get("/transfers/{account}/received")]
 async fn received_transfers(
     path: web::Path<String>,
     request: HttpRequest,
     transfers: web::Data<TransfersRepository>,
+    sunset: web::Data<ReceivedTransfersSunset>,
 ) -> actix_web::Result<impl Responder> {
     let account = path.into_inner();
+    // Logged to find the remaining callers before the endpoint is removed
+    log::warn!(
+        "Deprecated /transfers/{{account}}/received called by {} ({})",
+        request
+            .connection_info()
+            .realip_remote_addr()
+            .unwrap_or("unknown"),
+        request
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("unknown user agent")
+    );
     let params = web::Query::<HashMap<String, i64>>::from_query(request.query_string()).unwrap();
     let min_height = params.get("min_height").copied();
     let before = Instant::now();
-    let transfers: HashMap<String, Vec<Transfer>> =
-        web::block(move || transfers.find_received(&account, min_height))
+    let transfers =
+        logging::block_with_request_id(move || transfers.find_received(&account, min_height))
             .await?
             .map_err(error::ErrorInternalServerError)?;
     log::info!("Received transfers took {:?}", before.elapsed().as_millis());
-    Ok(HttpResponse::Ok().json(transfers))
+    let mut response = ok_response(transfers.truncated);
+    response
+        .insert_header(("Deprecation", "true"))
+        .insert_header((header::LINK, "</transfers>; rel=\"successor-version\""))
+        .insert_header((
+            header::WARNING,
+            "299 - \"Deprecated, use /transfers?to={account} instead\"",
+        ));
+    if let Some(sunset) = &sunset.0 {
+        response.insert_header(("Sunset", sunset.as_str()));
+    }
+    Ok(response.json(transfers.rows))
 }
 
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/transfers",
+    params(
+        ("from" = Option<String>, Query),
+        ("to" = Option<String>, Query),
+        ("min_height" = Option<i64>, Query),
+        ("exclude_self_transfers" = Option<bool>, Query, description = "Defaults to false")
+    ),
+    responses(
+        (status = 200, body = Vec<Transfer>),
+        (status = 400, description = "Invalid min_height or exclude_self_transfers")
+    )
+))]
 #[get("/transfers")]
 async fn get_transfers(
     request: HttpRequest,
     transfers: web::Data<TransfersRepository>,
+) -> actix_web::Result<impl Responder> {
+    let filters = match TransferFilters::parse(&request) {
+        Ok(filters) => filters,
+        Err(message) => return Ok(HttpResponse::BadRequest().body(message)),
+    };
+    let transfers = logging::block_with_request_id(move || {
+        transfers.find(
+            filters.from,
+            filters.to,
+            filters.min_height,
+            filters.exclude_self_transfers,
+        )
+    })
+    .await?
+    .map_err(error::ErrorInternalServerError)?;
+    Ok(ok_response(transfers.truncated).json(transfers.rows))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/transfers/count",
+    params(
+        ("from" = Option<String>, Query),
+        ("to" = Option<String>, Query),
+        ("min_height" = Option<i64>, Query),
+        ("exclude_self_transfers" = Option<bool>, Query, description = "Defaults to false")
+    ),
+    responses(
+        (status = 200, description = "The number of transfers /transfers would return with the same filters, regardless of MAX_ROWS", body = Object),
+        (status = 400, description = "Invalid min_height or exclude_self_transfers")
+    )
+))]
+#[get("/transfers/count")]
+async fn count_transfers(
+    request: HttpRequest,
+    transfers: web::Data<TransfersRepository>,
+) -> actix_web::Result<impl Responder> {
+    let filters = match TransferFilters::parse(&request) {
+        Ok(filters) => filters,
+        Err(message) => return Ok(HttpResponse::BadRequest().body(message)),
+    };
+    let count = logging::block_with_request_id(move || {
+        transfers.count(
+            filters.from,
+            filters.to,
+            filters.min_height,
+            filters.exclude_self_transfers,
+        )
+    })
+    .await?
+    .map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "count": count })))
+}
+
+/// Query params shared by `/transfers` and `/transfers/count`.
+struct TransferFilters {
+    from: Option<String>,
+    to: Option<String>,
+    min_height: Option<i64>,
+    exclude_self_transfers: bool,
+}
+
+impl TransferFilters {
+    fn parse(request: &HttpRequest) -> Result<Self, &'static str> {
+        let params =
+            web::Query::<HashMap<String, String>>::from_query(request.query_string()).unwrap();
+        let min_height = match params.get("min_height").map(|h| h.parse::<i64>()) {
+            Some(Ok(height)) => Some(height),
+            Some(Err(_)) => return Err("Invalid min_height"),
+            None => None,
+        };
+        let exclude_self_transfers = match params.get("exclude_self_transfers").map(|e| e.parse()) {
+            Some(Ok(exclude)) => exclude,
+            Some(Err(_)) => return Err("Invalid exclude_self_transfers"),
+            None => false,
+        };
+        Ok(TransferFilters {
+            from: params.get("from").map(|e| e.to_string()),
+            to: params.get("to").map(|e| e.to_string()),
+            min_height,
+            exclude_self_transfers,
+        })
+    }
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/events",
+    params(
+        ("module" = String, Query),
+        ("name" = String, Query),
+        ("param" = String, Query, description = "Value the event params must contain, parsed as JSON if possible"),
+        ("limit" = Option<i64>, Query, description = "Defaults to 100, at most 1000"),
+        ("cursor" = Option<String>, Query, description = "X-Next-Cursor header of the previous page")
+    ),
+    responses(
+        (status = 200, body = Vec<Event>, headers(("X-Next-Cursor" = String, description = "Cursor of the next page, only set when the page is full"))),
+        (status = 400, description = "Missing module, name or param, or invalid limit or cursor")
+    )
+))]
+#[get("/events")]
+async fn get_events(
+    request: HttpRequest,
+    events: web::Data<EventsRepository>,
+) -> actix_web::Result<impl Responder> {
+    let params = web::Query::<HashMap<String, String>>::from_query(request.query_string()).unwrap();
+    let (module, name, param) = match (
+        params.get("module"),
+        params.get("name"),
+        params.get("param"),
+    ) {
+        (Some(module), Some(name), Some(param)) => (module.clone(), name.clone(), param.clone()),
+        _ => {
+            return Ok(HttpResponse::BadRequest().body("Missing module, name or param"));
+        }
+    };
+    let (limit, after) = match page_params(&params) {
+        Ok(page) => page,
+        Err(response) => return Ok(response),
+    };
+    // Params can be any JSON value (eg. numbers), anything else is treated as a string
+    let param_value = serde_json::from_str::<serde_json::Value>(&param)
+        .unwrap_or(serde_json::Value::String(param));
+    let events = logging::block_with_request_id(move || {
+        events.find_by_module_name_and_param(&module, &name, &param_value, limit, after.as_ref())
+    })
+    .await?
+    .map_err(error::ErrorInternalServerError)?;
+    Ok(page_response(&events, limit).json(events))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/events/latest",
+    params(("limit" = Option<i64>, Query, description = "Defaults to 50, at most 1000")),
+    responses(
+        (status = 200, body = Vec<Event>),
+        (status = 400, description = "Invalid limit")
+    )
+))]
+#[get("/events/latest")]
+async fn latest_events(
+    request: HttpRequest,
+    events: web::Data<EventsRepository>,
+) -> actix_web::Result<impl Responder> {
+    let params = web::Query::<HashMap<String, String>>::from_query(request.query_string()).unwrap();
+    let limit = match params.get("limit").map(|l| l.parse::<i64>()) {
+        Some(Ok(limit)) if limit > 0 && limit <= 1000 => limit,
+        Some(_) => return Ok(HttpResponse::BadRequest().body("Invalid limit")),
+        None => 50,
+    };
+    let events = logging::block_with_request_id(move || events.find_latest(limit))
+        .await?
+        .map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(events))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    post,
+    path = "/events/search",
+    request_body(content = Object, description = "module, name, params matched with `contains` (values at any position) and `equals` (values by position, eg. `{\"0\": \"alice\"}`), limit (defaults to 100, at most 1000) and cursor (X-Next-Cursor header of the previous page)"),
+    responses(
+        (status = 200, body = Vec<Event>, headers(("X-Next-Cursor" = String, description = "Cursor of the next page, only set when the page is full"))),
+        (status = 400, description = "Invalid body, position, limit or cursor")
+    )
+))]
+#[post("/events/search")]
+async fn search_events(
+    body: web::Json<EventsSearch>,
+    events: web::Data<EventsRepository>,
+) -> actix_web::Result<impl Responder> {
+    let EventsSearch {
+        module,
+        name,
+        params,
+        limit,
+        cursor,
+    } = body.into_inner();
+    if params.equals.keys().any(|position| *position < 0) {
+        return Ok(HttpResponse::BadRequest().body("Invalid position"));
+    }
+    let limit = match limit {
+        Some(limit) if limit > 0 && limit <= 1000 => limit,
+        Some(_) => return Ok(HttpResponse::BadRequest().body("Invalid limit")),
+        None => 100,
+    };
+    let after = match cursor.map(|c| Cursor::decode(&c)) {
+        Some(Some(cursor)) => Some(cursor),
+        Some(None) => return Ok(HttpResponse::BadRequest().body("Invalid cursor")),
+        None => None,
+    };
+    let events = logging::block_with_request_id(move || {
+        events.search(&module, &name, &params, limit, after.as_ref())
+    })
+    .await?
+    .map_err(error::ErrorInternalServerError)?;
+    Ok(page_response(&events, limit).json(events))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/modules",
+    params(("min_height" = Option<i64>, Query)),
+    responses(
+        (status = 200, description = "The modules with their number of events and chains", body = Object),
+        (status = 400, description = "Invalid min_height")
+    )
+))]
+#[get("/modules")]
+async fn modules(
+    request: HttpRequest,
+    events: web::Data<EventsRepository>,
 ) -> actix_web::Result<impl Responder> {
     let params = web::Query::<HashMap<String, String>>::from_query(request.query_string()).unwrap();
-    let from = params.get("from").map(|e| e.to_string());
-    let to = params.get("to").map(|e| e.to_string());
     let min_height = match params.get("min_height").map(|h| h.parse::<i64>()) {
         Some(Ok(height)) => Some(height),
         Some(Err(_)) => return Ok(HttpResponse::BadRequest().body("Invalid min_height")),
         None => None,
     };
-    let transfers = web::block(move || transfers.find(from, to, min_height))
+    let modules = logging::block_with_request_id(move || events.list_modules(min_height))
         .await?
         .map_err(error::ErrorInternalServerError)?;
-    Ok(HttpResponse::Ok().json(transfers))
+    Ok(ok_response(modules.truncated).json(
+        modules
+            .rows
+            .into_iter()
+            .map(|(module, count, chains)| {
+                serde_json::json!({ "module": module, "count": count, "chains": chains })
+            })
+            .collect::<Vec<serde_json::Value>>(),
+    ))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/stats/volume",
+    params(
+        ("module" = String, Query),
+        ("min_height" = Option<i64>, Query),
+        ("max_height" = Option<i64>, Query),
+        ("exclude_self_transfers" = Option<bool>, Query, description = "Defaults to false")
+    ),
+    responses(
+        (status = 200, description = "The volume per chain and the total volume", body = Object),
+        (status = 400, description = "Missing module, invalid heights or invalid exclude_self_transfers")
+    )
+))]
+#[get("/stats/volume")]
+async fn volume(
+    request: HttpRequest,
+    transfers: web::Data<TransfersRepository>,
+) -> actix_web::Result<impl Responder> {
+    let params = web::Query::<HashMap<String, String>>::from_query(request.query_string()).unwrap();
+    let module = match params.get("module") {
+        Some(module) => module.to_string(),
+        None => return Ok(HttpResponse::BadRequest().body("Missing module")),
+    };
+    let min_height = match params.get("min_height").map(|h| h.parse::<i64>()) {
+        Some(Ok(height)) => Some(height),
+        Some(Err(_)) => return Ok(HttpResponse::BadRequest().body("Invalid min_height")),
+        None => None,
+    };
+    let max_height = match params.get("max_height").map(|h| h.parse::<i64>()) {
+        Some(Ok(height)) => Some(height),
+        Some(Err(_)) => return Ok(HttpResponse::BadRequest().body("Invalid max_height")),
+        None => None,
+    };
+    let exclude_self_transfers = match params.get("exclude_self_transfers").map(|e| e.parse()) {
+        Some(Ok(exclude)) => exclude,
+        Some(Err(_)) => {
+            return Ok(HttpResponse::BadRequest().body("Invalid exclude_self_transfers"))
+        }
+        None => false,
+    };
+    let query_module = module.clone();
+    let (chains, total) = logging::block_with_request_id(move || {
+        transfers.volume(
+            &query_module,
+            min_height,
+            max_height,
+            exclude_self_transfers,
+        )
+    })
+    .await?
+    .map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "module": module,
+        "min_height": min_height,
+        "max_height": max_height,
+        "exclude_self_transfers": exclude_self_transfers,
+        "chains": chains,
+        "total": total,
+    })))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/stats/supply",
+    params(("module" = String, Query)),
+    responses(
+        (status = 200, description = "The amounts minted minus the amounts burned per chain and overall", body = Object),
+        (status = 400, description = "Missing module")
+    )
+))]
+#[get("/stats/supply")]
+async fn supply(
+    request: HttpRequest,
+    transfers: web::Data<TransfersRepository>,
+) -> actix_web::Result<impl Responder> {
+    let params = web::Query::<HashMap<String, String>>::from_query(request.query_string()).unwrap();
+    let module = match params.get("module") {
+        Some(module) => module.to_string(),
+        None => return Ok(HttpResponse::BadRequest().body("Missing module")),
+    };
+    let query_module = module.clone();
+    let (chains, total) = logging::block_with_request_id(move || transfers.supply(&query_module))
+        .await?
+        .map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "module": module,
+        "chains": chains,
+        "total": total,
+    })))
+}
+
+/// Maximum number of heights the gas statistics are computed over, the medians sort all the
+/// transactions of the range.
+const MAX_GAS_STATS_SPAN: i64 = 10_000;
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/stats/gas",
+    params(
+        ("chain_id" = Option<i64>, Query, description = "Defaults to all chains"),
+        ("min_height" = Option<i64>, Query, description = "Defaults to the last 10000 heights"),
+        ("max_height" = Option<i64>, Query, description = "Defaults to the highest indexed block")
+    ),
+    responses(
+        (status = 200, description = "The average and median gas price and gas used per chain, none for chains without transactions in the range", body = Object),
+        (status = 400, description = "Invalid chain_id or heights, or a range of more than 10000 heights")
+    )
+))]
+#[get("/stats/gas")]
+async fn gas_stats(
+    request: HttpRequest,
+    blocks: web::Data<BlocksRepository>,
+    transactions: web::Data<TransactionsRepository>,
+) -> actix_web::Result<impl Responder> {
+    let params = web::Query::<HashMap<String, String>>::from_query(request.query_string()).unwrap();
+    let chain_id = match params.get("chain_id").map(|c| c.parse::<i64>()) {
+        Some(Ok(chain_id)) => match ChainId::validate(chain_id, MAINNET_CHAINS) {
+            Ok(chain) => Some(chain.0 as i64),
+            Err(e) => return Ok(HttpResponse::BadRequest().body(e)),
+        },
+        Some(Err(_)) => return Ok(HttpResponse::BadRequest().body("Invalid chain_id")),
+        None => None,
+    };
+    let min_height = match params.get("min_height").map(|h| h.parse::<i64>()) {
+        Some(Ok(height)) => Some(height),
+        Some(Err(_)) => return Ok(HttpResponse::BadRequest().body("Invalid min_height")),
+        None => None,
+    };
+    let max_height = match params.get("max_height").map(|h| h.parse::<i64>()) {
+        Some(Ok(height)) => height,
+        Some(Err(_)) => return Ok(HttpResponse::BadRequest().body("Invalid max_height")),
+        None => logging::block_with_request_id(move || blocks.latest_per_chain())
+            .await?
+            .map_err(error::ErrorInternalServerError)?
+            .iter()
+            .filter(|block| chain_id.map_or(true, |chain_id| block.chain_id == chain_id))
+            .map(|block| block.height)
+            .max()
+            .unwrap_or(0),
+    };
+    let min_height =
+        min_height.unwrap_or_else(|| max_height.saturating_sub(MAX_GAS_STATS_SPAN - 1).max(0));
+    if max_height.saturating_sub(min_height) >= MAX_GAS_STATS_SPAN {
+        return Ok(HttpResponse::BadRequest().body(format!(
+            "The range of heights can't span more than {} heights",
+            MAX_GAS_STATS_SPAN
+        )));
+    }
+    let chains = logging::block_with_request_id(move || {
+        transactions.gas_stats(chain_id, min_height, max_height)
+    })
+    .await?
+    .map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "chain_id": chain_id,
+        "min_height": min_height,
+        "max_height": max_height,
+        "chains": chains,
+    })))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/account/{account}/guard",
+    params(("account" = String, Path)),
+    responses((status = 200, body = Vec<AccountGuard>), (status = 404, description = "Guard not found"))
+))]
+#[get("/account/{account}/guard")]
+async fn account_guard(
+    path: web::Path<String>,
+    account_guards: web::Data<AccountGuardsRepository>,
+) -> actix_web::Result<impl Responder> {
+    let account = path.into_inner();
+    let guards = logging::block_with_request_id(move || account_guards.find_by_account(&account))
+        .await?
+        .map_err(error::ErrorInternalServerError)?;
+    Ok(match guards.rows.is_empty() {
+        true => HttpResponse::NotFound().body("Guard not found"),
+        false => ok_response(guards.truncated).json(guards.rows),
+    })
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/signer/{public_key}/transactions",
+    params(
+        ("public_key" = String, Path, description = "Public key of one of the signers"),
+        ("limit" = Option<i64>, Query, description = "Defaults to 100, at most 1000"),
+        ("cursor" = Option<String>, Query, description = "X-Next-Cursor header of the previous page")
+    ),
+    responses(
+        (status = 200, description = "The transactions signed with the key, most recent first", body = Vec<Transaction>, headers(("X-Next-Cursor" = String, description = "Cursor of the next page, only set when the page is full"))),
+        (status = 400, description = "Invalid limit or cursor")
+    )
+))]
+#[get("/signer/{public_key}/transactions")]
+async fn signer_transactions(
+    path: web::Path<String>,
+    request: HttpRequest,
+    transactions: web::Data<TransactionsRepository>,
+) -> actix_web::Result<impl Responder> {
+    let public_key = path.into_inner();
+    let params = web::Query::<HashMap<String, String>>::from_query(request.query_string()).unwrap();
+    let (limit, after) = match page_params(&params) {
+        Ok(page) => page,
+        Err(response) => return Ok(response),
+    };
+    let signed = logging::block_with_request_id(move || {
+        transactions.find_by_signer(&public_key, limit, after.as_ref())
+    })
+    .await?
+    .map_err(error::ErrorInternalServerError)?;
+    Ok(page_response(&signed, limit).json(signed))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/transactions",
+    params(
+        ("sender" = Option<String>, Query),
+        ("code" = Option<String>, Query, description = "Text the code of the transactions contains, eg. coin.transfer"),
+        ("status" = Option<String>, Query, description = "success or failure"),
+        ("limit" = Option<i64>, Query, description = "Defaults to 100, at most 1000"),
+        ("cursor" = Option<String>, Query, description = "X-Next-Cursor header of the previous page")
+    ),
+    responses(
+        (status = 200, description = "The matching transactions, most recent first", body = Vec<Transaction>, headers(("X-Next-Cursor" = String, description = "Cursor of the next page, only set when the page is full"))),
+        (status = 400, description = "Invalid status, limit or cursor")
+    )
+))]
+#[get("/transactions")]
+async fn search_transactions(
+    request: HttpRequest,
+    transactions: web::Data<TransactionsRepository>,
+) -> actix_web::Result<impl Responder> {
+    let params = web::Query::<HashMap<String, String>>::from_query(request.query_string()).unwrap();
+    let sender = params.get("sender").map(|e| e.to_string());
+    let code = params.get("code").map(|e| e.to_string());
+    let status = match params.get("status").map(|e| e.as_str()) {
+        Some(status @ ("success" | "failure")) => Some(status.to_string()),
+        Some(_) => return Ok(HttpResponse::BadRequest().body("Invalid status")),
+        None => None,
+    };
+    let (limit, after) = match page_params(&params) {
+        Ok(page) => page,
+        Err(response) => return Ok(response),
+    };
+    let found = logging::block_with_request_id(move || {
+        transactions.search(sender, code, status, limit, after.as_ref())
+    })
+    .await?
+    .map_err(error::ErrorInternalServerError)?;
+    Ok(page_response(&found, limit).json(found))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/account/{account}/first-seen",
+    params(("account" = String, Path)),
+    responses((status = 200, body = Object), (status = 404, description = "Account not found"))
+))]
+#[get("/account/{account}/first-seen")]
+async fn account_first_seen(
+    path: web::Path<String>,
+    transfers: web::Data<TransfersRepository>,
+) -> actix_web::Result<impl Responder> {
+    let account = path.into_inner();
+    let query_account = account.clone();
+    let first_seen = logging::block_with_request_id(move || transfers.first_seen(&query_account))
+        .await?
+        .map_err(error::ErrorInternalServerError)?;
+    Ok(match first_seen {
+        Some((height, creation_time)) => HttpResponse::Ok().json(serde_json::json!({
+            "account": account,
+            "height": height,
+            "creation_time": creation_time,
+        })),
+        None => HttpResponse::NotFound().body("Account not found"),
+    })
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/account/{account}/ledger",
+    params(
+        ("account" = String, Path),
+        ("module" = String, Query),
+        ("format" = Option<String>, Query, description = "json (default) or csv")
+    ),
+    responses(
+        (status = 200, description = "Transfers of the account in chronological order, with the change and running balance of the account", body = Object),
+        (status = 400, description = "Missing module or invalid format")
+    )
+))]
+#[get("/account/{account}/ledger")]
+async fn account_ledger(
+    path: web::Path<String>,
+    request: HttpRequest,
+    transfers: web::Data<TransfersRepository>,
+) -> actix_web::Result<impl Responder> {
+    let account = path.into_inner();
+    let params = web::Query::<HashMap<String, String>>::from_query(request.query_string()).unwrap();
+    let module = match params.get("module") {
+        Some(module) => module.to_string(),
+        None => return Ok(HttpResponse::BadRequest().body("Missing module")),
+    };
+    let csv = match params.get("format").map(|format| format.as_str()) {
+        None | Some("json") => false,
+        Some("csv") => true,
+        Some(_) => return Ok(HttpResponse::BadRequest().body("Invalid format")),
+    };
+    let ledger = logging::block_with_request_id(move || transfers.ledger(&account, &module))
+        .await?
+        .map_err(error::ErrorInternalServerError)?;
+    Ok(match csv {
+        true => ok_response(ledger.truncated)
+            .content_type("text/csv")
+            .body(ledger_csv(&ledger.rows)),
+        false => ok_response(ledger.truncated).json(ledger.rows),
+    })
+}
+
+fn ledger_csv(ledger: &[LedgerEntry]) -> String {
+    let mut csv = String::from(
+        "height,chain_id,creation_time,request_key,from_account,to_account,amount,change,balance\n",
+    );
+    for entry in ledger {
+        let transfer = &entry.transfer;
+        let row = [
+            transfer.height.to_string(),
+            transfer.chain_id.to_string(),
+            transfer.creation_time.to_string(),
+            transfer.request_key.clone(),
+            transfer.from_account.clone(),
+            transfer.to_account.clone(),
+            transfer.amount.to_string(),
+            entry.change.to_string(),
+            entry.balance.to_string(),
+        ];
+        csv.push_str(&row.map(|field| csv_field(&field)).join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+/// Quotes the field if needed, accounts can be any string.
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/account/{account}/summary",
+    params(("account" = String, Path)),
+    responses(
+        (status = 200, description = "Number of transactions and transfers of the account, first and last seen heights and balances per module and chain", body = Object),
+        (status = 404, description = "Account not found")
+    )
+))]
+#[get("/account/{account}/summary")]
+async fn account_summary(
+    path: web::Path<String>,
+    transactions: web::Data<TransactionsRepository>,
+    transfers: web::Data<TransfersRepository>,
+    cache: web::Data<AccountSummaryCache>,
+) -> actix_web::Result<impl Responder> {
+    let account = path.into_inner();
+    if let Some(summary) = cache.get(&account) {
+        return Ok(HttpResponse::Ok().json(summary));
+    }
+    let query_account = account.clone();
+    let summary = logging::block_with_request_id(
+        move || -> Result<Option<serde_json::Value>, db::DbError> {
+            let transactions_sent = transactions.count_by_sender(&query_account)?;
+            let (transfers_sent, transfers_received) =
+                transfers.count_by_account(&query_account)?;
+            if transactions_sent == 0 && transfers_sent == 0 && transfers_received == 0 {
+                return Ok(None);
+            }
+            let seen = |seen: Option<(i64, NaiveDateTime)>| {
+                seen.map(|(height, creation_time)| {
+                serde_json::json!({ "height": height, "creation_time": creation_time })
+            })
+            };
+            let balances = transfers.calculate_all_balances(&query_account, None)?;
+            Ok(Some(serde_json::json!({
+                "account": query_account,
+                "transactions_sent": transactions_sent,
+                "transfers_sent": transfers_sent,
+                "transfers_received": transfers_received,
+                "first_seen": seen(transfers.first_seen(&query_account)?),
+                "last_seen": seen(transfers.last_seen(&query_account)?),
+                "balances": balances,
+            })))
+        },
+    )
+    .await?
+    .map_err(error::ErrorInternalServerError)?;
+    Ok(match summary {
+        Some(summary) => {
+            cache.insert(account, summary.clone());
+            HttpResponse::Ok().json(summary)
+        }
+        None => HttpResponse::NotFound().body("Account not found"),
+    })
+}
+
+#[get("/metrics")]
+async fn metrics(
+    all_balances_cache: web::Data<AllBalancesCache>,
+    balance_cache: web::Data<BalanceCache>,
+    account_summary_cache: web::Data<AccountSummaryCache>,
+) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(render_cache_metrics(&[
+            ("all_balances", all_balances_cache.stats()),
+            ("balance", balance_cache.stats()),
+            ("account_summary", account_summary_cache.stats()),
+        ]))
+}
+
+/// Maximum age of the latest block of a chain for the chain to be considered synced.
+struct SyncThreshold(Duration);
+
+fn is_synced(
+    last_block_time: Option<NaiveDateTime>,
+    now: NaiveDateTime,
+    threshold: Duration,
+) -> bool {
+    match last_block_time {
+        Some(time) => now.signed_duration_since(time).num_seconds() <= threshold.as_secs() as i64,
+        None => false,
+    }
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/tip",
+    responses((status = 200, description = "The height, hash and creation time of the latest indexed block of every chain, by chain id", body = Object))
+))]
+#[get("/tip")]
+async fn tip(blocks: web::Data<BlocksRepository>) -> actix_web::Result<impl Responder> {
+    let latest = logging::block_with_request_id(move || blocks.latest_per_chain())
+        .await?
+        .map_err(error::ErrorInternalServerError)?;
+    let chains = latest
+        .into_iter()
+        .map(|block| {
+            (
+                block.chain_id.to_string(),
+                serde_json::json!({
+                    "height": block.height,
+                    "hash": block.hash,
+                    "creation_time": block.creation_time,
+                }),
+            )
+        })
+        .collect::<serde_json::Map<String, serde_json::Value>>();
+    Ok(HttpResponse::Ok().json(chains))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/status",
+    responses((status = 200, description = "The time of the latest indexed block of every chain and whether it's recent enough to be synced", body = Object))
+))]
+#[get("/status")]
+async fn status(
+    blocks: web::Data<BlocksRepository>,
+    threshold: web::Data<SyncThreshold>,
+) -> actix_web::Result<impl Responder> {
+    let times = logging::block_with_request_id(move || {
+        (0..MAINNET_CHAINS as i64)
+            .map(|chain_id| Ok((chain_id, blocks.latest_block_time(chain_id)?)))
+            .collect::<Result<Vec<(i64, Option<NaiveDateTime>)>, db::DbError>>()
+    })
+    .await?
+    .map_err(error::ErrorInternalServerError)?;
+    let now = Utc::now().naive_utc();
+    let chains = times
+        .into_iter()
+        .map(|(chain_id, last_block_time)| {
+            serde_json::json!({
+                "chain_id": chain_id,
+                "last_block_time": last_block_time,
+                "synced": is_synced(last_block_time, now, threshold.0),
+            })
+        })
+        .collect::<Vec<serde_json::Value>>();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "synced": chains.iter().all(|chain| chain["synced"] == true),
+        "chains": chains,
+    })))
+}
+
+/// Id of a request, taken from the request when valid, generated otherwise, and echoed in the
+/// response so that the log lines of a request can be found.
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Request ids from clients are only kept if they can't mess with the log lines.
+fn is_valid_request_id(request_id: &str) -> bool {
+    !request_id.is_empty()
+        && request_id.len() <= 128
+        && request_id.chars().all(|c| c.is_ascii_graphic())
+}
+
+/// Middleware handling every request with its id, added to the log lines written meanwhile,
+/// and logging how long the request took.
+fn request_id<S, B>(
+    request: ServiceRequest,
+    service: &S,
+) -> impl std::future::Future<Output = Result<ServiceResponse<B>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_valid_request_id(id))
+        .map(|id| id.to_string())
+        .unwrap_or_else(|| format!("{:032x}", rand::random::<u128>()));
+    let method = request.method().clone();
+    let path = request.path().to_string();
+    let started = Instant::now();
+    let response = service.call(request);
+    logging::with_request_id(id.clone(), async move {
+        let mut response = response.await?;
+        log::info!(
+            "{} {} {} in {:.2?}",
+            method,
+            path,
+            response.status().as_u16(),
+            started.elapsed()
+        );
+        response.headers_mut().insert(
+            header::HeaderName::from_static(REQUEST_ID_HEADER),
+            header::HeaderValue::from_str(&id).unwrap(),
+        );
+        Ok(response)
+    })
+}
+
+/// Bearer token of the admin endpoints, which are disabled when `API_ADMIN_TOKEN` is not set.
+struct AdminToken(Option<String>);
+
+impl AdminToken {
+    fn authorizes(&self, request: &HttpRequest) -> bool {
+        let provided = request
+            .headers()
+            .get(actix_web::http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match (&self.0, provided) {
+            (Some(token), Some(provided)) => {
+                constant_time_eq(token.as_bytes(), provided.as_bytes())
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Compares in constant time, so that the token can't be guessed from the response times.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Middleware rejecting the requests to the routes under `/admin` without the admin bearer
+/// token with a 401. The other routes are read-only and stay open.
+fn admin_auth<S, B>(
+    request: ServiceRequest,
+    service: &S,
+) -> impl std::future::Future<Output = Result<ServiceResponse<EitherBody<B>>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let path = request.path();
+    let authorized = !(path == "/admin" || path.starts_with("/admin/"))
+        || request
+            .app_data::<web::Data<AdminToken>>()
+            .is_some_and(|token| token.authorizes(request.request()));
+    let response = match authorized {
+        true => Ok(service.call(request)),
+        false => Err(request.into_response(HttpResponse::Unauthorized().body("Unauthorized"))),
+    };
+    async move {
+        match response {
+            Ok(response) => response.await.map(ServiceResponse::map_into_left_body),
+            Err(unauthorized) => Ok(unauthorized.map_into_right_body()),
+        }
+    }
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    post,
+    path = "/admin/fill-gaps",
+    params(("chain_id" = i64, Query)),
+    responses(
+        (status = 200, description = "The gaps found on the chain, with the error of those which couldn't be filled", body = Object),
+        (status = 400, description = "Missing or invalid chain_id"),
+        (status = 401, description = "Missing or invalid bearer token")
+    )
+))]
+#[post("/admin/fill-gaps")]
+async fn admin_fill_gaps(
+    request: HttpRequest,
+    chainweb_client: web::Data<ChainwebClient>,
+    blocks: web::Data<BlocksRepository>,
+) -> actix_web::Result<impl Responder> {
+    let params = web::Query::<HashMap<String, String>>::from_query(request.query_string()).unwrap();
+    let chain = match params.get("chain_id").map(|c| c.parse::<i64>()) {
+        Some(Ok(chain_id)) => match ChainId::validate(chain_id, MAINNET_CHAINS) {
+            Ok(chain) => chain,
+            Err(e) => return Ok(HttpResponse::BadRequest().body(e)),
+        },
+        Some(Err(_)) => return Ok(HttpResponse::BadRequest().body("Invalid chain_id")),
+        None => return Ok(HttpResponse::BadRequest().body("Missing chain_id")),
+    };
+    let chain_id = chain.0 as i64;
+    let query_blocks = blocks.clone();
+    let query_chain = chain.clone();
+    let found_gaps =
+        logging::block_with_request_id(move || gaps::find_gaps(&query_chain, &query_blocks))
+            .await?
+            .map_err(error::ErrorInternalServerError)?;
+    log::info!(
+        "Filling {} gaps of chain {} on demand",
+        found_gaps.len(),
+        chain_id
+    );
+    let indexer = Indexer::from_env(&chainweb_client, &blocks.pool);
+    let mut filled = gaps::fill_chain_gaps(&chain, found_gaps, &indexer).await;
+    filled.sort_by_key(|gap| gap.lower_height);
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "chain_id": chain_id,
+        "missing_blocks": filled.iter().map(|gap| gap.missing_blocks).sum::<i64>(),
+        "failed": filled.iter().filter(|gap| gap.error.is_some()).count(),
+        "gaps": filled,
+    })))
+}
+
+#[cfg(feature = "openapi")]
+mod openapi {
+    use super::*;
+    use utoipa::OpenApi;
+
+    #[derive(OpenApi)]
+    #[openapi(
+        paths(
+            crate::tx,
+            crate::tx_full,
+            crate::pact,
+            crate::txs,
+            crate::transactions_batch,
+            crate::all_balances,
+            crate::balance,
+            crate::get_transfers,
+            crate::count_transfers,
+            crate::get_events,
+            crate::latest_events,
+            crate::search_events,
+            crate::modules,
+            crate::volume,
+            crate::supply,
+            crate::gas_stats,
+            crate::account_guard,
+            crate::signer_transactions,
+            crate::search_transactions,
+            crate::account_first_seen,
+            crate::account_ledger,
+            crate::account_summary,
+            crate::status,
+            crate::tip,
+            crate::admin_fill_gaps
+        ),
+        components(schemas(AccountGuard, Block, Event, Transaction, Transfer))
+    )]
+    struct ApiDoc;
+
+    #[get("/openapi.json")]
+    pub async fn openapi_json() -> impl Responder {
+        HttpResponse::Ok().json(ApiDoc::openapi())
+    }
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
     dotenv().ok();
+    logging::init();
     let port = env::var("API_PORT")
         .unwrap_or_else(|_| "80".to_string())
         .parse::<u16>()
         .expect("Invalid API_PORT");
+    let bind_addr = env::var("API_BIND_ADDR").unwrap_or_else(|_| "0.0.0.0".to_string());
+    let workers = env::var("API_WORKERS")
+        .ok()
+        .map(|workers| workers.parse::<usize>().expect("Invalid API_WORKERS"));
+    log::info!("Lists capped at {} rows", db::max_rows());
+
+    let sync_threshold = env::var("STATUS_SYNC_THRESHOLD")
+        .unwrap_or_else(|_| "120".to_string())
+        .parse::<u64>()
+        .expect("Invalid STATUS_SYNC_THRESHOLD");
 
     let pool = db::initialize_db_pool();
+    db::spawn_pool_monitor(pool.clone());
+    // Migrations are run by the indexer, only warn about a schema which doesn't match the API
+    let migrations = db::migration_status(&mut pool.get().expect("Failed to get a connection"))
+        .expect("Failed to get the migration status");
+    if !migrations.pending.is_empty() || !migrations.unknown.is_empty() {
+        log::warn!(
+            "The database schema doesn't match this binary, pending migrations: {:?}, unknown migrations: {:?}",
+            migrations.pending,
+            migrations.unknown
+        );
+    }
+    let account_guards = AccountGuardsRepository { pool: pool.clone() };
+    let blocks = BlocksRepository { pool: pool.clone() };
+    let events = EventsRepository { pool: pool.clone() };
     let transactions = TransactionsRepository { pool: pool.clone() };
     let transfers = TransfersRepository { pool: pool.clone() };
+    let cache_ttl = env::var("BALANCE_CACHE_TTL")
+        .unwrap_or_else(|_| "5".to_string())
+        .parse::<u64>()
+        .expect("Invalid BALANCE_CACHE_TTL");
+    let cache_capacity = env::var("BALANCE_CACHE_CAPACITY")
+        .unwrap_or_else(|_| "10000".to_string())
+        .parse::<usize>()
+        .expect("Invalid BALANCE_CACHE_CAPACITY");
+    let all_balances_cache = web::Data::new(AllBalancesCache::new(
+        Duration::from_secs(cache_ttl),
+        cache_capacity,
+    ));
+    let balance_cache = web::Data::new(BalanceCache::new(
+        Duration::from_secs(cache_ttl),
+        cache_capacity,
+    ));
+    let summary_cache_ttl = env::var("ACCOUNT_SUMMARY_CACHE_TTL")
+        .unwrap_or_else(|_| "10".to_string())
+        .parse::<u64>()
+        .expect("Invalid ACCOUNT_SUMMARY_CACHE_TTL");
+    let account_summary_cache = web::Data::new(AccountSummaryCache::new(
+        Duration::from_secs(summary_cache_ttl),
+        cache_capacity,
+    ));
+
+    let sync_threshold = web::Data::new(SyncThreshold(Duration::from_secs(sync_threshold)));
+    let token_decimals = web::Data::new(TokenDecimals::from_env());
+    let admin_token = web::Data::new(AdminToken(
+        env::var("API_ADMIN_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty()),
+    ));
+    let received_transfers_sunset = web::Data::new(ReceivedTransfersSunset(
+        env::var("API_RECEIVED_TRANSFERS_SUNSET")
+            .ok()
+            .filter(|sunset| !sunset.is_empty())
+            .map(|sunset| {
+                DateTime::parse_from_rfc2822(&sunset)
+                    .expect("Invalid API_RECEIVED_TRANSFERS_SUNSET");
+                sunset
+            }),
+    ));
+    let chainweb_client = web::Data::new(ChainwebClient::new());
 
-    HttpServer::new(move || {
-        App::new()
+    let server = HttpServer::new(move || {
+        let app = App::new()
+            .wrap_fn(admin_auth)
+            .wrap(middleware::Compress::default())
+            .wrap_fn(request_id)
+            .app_data(web::Data::new(account_guards.clone()))
+            .app_data(web::Data::new(blocks.clone()))
+            .app_data(web::Data::new(events.clone()))
             .app_data(web::Data::new(transactions.clone()))
             .app_data(web::Data::new(transfers.clone()))
+            .app_data(all_balances_cache.clone())
+            .app_data(balance_cache.clone())
+            .app_data(account_summary_cache.clone())
+            .app_data(sync_threshold.clone())
+            .app_data(token_decimals.clone())
+            .app_data(admin_token.clone())
+            .app_data(received_transfers_sunset.clone())
+            .app_data(chainweb_client.clone())
             .service(tx)
+            .service(tx_full)
+            .service(pact)
             .service(txs)
+            .service(transactions_batch)
             .service(balance)
             .service(all_balances)
             .service(received_transfers)
             .service(get_transfers)
-    })
-    .bind(("0.0.0.0", port))?
-    .run()
-    .await
+            .service(count_transfers)
+            .service(account_first_seen)
+            .service(account_guard)
+            .service(signer_transactions)
+            .service(search_transactions)
+            .service(account_ledger)
+            .service(account_summary)
+            .service(get_events)
+            .service(latest_events)
+            .service(search_events)
+            .service(modules)
+            .service(volume)
+            .service(supply)
+            .service(gas_stats)
+            .service(status)
+            .service(tip)
+            .service(metrics)
+            .service(admin_fill_gaps);
+        #[cfg(feature = "openapi")]
+        let app = app.service(openapi::openapi_json);
+        app
+    });
+    let server = match workers {
+        Some(workers) => server.workers(workers),
+        None => server,
+    };
+    server.bind((bind_addr, port))?.run().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test as actix_test;
+    use serial_test::serial;
+
+    fn make_block(height: i64) -> Block {
+        Block {
+            chain_id: 0,
+            hash: format!("hash-{}", height),
+            height,
+            parent: "parent".to_string(),
+            weight: BigDecimal::from(0),
+            creation_time: Utc::now().naive_utc(),
+            epoch: Utc::now().naive_utc(),
+            flags: BigDecimal::from(0),
+            miner: "miner".to_string(),
+            nonce: BigDecimal::from(0),
+            payload: "payload".to_string(),
+            pow_hash: "".to_string(),
+            predicate: "predicate".to_string(),
+            target: BigDecimal::from(1),
+            difficulty: None,
+        }
+    }
+
+    fn make_transfer(height: i64, idx: i64) -> Transfer {
+        Transfer {
+            amount: BigDecimal::from(1),
+            authorized_amount: None,
+            block: format!("hash-{}", height),
+            chain_id: 0,
+            creation_time: Utc::now().naive_utc(),
+            from_account: "alice".to_string(),
+            height,
+            idx,
+            module_hash: "module-hash".to_string(),
+            module_name: "coin".to_string(),
+            pact_id: None,
+            request_key: format!("request-key-{}-{}", height, idx),
+            self_transfer: false,
+            to_account: "bob".to_string(),
+        }
+    }
+
+    fn make_transaction(request_key: &str) -> Transaction {
+        Transaction {
+            bad_result: None,
+            block: "hash-1".to_string(),
+            chain_id: 0,
+            code: None,
+            continuation: None,
+            creation_time: Utc::now().naive_utc(),
+            data: None,
+            error_message: None,
+            error_type: None,
+            gas: 0,
+            gas_limit: 0,
+            gas_price: 0.0,
+            good_result: None,
+            height: 1,
+            is_gas_station: false,
+            keysets: None,
+            logs: None,
+            metadata: None,
+            nonce: "nonce".to_string(),
+            num_events: None,
+            pact_id: None,
+            proof: None,
+            raw_cmd: None,
+            request_key: request_key.to_string(),
+            rollback: None,
+            sender: "sender".to_string(),
+            signatures_valid: None,
+            signers: vec![],
+            status: "success".to_string(),
+            step: None,
+            ttl: 0,
+            tx_id: None,
+        }
+    }
+
+    #[test]
+    fn test_ttl_cache_expires_and_evicts_entries() {
+        let cache = TtlCache::new(Duration::from_millis(50), 2);
+        assert_eq!(cache.get(&"a"), None);
+        cache.insert("a", 1);
+        assert_eq!(cache.get(&"a"), Some(1));
+        cache.insert("b", 2);
+        cache.insert("c", 3);
+        assert_eq!(cache.get(&"a"), None);
+        assert_eq!(cache.get(&"b"), Some(2));
+        assert_eq!(cache.get(&"c"), Some(3));
+        assert_eq!(cache.hits.load(Ordering::Relaxed), 3);
+        assert_eq!(cache.misses.load(Ordering::Relaxed), 2);
+        // Inserted again, b is now newer than c
+        cache.insert("b", 4);
+        cache.insert("d", 5);
+        assert_eq!(cache.get(&"b"), Some(4));
+        assert_eq!(cache.get(&"c"), None);
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(cache.get(&"b"), None);
+        cache.insert("e", 6);
+        assert_eq!(cache.entries.lock().unwrap().values.len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_cache_metrics() {
+        let balance_cache = BalanceCache::new(Duration::from_secs(60), 10);
+        let key = ("alice".to_string(), "coin".to_string());
+        balance_cache.get(&key);
+        balance_cache.insert(key.clone(), HashMap::new());
+        balance_cache.get(&key);
+        balance_cache.get(&key);
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(AllBalancesCache::new(
+                    Duration::from_secs(60),
+                    10,
+                )))
+                .app_data(web::Data::new(balance_cache))
+                .app_data(web::Data::new(AccountSummaryCache::new(
+                    Duration::from_secs(60),
+                    10,
+                )))
+                .service(metrics),
+        )
+        .await;
+        let request = actix_test::TestRequest::get().uri("/metrics").to_request();
+        let body = actix_test::call_and_read_body(&app, request).await;
+        assert_eq!(
+            std::str::from_utf8(&body).unwrap(),
+            "# HELP bento_api_cache_hits_total Lookups of a cache which found a value.\n\
+             # TYPE bento_api_cache_hits_total counter\n\
+             bento_api_cache_hits_total{cache=\"all_balances\"} 0\n\
+             bento_api_cache_hits_total{cache=\"balance\"} 2\n\
+             bento_api_cache_hits_total{cache=\"account_summary\"} 0\n\
+             # HELP bento_api_cache_misses_total Lookups of a cache which found no value.\n\
+             # TYPE bento_api_cache_misses_total counter\n\
+             bento_api_cache_misses_total{cache=\"all_balances\"} 0\n\
+             bento_api_cache_misses_total{cache=\"balance\"} 1\n\
+             bento_api_cache_misses_total{cache=\"account_summary\"} 0\n"
+        );
+    }
+
+    #[test]
+    fn test_ledger_csv() {
+        let creation_time = NaiveDateTime::from_timestamp_opt(1700000000, 0).unwrap();
+        let ledger = vec![LedgerEntry {
+            transfer: Transfer {
+                creation_time,
+                to_account: "bob, \"the builder\"".to_string(),
+                ..make_transfer(1, 0)
+            },
+            change: BigDecimal::from(-1),
+            balance: "2.5".parse().unwrap(),
+        }];
+        assert_eq!(
+            ledger_csv(&ledger),
+            "height,chain_id,creation_time,request_key,from_account,to_account,amount,change,balance\n\
+             1,0,2023-11-14 22:13:20,request-key-1-0,alice,\"bob, \"\"the builder\"\"\",1,-1,2.5\n"
+        );
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_large_responses_are_compressed() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let transfers = TransfersRepository { pool: pool.clone() };
+        blocks.insert_batch(&[make_block(1)]).unwrap();
+        transfers
+            .insert_batch(&(0..200).map(|idx| make_transfer(1, idx)).collect())
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .wrap(middleware::Compress::default())
+                .app_data(web::Data::new(transfers.clone()))
+                .service(get_transfers),
+        )
+        .await;
+        let request = actix_test::TestRequest::get()
+            .uri("/transfers?from=alice")
+            .insert_header((header::ACCEPT_ENCODING, "gzip"))
+            .to_request();
+        let response = actix_test::call_service(&app, request).await;
+        assert!(response.status().is_success());
+        assert_eq!(
+            response.headers().get(header::CONTENT_ENCODING).unwrap(),
+            "gzip"
+        );
+        let body = actix_test::read_body(response).await;
+        assert!(serde_json::from_slice::<serde_json::Value>(&body).is_err());
+
+        transfers.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_gas_stats_range() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let transactions = TransactionsRepository { pool: pool.clone() };
+        blocks.insert_batch(&[make_block(20_000)]).unwrap();
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(blocks.clone()))
+                .app_data(web::Data::new(transactions.clone()))
+                .service(gas_stats),
+        )
+        .await;
+        let get = |uri: &str| actix_test::TestRequest::get().uri(uri).to_request();
+
+        let response = actix_test::call_service(&app, get("/stats/gas?chain_id=99")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response =
+            actix_test::call_service(&app, get("/stats/gas?min_height=0&max_height=10000")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Defaults to the last heights up to the highest indexed block
+        let body: serde_json::Value =
+            actix_test::call_and_read_body_json(&app, get("/stats/gas?chain_id=0")).await;
+        assert_eq!(body["min_height"], 10_001);
+        assert_eq!(body["max_height"], 20_000);
+        let body: serde_json::Value =
+            actix_test::call_and_read_body_json(&app, get("/stats/gas?max_height=5000")).await;
+        assert_eq!(body["min_height"], 0);
+
+        blocks.delete_all().unwrap();
+    }
+
+    #[actix_web::test]
+    #[serial]
+    #[allow(deprecated)]
+    async fn test_received_transfers_is_deprecated() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let transfers = TransfersRepository { pool: pool.clone() };
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(transfers.clone()))
+                .app_data(web::Data::new(ReceivedTransfersSunset(Some(
+                    "Sat, 01 Jun 2024 00:00:00 GMT".to_string(),
+                ))))
+                .service(received_transfers),
+        )
+        .await;
+        let request = actix_test::TestRequest::get()
+            .uri("/transfers/bob/received")
+            .to_request();
+        let response = actix_test::call_service(&app, request).await;
+        assert!(response.status().is_success());
+        let headers = response.headers();
+        assert_eq!(headers.get("Deprecation").unwrap(), "true");
+        assert_eq!(
+            headers.get("Sunset").unwrap(),
+            "Sat, 01 Jun 2024 00:00:00 GMT"
+        );
+        assert_eq!(
+            headers.get(header::LINK).unwrap(),
+            "</transfers>; rel=\"successor-version\""
+        );
+        assert!(headers.get(header::WARNING).is_some());
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_transactions_batch() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let transactions = TransactionsRepository { pool: pool.clone() };
+        blocks.insert_batch(&[make_block(1)]).unwrap();
+        transactions
+            .insert_batch(
+                &["request-key-1", "request-key-2", "request-key-3"]
+                    .iter()
+                    .map(|request_key| make_transaction(request_key))
+                    .collect::<Vec<Transaction>>(),
+            )
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(transactions.clone()))
+                .service(transactions_batch),
+        )
+        .await;
+        let request = actix_test::TestRequest::post()
+            .uri("/transactions/batch")
+            .set_json(serde_json::json!({
+                "request_keys": ["request-key-1", "request-key-3", "unknown"]
+            }))
+            .to_request();
+        let response: serde_json::Value = actix_test::call_and_read_body_json(&app, request).await;
+        let mut request_keys = response
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|transaction| transaction["request_key"].as_str().unwrap())
+            .collect::<Vec<&str>>();
+        request_keys.sort();
+        assert_eq!(request_keys, vec!["request-key-1", "request-key-3"]);
+
+        let request = actix_test::TestRequest::post()
+            .uri("/transactions/batch")
+            .set_json(serde_json::json!({
+                "request_keys": (0..=MAX_BATCH_REQUEST_KEYS)
+                    .map(|i| format!("request-key-{}", i))
+                    .collect::<Vec<String>>()
+            }))
+            .to_request();
+        let response = actix_test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        transactions.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_search_transactions_by_status() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let transactions = TransactionsRepository { pool: pool.clone() };
+        blocks.insert_batch(&[make_block(1)]).unwrap();
+        let failed = |request_key: &str, sender: &str| Transaction {
+            bad_result: Some(serde_json::json!({"message": "Insufficient funds"})),
+            sender: sender.to_string(),
+            status: "failure".to_string(),
+            ..make_transaction(request_key)
+        };
+        transactions
+            .insert_batch(&[
+                make_transaction("request-key-1"),
+                failed("request-key-2", "sender"),
+                failed("request-key-3", "other-sender"),
+            ])
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(transactions.clone()))
+                .service(search_transactions),
+        )
+        .await;
+        for (uri, expected) in [
+            (
+                "/transactions?status=failure",
+                vec!["request-key-2", "request-key-3"],
+            ),
+            (
+                "/transactions?status=failure&sender=sender",
+                vec!["request-key-2"],
+            ),
+            ("/transactions?status=success", vec!["request-key-1"]),
+        ] {
+            let request = actix_test::TestRequest::get().uri(uri).to_request();
+            let response: serde_json::Value =
+                actix_test::call_and_read_body_json(&app, request).await;
+            let mut request_keys = response
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|transaction| transaction["request_key"].as_str().unwrap())
+                .collect::<Vec<&str>>();
+            request_keys.sort();
+            assert_eq!(request_keys, expected, "{}", uri);
+        }
+
+        // Paginated with the cursor of the previous page
+        let mut uri = "/transactions?status=failure&limit=1".to_string();
+        let mut pages = vec![];
+        loop {
+            let request = actix_test::TestRequest::get().uri(&uri).to_request();
+            let response = actix_test::call_service(&app, request).await;
+            let next_cursor = response
+                .headers()
+                .get("X-Next-Cursor")
+                .map(|cursor| cursor.to_str().unwrap().to_string());
+            let page: serde_json::Value = actix_test::read_body_json(response).await;
+            pages.push(page.as_array().unwrap().len());
+            match next_cursor {
+                Some(cursor) => {
+                    uri = format!("/transactions?status=failure&limit=1&cursor={}", cursor)
+                }
+                None => break,
+            }
+        }
+        assert_eq!(pages, vec![1, 1, 0]);
+
+        for uri in [
+            "/transactions?status=failed",
+            "/transactions?limit=0",
+            "/transactions?cursor=invalid",
+        ] {
+            let request = actix_test::TestRequest::get().uri(uri).to_request();
+            let response = actix_test::call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        }
+
+        transactions.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_pact() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let transactions = TransactionsRepository { pool: pool.clone() };
+        blocks
+            .insert_batch(&[make_block(1), make_block(2)])
+            .unwrap();
+        let make_step = |request_key: &str, height: i64, step: i64| Transaction {
+            block: format!("hash-{}", height),
+            continuation: Some(serde_json::json!({
+                "pactId": "pact-id",
+                "step": step,
+                "stepCount": 2,
+                "stepHasRollback": false
+            })),
+            height,
+            pact_id: Some("pact-id".to_string()),
+            rollback: Some(false),
+            step: Some(step),
+            ..make_transaction(request_key)
+        };
+        transactions
+            .insert_batch(&[make_step("pact-id", 1, 0)])
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(transactions.clone()))
+                .service(pact),
+        )
+        .await;
+        let steps = |response: &serde_json::Value| {
+            response["steps"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|step| {
+                    (
+                        step["step"].as_i64().unwrap(),
+                        step["status"].as_str().unwrap().to_string(),
+                        step["transactions"].as_array().unwrap().len(),
+                    )
+                })
+                .collect::<Vec<(i64, String, usize)>>()
+        };
+        let request = actix_test::TestRequest::get()
+            .uri("/pact/pact-id")
+            .to_request();
+        let response: serde_json::Value = actix_test::call_and_read_body_json(&app, request).await;
+        assert_eq!(response["step_count"], 2);
+        assert_eq!(response["complete"], false);
+        assert_eq!(
+            steps(&response),
+            vec![(0, "success".to_string(), 1), (1, "pending".to_string(), 0)]
+        );
+
+        // The second step failed once before succeeding
+        transactions
+            .insert_batch(&[
+                Transaction {
+                    bad_result: Some(serde_json::json!({"message": "failure"})),
+                    status: "failure".to_string(),
+                    ..make_step("step-1-failed", 1, 1)
+                },
+                make_step("step-1", 2, 1),
+            ])
+            .unwrap();
+        let request = actix_test::TestRequest::get()
+            .uri("/pact/pact-id")
+            .to_request();
+        let response: serde_json::Value = actix_test::call_and_read_body_json(&app, request).await;
+        assert_eq!(response["complete"], true);
+        assert_eq!(
+            steps(&response),
+            vec![(0, "success".to_string(), 1), (1, "success".to_string(), 2)]
+        );
+        assert_eq!(
+            response["steps"][1]["transactions"][1]["request_key"],
+            "step-1"
+        );
+
+        // Steps before an executed step which aren't indexed are missing
+        transactions.delete_all().unwrap();
+        transactions
+            .insert_batch(&[make_step("step-1", 2, 1)])
+            .unwrap();
+        let request = actix_test::TestRequest::get()
+            .uri("/pact/pact-id")
+            .to_request();
+        let response: serde_json::Value = actix_test::call_and_read_body_json(&app, request).await;
+        assert_eq!(
+            steps(&response),
+            vec![(0, "missing".to_string(), 0), (1, "success".to_string(), 1)]
+        );
+
+        let request = actix_test::TestRequest::get()
+            .uri("/pact/unknown")
+            .to_request();
+        let response = actix_test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        transactions.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_admin_fill_gaps() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        blocks
+            .insert_batch(&[make_block(1), make_block(2), make_block(5)])
+            .unwrap();
+        // Nothing listens on this port, filling the gap fails without retrying
+        let node_host = env::var("CHAINWEB_NODE_HOST").unwrap();
+        env::set_var("CHAINWEB_NODE_HOST", "http://127.0.0.1:1");
+        env::set_var("INDEXER_CHAIN_RETRIES", "0");
+        let chainweb_client = ChainwebClient::new();
+
+        let app = actix_test::init_service(
+            App::new()
+                .wrap_fn(admin_auth)
+                .app_data(web::Data::new(blocks.clone()))
+                .app_data(web::Data::new(AdminToken(Some("secret".to_string()))))
+                .app_data(web::Data::new(chainweb_client))
+                .service(admin_fill_gaps),
+        )
+        .await;
+        let request = |uri: &str| {
+            actix_test::TestRequest::post()
+                .uri(uri)
+                .insert_header((header::AUTHORIZATION, "Bearer secret"))
+                .to_request()
+        };
+        let response =
+            actix_test::call_service(&app, request("/admin/fill-gaps?chain_id=20")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response: serde_json::Value =
+            actix_test::call_and_read_body_json(&app, request("/admin/fill-gaps?chain_id=0")).await;
+        env::set_var("CHAINWEB_NODE_HOST", node_host);
+        env::remove_var("INDEXER_CHAIN_RETRIES");
+        assert_eq!(response["chain_id"], 0);
+        assert_eq!(response["missing_blocks"], 2);
+        assert_eq!(response["failed"], 1);
+        assert_eq!(response["gaps"][0]["lower_height"], 2);
+        assert_eq!(response["gaps"][0]["upper_height"], 5);
+        assert!(response["gaps"][0]["error"].is_string());
+
+        blocks.delete_all().unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_admin_routes_require_the_token() {
+        let app = actix_test::init_service(
+            App::new()
+                .wrap_fn(admin_auth)
+                .app_data(web::Data::new(AdminToken(Some("secret".to_string()))))
+                .route("/admin/ping", web::post().to(HttpResponse::Ok))
+                .route("/administrators", web::get().to(HttpResponse::Ok))
+                .route("/ping", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let admin = || actix_test::TestRequest::post().uri("/admin/ping");
+        let response = actix_test::call_service(&app, admin().to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let request = admin()
+            .insert_header((header::AUTHORIZATION, "Bearer wrong"))
+            .to_request();
+        let response = actix_test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let request = admin()
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_request();
+        let response = actix_test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Read-only endpoints stay open
+        for uri in ["/ping", "/administrators"] {
+            let request = actix_test::TestRequest::get().uri(uri).to_request();
+            let response = actix_test::call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_request_id() {
+        let app = actix_test::init_service(App::new().wrap_fn(request_id).route(
+            "/",
+            web::get().to(|| async { logging::request_id().unwrap_or_default() }),
+        ))
+        .await;
+
+        // Ids from clients are kept
+        let request = actix_test::TestRequest::get()
+            .uri("/")
+            .insert_header(("X-Request-Id", "client-id"))
+            .to_request();
+        let response = actix_test::call_service(&app, request).await;
+        assert_eq!(response.headers().get("X-Request-Id").unwrap(), "client-id");
+        assert_eq!(actix_test::read_body(response).await, "client-id");
+
+        // Or generated when missing or invalid
+        for request in [
+            actix_test::TestRequest::get().uri("/"),
+            actix_test::TestRequest::get()
+                .uri("/")
+                .insert_header(("X-Request-Id", "with spaces")),
+        ] {
+            let response = actix_test::call_service(&app, request.to_request()).await;
+            let id = response.headers().get("X-Request-Id").unwrap().clone();
+            assert_eq!(id.len(), 32);
+            assert_eq!(actix_test::read_body(response).await, id.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_admin_token() {
+        let request = actix_test::TestRequest::default()
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_http_request();
+        assert!(AdminToken(Some("secret".to_string())).authorizes(&request));
+        assert!(!AdminToken(Some("secret2".to_string())).authorizes(&request));
+        // Admin endpoints are disabled without a token
+        assert!(!AdminToken(None).authorizes(&request));
+        let request = actix_test::TestRequest::default()
+            .insert_header((header::AUTHORIZATION, "secret"))
+            .to_http_request();
+        assert!(!AdminToken(Some("secret".to_string())).authorizes(&request));
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_status_reports_stale_chains() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        // Whole seconds as the database doesn't keep nanoseconds
+        let stale_time =
+            NaiveDateTime::from_timestamp_opt(Utc::now().timestamp() - 600, 0).unwrap();
+        blocks
+            .insert_batch(&[
+                make_block(1),
+                Block {
+                    chain_id: 1,
+                    hash: "stale-hash".to_string(),
+                    creation_time: stale_time,
+                    ..make_block(1)
+                },
+            ])
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(blocks.clone()))
+                .app_data(web::Data::new(SyncThreshold(Duration::from_secs(60))))
+                .service(status),
+        )
+        .await;
+        let request = actix_test::TestRequest::get().uri("/status").to_request();
+        let response: serde_json::Value = actix_test::call_and_read_body_json(&app, request).await;
+        assert_eq!(response["synced"], false);
+        let chains = response["chains"].as_array().unwrap();
+        assert_eq!(chains.len(), MAINNET_CHAINS as usize);
+        assert_eq!(chains[0]["synced"], true);
+        assert_eq!(chains[1]["synced"], false);
+        assert_eq!(chains[1]["last_block_time"], serde_json::json!(stale_time));
+        // Chains without blocks are never synced
+        assert_eq!(chains[2]["synced"], false);
+        assert!(chains[2]["last_block_time"].is_null());
+
+        blocks.delete_all().unwrap();
+    }
 }
@@ -1,10 +1,22 @@
-use bento::chainweb_client::ChainwebClient;
+use actix_web::{get, web, App, HttpResponse, HttpServer, Responder};
+use bento::chainweb_client::{ChainId, ChainwebClient, MAINNET_CHAINS};
 use bento::db;
 use bento::gaps;
 use bento::indexer::*;
+use bento::logging;
+use bento::maintenance;
+use bento::metrics::IndexerMetrics;
+use bento::pause;
+use bento::progress::IndexingProgress;
+use bento::repair;
 use bento::repository::*;
+use bento::transfers;
+use bento::verify;
 use clap::{Parser, Subcommand};
 use dotenvy::dotenv;
+use std::env;
+use std::error::Error;
+use std::sync::Arc;
 
 #[derive(Parser)]
 /// By default new blocks are indexed as they are mined. For backfilling and filling gaps use the
@@ -16,42 +28,230 @@ struct IndexerCli {
 
 #[derive(Subcommand)]
 enum Command {
-    /// Backfill blocks
-    Backfill,
+    /// Backfill blocks, exits with a non-zero code if some chains failed
+    Backfill {
+        /// Only index the blocks from this height
+        #[arg(long)]
+        min_height: Option<i64>,
+        /// Only index the blocks up to this height
+        #[arg(long)]
+        max_height: Option<i64>,
+        /// Backfill even more heights than INDEXER_MAX_BACKFILL_SPAN
+        #[arg(long)]
+        force: bool,
+    },
     /// Index missed blocks
-    Gaps,
+    Gaps {
+        /// Only find the gaps, without filling them
+        #[arg(long)]
+        detect_only: bool,
+        /// Write a JSON report of the gaps found and filled per chain to the given file,
+        /// or to stdout when no file is given
+        #[arg(long, value_name = "FILE", num_args = 0..=1, default_missing_value = "-")]
+        report_json: Option<String>,
+    },
+    /// Backfill transfers from the indexed events
+    Transfers {
+        /// Only reprocess the transfers of this chain, between --min-height and --max-height
+        #[arg(long, requires_all = ["min_height", "max_height"], value_parser = parse_chain_id)]
+        chain_id: Option<i64>,
+        #[arg(long, requires = "chain_id")]
+        min_height: Option<i64>,
+        #[arg(long, requires = "chain_id")]
+        max_height: Option<i64>,
+        /// Number of heights processed at once when backfilling
+        #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(i64).range(1..))]
+        batch_size: i64,
+    },
+    /// Verify the indexed blocks, exits with a non-zero code if discrepancies are found
+    Verify {
+        /// Number of random heights per chain to compare with the node
+        #[arg(long, default_value_t = 0)]
+        spot_checks: usize,
+    },
+    /// Print the applied and pending database migrations, without running them
+    Migrations,
+    /// Re-derive the module hash and qualified name of the events indexed before these were fixed.
+    /// Events already repaired are skipped, pass the last logged height to resume faster
+    Migrate {
+        /// Only repair the events of this chain
+        #[arg(long, value_parser = parse_chain_id)]
+        chain_id: Option<i64>,
+        #[arg(long, default_value_t = 0)]
+        from_height: i64,
+        /// Number of heights processed at once
+        #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(i64).range(1..))]
+        batch_size: i64,
+    },
+}
+
+/// Rejects the chains mainnet doesn't have, rather than failing later with errors from the node.
+fn parse_chain_id(chain_id: &str) -> Result<i64, String> {
+    let chain_id = chain_id
+        .parse::<i64>()
+        .map_err(|e| format!("Invalid chain id {}: {}", chain_id, e))?;
+    ChainId::validate(chain_id, MAINNET_CHAINS).map(|chain| chain.0 as i64)
+}
+
+#[get("/indexer/progress")]
+async fn indexer_progress(progress: web::Data<IndexingProgress>) -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({ "chains": progress.snapshot() }))
+}
+
+#[get("/metrics")]
+async fn indexer_metrics(metrics: web::Data<IndexerMetrics>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.render())
+}
+
+/// Serves the indexing rate of every chain and the metrics of the indexer on the given port
+/// while the indexer runs.
+fn serve_progress(
+    port: u16,
+    progress: Arc<IndexingProgress>,
+    metrics: Arc<IndexerMetrics>,
+) -> std::io::Result<()> {
+    let progress = web::Data::from(progress);
+    let metrics = web::Data::from(metrics);
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(progress.clone())
+            .app_data(metrics.clone())
+            .service(indexer_progress)
+            .service(indexer_metrics)
+    })
+    .workers(1)
+    .bind(("0.0.0.0", port))?
+    .run();
+    log::info!("Serving the indexing progress on port {}", port);
+    tokio::spawn(server);
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
     dotenv().ok();
+    logging::init();
 
+    let args = IndexerCli::parse();
     let pool = db::initialize_db_pool();
-    db::run_migrations(&mut pool.get().unwrap()).unwrap();
+    db::spawn_pool_monitor(pool.clone());
+    if !matches!(args.command, Some(Command::Migrations)) {
+        db::run_migrations(&mut pool.get()?).map_err(|e| e as Box<dyn Error>)?;
+        maintenance::spawn_table_maintenance(pool.clone());
+    }
 
     let blocks = BlocksRepository { pool: pool.clone() };
     let events = EventsRepository { pool: pool.clone() };
-    let transactions = TransactionsRepository { pool: pool.clone() };
     let transfers_repo = TransfersRepository { pool: pool.clone() };
     let chainweb_client = ChainwebClient::new();
-    let indexer = Indexer {
-        chainweb_client: &chainweb_client,
-        blocks: blocks.clone(),
-        events: events.clone(),
-        transactions: transactions.clone(),
-        transfers: transfers_repo.clone(),
-    };
+    let indexer = Indexer::from_env(&chainweb_client, &pool);
+    pause::toggle_on_sigusr1(indexer.pause.clone())?;
+    if let Ok(port) = env::var("INDEXER_PROGRESS_PORT") {
+        let port = port.parse::<u16>().expect("Invalid INDEXER_PROGRESS_PORT");
+        serve_progress(
+            port,
+            indexer.indexing_progress.clone(),
+            indexer.metrics.clone(),
+        )?;
+    }
 
-    let args = IndexerCli::parse();
     match args.command {
-        Some(Command::Backfill) => {
+        Some(Command::Backfill {
+            min_height,
+            max_height,
+            force,
+        }) => {
             log::info!("Backfilling blocks...");
-            indexer.backfill().await?;
+            let summary = indexer.backfill(min_height, max_height, force).await?;
+            let failed_chains = summary.failed_chains();
+            if !failed_chains.is_empty() {
+                log::error!(
+                    "Backfill failed on {} chains: {}",
+                    failed_chains.len(),
+                    failed_chains
+                        .iter()
+                        .map(|chain| chain.to_string())
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                );
+                std::process::exit(1);
+            }
+        }
+        Some(Command::Gaps {
+            detect_only,
+            report_json,
+        }) => {
+            match detect_only {
+                true => log::info!("Finding gaps..."),
+                false => log::info!("Filling gaps..."),
+            }
+            let report = gaps::fill_gaps(&chainweb_client, &blocks, &indexer, detect_only).await?;
+            match report_json.as_deref() {
+                Some("-") => println!("{}", serde_json::to_string_pretty(&report)?),
+                Some(path) => std::fs::write(path, serde_json::to_string_pretty(&report)?)?,
+                None => {}
+            }
+        }
+        Some(Command::Transfers {
+            chain_id,
+            min_height,
+            max_height,
+            batch_size,
+        }) => match (chain_id, min_height, max_height) {
+            (Some(chain_id), Some(min_height), Some(max_height)) => {
+                log::info!("Reprocessing transfers...");
+                transfers::reprocess_range(
+                    chain_id,
+                    min_height,
+                    max_height,
+                    &events,
+                    &blocks,
+                    &transfers_repo,
+                )
+                .map_err(|e| e as Box<dyn std::error::Error>)?;
+            }
+            _ => {
+                log::info!("Backfilling transfers...");
+                transfers::backfill(
+                    batch_size,
+                    &chainweb_client,
+                    &blocks,
+                    &events,
+                    &transfers_repo,
+                )
+                .await?;
+            }
+        },
+        Some(Command::Verify { spot_checks }) => {
+            log::info!("Verifying blocks...");
+            let discrepancies = verify::verify(&chainweb_client, &blocks, spot_checks).await?;
+            if !discrepancies.is_empty() {
+                log::error!("Found {} discrepancies", discrepancies.len());
+                std::process::exit(1);
+            }
+        }
+        Some(Command::Migrations) => {
+            let status = db::migration_status(&mut pool.get()?).map_err(|e| e as Box<dyn Error>)?;
+            for version in status.applied {
+                println!("[X] {}", version);
+            }
+            for version in status.pending {
+                println!("[ ] {}", version);
+            }
+            for version in status.unknown {
+                println!("[?] {} (unknown to this binary)", version);
+            }
         }
-        Some(Command::Gaps) => {
-            log::info!("Filling gaps...");
-            gaps::fill_gaps(&chainweb_client, &blocks, &indexer).await?;
+        Some(Command::Migrate {
+            chain_id,
+            from_height,
+            batch_size,
+        }) => {
+            log::info!("Repairing events...");
+            repair::repair_events(&chainweb_client, &events, chain_id, from_height, batch_size)
+                .await?;
         }
         None => {
             log::info!("Indexing blocks...");
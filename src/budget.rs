@@ -0,0 +1,169 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::Semaphore;
+
+/// Caps the approximate number of bytes of block payloads and transaction results held in memory
+/// at once by the chains indexed concurrently. Batches reserve their size before being fetched
+/// and processed, and wait while the budget is exhausted.
+pub struct MemoryBudget {
+    /// One permit per KiB.
+    semaphore: Semaphore,
+    capacity: u32,
+    /// Average size of the payloads fetched so far, to estimate the size of the next batches.
+    average_payload_size: AtomicUsize,
+    /// Size reserved for a batch until payloads are recorded, the budget split between the
+    /// batches held at once.
+    initial_batch_size: usize,
+}
+
+impl MemoryBudget {
+    /// `batches` is the number of batches expected to be held at once.
+    pub fn new(megabytes: u32, batches: usize) -> Self {
+        let capacity = megabytes.saturating_mul(1024).max(1);
+        MemoryBudget {
+            semaphore: Semaphore::new(capacity as usize),
+            capacity,
+            average_payload_size: AtomicUsize::new(0),
+            initial_batch_size: capacity as usize * 1024 / batches.max(1),
+        }
+    }
+
+    /// Reads the budget in megabytes from `INDEXER_PAYLOAD_MEMORY_BUDGET`, defaults to 512.
+    pub fn from_env(batches: usize) -> Self {
+        MemoryBudget::new(
+            std::env::var("INDEXER_PAYLOAD_MEMORY_BUDGET")
+                .unwrap_or_else(|_| "512".to_string())
+                .parse::<u32>()
+                .expect("Invalid INDEXER_PAYLOAD_MEMORY_BUDGET"),
+            batches,
+        )
+    }
+
+    /// Estimated size in bytes of the given number of payloads. Until payloads are recorded,
+    /// a batch is estimated to take its share of the budget.
+    pub fn estimate(&self, payloads: usize) -> usize {
+        match self.average_payload_size.load(Ordering::Relaxed) {
+            _ if payloads == 0 => 0,
+            0 => self.initial_batch_size,
+            average => average * payloads,
+        }
+    }
+
+    /// Records the total size of fetched payloads, to refine the estimates.
+    pub fn record(&self, payloads: usize, bytes: usize) {
+        if payloads == 0 {
+            return;
+        }
+        let average = bytes / payloads;
+        let previous = self.average_payload_size.load(Ordering::Relaxed);
+        let average = match previous {
+            0 => average,
+            previous => (previous * 7 + average) / 8,
+        };
+        self.average_payload_size.store(average, Ordering::Relaxed);
+    }
+
+    /// Reserves the given number of bytes, waiting until enough are released.
+    /// A reservation larger than the whole budget waits for the budget to be entirely free.
+    pub async fn reserve(&self, bytes: usize) -> Reservation<'_> {
+        let permits = self.permits(bytes);
+        if self.semaphore.available_permits() < permits as usize {
+            log::debug!(
+                "Waiting for {} KiB of the payload memory budget, {} KiB available",
+                permits,
+                self.semaphore.available_permits()
+            );
+        }
+        self.semaphore
+            .acquire_many(permits)
+            .await
+            .expect("The memory budget is never closed")
+            .forget();
+        Reservation {
+            budget: self,
+            permits,
+        }
+    }
+
+    /// Size in bytes which isn't reserved.
+    pub fn available(&self) -> usize {
+        self.semaphore.available_permits() * 1024
+    }
+
+    fn permits(&self, bytes: usize) -> u32 {
+        ((bytes + 1023) / 1024).min(self.capacity as usize) as u32
+    }
+}
+
+/// Bytes reserved from a [`MemoryBudget`], released when dropped.
+pub struct Reservation<'a> {
+    budget: &'a MemoryBudget,
+    permits: u32,
+}
+
+impl Reservation<'_> {
+    /// Resizes the reservation, eg. to the actual size of a batch once fetched. Growing releases
+    /// the reservation and waits for the new size, so that growing reservations never wait for
+    /// each other.
+    pub async fn resize(&mut self, bytes: usize) {
+        let permits = self.budget.permits(bytes);
+        if permits <= self.permits {
+            self.budget
+                .semaphore
+                .add_permits((self.permits - permits) as usize);
+            self.permits = permits;
+            return;
+        }
+        self.budget.semaphore.add_permits(self.permits as usize);
+        self.permits = 0;
+        *self = self.budget.reserve(bytes).await;
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.budget.semaphore.add_permits(self.permits as usize);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_memory_budget() {
+        let budget = MemoryBudget::new(1, 1);
+        let mut first = budget.reserve(600 * 1024).await;
+        assert_eq!(budget.available(), 424 * 1024);
+
+        // Waits until the first reservation shrinks
+        let second = budget.reserve(500 * 1024);
+        tokio::pin!(second);
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut second)
+            .await
+            .is_err());
+        first.resize(100 * 1024).await;
+        let second = second.await;
+        assert_eq!(budget.available(), 424 * 1024);
+
+        // Growing releases the reservation first, larger ones wait for the whole budget
+        drop(second);
+        first.resize(10 * 1024 * 1024).await;
+        assert_eq!(budget.available(), 0);
+        drop(first);
+        assert_eq!(budget.available(), 1024 * 1024);
+    }
+
+    #[test]
+    fn test_memory_budget_estimate() {
+        let budget = MemoryBudget::new(1, 4);
+        assert_eq!(budget.estimate(10), 256 * 1024);
+        assert_eq!(budget.estimate(0), 0);
+        budget.record(10, 10_000);
+        assert_eq!(budget.estimate(10), 10_000);
+        budget.record(1, 9_000);
+        assert_eq!(budget.estimate(1), 2_000);
+        budget.record(0, 0);
+        assert_eq!(budget.estimate(1), 2_000);
+    }
+}
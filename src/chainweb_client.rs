@@ -3,11 +3,13 @@ use bigdecimal::BigDecimal;
 use eventsource_client::SSE;
 use futures::Stream;
 use reqwest::Url;
+use serde::de::DeserializeOwned;
 use serde::Deserializer;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::env;
 use std::fmt::Display;
+use std::time::{Duration, Instant};
 use std::{collections::HashMap, error::Error};
 
 #[derive(Deserialize, Debug)]
@@ -33,6 +35,21 @@ pub struct BlockHash {
 #[derive(Deserialize, Debug, PartialEq, Eq, Hash, Clone)]
 pub struct ChainId(pub u16);
 
+impl ChainId {
+    /// Returns the chain if a network of `chains` chains has it, eg. chains 0 to 19 with
+    /// `MAINNET_CHAINS`, so that invalid chains are reported before querying the node.
+    pub fn validate(chain_id: i64, chains: u16) -> Result<ChainId, String> {
+        match (0..chains as i64).contains(&chain_id) {
+            true => Ok(ChainId(chain_id as u16)),
+            false => Err(format!(
+                "Invalid chain id {}, expected a chain between 0 and {}",
+                chain_id,
+                chains.saturating_sub(1)
+            )),
+        }
+    }
+}
+
 impl Display for ChainId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.0)
@@ -42,6 +59,9 @@ impl Display for ChainId {
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct Hash(pub String);
 
+/// Number of chains on mainnet since the 20-chain fork.
+pub const MAINNET_CHAINS: u16 = 20;
+
 #[derive(Deserialize, Debug)]
 pub struct Cut {
     pub height: u32,
@@ -51,7 +71,17 @@ pub struct Cut {
     pub id: String,
 }
 
-#[derive(Deserialize, Debug)]
+impl Cut {
+    /// Returns the chains, out of the first `expected_chains`, that are not present in the cut.
+    pub fn missing_chains(&self, expected_chains: u16) -> Vec<ChainId> {
+        (0..expected_chains)
+            .map(ChainId)
+            .filter(|chain| !self.hashes.contains_key(chain))
+            .collect()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
 pub struct BlockHeader {
     #[serde(rename(deserialize = "creationTime"))]
     pub creation_time: i64,
@@ -104,6 +134,17 @@ pub struct BlockPayload {
     pub transactions_hash: String,
 }
 
+impl BlockPayload {
+    /// Approximate number of bytes held in memory by the payload, mostly its transactions.
+    pub fn approximate_size(&self) -> usize {
+        self.miner_data.len()
+            + self.outputs_hash.len()
+            + self.payload_hash.len()
+            + self.transactions_hash.len()
+            + self.transactions.iter().map(String::len).sum::<usize>()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct Sig {
     pub sig: String,
@@ -186,7 +227,16 @@ fn de_f64_or_u64_or_string_as_u64<'de, D: Deserializer<'de>>(
 #[derive(Deserialize, Debug, PartialEq)]
 pub struct Signer {
     #[serde(rename(deserialize = "pubKey"))]
-    public_key: String,
+    pub public_key: String,
+    /// Capabilities the signature is scoped to, unrestricted when missing.
+    #[serde(default)]
+    pub clist: Option<Vec<Capability>>,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct Capability {
+    pub name: String,
+    pub args: Vec<Value>,
 }
 
 #[derive(Deserialize, Debug, PartialEq)]
@@ -221,6 +271,53 @@ pub struct ContPayload {
     pub step: u32,
 }
 
+/// Verifies that the hash of the transaction is the blake2b-256 hash of its command
+/// and that every signature is a valid ed25519 signature of the hash by the corresponding signer.
+pub fn verify_signatures(signed_tx: &SignedTransaction) -> bool {
+    use blake2::digest::consts::U32;
+    use blake2::{Blake2b, Digest};
+    use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+    let hash = match base64_url::decode(&signed_tx.hash) {
+        Ok(hash) => hash,
+        Err(_) => return false,
+    };
+    if Blake2b::<U32>::digest(signed_tx.cmd.as_bytes()).as_slice() != hash.as_slice() {
+        return false;
+    }
+    let command = match serde_json::from_str::<Command>(&signed_tx.cmd) {
+        Ok(command) => command,
+        Err(_) => return false,
+    };
+    if command.signers.len() != signed_tx.sigs.len() {
+        return false;
+    }
+    command
+        .signers
+        .iter()
+        .zip(signed_tx.sigs.iter())
+        .all(|(signer, sig)| {
+            let public_key = decode_hex::<32>(&signer.public_key)
+                .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok());
+            let signature = decode_hex::<64>(&sig.sig).map(|bytes| Signature::from_bytes(&bytes));
+            match (public_key, signature) {
+                (Some(public_key), Some(signature)) => public_key.verify(&hash, &signature).is_ok(),
+                _ => false,
+            }
+        })
+}
+
+fn decode_hex<const N: usize>(value: &str) -> Option<[u8; N]> {
+    if value.len() != N * 2 || !value.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
 pub mod tx_result {
     use super::*;
 
@@ -230,6 +327,16 @@ pub mod tx_result {
         pub namespace: Option<String>,
     }
 
+    impl Module {
+        /// Name of the module with its namespace, eg. `marmalade-v2.ledger`.
+        pub fn qualified_name(&self) -> String {
+            match &self.namespace {
+                Some(namespace) => format!("{}.{}", namespace, self.name),
+                None => self.name.to_string(),
+            }
+        }
+    }
+
     #[derive(Deserialize, Debug)]
     pub struct Event {
         pub module: Module,
@@ -279,30 +386,181 @@ pub mod tx_result {
         #[serde(rename(deserialize = "txId"))]
         pub tx_id: Option<i64>,
     }
+
+    impl PactTransactionResult {
+        /// Approximate number of bytes held in memory by the result, mostly its events and data.
+        pub fn approximate_size(&self) -> usize {
+            let value_size = |value: &Value| value.to_string().len();
+            self.request_key.len()
+                + self.logs.len()
+                + self.metadata.block_hash.len()
+                + self.metadata.prev_block_hash.len()
+                + self.continuation.as_ref().map_or(0, value_size)
+                + self.result.data.as_ref().map_or(0, value_size)
+                + self.result.error.as_ref().map_or(0, value_size)
+                + self.events.as_ref().map_or(0, |events| {
+                    events
+                        .iter()
+                        .map(|event| {
+                            event.name.len() + event.module_hash.len() + value_size(&event.params)
+                        })
+                        .sum()
+                })
+        }
+    }
+}
+
+/// Spaces out requests so that no more than `requests_per_second` are sent,
+/// shared by all the tasks using the same client.
+struct RateLimiter {
+    interval: Duration,
+    next_slot: tokio::sync::Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(requests_per_second: f64) -> Self {
+        RateLimiter {
+            interval: Duration::from_secs_f64(1.0 / requests_per_second),
+            next_slot: tokio::sync::Mutex::new(Instant::now()),
+        }
+    }
+
+    async fn acquire(&self) {
+        let mut next_slot = self.next_slot.lock().await;
+        let now = Instant::now();
+        if *next_slot > now {
+            tokio::time::sleep_until((*next_slot).into()).await;
+        }
+        *next_slot = std::cmp::max(now, *next_slot) + self.interval;
+    }
+}
+
+/// Longest part of an undecodable response body kept in the error.
+const BODY_SNIPPET_LEN: usize = 200;
+
+/// A response of the node which isn't the expected JSON, often an HTML error page of a proxy.
+#[derive(Debug)]
+pub struct DecodeError {
+    pub url: String,
+    pub status: reqwest::StatusCode,
+    /// Start of the body, truncated to `BODY_SNIPPET_LEN` characters.
+    pub body: String,
+    pub source: serde_json::Error,
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Invalid JSON from {} ({}): {}, body: {:?}",
+            self.url, self.status, self.source, self.body
+        )
+    }
+}
+
+impl Error for DecodeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Decodes the JSON body of a response, keeping the start of the body in the error
+/// when it can't be decoded rather than only the position where decoding failed.
+async fn decode_json<T: DeserializeOwned>(
+    response: reqwest::Response,
+) -> Result<T, Box<dyn Error>> {
+    let url = response.url().to_string();
+    let status = response.status();
+    let body = response.text().await?;
+    serde_json::from_str(&body).map_err(|source| {
+        DecodeError {
+            url,
+            status,
+            body: body.chars().take(BODY_SNIPPET_LEN).collect(),
+            source,
+        }
+        .into()
+    })
 }
 
 pub struct ChainwebClient {
     base_url: String,
+    rate_limiter: Option<RateLimiter>,
+    /// Shared by all requests so that connections are reused
+    http_client: reqwest::Client,
+    user_agent: String,
 }
 
 impl ChainwebClient {
     pub fn new() -> Self {
         let host = env::var("CHAINWEB_NODE_HOST").expect("Missing CHAINWEB_NODE_HOST");
+        let rate_limiter = env::var("CHAINWEB_NODE_REQUESTS_PER_SECOND")
+            .ok()
+            .map(|rate| {
+                rate.parse::<f64>()
+                    .expect("Invalid CHAINWEB_NODE_REQUESTS_PER_SECOND")
+            })
+            .filter(|rate| *rate > 0.0)
+            .map(RateLimiter::new);
+        let user_agent = env::var("CHAINWEB_NODE_USER_AGENT")
+            .unwrap_or_else(|_| format!("bento/{}", env!("CARGO_PKG_VERSION")));
+        let http_client = reqwest::Client::builder()
+            .user_agent(&user_agent)
+            .build()
+            .expect("Invalid CHAINWEB_NODE_USER_AGENT");
         ChainwebClient {
             base_url: format!("{host}/chainweb/0.0/mainnet01"),
+            rate_limiter,
+            http_client,
+            user_agent,
+        }
+    }
+
+    /// Waits for the rate limiter, if one is configured, before sending a request to the node.
+    async fn throttle(&self) {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire().await;
         }
     }
 
     pub async fn get_cut(&self) -> Result<Cut, Box<dyn Error>> {
+        self.throttle().await;
         let endpoint = "/cut";
         let url = Url::parse(&format!("{}{}", self.base_url, endpoint)).unwrap();
-        let response = reqwest::Client::new()
-            .get(url)
-            .send()
-            .await?
-            .json::<Cut>()
-            .await?;
-        Ok(response)
+        let response = self.http_client.get(url).send().await?;
+        decode_json(response).await
+    }
+
+    /// Same as `get_cut` but makes sure the cut contains all the chains. If some chains are missing
+    /// the request is retried a few times, after which a partial cut is returned with a warning.
+    /// An empty cut is an error.
+    pub async fn get_complete_cut(&self) -> Result<Cut, Box<dyn Error>> {
+        let retries = 3;
+        let mut attempt = 1;
+        loop {
+            let cut = self.get_cut().await?;
+            let missing_chains = cut.missing_chains(MAINNET_CHAINS);
+            if missing_chains.is_empty() {
+                return Ok(cut);
+            }
+            log::warn!(
+                "Cut contains {} out of {} chains, missing chains: {:?} (attempt {}/{})",
+                cut.hashes.len(),
+                MAINNET_CHAINS,
+                missing_chains.iter().map(|e| e.0).collect::<Vec<u16>>(),
+                attempt,
+                retries
+            );
+            if attempt == retries {
+                if cut.hashes.is_empty() {
+                    return Err("Node returned an empty cut".into());
+                }
+                log::warn!("Continuing with a partial cut");
+                return Ok(cut);
+            }
+            attempt += 1;
+            tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+        }
     }
 
     #[allow(dead_code)]
@@ -311,17 +569,12 @@ impl ChainwebClient {
         chain: &ChainId,
         bounds: &Bounds,
     ) -> Result<BlockHeaderBranchResponse, Box<dyn Error>> {
+        self.throttle().await;
         let endpoint = format!("/chain/{chain}/hash/branch");
         let mut url = Url::parse(&format!("{}{}", self.base_url, endpoint)).unwrap();
         url.query_pairs_mut().append_pair("limit", "50");
-        let response = reqwest::Client::new()
-            .post(url)
-            .json(bounds)
-            .send()
-            .await?
-            .json::<BlockHeaderBranchResponse>()
-            .await?;
-        Ok(response)
+        let response = self.http_client.post(url).json(bounds).send().await?;
+        decode_json(response).await
     }
 
     pub async fn get_block_headers_branches(
@@ -332,6 +585,7 @@ impl ChainwebClient {
         min_height: Option<u64>,
         max_height: Option<u64>,
     ) -> Result<BlockHeaderResponse, Box<dyn Error>> {
+        self.throttle().await;
         let endpoint = format!("/chain/{chain}/header/branch");
         let mut url = Url::parse(&format!("{}{}", self.base_url, endpoint)).unwrap();
         url.query_pairs_mut().append_pair("limit", "50");
@@ -353,15 +607,38 @@ impl ChainwebClient {
                 .parse()
                 .unwrap(),
         );
-        let response: BlockHeaderResponse = reqwest::Client::new()
+        let response = self
+            .http_client
             .post(url)
             .json(bounds)
             .headers(headers)
             .send()
-            .await?
-            .json()
             .await?;
-        Ok(response)
+        decode_json(response).await
+    }
+
+    /// Resolves the hash of the block at `height` on the branch ending with the block `upper_hash`,
+    /// `None` if the branch has no block at this height.
+    pub async fn get_block_hash_at_height(
+        &self,
+        chain: &ChainId,
+        upper_hash: &str,
+        height: u64,
+    ) -> Result<Option<String>, Box<dyn Error>> {
+        let bounds = Bounds {
+            lower: vec![],
+            upper: vec![Hash(upper_hash.to_string())],
+        };
+        // The min height is not always inclusive, so we ask for the block before as well
+        // and pick the one at the requested height.
+        let response = self
+            .get_block_headers_branches(chain, &bounds, &None, height.checked_sub(1), Some(height))
+            .await?;
+        Ok(response
+            .items
+            .into_iter()
+            .find(|header| header.height == height)
+            .map(|header| header.hash))
     }
 
     pub async fn get_block_payload_batch(
@@ -369,16 +646,16 @@ impl ChainwebClient {
         chain: &ChainId,
         block_payload_hash: Vec<&str>,
     ) -> Result<Vec<BlockPayload>, Box<dyn Error>> {
+        self.throttle().await;
         let endpoint = format!("/chain/{chain}/payload/batch");
         let url = Url::parse(&format!("{}{}", self.base_url, endpoint)).unwrap();
-        let response: Vec<BlockPayload> = reqwest::Client::new()
+        let response = self
+            .http_client
             .post(url)
             .json(&block_payload_hash)
             .send()
-            .await?
-            .json()
             .await?;
-        Ok(response)
+        decode_json(response).await
     }
 
     pub async fn poll(
@@ -386,16 +663,16 @@ impl ChainwebClient {
         request_keys: &Vec<String>,
         chain: &ChainId,
     ) -> Result<HashMap<String, PactTransactionResult>, Box<dyn Error>> {
+        self.throttle().await;
         let endpoint = format!("/chain/{chain}/pact/api/v1/poll");
         let url = Url::parse(&format!("{}{}", self.base_url, endpoint)).unwrap();
-        let response: HashMap<String, PactTransactionResult> = reqwest::Client::new()
+        let response = self
+            .http_client
             .post(url)
             .json(&serde_json::json!({ "requestKeys": request_keys }))
             .send()
-            .await?
-            .json()
             .await?;
-        Ok(response)
+        decode_json(response).await
     }
 
     #[allow(dead_code)]
@@ -405,11 +682,11 @@ impl ChainwebClient {
     {
         use eventsource_client as es;
         use eventsource_client::Client;
-        use std::time::Duration;
 
         let endpoint = "/header/updates".to_string();
         let url = Url::parse(&format!("{}{}", self.base_url, endpoint)).unwrap();
         let client = es::ClientBuilder::for_url(url.as_str())?
+            .header("User-Agent", &self.user_agent)?
             .reconnect(
                 es::ReconnectOptions::reconnect(true)
                     .retry_initial(false)
@@ -434,6 +711,212 @@ impl Default for ChainwebClient {
 mod tests {
     use super::*;
 
+    fn make_signed_tx() -> SignedTransaction {
+        SignedTransaction {
+            cmd: String::from("{\"networkId\":\"mainnet01\",\"payload\":{\"exec\":{\"data\":{\"keyset\":{\"pred\":\"keys-all\",\"keys\":[\"56df77b51a5b6100dd25eb7b9cb55f3d1994f21369cb565cf9d9f7c1d630d1ef\"]}},\"code\":\"(free.radio02.add-received \\\"30ae7bfffee347e6\\\" \\\"U2FsdGVkX1/96zcn8NhZ3ih4dRhy0Thvm72dnl7HKAI=;;;;;qTUcRG54XW+vRuO+ttj+iaxOwojNSIwCZCXtufJVFfPDbkVvLbY885sD0GY+7rlNjZyfprGhWfTthAOP9bq8Io/5yxu888zPFZdfQD1ngVrk0RzhZ3Ac2HtJXtGBJUKr21j/T5d//WBTgCmtXIi+GvqH2Nrhq6PuVZmyvlTYSP8=\\\" )\"}},\"signers\":[{\"pubKey\":\"56df77b51a5b6100dd25eb7b9cb55f3d1994f21369cb565cf9d9f7c1d630d1ef\"}],\"meta\":{\"creationTime\":1687691365,\"ttl\":28800,\"gasLimit\":1000,\"chainId\":\"0\",\"gasPrice\":0.000001,\"sender\":\"k:56df77b51a5b6100dd25eb7b9cb55f3d1994f21369cb565cf9d9f7c1d630d1ef\"},\"nonce\":\"\\\"2023-06-25T11:09:44.635Z\\\"\"}"),
+            hash: String::from("gaD_OZdL3cJKGelC73laoBDJjWJTkstkkjIAIKOOq1U"),
+            sigs: vec![Sig {
+                sig: String::from("328aa76e9f04055e7a0a47318030721508f23ac9b14689a6ce0e60b63be4226cafcb3d421138349ae6adad4610f30460041da40df22d461549892560355df102"),
+            }],
+        }
+    }
+
+    /// Serves the headers of a single chain whose block at height `h` has hash `hash-{h}`,
+    /// up to height 100. Like the node, the min height is exclusive.
+    async fn start_mock_node() -> String {
+        use actix_web::{web, App, HttpResponse, HttpServer};
+
+        async fn header_branch(
+            query: web::Query<HashMap<String, u64>>,
+            bounds: web::Json<serde_json::Value>,
+        ) -> HttpResponse {
+            let upper = bounds["upper"][0]
+                .as_str()
+                .and_then(|hash| hash.strip_prefix("hash-"))
+                .and_then(|height| height.parse::<u64>().ok())
+                .unwrap_or(100);
+            let max_height = query.get("maxheight").copied().unwrap_or(upper).min(upper);
+            let items = (0..=max_height)
+                .rev()
+                .filter(|height| query.get("minheight").map_or(true, |min| height > min))
+                .take(50)
+                .map(|height| {
+                    serde_json::json!({
+                        "creationTime": 0,
+                        "parent": format!("hash-{}", height.saturating_sub(1)),
+                        "height": height,
+                        "hash": format!("hash-{}", height),
+                        "chainId": 0,
+                        "payloadHash": "payload-hash",
+                        "weight": "weight",
+                        "featureFlags": 0,
+                        "epochStart": 0,
+                        "adjacents": {},
+                        "chainwebVersion": "mainnet01",
+                        "target": "target",
+                        "nonce": "0"
+                    })
+                })
+                .collect::<Vec<serde_json::Value>>();
+            HttpResponse::Ok()
+                .json(serde_json::json!({ "items": items, "limit": 50, "next": null }))
+        }
+
+        let server = HttpServer::new(|| {
+            App::new().route(
+                "/chainweb/0.0/mainnet01/chain/{chain}/header/branch",
+                web::post().to(header_branch),
+            )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let address = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+        format!("http://{}", address)
+    }
+
+    #[actix_web::test]
+    #[serial_test::serial]
+    async fn test_get_block_hash_at_height() {
+        let client = client_of(start_mock_node().await);
+        let chain = ChainId(0);
+        assert_eq!(
+            client
+                .get_block_hash_at_height(&chain, "hash-100", 42)
+                .await
+                .unwrap(),
+            Some("hash-42".to_string())
+        );
+        assert_eq!(
+            client
+                .get_block_hash_at_height(&chain, "hash-100", 0)
+                .await
+                .unwrap(),
+            Some("hash-0".to_string())
+        );
+        // Blocks above the upper bound are not on the branch
+        assert_eq!(
+            client
+                .get_block_hash_at_height(&chain, "hash-50", 60)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    /// Creates a client of the given node, restoring `CHAINWEB_NODE_HOST` so that later tests
+    /// don't get the address of a node that's gone.
+    fn client_of(node_host: String) -> ChainwebClient {
+        let previous_host = std::env::var("CHAINWEB_NODE_HOST");
+        std::env::set_var("CHAINWEB_NODE_HOST", node_host);
+        let client = ChainwebClient::new();
+        match previous_host {
+            Ok(host) => std::env::set_var("CHAINWEB_NODE_HOST", host),
+            Err(_) => std::env::remove_var("CHAINWEB_NODE_HOST"),
+        }
+        client
+    }
+
+    #[actix_web::test]
+    #[serial_test::serial]
+    async fn test_non_json_response_is_reported_with_body() {
+        use actix_web::{web, App, HttpResponse, HttpServer};
+
+        let page = format!(
+            "<html><body>502 Bad Gateway{}</body></html>",
+            " ".repeat(500)
+        );
+        let server = HttpServer::new(move || {
+            let page = page.clone();
+            App::new().route(
+                "/chainweb/0.0/mainnet01/cut",
+                web::get().to(move || {
+                    let page = page.clone();
+                    async move {
+                        HttpResponse::BadGateway()
+                            .content_type("text/html")
+                            .body(page)
+                    }
+                }),
+            )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let address = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+        let client = client_of(format!("http://{}", address));
+
+        let error = client.get_cut().await.unwrap_err();
+        let error = error.downcast_ref::<DecodeError>().unwrap();
+        assert_eq!(error.status, reqwest::StatusCode::BAD_GATEWAY);
+        assert!(error.url.ends_with("/cut"));
+        assert!(error.body.starts_with("<html><body>502 Bad Gateway"));
+        assert_eq!(error.body.chars().count(), BODY_SNIPPET_LEN);
+        assert!(error.to_string().contains("502 Bad Gateway"));
+    }
+
+    #[test]
+    fn test_verify_signatures() {
+        assert!(verify_signatures(&make_signed_tx()));
+
+        let mut tampered_sig = make_signed_tx();
+        tampered_sig.sigs[0].sig.replace_range(0..2, "42");
+        assert!(!verify_signatures(&tampered_sig));
+
+        let mut tampered_cmd = make_signed_tx();
+        tampered_cmd.cmd = tampered_cmd.cmd.replace("1687691365", "1687691366");
+        assert!(!verify_signatures(&tampered_cmd));
+
+        let mut missing_sig = make_signed_tx();
+        missing_sig.sigs.clear();
+        assert!(!verify_signatures(&missing_sig));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_spaces_out_requests() {
+        let rate_limiter = RateLimiter::new(20.0);
+        let before = Instant::now();
+        for _ in 0..5 {
+            rate_limiter.acquire().await;
+        }
+        // The first request goes through immediately, the next 4 wait 50ms each
+        assert!(before.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_cut_missing_chains() {
+        let json = serde_json::json!({
+            "height": 100,
+            "weight": "weight",
+            "hashes": {
+                "0": {"height": 5, "hash": "hash-0"},
+                "1": {"height": 5, "hash": "hash-1"},
+                "3": {"height": 5, "hash": "hash-3"}
+            },
+            "instance": "mainnet01",
+            "id": "id"
+        });
+        let cut = serde_json::from_value::<Cut>(json).unwrap();
+        assert_eq!(cut.missing_chains(4), vec![ChainId(2)]);
+        assert_eq!(cut.missing_chains(2), vec![]);
+        assert_eq!(cut.missing_chains(MAINNET_CHAINS).len(), 17);
+    }
+
+    #[test]
+    fn test_chain_id_validate() {
+        assert_eq!(ChainId::validate(0, MAINNET_CHAINS), Ok(ChainId(0)));
+        assert_eq!(ChainId::validate(19, MAINNET_CHAINS), Ok(ChainId(19)));
+        assert_eq!(
+            ChainId::validate(20, MAINNET_CHAINS),
+            Err("Invalid chain id 20, expected a chain between 0 and 19".to_string())
+        );
+        assert!(ChainId::validate(-1, MAINNET_CHAINS).is_err());
+        assert!(ChainId::validate(65536, MAINNET_CHAINS).is_err());
+        assert!(ChainId::validate(3, 3).is_err());
+    }
+
     #[test]
     fn test_parsing_cont_command_json() {
         let json = "{\"networkId\":\"mainnet01\",\"payload\":{\"cont\":{\"proof\":\"proof\",\"pactId\":\"AoKZVe35EWK-2a-kj_tD6vC8Ifdt1mdQyK0_2Rm_Jto\",\"rollback\":false,\"step\":1,\"data\":{}}},\"signers\":[],\"meta\":{\"creationTime\":1687931936,\"ttl\":3600,\"gasLimit\":850,\"chainId\":\"0\",\"gasPrice\":1e-8,\"sender\":\"xwallet-xchain-gas\"},\"nonce\":\"\\\"2023-06-28T05:59:55.767Z\\\"\"}";
@@ -490,6 +973,7 @@ mod tests {
                 public_key: String::from(
                     "48484c674e734ba4deef7289b47c14d0743e914e2fc0863b9859ac0ec2715173",
                 ),
+                clist: None,
             }],
             meta: Meta {
                 chain_id: String::from("0"),
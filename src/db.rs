@@ -1,33 +1,266 @@
-use diesel::pg::PgConnection;
+use diesel::migration::MigrationSource;
+use diesel::pg::{Pg, PgConnection};
 use diesel::r2d2;
+use diesel::RunQueryDsl;
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 use std::env;
 use std::error::Error;
+use std::sync::OnceLock;
+use std::time::Duration;
 
 pub type DbPool = r2d2::Pool<r2d2::ConnectionManager<PgConnection>>;
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("./migrations");
 pub type DbError = Box<dyn Error + Send + Sync + 'static>;
 
-pub fn initialize_db_pool() -> DbPool {
+pub fn database_url() -> String {
     let postgres_user = env::var("POSTGRES_USER").expect("Missing POSTGRES_USER");
     let postgres_password = env::var("POSTGRES_PASSWORD").expect("Missing POSTGRES_PASSWORD");
     let postgres_host = env::var("POSTGRES_HOST").expect("Missing POSTGRES_HOST");
     let postgres_db = env::var("POSTGRES_DB").expect("Missing POSTGRES_DB");
-    let database_url = format!(
+    format!(
         "postgres://{}:{}@{}/{}",
         postgres_user, postgres_password, postgres_host, postgres_db
-    );
+    )
+}
+
+/// Sets the `search_path` of the connections of the pool to the schema, so that instances
+/// sharing a database (eg. mainnet and testnet) each have their tables in their own schema.
+#[derive(Debug)]
+struct SearchPath(String);
+
+impl r2d2::CustomizeConnection<PgConnection, r2d2::Error> for SearchPath {
+    fn on_acquire(&self, connection: &mut PgConnection) -> Result<(), r2d2::Error> {
+        // Quoted so that the schema is used as is, whatever its case or characters
+        let schema = format!("\"{}\"", self.0.replace('"', "\"\""));
+        diesel::sql_query(format!("SET search_path TO {}", schema))
+            .execute(connection)
+            .map_err(r2d2::Error::QueryError)?;
+        Ok(())
+    }
+}
+
+pub fn initialize_db_pool() -> DbPool {
+    let database_url = database_url();
+    let connection_timeout = env::var("POSTGRES_CONNECTION_TIMEOUT")
+        .unwrap_or_else(|_| "30".to_string())
+        .parse::<u64>()
+        .expect("Invalid POSTGRES_CONNECTION_TIMEOUT");
+    let schema = env::var("POSTGRES_SCHEMA")
+        .ok()
+        .filter(|schema| !schema.is_empty());
     let manager = r2d2::ConnectionManager::<PgConnection>::new(database_url);
-    r2d2::Pool::builder()
-        .build(manager)
-        .expect("Failed to create pool")
+    let builder = r2d2::Pool::builder()
+        // Stale connections (eg. after a Postgres restart) are discarded and replaced
+        .test_on_check_out(true)
+        .connection_timeout(Duration::from_secs(connection_timeout));
+    let builder = match schema {
+        Some(schema) => builder.connection_customizer(Box::new(SearchPath(schema))),
+        None => builder,
+    };
+    builder.build(manager).expect("Failed to create pool")
 }
 
-pub fn run_migrations(
-    connection: &mut impl MigrationHarness<diesel::pg::Pg>,
-) -> Result<(), DbError> {
-    connection
-        .run_pending_migrations(MIGRATIONS)
-        .expect("Failed to run migrations");
+/// Logs the utilization of the pool every `POSTGRES_POOL_LOG_INTERVAL` seconds (60 by default,
+/// 0 to disable), with a warning when all its connections are in use as getting a connection
+/// then waits, up to `POSTGRES_CONNECTION_TIMEOUT`, for another one to be released.
+pub fn spawn_pool_monitor(pool: DbPool) {
+    let interval = env::var("POSTGRES_POOL_LOG_INTERVAL")
+        .unwrap_or_else(|_| "60".to_string())
+        .parse::<u64>()
+        .expect("Invalid POSTGRES_POOL_LOG_INTERVAL");
+    if interval == 0 {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(interval));
+        loop {
+            interval.tick().await;
+            let state = pool.state();
+            let (usage, saturated) =
+                pool_usage(state.connections, state.idle_connections, pool.max_size());
+            match saturated {
+                true => log::warn!("Database pool saturated: {}", usage),
+                false => log::info!("Database pool: {}", usage),
+            }
+        }
+    });
+}
+
+/// Describes the utilization of a pool, saturated when all of its connections are in use.
+fn pool_usage(connections: u32, idle_connections: u32, max_size: u32) -> (String, bool) {
+    let in_use = connections - idle_connections;
+    (
+        format!(
+            "{} of {} connections in use, {} idle",
+            in_use, max_size, idle_connections
+        ),
+        in_use >= max_size,
+    )
+}
+
+static MAX_ROWS: OnceLock<i64> = OnceLock::new();
+
+/// Maximum number of rows returned by the list queries, so that a single query can't load an
+/// unbounded number of rows in memory. Set with `MAX_ROWS`, defaults to 10000.
+/// Read once, on the first call, which the binaries make at startup.
+pub fn max_rows() -> i64 {
+    *MAX_ROWS.get_or_init(|| {
+        env::var("MAX_ROWS")
+            .unwrap_or_else(|_| "10000".to_string())
+            .parse::<i64>()
+            .ok()
+            .filter(|max_rows| *max_rows > 0)
+            .expect("Invalid MAX_ROWS")
+    })
+}
+
+/// Versions of the migrations embedded in the binary, compared to the ones applied to the database.
+#[derive(Debug, PartialEq)]
+pub struct MigrationStatus {
+    pub applied: Vec<String>,
+    pub pending: Vec<String>,
+    /// Applied to the database but unknown to this binary, eg. when running an old binary
+    /// against a schema migrated by a newer one.
+    pub unknown: Vec<String>,
+}
+
+pub fn migration_status(
+    connection: &mut impl MigrationHarness<Pg>,
+) -> Result<MigrationStatus, DbError> {
+    let mut embedded = MigrationSource::<Pg>::migrations(&MIGRATIONS)?
+        .iter()
+        .map(|migration| migration.name().version().to_string())
+        .collect::<Vec<String>>();
+    embedded.sort();
+    let mut applied = connection
+        .applied_migrations()?
+        .iter()
+        .map(|version| version.to_string())
+        .collect::<Vec<String>>();
+    applied.sort();
+    let (known, unknown): (Vec<String>, Vec<String>) = applied
+        .into_iter()
+        .partition(|version| embedded.contains(version));
+    let pending = embedded
+        .into_iter()
+        .filter(|version| !known.contains(version))
+        .collect();
+    Ok(MigrationStatus {
+        applied: known,
+        pending,
+        unknown,
+    })
+}
+
+/// Runs the pending migrations, refusing to run if the database has migrations
+/// this binary doesn't know about as its queries may not match the schema anymore.
+pub fn run_migrations(connection: &mut impl MigrationHarness<Pg>) -> Result<(), DbError> {
+    let status = migration_status(connection)?;
+    if !status.unknown.is_empty() {
+        return Err(format!(
+            "The database has migrations unknown to this binary ({}), it was probably migrated by a newer version",
+            status.unknown.join(", ")
+        )
+        .into());
+    }
+    connection.run_pending_migrations(MIGRATIONS)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::BlocksRepository;
+    use diesel::sql_types::{BigInt, Text};
+    use serial_test::serial;
+
+    #[derive(diesel::QueryableByName)]
+    struct Schema {
+        #[diesel(sql_type = Text)]
+        schema: String,
+    }
+
+    #[derive(diesel::QueryableByName)]
+    struct Count {
+        #[diesel(sql_type = BigInt)]
+        count: i64,
+    }
+
+    #[test]
+    fn test_pool_usage() {
+        assert_eq!(
+            pool_usage(4, 1, 10),
+            ("3 of 10 connections in use, 1 idle".to_string(), false)
+        );
+        assert_eq!(
+            pool_usage(10, 0, 10),
+            ("10 of 10 connections in use, 0 idle".to_string(), true)
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_pool_uses_the_configured_schema() {
+        dotenvy::from_filename(".env.test").ok();
+        let mut public_conn = initialize_db_pool().get().unwrap();
+        diesel::sql_query("DROP SCHEMA IF EXISTS \"Bento-Testnet\" CASCADE")
+            .execute(&mut public_conn)
+            .unwrap();
+        diesel::sql_query("CREATE SCHEMA \"Bento-Testnet\"")
+            .execute(&mut public_conn)
+            .unwrap();
+
+        env::set_var("POSTGRES_SCHEMA", "Bento-Testnet");
+        let pool = initialize_db_pool();
+        env::remove_var("POSTGRES_SCHEMA");
+        let mut conn = pool.get().unwrap();
+        let current = diesel::sql_query("SELECT current_schema() AS schema")
+            .get_result::<Schema>(&mut conn)
+            .unwrap();
+        assert_eq!(current.schema, "Bento-Testnet");
+        // The schema has its own tables and migrations
+        run_migrations(&mut conn).unwrap();
+        assert!(migration_status(&mut conn).unwrap().pending.is_empty());
+        let tables = diesel::sql_query(
+            "SELECT count(*) AS count FROM information_schema.tables \
+             WHERE table_schema = 'Bento-Testnet' AND table_name = 'blocks'",
+        )
+        .get_result::<Count>(&mut conn)
+        .unwrap();
+        assert_eq!(tables.count, 1);
+        let blocks = BlocksRepository { pool: pool.clone() };
+        assert_eq!(blocks.count(0).unwrap(), 0);
+
+        diesel::sql_query("DROP SCHEMA \"Bento-Testnet\" CASCADE")
+            .execute(&mut public_conn)
+            .unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_run_migrations_refuses_unknown_migrations() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = initialize_db_pool();
+        let mut conn = pool.get().unwrap();
+        run_migrations(&mut conn).unwrap();
+        let status = migration_status(&mut conn).unwrap();
+        assert!(!status.applied.is_empty());
+        assert!(status.pending.is_empty());
+        assert!(status.unknown.is_empty());
+
+        diesel::sql_query(
+            "INSERT INTO __diesel_schema_migrations (version) VALUES ('99991231000000')",
+        )
+        .execute(&mut conn)
+        .unwrap();
+        let status = migration_status(&mut conn).unwrap();
+        assert_eq!(status.unknown, vec!["99991231000000".to_string()]);
+        assert!(run_migrations(&mut conn).is_err());
+
+        diesel::sql_query(
+            "DELETE FROM __diesel_schema_migrations WHERE version = '99991231000000'",
+        )
+        .execute(&mut conn)
+        .unwrap();
+    }
+}
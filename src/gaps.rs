@@ -1,21 +1,26 @@
 use std::vec;
 
 use futures::{stream, StreamExt};
+use serde::Serialize;
 
 use crate::chainweb_client::{Bounds, ChainId, ChainwebClient, Hash};
 use crate::indexer::Indexer;
 use crate::models::Block;
 use crate::{db::DbError, repository::BlocksRepository};
 
+/// Finds the gaps of every chain and fills them, unless `detect_only` is set.
+/// Returns the report of the gaps found and filled, per chain.
 pub async fn fill_gaps<'a>(
     chainweb_client: &ChainwebClient,
     blocks_repo: &BlocksRepository,
     indexer: &Indexer<'a>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let cut = chainweb_client.get_cut().await.unwrap();
-    let gaps = cut
-        .hashes
-        .keys()
+    detect_only: bool,
+) -> Result<GapsReport, Box<dyn std::error::Error>> {
+    let cut = chainweb_client.get_complete_cut().await?;
+    let mut chains = cut.hashes.keys().collect::<Vec<&ChainId>>();
+    chains.sort_by_key(|chain| chain.0);
+    let gaps = chains
+        .into_iter()
         .map(|chain| {
             let gaps = find_gaps(chain, blocks_repo).unwrap();
             let missing_blocks = gaps
@@ -28,40 +33,151 @@ pub async fn fill_gaps<'a>(
         })
         .collect::<Vec<(&ChainId, Vec<(Block, Block)>)>>();
 
-    for el in gaps {
-        let (chain, gaps) = el;
-        log::info!("Filling {} gaps for chain: {:?}", gaps.len(), chain);
-        gaps.iter().for_each(|e| {
-            log::info!(
-                "Gap: {} - {}, size: {}",
-                e.0.height,
-                e.1.height,
-                e.1.height - e.0.height - 1
-            )
-        });
-        stream::iter(gaps)
-            .map(|(lower_bound, upper_bound)| async move {
-                indexer
-                    .index_chain(
-                        Bounds {
-                            lower: vec![Hash(lower_bound.hash.clone())],
-                            upper: vec![Hash(upper_bound.hash.clone())],
-                        },
-                        chain,
-                        false,
-                    )
-                    .await
-            })
-            .buffer_unordered(4)
-            .for_each(|result| {
-                if let Err(e) = result {
-                    log::error!("Error filling gap: {:?}", e);
-                }
-                async {}
-            })
-            .await;
+    let mut report = GapsReport {
+        detect_only,
+        chains: vec![],
+    };
+    for (chain, gaps) in gaps {
+        let gaps = match detect_only {
+            true => gaps
+                .iter()
+                .map(|(lower, upper)| Gap::detected(lower, upper))
+                .collect(),
+            false => fill_chain_gaps(chain, gaps, indexer)
+                .await
+                .iter()
+                .map(Gap::from)
+                .collect(),
+        };
+        report.chains.push(ChainGaps::new(chain, gaps));
+    }
+    Ok(report)
+}
+
+/// Gaps found on every chain by the `gaps` command, and the outcome of their filling.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GapsReport {
+    /// The gaps were only found, not filled.
+    pub detect_only: bool,
+    pub chains: Vec<ChainGaps>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChainGaps {
+    pub chain_id: i64,
+    pub missing_blocks: i64,
+    pub filled_blocks: i64,
+    /// Ordered by height.
+    pub gaps: Vec<Gap>,
+}
+
+impl ChainGaps {
+    pub fn new(chain: &ChainId, mut gaps: Vec<Gap>) -> Self {
+        gaps.sort_by_key(|gap| gap.start_height);
+        ChainGaps {
+            chain_id: chain.0 as i64,
+            missing_blocks: gaps.iter().map(|gap| gap.size).sum(),
+            filled_blocks: gaps
+                .iter()
+                .filter(|gap| gap.filled)
+                .map(|gap| gap.size)
+                .sum(),
+            gaps,
+        }
+    }
+}
+
+/// A range of missing blocks.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Gap {
+    /// Height of the first missing block.
+    pub start_height: i64,
+    /// Height of the last missing block.
+    pub end_height: i64,
+    pub size: i64,
+    pub filled: bool,
+    pub error: Option<String>,
+}
+
+impl Gap {
+    /// A gap between the given indexed blocks, which wasn't filled.
+    pub fn detected(lower: &Block, upper: &Block) -> Self {
+        Gap {
+            start_height: lower.height + 1,
+            end_height: upper.height - 1,
+            size: upper.height - lower.height - 1,
+            filled: false,
+            error: None,
+        }
     }
-    Ok(())
+}
+
+impl From<&FilledGap> for Gap {
+    fn from(gap: &FilledGap) -> Self {
+        Gap {
+            start_height: gap.lower_height + 1,
+            end_height: gap.upper_height - 1,
+            size: gap.missing_blocks,
+            filled: gap.error.is_none(),
+            error: gap.error.clone(),
+        }
+    }
+}
+
+/// A gap between two indexed blocks of a chain, with the outcome of its filling.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FilledGap {
+    pub chain_id: i64,
+    /// Height of the indexed block right below the gap.
+    pub lower_height: i64,
+    /// Height of the indexed block right above the gap.
+    pub upper_height: i64,
+    pub missing_blocks: i64,
+    pub error: Option<String>,
+}
+
+/// Indexes the missing blocks of the given gaps of a chain, as found by [`find_gaps`].
+/// A gap that fails to be filled doesn't stop the others, the error is returned with the gap.
+pub async fn fill_chain_gaps<'a>(
+    chain: &ChainId,
+    gaps: Vec<(Block, Block)>,
+    indexer: &Indexer<'a>,
+) -> Vec<FilledGap> {
+    log::info!("Filling {} gaps for chain: {:?}", gaps.len(), chain);
+    gaps.iter().for_each(|e| {
+        log::info!(
+            "Gap: {} - {}, size: {}",
+            e.0.height,
+            e.1.height,
+            e.1.height - e.0.height - 1
+        )
+    });
+    stream::iter(gaps)
+        .map(|(lower_bound, upper_bound)| async move {
+            let result = indexer
+                .index_chain(
+                    Bounds {
+                        lower: vec![Hash(lower_bound.hash.clone())],
+                        upper: vec![Hash(upper_bound.hash.clone())],
+                    },
+                    chain,
+                    false,
+                )
+                .await;
+            if let Err(e) = &result {
+                log::error!("Error filling gap: {:?}", e);
+            }
+            FilledGap {
+                chain_id: chain.0 as i64,
+                lower_height: lower_bound.height,
+                upper_height: upper_bound.height,
+                missing_blocks: upper_bound.height - lower_bound.height - 1,
+                error: result.err().map(|e| e.to_string()),
+            }
+        })
+        .buffer_unordered(4)
+        .collect::<Vec<FilledGap>>()
+        .await
 }
 
 /// Check if there are any gaps in the blocks table
@@ -186,6 +302,7 @@ mod tests {
             pow_hash: "".to_string(),
             predicate: "predicate".to_string(),
             target: BigDecimal::from(1),
+            difficulty: None,
         }
     }
 
@@ -268,4 +385,81 @@ mod tests {
         assert!(gaps_heights == vec![(8, 10), (6, 8), (2, 4)]);
         blocks.delete_all().unwrap();
     }
+
+    #[test]
+    #[serial]
+    fn test_gaps_report() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        blocks
+            .insert_batch(&[
+                make_block(0, 0),
+                make_block(0, 1),
+                make_block(0, 4),
+                make_block(0, 5),
+                make_block(0, 9),
+            ])
+            .unwrap();
+        let chain = ChainId(0);
+        let found_gaps = find_gaps(&chain, &blocks).unwrap();
+        let detected = GapsReport {
+            detect_only: true,
+            chains: vec![ChainGaps::new(
+                &chain,
+                found_gaps
+                    .iter()
+                    .map(|(lower, upper)| Gap::detected(lower, upper))
+                    .collect(),
+            )],
+        };
+        assert_eq!(
+            serde_json::to_value(&detected).unwrap(),
+            serde_json::json!({
+                "detect_only": true,
+                "chains": [{
+                    "chain_id": 0,
+                    "missing_blocks": 5,
+                    "filled_blocks": 0,
+                    "gaps": [
+                        {"start_height": 2, "end_height": 3, "size": 2, "filled": false, "error": null},
+                        {"start_height": 6, "end_height": 8, "size": 3, "filled": false, "error": null}
+                    ]
+                }]
+            })
+        );
+
+        let filled = ChainGaps::new(
+            &chain,
+            vec![
+                Gap::from(&FilledGap {
+                    chain_id: 0,
+                    lower_height: 5,
+                    upper_height: 9,
+                    missing_blocks: 3,
+                    error: Some("node unavailable".to_string()),
+                }),
+                Gap::from(&FilledGap {
+                    chain_id: 0,
+                    lower_height: 1,
+                    upper_height: 4,
+                    missing_blocks: 2,
+                    error: None,
+                }),
+            ],
+        );
+        assert_eq!(
+            serde_json::to_value(&filled).unwrap(),
+            serde_json::json!({
+                "chain_id": 0,
+                "missing_blocks": 5,
+                "filled_blocks": 2,
+                "gaps": [
+                    {"start_height": 2, "end_height": 3, "size": 2, "filled": true, "error": null},
+                    {"start_height": 6, "end_height": 8, "size": 3, "filled": false, "error": "node unavailable"}
+                ]
+            })
+        );
+        blocks.delete_all().unwrap();
+    }
 }
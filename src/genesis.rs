@@ -0,0 +1,82 @@
+use crate::chainweb_client::ChainId;
+use std::collections::HashMap;
+
+/// Height of the first block of chains 10 to 19 on mainnet, added by the transition to 20 chains.
+const MAINNET_TWENTY_CHAINS_HEIGHT: i64 = 852054;
+
+/// Height of the first block of every chain, the floor below which there is nothing to backfill.
+/// Chains which aren't listed start at height 0.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GenesisHeights {
+    heights: HashMap<u16, i64>,
+}
+
+impl GenesisHeights {
+    /// Known heights of the given network, eg. `mainnet01`, none for the other networks.
+    pub fn known(network: &str) -> Self {
+        let heights = match network {
+            "mainnet01" => (10..20)
+                .map(|chain| (chain, MAINNET_TWENTY_CHAINS_HEIGHT))
+                .collect(),
+            _ => HashMap::new(),
+        };
+        GenesisHeights { heights }
+    }
+
+    /// Parses a comma separated list of `chain:height`, eg. `10:852054,11:852054`.
+    pub fn parse(config: &str) -> Result<Self, String> {
+        let mut heights = HashMap::new();
+        for chain in config.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+            let (chain, height) = chain
+                .split_once(':')
+                .ok_or_else(|| format!("Missing genesis height for chain {}", chain))?;
+            let chain = chain
+                .trim()
+                .parse::<u16>()
+                .map_err(|_| format!("Invalid chain {}", chain))?;
+            let height = height
+                .trim()
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid genesis height for chain {}", chain))?;
+            heights.insert(chain, height as i64);
+        }
+        Ok(GenesisHeights { heights })
+    }
+
+    /// Reads the heights from `INDEXER_GENESIS_HEIGHTS`, `None` when the variable is not set
+    /// so that the known heights of the network are used.
+    pub fn from_env() -> Option<Self> {
+        std::env::var("INDEXER_GENESIS_HEIGHTS")
+            .ok()
+            .map(|config| GenesisHeights::parse(&config).expect("Invalid INDEXER_GENESIS_HEIGHTS"))
+    }
+
+    pub fn height(&self, chain: &ChainId) -> i64 {
+        self.heights.get(&chain.0).copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_genesis_heights() {
+        let mainnet = GenesisHeights::known("mainnet01");
+        assert_eq!(mainnet.height(&ChainId(0)), 0);
+        assert_eq!(mainnet.height(&ChainId(9)), 0);
+        assert_eq!(mainnet.height(&ChainId(10)), 852054);
+        assert_eq!(mainnet.height(&ChainId(19)), 852054);
+        assert_eq!(
+            GenesisHeights::known("development"),
+            GenesisHeights::default()
+        );
+
+        let configured = GenesisHeights::parse("10:332604, 11 : 332604").unwrap();
+        assert_eq!(configured.height(&ChainId(11)), 332604);
+        assert_eq!(configured.height(&ChainId(12)), 0);
+        assert!(GenesisHeights::parse("10").is_err());
+        assert!(GenesisHeights::parse("ten:1").is_err());
+        assert!(GenesisHeights::parse("10:-1").is_err());
+    }
+}
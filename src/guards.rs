@@ -0,0 +1,262 @@
+use crate::db::DbError;
+use crate::models::{AccountGuard, Transaction};
+use crate::store::GuardStore;
+
+/// Functions setting the guard of an account, with the position of the account
+/// among the string arguments of the call.
+const GUARD_FUNCTIONS: [(&str, usize); 3] =
+    [("create-account", 0), ("transfer-create", 1), ("rotate", 0)];
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Str(String),
+    Atom(String),
+}
+
+/// Captures the guards set by successful `create-account`, `transfer-create` and `rotate`
+/// calls and stores the most recent guard of every account.
+pub fn process_guards(
+    transactions: &[Transaction],
+    repository: &impl GuardStore,
+) -> Result<(), DbError> {
+    let guards = transactions
+        .iter()
+        .flat_map(parse_guards)
+        .collect::<Vec<AccountGuard>>();
+    if !guards.is_empty() {
+        repository.upsert_batch(&guards)?;
+    }
+    Ok(())
+}
+
+/// Finds the account guards set by the transaction. Only guards read from the transaction
+/// data with `read-keyset` can be captured, calls using any other guard are ignored.
+fn parse_guards(tx: &Transaction) -> Vec<AccountGuard> {
+    let (code, data) = match (&tx.code, &tx.data, &tx.good_result) {
+        (Some(code), Some(data), Some(_)) => (code, data),
+        _ => return vec![],
+    };
+    let tokens = tokenize(code);
+    let mut guards = vec![];
+    for (position, window) in tokens.windows(2).enumerate() {
+        let function = match window {
+            [Token::Open, Token::Atom(function)] => function,
+            _ => continue,
+        };
+        let (module, account_position) = match GUARD_FUNCTIONS.iter().find_map(|(name, index)| {
+            function
+                .strip_suffix(name)
+                .and_then(|module| module.strip_suffix('.'))
+                .map(|module| (module, *index))
+        }) {
+            Some(found) => found,
+            None => continue,
+        };
+        let (strings, keyset) = call_arguments(&tokens[position + 2..]);
+        let guard = keyset
+            .and_then(|keyset| data.get(keyset))
+            .filter(|guard| guard.get("keys").is_some() && guard.get("pred").is_some());
+        if let (Some(account), Some(guard)) = (strings.get(account_position), guard) {
+            guards.push(AccountGuard {
+                account: account.to_string(),
+                chain_id: tx.chain_id,
+                module: module.to_string(),
+                guard: guard.clone(),
+                height: tx.height,
+                request_key: tx.request_key.clone(),
+            });
+        }
+    }
+    guards
+}
+
+/// Returns the string arguments of the call starting at the given tokens
+/// and the name of the keyset read with `read-keyset`, if any.
+fn call_arguments(tokens: &[Token]) -> (Vec<&str>, Option<&str>) {
+    let mut depth = 0;
+    let mut strings = vec![];
+    let mut keyset = None;
+    for (position, token) in tokens.iter().enumerate() {
+        match token {
+            Token::Open => depth += 1,
+            Token::Close if depth == 0 => break,
+            Token::Close => depth -= 1,
+            Token::Str(value) if depth == 0 => strings.push(value.as_str()),
+            Token::Atom(atom) if atom == "read-keyset" && keyset.is_none() => {
+                keyset = match tokens.get(position + 1) {
+                    Some(Token::Str(name)) | Some(Token::Atom(name)) => {
+                        Some(name.trim_start_matches('\''))
+                    }
+                    _ => None,
+                };
+            }
+            _ => {}
+        }
+    }
+    (strings, keyset)
+}
+
+fn tokenize(code: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut chars = code.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            '"' => {
+                let mut value = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => value.extend(chars.next()),
+                        '"' => break,
+                        _ => value.push(c),
+                    }
+                }
+                tokens.push(Token::Str(value));
+            }
+            c if c.is_whitespace() => {}
+            c => {
+                let mut value = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' || c == '"' {
+                        break;
+                    }
+                    value.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Atom(value));
+            }
+        }
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use crate::repository::AccountGuardsRepository;
+    use chrono::Utc;
+    use serial_test::serial;
+
+    fn make_tx(height: i64, code: &str, data: serde_json::Value) -> Transaction {
+        Transaction {
+            bad_result: None,
+            block: "block".to_string(),
+            chain_id: 0,
+            code: Some(code.to_string()),
+            continuation: None,
+            creation_time: Utc::now().naive_utc(),
+            data: Some(data),
+            error_message: None,
+            error_type: None,
+            gas: 0,
+            gas_limit: 0,
+            gas_price: 0.0,
+            good_result: Some(serde_json::json!("Write succeeded")),
+            height,
+            is_gas_station: false,
+            keysets: None,
+            logs: None,
+            metadata: None,
+            nonce: "nonce".to_string(),
+            num_events: None,
+            pact_id: None,
+            proof: None,
+            raw_cmd: None,
+            request_key: format!("request-key-{}", height),
+            rollback: None,
+            sender: "sender".to_string(),
+            signatures_valid: None,
+            signers: vec![],
+            status: "success".to_string(),
+            step: None,
+            ttl: 0,
+            tx_id: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_guards() {
+        let keyset = serde_json::json!({"keys": ["bob-key"], "pred": "keys-all"});
+        let tx = make_tx(
+            1,
+            "(coin.transfer-create \"alice\" \"bob\" (read-keyset \"ks\") 1.0)",
+            serde_json::json!({ "ks": keyset }),
+        );
+        assert_eq!(
+            parse_guards(&tx),
+            vec![AccountGuard {
+                account: "bob".to_string(),
+                chain_id: 0,
+                module: "coin".to_string(),
+                guard: keyset.clone(),
+                height: 1,
+                request_key: "request-key-1".to_string(),
+            }]
+        );
+
+        let tx = make_tx(
+            1,
+            "(free.token.create-account \"carol\" (read-keyset 'ks))",
+            serde_json::json!({ "ks": keyset }),
+        );
+        let guards = parse_guards(&tx);
+        assert_eq!(guards.len(), 1);
+        assert_eq!(guards[0].account, "carol");
+        assert_eq!(guards[0].module, "free.token");
+
+        // Guards which are not read from the data are ignored
+        let tx = make_tx(
+            1,
+            "(coin.create-account \"dave\" (keyset-ref-guard \"ns-admin\"))",
+            serde_json::json!({}),
+        );
+        assert!(parse_guards(&tx).is_empty());
+
+        // Failed transactions are ignored
+        let tx = Transaction {
+            good_result: None,
+            status: "failure".to_string(),
+            ..make_tx(
+                1,
+                "(coin.rotate \"bob\" (read-keyset \"ks\"))",
+                serde_json::json!({ "ks": keyset }),
+            )
+        };
+        assert!(parse_guards(&tx).is_empty());
+    }
+
+    #[test]
+    #[serial]
+    fn test_process_guards_keeps_the_most_recent_guard() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let repository = AccountGuardsRepository { pool: pool.clone() };
+        let old_keyset = serde_json::json!({"keys": ["old-key"], "pred": "keys-all"});
+        let new_keyset = serde_json::json!({"keys": ["new-key"], "pred": "keys-any"});
+        let create = make_tx(
+            1,
+            "(coin.create-account \"bob\" (read-keyset \"ks\"))",
+            serde_json::json!({ "ks": old_keyset }),
+        );
+        let rotate = make_tx(
+            2,
+            "(coin.rotate \"bob\" (read-keyset \"ks\"))",
+            serde_json::json!({ "ks": new_keyset }),
+        );
+        // Backfilling goes from the most recent blocks to the oldest ones
+        process_guards(&[rotate], &repository).unwrap();
+        process_guards(&[create], &repository).unwrap();
+
+        let guards = repository.find_by_account("bob").unwrap().rows;
+        assert_eq!(guards.len(), 1);
+        assert_eq!(guards[0].guard, new_keyset);
+        assert_eq!(guards[0].height, 2);
+        assert!(repository.find_by_account("alice").unwrap().rows.is_empty());
+
+        repository.delete_all().unwrap();
+    }
+}
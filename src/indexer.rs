@@ -1,43 +1,451 @@
-use bigdecimal::BigDecimal;
+use bigdecimal::num_bigint::{BigInt, Sign};
+use bigdecimal::{BigDecimal, Zero};
 use chrono::NaiveDateTime;
 use core::panic;
 use futures::stream;
 use futures::StreamExt;
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::str::FromStr;
-use std::time::Instant;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::vec;
 
 use super::chainweb_client::{
-    tx_result::PactTransactionResult, BlockHeader, BlockPayload, Bounds, ChainId, Command, Cut,
-    Hash, Payload, SignedTransaction,
+    tx_result::{PactTransactionResult, Status},
+    BlockHeader, BlockPayload, Bounds, ChainId, Command, Cut, Hash, Payload, SignedTransaction,
+    Signer,
 };
 use super::models::*;
 use super::repository::*;
-use crate::chainweb_client::ChainwebClient;
-use crate::db::DbError;
-use crate::transfers;
+use crate::budget::MemoryBudget;
+use crate::chainweb_client::{verify_signatures, ChainwebClient};
+use crate::db::{DbError, DbPool};
+use crate::genesis::GenesisHeights;
+use crate::guards;
+use crate::metrics::IndexerMetrics;
+use crate::pause::Pause;
+use crate::progress::IndexingProgress;
+use crate::projection;
+use crate::sink::{Batch, Sinks};
+use crate::store::{
+    BlockStore, CursorStore, EventStore, GuardStore, TransactionStore, TransferStore,
+};
+
+static SAVE_BLOCK_LOCK: Mutex<()> = Mutex::new(());
+/// Number of chains backfilled at once.
+const BACKFILL_CONCURRENCY: usize = 4;
+/// Headers from the stream queued beyond this are dropped, leaving gaps.
+const MAX_PENDING_HEADERS: usize = 1000;
+/// Blocks from the stream kept buffered while their saves fail, eg. while the database is down.
+const MAX_BUFFERED_BLOCKS: usize = 1000;
+/// How often the queued headers from the stream are retried, about the time between blocks.
+const PENDING_HEADERS_RETRY_INTERVAL: Duration = Duration::from_secs(30);
 
-pub struct Indexer<'a> {
+/// Indexes the blocks of the node. Everything is stored by the repositories, unless other
+/// stores are given, eg. in-memory ones in tests.
+pub struct Indexer<
+    'a,
+    B = BlocksRepository,
+    E = EventsRepository,
+    T = TransactionsRepository,
+    F = TransfersRepository,
+    G = AccountGuardsRepository,
+    C = StreamCursorsRepository,
+> {
     pub chainweb_client: &'a ChainwebClient,
-    pub blocks: BlocksRepository,
-    pub events: EventsRepository,
-    pub transactions: TransactionsRepository,
-    pub transfers: TransfersRepository,
+    pub blocks: B,
+    pub events: E,
+    pub transactions: T,
+    pub transfers: F,
+    pub account_guards: G,
+    /// Last block of every chain processed by the headers stream, where it resumes from.
+    pub stream_cursors: C,
+    /// When set, only events emitted by these modules are persisted.
+    pub indexed_modules: Option<Vec<String>>,
+    /// Number of times a failing batch is retried before giving up on the chain.
+    pub chain_retries: u32,
+    /// Number of immediate retries of the payload fetch of a header from the stream, before
+    /// the header is queued to be retried in the background.
+    pub payload_retries: u32,
+    /// When set, the signatures of every transaction are verified, which is expensive.
+    pub verify_signatures: bool,
+    /// When set, the signed command of every transaction is stored in `raw_cmd` so it can
+    /// be parsed again without fetching the payload from the node.
+    pub store_raw_cmd: bool,
+    /// When unset, transactions are not stored, only the blocks with their events and transfers.
+    /// Their results are still fetched from the node, as events are derived from them.
+    pub index_transactions: bool,
+    /// Accounts of known gas stations, transactions they send are flagged with `is_gas_station`.
+    pub gas_stations: Vec<String>,
+    /// Number of headers from the stream processed concurrently. Headers may then be
+    /// processed out of order, orphans are still handled as block saves are serialized.
+    pub stream_concurrency: usize,
+    /// Number of blocks from the stream buffered before being saved in a single database
+    /// transaction. With 1, blocks are saved as soon as they're fetched.
+    pub stream_buffer_size: usize,
+    /// Buffered blocks are saved at least this often, however many there are.
+    pub stream_flush_interval: Duration,
+    /// How often the progress of the backfill of every chain is logged.
+    pub progress_interval: Duration,
+    /// The headers stream is restarted when no event is received for this long.
+    pub stream_timeout: Duration,
+    /// Wait before restarting a stalled stream when no block was missed meanwhile, the node
+    /// then being idle too. New blocks come from the stream, there's no polling otherwise.
+    pub poll_interval: Duration,
+    /// Caps the size of the payloads of the batches processed concurrently.
+    pub payload_budget: MemoryBudget,
+    /// Indexing rate of every chain, served by the progress endpoint when enabled.
+    pub indexing_progress: Arc<IndexingProgress>,
+    /// Counters of the indexer, served by the metrics endpoint when enabled.
+    pub metrics: Arc<IndexerMetrics>,
+    /// Toggled by SIGUSR1, no request is sent to the node and nothing is saved while paused.
+    /// Headers from the stream are dropped meanwhile, the missed blocks are caught up on resume.
+    pub pause: Arc<Pause>,
+    /// Headers from the stream whose payload couldn't be fetched, waiting to be retried.
+    /// At most `MAX_PENDING_HEADERS`.
+    pub pending_headers: Mutex<Vec<(ChainId, BlockHeader)>>,
+    /// Headers from the stream being indexed. Like the pending ones, the stream cursors aren't
+    /// moved past them.
+    pub in_flight_headers: Mutex<Vec<(ChainId, BlockHeader)>>,
+    /// Height of the first block of every chain, the known heights of the network when `None`.
+    pub genesis_heights: Option<GenesisHeights>,
+    /// Number of heights, summed over all chains, a backfill refuses to index unless forced.
+    /// Unlimited when `None`.
+    pub max_backfill_span: Option<i64>,
+    /// Outputs the indexed blocks, events and transfers are published to once saved.
+    pub sinks: Sinks,
+}
+
+/// Outcome of a backfill, per chain.
+#[derive(Debug)]
+pub struct BackfillSummary {
+    pub outcomes: Vec<(ChainId, Result<(), String>)>,
+}
+
+impl BackfillSummary {
+    pub fn new(mut outcomes: Vec<(ChainId, Result<(), String>)>) -> Self {
+        outcomes.sort_by_key(|(chain, _)| chain.0);
+        BackfillSummary { outcomes }
+    }
+
+    pub fn failed_chains(&self) -> Vec<&ChainId> {
+        let mut chains = self
+            .outcomes
+            .iter()
+            .filter(|(_, result)| result.is_err())
+            .map(|(chain, _)| chain)
+            .collect::<Vec<&ChainId>>();
+        chains.dedup();
+        chains
+    }
+
+    pub fn log(&self) {
+        for (chain, result) in &self.outcomes {
+            match result {
+                Ok(_) => log::info!("Chain {}: backfill completed", chain),
+                Err(e) => log::error!("Chain {}: backfill failed: {}", chain, e),
+            }
+        }
+    }
+}
+
+/// Why the headers stream stopped.
+#[derive(Debug, PartialEq)]
+enum StreamEnd {
+    /// The stream ended or the indexer is shutting down.
+    Stopped,
+    /// No event was received for too long, the stream should be restarted.
+    Stalled,
+}
+
+/// Header from the stream being indexed, registered among the headers in flight until dropped.
+struct InFlight<'a> {
+    headers: &'a Mutex<Vec<(ChainId, BlockHeader)>>,
+    chain_id: ChainId,
+    hash: String,
+}
+
+impl<'a> InFlight<'a> {
+    fn new(
+        headers: &'a Mutex<Vec<(ChainId, BlockHeader)>>,
+        chain_id: &ChainId,
+        header: &BlockHeader,
+    ) -> Self {
+        headers
+            .lock()
+            .unwrap()
+            .push((chain_id.clone(), header.clone()));
+        InFlight {
+            headers,
+            chain_id: chain_id.clone(),
+            hash: header.hash.clone(),
+        }
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        let mut headers = self
+            .headers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(position) = headers
+            .iter()
+            .position(|(chain_id, header)| *chain_id == self.chain_id && header.hash == self.hash)
+        {
+            headers.remove(position);
+        }
+    }
+}
+
+/// Completes when the indexer is asked to stop, with Ctrl-C or SIGTERM, eg. by `docker stop`.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(e) => {
+                log::warn!("Unable to listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Completes once no event has been received for the given timeout,
+/// `last_event` being updated whenever an event is received.
+async fn watch_stream(last_event: &Mutex<Instant>, timeout: Duration) {
+    loop {
+        let deadline = *last_event.lock().unwrap() + timeout;
+        tokio::time::sleep_until(deadline.into()).await;
+        if last_event.lock().unwrap().elapsed() >= timeout {
+            return;
+        }
+    }
+}
+
+/// Progress of the backfill of a chain. Batches go from the upper bound down to the lower bound,
+/// so the progress is the share of the heights between them already indexed.
+struct BackfillProgress {
+    chain: u16,
+    /// Only resolved when the progress is first logged, short backfills don't need it.
+    lower_height: Option<u64>,
+    upper_height: Option<u64>,
+    current_height: Option<u64>,
+    started: Instant,
+    last_report: Instant,
+}
+
+impl BackfillProgress {
+    fn new(chain: u16) -> Self {
+        BackfillProgress {
+            chain,
+            lower_height: None,
+            upper_height: None,
+            current_height: None,
+            started: Instant::now(),
+            last_report: Instant::now(),
+        }
+    }
+
+    /// Records the heights of the highest and lowest blocks of an indexed batch.
+    fn update(&mut self, highest: u64, lowest: u64) {
+        self.upper_height.get_or_insert(highest);
+        self.current_height = Some(lowest);
+    }
+
+    /// Share of the heights already indexed, between 0 and 1.
+    fn completed(&self) -> f64 {
+        let lower = self.lower_height.unwrap_or_default();
+        match (self.upper_height, self.current_height) {
+            (Some(upper), Some(current)) if upper > lower => {
+                let total = upper - lower;
+                let done = upper - current.max(lower);
+                done as f64 / total as f64
+            }
+            (Some(_), Some(_)) => 1.0,
+            _ => 0.0,
+        }
+    }
+
+    /// Number of heights left to index, once the lower height is known.
+    fn remaining(&self) -> Option<u64> {
+        match (self.lower_height, self.current_height) {
+            (Some(lower), Some(current)) => Some(current.saturating_sub(lower)),
+            _ => None,
+        }
+    }
+
+    /// Estimated time left, assuming the remaining heights are indexed at the same pace.
+    fn eta(&self, elapsed: Duration) -> Option<Duration> {
+        let completed = self.completed();
+        match completed > 0.0 {
+            true => Some(elapsed.mul_f64((1.0 - completed) / completed)),
+            false => None,
+        }
+    }
+
+    fn is_due(&self, interval: Duration) -> bool {
+        self.last_report.elapsed() >= interval
+    }
+
+    fn report(&mut self) {
+        self.last_report = Instant::now();
+        let elapsed = self.started.elapsed();
+        log::info!(
+            "Chain {}: backfill {:.1}% complete, at height {}, ETA: {}",
+            self.chain,
+            self.completed() * 100.0,
+            self.current_height.unwrap_or_default(),
+            match self.eta(elapsed) {
+                Some(eta) => format!("{:.0?}", eta),
+                None => "unknown".to_string(),
+            }
+        );
+    }
 }
 
 impl<'a> Indexer<'a> {
-    pub async fn backfill(&self) -> Result<(), Box<dyn Error>> {
-        let cut = self.chainweb_client.get_cut().await.unwrap();
-        let bounds: Vec<(ChainId, Bounds)> = self.get_all_bounds(&cut);
-        stream::iter(bounds)
-            .map(|(chain, bounds)| async move { self.index_chain(bounds, &chain, false).await })
-            .buffer_unordered(4)
-            .collect::<Vec<Result<(), Box<dyn Error>>>>()
+    /// Creates an indexer using the given pool, configured from the `INDEXER_*` environment variables.
+    pub fn from_env(chainweb_client: &'a ChainwebClient, pool: &DbPool) -> Self {
+        use std::env;
+        Indexer {
+            chainweb_client,
+            blocks: BlocksRepository { pool: pool.clone() },
+            events: EventsRepository { pool: pool.clone() },
+            transactions: TransactionsRepository { pool: pool.clone() },
+            transfers: TransfersRepository { pool: pool.clone() },
+            account_guards: AccountGuardsRepository { pool: pool.clone() },
+            stream_cursors: StreamCursorsRepository { pool: pool.clone() },
+            indexed_modules: indexed_modules_from_env(),
+            chain_retries: env::var("INDEXER_CHAIN_RETRIES")
+                .unwrap_or_else(|_| "3".to_string())
+                .parse::<u32>()
+                .expect("Invalid INDEXER_CHAIN_RETRIES"),
+            payload_retries: env::var("INDEXER_PAYLOAD_RETRIES")
+                .unwrap_or_else(|_| "2".to_string())
+                .parse::<u32>()
+                .expect("Invalid INDEXER_PAYLOAD_RETRIES"),
+            verify_signatures: env::var("VERIFY_SIGS")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse::<bool>()
+                .expect("Invalid VERIFY_SIGS"),
+            store_raw_cmd: env::var("INDEXER_STORE_RAW_CMD")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse::<bool>()
+                .expect("Invalid INDEXER_STORE_RAW_CMD"),
+            index_transactions: env::var("INDEX_TRANSACTIONS")
+                .unwrap_or_else(|_| "true".to_string())
+                .parse::<bool>()
+                .expect("Invalid INDEX_TRANSACTIONS"),
+            gas_stations: gas_stations_from_env(),
+            stream_concurrency: env::var("INDEXER_STREAM_CONCURRENCY")
+                .unwrap_or_else(|_| "1".to_string())
+                .parse::<usize>()
+                .expect("Invalid INDEXER_STREAM_CONCURRENCY"),
+            stream_buffer_size: env::var("INDEXER_STREAM_BUFFER_SIZE")
+                .unwrap_or_else(|_| "1".to_string())
+                .parse::<usize>()
+                .expect("Invalid INDEXER_STREAM_BUFFER_SIZE"),
+            stream_flush_interval: Duration::from_secs(
+                env::var("INDEXER_STREAM_FLUSH_INTERVAL")
+                    .unwrap_or_else(|_| "5".to_string())
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|interval| *interval > 0)
+                    .expect("Invalid INDEXER_STREAM_FLUSH_INTERVAL"),
+            ),
+            progress_interval: Duration::from_secs(
+                env::var("INDEXER_PROGRESS_INTERVAL")
+                    .unwrap_or_else(|_| "60".to_string())
+                    .parse::<u64>()
+                    .expect("Invalid INDEXER_PROGRESS_INTERVAL"),
+            ),
+            stream_timeout: Duration::from_secs(
+                env::var("INDEXER_STREAM_TIMEOUT")
+                    .unwrap_or_else(|_| "120".to_string())
+                    .parse::<u64>()
+                    .ok()
+                    // A zero timeout would restart the stream in a loop
+                    .filter(|timeout| *timeout > 0)
+                    .expect("Invalid INDEXER_STREAM_TIMEOUT"),
+            ),
+            poll_interval: Duration::from_secs(
+                env::var("INDEXER_POLL_INTERVAL_SECS")
+                    .unwrap_or_else(|_| "0".to_string())
+                    .parse::<u64>()
+                    .expect("Invalid INDEXER_POLL_INTERVAL_SECS"),
+            ),
+            payload_budget: MemoryBudget::from_env(BACKFILL_CONCURRENCY),
+            indexing_progress: Arc::new(IndexingProgress::default()),
+            metrics: Arc::new(IndexerMetrics::default()),
+            pause: Arc::new(Pause::default()),
+            pending_headers: Mutex::new(vec![]),
+            in_flight_headers: Mutex::new(vec![]),
+            genesis_heights: GenesisHeights::from_env(),
+            max_backfill_span: env::var("INDEXER_MAX_BACKFILL_SPAN").ok().map(|span| {
+                span.parse::<i64>()
+                    .expect("Invalid INDEXER_MAX_BACKFILL_SPAN")
+            }),
+            sinks: Sinks::from_env(),
+        }
+    }
+}
+
+impl<
+        'a,
+        B: BlockStore,
+        E: EventStore,
+        T: TransactionStore,
+        F: TransferStore,
+        G: GuardStore,
+        C: CursorStore,
+    > Indexer<'a, B, E, T, F, G, C>
+{
+    /// Backfills all chains. Chains are indexed independently, a chain that keeps failing
+    /// after the configured retries doesn't stop the others. The outcome of every chain
+    /// is returned in the summary.
+    /// When a min or max height is given only the blocks between these heights are indexed,
+    /// otherwise everything missing below and above the indexed blocks is.
+    /// Backfills of more heights than the configured maximum are refused unless forced.
+    pub async fn backfill(
+        &self,
+        min_height: Option<i64>,
+        max_height: Option<i64>,
+        force: bool,
+    ) -> Result<BackfillSummary, Box<dyn Error>> {
+        let cut = self.chainweb_client.get_complete_cut().await?;
+        if !force {
+            self.check_backfill_span(&cut, min_height, max_height)?;
+        }
+        let bounds: Vec<(ChainId, Bounds)> = match (min_height, max_height) {
+            (None, None) => self.get_all_bounds(&cut),
+            _ => {
+                self.get_bounds_between_heights(&cut, min_height.unwrap_or(0), max_height)
+                    .await?
+            }
+        };
+        let outcomes = stream::iter(bounds)
+            .map(|(chain, bounds)| async move {
+                let result = self.index_chain(bounds, &chain, false).await;
+                (chain, result.map_err(|e| e.to_string()))
+            })
+            .buffer_unordered(BACKFILL_CONCURRENCY)
+            .collect::<Vec<(ChainId, Result<(), String>)>>()
             .await;
-        Ok(())
+        let summary = BackfillSummary::new(outcomes);
+        summary.log();
+        Ok(summary)
     }
 
     pub async fn backfill_range(
@@ -47,11 +455,11 @@ impl<'a> Indexer<'a> {
         chain: i64,
         force_update: bool,
     ) -> Result<(), Box<dyn Error>> {
-        let cut = self.chainweb_client.get_cut().await.unwrap();
+        let cut = self.chainweb_client.get_cut().await?;
         let latest_block_hash = cut
             .hashes
             .get(&ChainId(chain as u16))
-            .unwrap()
+            .ok_or_else(|| format!("Chain {} not found in the cut", chain))?
             .hash
             .to_string();
         let bounds = Bounds {
@@ -91,46 +499,303 @@ impl<'a> Indexer<'a> {
         force_update: bool,
     ) -> Result<(), Box<dyn Error>> {
         log::info!("Indexing chain: {}, bounds: {:?}", chain.0, bounds);
+        let mut progress = BackfillProgress::new(chain.0);
+        self.indexing_progress.start(chain.0);
         let mut next_bounds = bounds;
+        let mut failed_attempts = 0;
         loop {
+            self.pause.wait_while_paused().await;
             let before = Instant::now();
-            let response = self
-                .chainweb_client
-                .get_block_headers_branches(chain, &next_bounds, &None, None, None)
+            match self
+                .index_next_batch(&next_bounds, chain, force_update, &mut progress)
                 .await
-                .unwrap();
-            match response.items[..] {
-                [] => return Ok(()),
-                _ => {
+            {
+                Ok(Some(bounds)) => {
+                    next_bounds = bounds;
+                    failed_attempts = 0;
                     log::info!(
-                        "Chain {}: retrieved {} blocks, between heights {} and {}",
+                        "Chain {}, elapsed time per batch: {:.2?}",
                         chain.0,
-                        response.items.len(),
-                        response.items.first().unwrap().height,
-                        response.items.last().unwrap().height
+                        before.elapsed()
                     );
-                    let previous_bounds = next_bounds.clone();
-                    next_bounds = Bounds {
-                        upper: vec![Hash(response.items.last().unwrap().hash.to_string())],
-                        ..next_bounds
-                    };
-
-                    if next_bounds == previous_bounds {
-                        log::info!("Chain {}: fetched all blocks within given bounds.", chain.0);
-                        return Ok(());
+                    if progress.is_due(self.progress_interval) {
+                        if progress.lower_height.is_none() {
+                            progress.lower_height =
+                                Some(self.get_lower_height(&next_bounds, chain).await);
+                        }
+                        progress.report();
+                    }
+                }
+                Ok(None) => {
+                    self.indexing_progress.finish(chain.0);
+                    return Ok(());
+                }
+                Err(e) => {
+                    failed_attempts += 1;
+                    if failed_attempts > self.chain_retries {
+                        log::error!(
+                            "Chain {}: giving up after {} failed attempts: {}",
+                            chain.0,
+                            failed_attempts,
+                            e
+                        );
+                        return Err(e);
                     }
+                    log::warn!(
+                        "Chain {}: error indexing batch: {}, retrying ({}/{})",
+                        chain.0,
+                        e,
+                        failed_attempts,
+                        self.chain_retries
+                    );
+                    tokio::time::sleep(Duration::from_secs(2u64.pow(failed_attempts))).await;
                 }
             }
-            self.process_headers(response.items, chain, force_update)
-                .await?;
-            log::info!(
-                "Chain {}, elapsed time per batch: {:.2?}",
-                chain.0,
-                before.elapsed()
-            );
         }
     }
+
+    /// Returns the height of the lower bound, which is excluded from the bounds, to know
+    /// how many blocks are left to index. Without a lower bound blocks are indexed down to
+    /// the genesis block, assumed at height 0.
+    async fn get_lower_height(&self, bounds: &Bounds, chain: &ChainId) -> u64 {
+        if bounds.lower.is_empty() {
+            return 0;
+        }
+        let lower_bounds = Bounds {
+            lower: vec![],
+            upper: bounds.lower.clone(),
+        };
+        match self
+            .chainweb_client
+            .get_block_headers_branches(chain, &lower_bounds, &None, None, None)
+            .await
+        {
+            Ok(response) => response.items.first().map_or(0, |header| header.height),
+            Err(e) => {
+                log::warn!(
+                    "Chain {}: could not get the height of the lower bound, the backfill progress will be inaccurate: {}",
+                    chain.0,
+                    e
+                );
+                0
+            }
+        }
+    }
+
+    /// Fetches and indexes the next batch of headers within the bounds.
+    /// Returns the bounds of the remaining blocks or `None` if there is nothing left to index.
+    async fn index_next_batch(
+        &self,
+        bounds: &Bounds,
+        chain: &ChainId,
+        force_update: bool,
+        progress: &mut BackfillProgress,
+    ) -> Result<Option<Bounds>, Box<dyn Error>> {
+        let response = self
+            .chainweb_client
+            .get_block_headers_branches(chain, bounds, &None, None, None)
+            .await?;
+        let last_header = match response.items.last() {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        log::info!(
+            "Chain {}: retrieved {} blocks, between heights {} and {}",
+            chain.0,
+            response.items.len(),
+            response.items.first().unwrap().height,
+            last_header.height
+        );
+        let next_bounds = Bounds {
+            upper: vec![Hash(last_header.hash.to_string())],
+            ..bounds.clone()
+        };
+        if next_bounds == *bounds {
+            log::info!("Chain {}: fetched all blocks within given bounds.", chain.0);
+            return Ok(None);
+        }
+        let (highest, lowest) = (response.items.first().unwrap().height, last_header.height);
+        let count = response.items.len() as u64;
+        self.process_headers(response.items, chain, force_update)
+            .await?;
+        progress.update(highest, lowest);
+        self.indexing_progress
+            .record(chain.0, count, lowest, progress.remaining());
+        Ok(Some(next_bounds))
+    }
+
+    /// Errors when the backfill between the heights would index more heights than
+    /// `max_backfill_span`, eg. because of a typo in the heights.
+    fn check_backfill_span(
+        &self,
+        cut: &Cut,
+        min_height: Option<i64>,
+        max_height: Option<i64>,
+    ) -> Result<(), Box<dyn Error>> {
+        let max_span = match self.max_backfill_span {
+            Some(max_span) => max_span,
+            None => return Ok(()),
+        };
+        let span = self.backfill_span(cut, min_height, max_height)?;
+        log::info!("Backfilling {} heights over all chains", span);
+        match span > max_span {
+            true => Err(format!(
+                "The backfill would index {} heights over all chains, more than INDEXER_MAX_BACKFILL_SPAN ({}). \
+                 Narrow it down with --min-height and --max-height, or pass --force",
+                span, max_span
+            )
+            .into()),
+            false => Ok(()),
+        }
+    }
+
+    /// Number of heights of all chains a backfill between the heights indexes, leaving out
+    /// the heights which are already indexed the same way the bounds of the backfill do.
+    fn backfill_span(
+        &self,
+        cut: &Cut,
+        min_height: Option<i64>,
+        max_height: Option<i64>,
+    ) -> Result<i64, Box<dyn Error>> {
+        let genesis_heights = self.genesis_heights(cut);
+        let mut span = 0;
+        for (chain, last_block_hash) in cut.hashes.iter() {
+            let genesis_height = genesis_heights.height(chain);
+            let current_height = last_block_hash.height as i64;
+            span += match (min_height, max_height) {
+                (None, None) => match self
+                    .blocks
+                    .find_min_max_height_blocks(chain.0 as i64)
+                    .map_err(|e| e as Box<dyn Error>)?
+                {
+                    (Some(min_block), Some(max_block)) => {
+                        (current_height - max_block.height).max(0)
+                            + (min_block.height - genesis_height).max(0)
+                    }
+                    _ => current_height - genesis_height + 1,
+                },
+                _ => {
+                    let chain_min_height = min_height.unwrap_or(0).max(genesis_height);
+                    let chain_max_height = max_height.unwrap_or(current_height).min(current_height);
+                    match chain_min_height > chain_max_height {
+                        true => 0,
+                        false => self
+                            .blocks
+                            .find_missing_ranges(chain.0 as i64, chain_min_height, chain_max_height)
+                            .map_err(|e| e as Box<dyn Error>)?
+                            .iter()
+                            .map(|(range_min, range_max)| range_max - range_min + 1)
+                            .sum(),
+                    }
+                }
+            };
+        }
+        Ok(span)
+    }
+
+    /// Genesis heights of the chains, as configured or otherwise the known ones of the network
+    /// of the cut.
+    fn genesis_heights(&self, cut: &Cut) -> GenesisHeights {
+        self.genesis_heights
+            .clone()
+            .unwrap_or_else(|| GenesisHeights::known(&cut.instance))
+    }
+
+    /// Returns the bounds of the blocks between the given heights (inclusive) on every chain,
+    /// resolving the hashes of the blocks at these heights on the branch of the cut.
+    /// Without a max height the bounds go up to the cut, the min height is raised to the
+    /// genesis height of chains which started later. Heights which are already indexed are
+    /// left out, so a chain gets bounds for every range of missing blocks, or none at all.
+    async fn get_bounds_between_heights(
+        &self,
+        cut: &Cut,
+        min_height: i64,
+        max_height: Option<i64>,
+    ) -> Result<Vec<(ChainId, Bounds)>, Box<dyn Error>> {
+        let genesis_heights = self.genesis_heights(cut);
+        let mut chains = cut.hashes.iter().collect::<Vec<_>>();
+        chains.sort_by_key(|(chain, _)| chain.0);
+        let mut bounds: Vec<(ChainId, Bounds)> = vec![];
+        for (chain, last_block_hash) in chains {
+            let genesis_height = genesis_heights.height(chain);
+            let chain_min_height = min_height.max(genesis_height);
+            let current_height = last_block_hash.height as i64;
+            let chain_max_height = max_height.unwrap_or(current_height).min(current_height);
+            if chain_min_height > chain_max_height {
+                log::info!(
+                    "Chain {}: no blocks between heights {} and {}",
+                    chain.0,
+                    chain_min_height,
+                    chain_max_height
+                );
+                continue;
+            }
+            // Only the heights which are not indexed yet are fetched from the node
+            let missing_ranges = self
+                .blocks
+                .find_missing_ranges(chain.0 as i64, chain_min_height, chain_max_height)
+                .map_err(|e| e as Box<dyn Error>)?;
+            if missing_ranges.is_empty() {
+                log::info!(
+                    "Chain {}: all blocks between heights {} and {} are already indexed",
+                    chain.0,
+                    chain_min_height,
+                    chain_max_height
+                );
+            }
+            for (range_min, range_max) in missing_ranges {
+                let upper = match range_max == current_height {
+                    true => Some(last_block_hash.hash.to_string()),
+                    false => {
+                        self.chainweb_client
+                            .get_block_hash_at_height(
+                                chain,
+                                &last_block_hash.hash,
+                                range_max as u64,
+                            )
+                            .await?
+                    }
+                };
+                // Chains whose genesis height isn't known have no blocks below their first one
+                let upper = match upper {
+                    Some(upper) => upper,
+                    None => {
+                        log::info!("Chain {}: no block at height {}", chain.0, range_max);
+                        continue;
+                    }
+                };
+                // The lower bound is excluded, so it's the block right before the min height.
+                // There is no such block at the genesis height, chains are indexed from their first one.
+                let lower = match range_min > genesis_height {
+                    true => {
+                        self.chainweb_client
+                            .get_block_hash_at_height(chain, &upper, (range_min - 1) as u64)
+                            .await?
+                    }
+                    false => None,
+                };
+                log::info!(
+                    "Chain {}: indexing blocks between heights {} and {}",
+                    chain.0,
+                    range_min,
+                    range_max
+                );
+                bounds.push((
+                    chain.clone(),
+                    Bounds {
+                        lower: lower.map(Hash).into_iter().collect(),
+                        upper: vec![Hash(upper)],
+                    },
+                ));
+            }
+        }
+        Ok(bounds)
+    }
+
+    /// Returns the bounds of the blocks missing above and below the indexed blocks of every chain,
+    /// down to the genesis height of the chain.
     fn get_all_bounds(&self, cut: &Cut) -> Vec<(ChainId, Bounds)> {
+        let genesis_heights = self.genesis_heights(cut);
         let mut bounds: Vec<(ChainId, Bounds)> = vec![];
         cut.hashes.iter().for_each(|(chain, last_block_hash)| {
             log::info!(
@@ -152,7 +817,7 @@ impl<'a> Indexer<'a> {
                             upper: vec![Hash(last_block_hash.hash.to_string())],
                         },
                     ));
-                    if min_block.height > 0 {
+                    if min_block.height > genesis_heights.height(chain) {
                         bounds.push((
                             chain.clone(),
                             Bounds {
@@ -181,96 +846,125 @@ impl<'a> Indexer<'a> {
         chain_id: &ChainId,
         force_update: bool,
     ) -> Result<(), Box<dyn Error>> {
+        // Held until the batch is saved, other batches wait while the budget is exhausted
+        let mut reservation = self
+            .payload_budget
+            .reserve(self.payload_budget.estimate(headers.len()))
+            .await;
         let payloads = self
             .chainweb_client
-            .get_block_payload_batch(
-                chain_id,
-                headers
-                    .iter()
-                    .map(|e| e.payload_hash.as_str())
-                    .collect::<Vec<&str>>(),
-            )
-            .await
-            .unwrap();
-        let blocks = self.build_blocks(&headers, &payloads);
-
-        if force_update {
-            blocks
+            .get_block_payload_batch(chain_id, unique_payload_hashes(&headers))
+            .await?;
+        let payloads_size = payloads
+            .iter()
+            .map(BlockPayload::approximate_size)
+            .sum::<usize>();
+        reservation.resize(payloads_size).await;
+        let blocks = build_blocks(&headers, &payloads)?;
+        let signed_txs_by_hash = get_signed_txs_from_payloads(&payloads);
+        // Empty blocks are common, batches of them have no results to fetch
+        let tx_results = match signed_txs_by_hash.is_empty() {
+            true => vec![],
+            false => {
+                let request_keys: Vec<String> =
+                    signed_txs_by_hash.keys().map(|e| e.to_string()).collect();
+                self.fetch_transactions_results(&request_keys[..], chain_id)
+                    .await?
+            }
+        };
+        // The results are held along with the payloads until the batch is saved
+        let batch_size = payloads_size
+            + tx_results
                 .iter()
-                .for_each(|block| match self.delete_block_data(block) {
-                    Ok(_) => {}
-                    Err(e) => panic!("Error deleting data for block {}: {:#?}", block.hash, e),
-                });
-        }
-
-        match self.blocks.insert_batch(&blocks) {
-            Ok(_) => {}
-            Err(e) => panic!("Error inserting blocks: {:#?}", e),
-        }
+                .map(PactTransactionResult::approximate_size)
+                .sum::<usize>();
+        self.payload_budget.record(payloads.len(), batch_size);
+        reservation.resize(batch_size).await;
+        let txs = get_transactions_from_payload(
+            &signed_txs_by_hash,
+            &tx_results,
+            chain_id,
+            self.verify_signatures,
+            self.store_raw_cmd,
+            &self.gas_stations,
+        );
+        let events = get_events_from_txs(
+            &tx_results,
+            &signed_txs_by_hash,
+            self.indexed_modules.as_deref(),
+        );
+        let mut transfers = projection::project_transfers(&events, &blocks);
+        projection::authorize_transfers(&mut transfers, &signed_txs_by_hash);
+        let mut blocks = group_by_block(blocks, txs, events, transfers);
 
-        let signed_txs_by_hash = get_signed_txs_from_payloads(&payloads);
-        let request_keys: Vec<String> = signed_txs_by_hash.keys().map(|e| e.to_string()).collect();
-        let tx_results = self
-            .fetch_transactions_results(&request_keys[..], chain_id)
-            .await?;
-        let txs = get_transactions_from_payload(&signed_txs_by_hash, &tx_results, chain_id);
-        if !txs.is_empty() {
-            match self.transactions.insert_batch(&txs) {
-                Ok(inserted) => log::info!("Inserted {} transactions", inserted),
-                Err(e) => panic!("Error inserting transactions: {:#?}", e),
-            }
-            let events = get_events_from_txs(&tx_results, &signed_txs_by_hash);
-            if !events.is_empty() {
-                match self.events.insert_batch(&events) {
-                    Ok(inserted) => {
-                        log::info!("Inserted {} events", inserted);
-                        match transfers::process_transfers(&events, &blocks, &self.transfers) {
-                            Ok(_) => {}
-                            Err(e) => panic!("Error updating balances: {:#?}", e),
-                        }
-                    }
-                    Err(e) => panic!("Error inserting events: {:#?}", e),
-                }
+        // Reprocessed blocks are replaced with their data derived again, new blocks are
+        // inserted leaving those already indexed as they are, along with their data
+        let saved = match force_update {
+            true => self.save_blocks_data(&mut blocks).map(|_| blocks),
+            false => self.insert_blocks_data(blocks),
+        };
+        let blocks = match saved {
+            Ok(blocks) => blocks,
+            Err(e) => {
+                log::error!("Error saving {} blocks: {:#?}", headers.len(), e);
+                return Err(e);
             }
-        }
+        };
+        self.process_saved_guards(&blocks);
+        self.publish_blocks_data(&blocks).await;
         Ok(())
     }
 
+    /// Indexes the block of the header, saved with its transactions, events and transfers in a
+    /// single database transaction, replacing the block at its height if any.
     async fn process_header(
         &self,
         header: &BlockHeader,
         chain_id: &ChainId,
     ) -> Result<(), Box<dyn Error>> {
-        let payloads = self
-            .chainweb_client
-            .get_block_payload_batch(chain_id, vec![header.payload_hash.as_str()])
-            .await?;
-        if payloads.is_empty() {
-            log::error!(
-                "No payload received from node, payload hash: {}, height: {}, chain: {}",
-                header.payload_hash,
-                header.height,
-                chain_id.0
-            );
-            //TODO: Should probably retry here
-            return Err("Unable to retrieve payload".into());
+        let mut blocks = [self.fetch_block(header, chain_id).await?];
+        if let Err(e) = self.save_blocks_data(&mut blocks) {
+            log::error!("Error saving block {}: {:#?}", header.hash, e);
+            return Err(e);
+        }
+        self.process_saved_guards(&blocks);
+        self.publish_blocks_data(&blocks).await;
+        Ok(())
+    }
+
+    /// Fetches the payload and the transaction results of the block of the header and returns
+    /// the block with its transactions, events and transfers, without saving anything.
+    async fn fetch_block(
+        &self,
+        header: &BlockHeader,
+        chain_id: &ChainId,
+    ) -> Result<BlockData, Box<dyn Error>> {
+        let payload = self.fetch_payload(header, chain_id).await?;
+        let block = build_block(header, &payload);
+        let signed_txs_by_hash = get_signed_txs_from_payload(&payload);
+        // Empty blocks are common, there are no results to fetch nor anything derived from them
+        if signed_txs_by_hash.is_empty() {
+            return Ok(BlockData {
+                block,
+                transactions: vec![],
+                events: vec![],
+                transfers: vec![],
+            });
         }
-        let block = build_block(header, &payloads[0]);
-        match self.save_block(&block) {
-            Err(e) => {
-                log::error!("Error saving block: {:#?}", e);
-                return Err(e);
-            }
-            Ok(block) => block,
-        };
-        let signed_txs_by_hash = get_signed_txs_from_payload(&payloads[0]);
         let request_keys: Vec<String> = signed_txs_by_hash.keys().map(|e| e.to_string()).collect();
         let before = Instant::now();
         let tx_results = self
             .fetch_transactions_results(&request_keys[..], chain_id)
             .await?;
         log::info!("Elapsed time to get results: {:.2?}", before.elapsed());
-        let txs = get_transactions_from_payload(&signed_txs_by_hash, &tx_results, chain_id);
+        let txs = get_transactions_from_payload(
+            &signed_txs_by_hash,
+            &tx_results,
+            chain_id,
+            self.verify_signatures,
+            self.store_raw_cmd,
+            &self.gas_stations,
+        );
         txs.iter().for_each(|tx| {
             if tx.block != block.hash {
                 log::error!(
@@ -283,142 +977,627 @@ impl<'a> Indexer<'a> {
             .into_iter()
             .filter(|tx| tx.block == block.hash)
             .collect::<Vec<Transaction>>();
-        match self.transactions.insert_batch(&txs) {
-            Ok(inserted) => {
-                if inserted > 0 {
-                    log::info!("Inserted {} transactions", inserted)
-                }
-            }
-            Err(e) => panic!("Error inserting transactions: {:#?}", e),
-        }
-        let events = get_events_from_txs(&tx_results, &signed_txs_by_hash);
+        let events = get_events_from_txs(
+            &tx_results,
+            &signed_txs_by_hash,
+            self.indexed_modules.as_deref(),
+        );
         let events = events
             .into_iter()
             .filter(|e| e.block == block.hash)
             .collect::<Vec<Event>>();
-        match self.events.insert_batch(&events) {
-            Ok(inserted) => {
-                if inserted > 0 {
-                    log::info!("Inserted {} events", inserted);
-                    match transfers::process_transfers(&events, &[block], &self.transfers) {
+        let mut transfers = projection::project_transfers(&events, std::slice::from_ref(&block));
+        projection::authorize_transfers(&mut transfers, &signed_txs_by_hash);
+        Ok(BlockData {
+            block,
+            transactions: txs,
+            events,
+            transfers,
+        })
+    }
+
+    /// Fetches the payload of the header, retrying right away up to `payload_retries` times
+    /// to get past transient node failures. A header whose payload still can't be fetched is
+    /// queued to be retried in the background, rather than leaving a gap.
+    async fn fetch_payload(
+        &self,
+        header: &BlockHeader,
+        chain_id: &ChainId,
+    ) -> Result<BlockPayload, Box<dyn Error>> {
+        for attempt in 0..=self.payload_retries {
+            let result = self
+                .chainweb_client
+                .get_block_payload_batch(chain_id, vec![header.payload_hash.as_str()])
+                .await;
+            match result {
+                Ok(mut payloads) if !payloads.is_empty() => return Ok(payloads.remove(0)),
+                Ok(_) => log::warn!(
+                    "Chain {}: no payload received for height {}, payload hash: {} ({}/{})",
+                    chain_id.0,
+                    header.height,
+                    header.payload_hash,
+                    attempt + 1,
+                    self.payload_retries + 1
+                ),
+                Err(e) => log::warn!(
+                    "Chain {}: error fetching the payload of height {}: {} ({}/{})",
+                    chain_id.0,
+                    header.height,
+                    e,
+                    attempt + 1,
+                    self.payload_retries + 1
+                ),
+            }
+        }
+        log::error!(
+            "Chain {}: unable to retrieve the payload of height {}, queued for retry",
+            chain_id.0,
+            header.height
+        );
+        self.queue_pending_header(chain_id, header);
+        Err("Unable to retrieve payload".into())
+    }
+
+    /// Queues the header to be retried, unless `MAX_PENDING_HEADERS` are already queued, eg. while
+    /// the node keeps failing, the header is then dropped and left to the gaps command.
+    fn queue_pending_header(&self, chain_id: &ChainId, header: &BlockHeader) {
+        let mut pending_headers = self.pending_headers.lock().unwrap();
+        // Headers failing again when retried are still queued
+        if pending_headers.iter().any(|(pending_chain, pending)| {
+            pending_chain == chain_id && pending.hash == header.hash
+        }) {
+            return;
+        }
+        if pending_headers.len() >= MAX_PENDING_HEADERS {
+            log::error!(
+                "Chain {}: {} headers already queued, dropping height {}",
+                chain_id.0,
+                pending_headers.len(),
+                header.height
+            );
+            return;
+        }
+        pending_headers.push((chain_id.clone(), header.clone()));
+    }
+
+    /// Indexes the new blocks from the headers stream. When no header is received for
+    /// `stream_timeout` the stream is considered stalled: it's restarted, after indexing the
+    /// blocks produced in the meantime.
+    /// Before the stream starts, the blocks produced since the last run are indexed from the
+    /// stream cursors.
+    pub async fn listen_headers_stream(&self) -> Result<(), Box<dyn Error>> {
+        let buffer = tokio::sync::Mutex::new(Vec::<BlockData>::new());
+        match self.resume_from_cursors().await {
+            Ok(behind) => log::info!("Resumed {} chains from the stream cursors", behind),
+            // What's still missing is left to the gaps command
+            Err(e) => log::error!("Error resuming from the stream cursors: {}", e),
+        }
+        loop {
+            let result = self.stream_headers(&buffer).await;
+            // Buffered blocks are saved before returning, whatever the reason the stream stopped,
+            // unless paused
+            let flushed = self.flush(&buffer).await;
+            match result? {
+                StreamEnd::Stalled => {
+                    // The blocks are kept buffered, to be saved with the next flush
+                    if let Err(e) = flushed {
+                        log::error!("Error flushing buffered blocks: {}", e);
+                    }
+                    log::warn!(
+                        "No header received for {:?}, restarting the stream",
+                        self.stream_timeout
+                    );
+                    self.pause.wait_while_paused().await;
+                    match self.catch_up().await {
+                        Ok(0) if !self.poll_interval.is_zero() => {
+                            log::info!(
+                                "No block missed, waiting {:?} before restarting the stream",
+                                self.poll_interval
+                            );
+                            tokio::time::sleep(self.poll_interval).await;
+                            // The blocks produced while waiting aren't sent by the new stream
+                            if let Err(e) = self.catch_up().await {
+                                log::error!(
+                                    "Error indexing the blocks produced while waiting: {}",
+                                    e
+                                )
+                            }
+                        }
                         Ok(_) => {}
-                        Err(e) => panic!("Error updating balances: {:#?}", e),
+                        Err(e) => {
+                            log::error!("Error indexing the blocks missed by the stream: {}", e)
+                        }
                     }
                 }
+                StreamEnd::Stopped => return flushed,
             }
-            Err(e) => panic!("Error inserting events: {:#?}", e),
         }
-        Ok(())
     }
 
-    pub async fn listen_headers_stream(&self) -> Result<(), Box<dyn Error>> {
+    async fn stream_headers(
+        &self,
+        buffer: &tokio::sync::Mutex<Vec<BlockData>>,
+    ) -> Result<StreamEnd, Box<dyn Error>> {
         use crate::chainweb_client::BlockHeaderEvent;
         use eventsource_client as es;
         use futures::stream::TryStreamExt;
 
-        match self.chainweb_client.start_headers_stream() {
-            Ok(stream) => {
-                log::info!("Stream started");
-                match stream
-                    .try_for_each(|event| async move {
-                        if let es::SSE::Event(ev) = event {
-                            if ev.event_type == "BlockHeader" {
-                                let block_header_event: BlockHeaderEvent =
-                                    serde_json::from_str(&ev.data).unwrap();
-                                let chain_id = block_header_event.header.chain_id.clone();
+        let stream = match self.chainweb_client.start_headers_stream() {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::error!("Stream error: {:?}", e);
+                return Err("Error".into());
+            }
+        };
+        log::info!("Stream started");
+        let last_event = Mutex::new(Instant::now());
+        let last_event = &last_event;
+        let process_stream =
+            stream.try_for_each_concurrent(self.stream_concurrency, |event| async move {
+                if let es::SSE::Event(ev) = event {
+                    *last_event.lock().unwrap() = Instant::now();
+                    if ev.event_type == "BlockHeader" {
+                        let block_header_event: BlockHeaderEvent =
+                            serde_json::from_str(&ev.data).unwrap();
+                        let chain_id = block_header_event.header.chain_id.clone();
+                        log::info!(
+                            "Chain {} header, height {} received",
+                            chain_id,
+                            block_header_event.header.height
+                        );
+                        // Dropped, the blocks produced while paused are caught up once resumed
+                        if self.pause.is_paused() {
+                            return Ok(());
+                        }
+                        let result = self
+                            .index_stream_header(&block_header_event.header, &chain_id, buffer)
+                            .await;
+                        match result {
+                            Ok(_) => {
                                 log::info!(
-                                    "Chain {} header, height {} received",
+                                    "Chain {} header, height {} processed",
                                     chain_id,
-                                    block_header_event.header.height
+                                    block_header_event.header.height,
                                 );
-                                match self
-                                    .process_header(&block_header_event.header, &chain_id)
-                                    .await
-                                {
-                                    Ok(_) => {
-                                        log::info!(
-                                            "Chain {} header, height {} processed",
-                                            chain_id,
-                                            block_header_event.header.height,
-                                        );
-                                    }
-                                    Err(e) => log::error!("Error processing headers: {:#?}", e),
-                                }
                             }
+                            Err(e) => log::error!("Error processing headers: {:#?}", e),
                         }
-                        Ok(())
-                    })
-                    .await
-                {
-                    Ok(_) => {
-                        log::info!("Headers stream ended");
-                        Ok(())
                     }
-                    Err(_) => Err("Stream error".into()),
                 }
+                Ok(())
+            });
+        let flush_periodically = async {
+            // Without buffering every block is saved as it's received, there's nothing to flush
+            if self.stream_buffer_size <= 1 {
+                return std::future::pending::<()>().await;
             }
-            Err(e) => {
-                log::error!("Stream error: {:?}", e);
-                Err("Error".into())
+            let mut interval = tokio::time::interval(self.stream_flush_interval);
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.flush(buffer).await {
+                    log::error!("Error flushing buffered blocks: {:#?}", e);
+                }
             }
-        }
-    }
-
-    /// Builds the list of blocks from the given headers and payloads
-    /// and inserts them in the database in a single transaction.
-    fn build_blocks(&self, headers: &[BlockHeader], payloads: &[BlockPayload]) -> Vec<Block> {
-        let headers_by_payload_hash = headers
-            .iter()
-            .map(|e| (e.payload_hash.clone(), e))
-            .collect::<HashMap<String, &BlockHeader>>();
-        let payloads_by_hash = payloads
-            .iter()
-            .map(|e| (e.payload_hash.clone(), e))
-            .collect::<HashMap<String, &BlockPayload>>();
-        headers_by_payload_hash
-            .into_iter()
-            .map(|(payload_hash, header)| {
-                build_block(header, payloads_by_hash.get(&payload_hash).unwrap())
-            })
-            .collect::<Vec<Block>>()
-    }
-
-    /// Dealing with duplicate blocks (this only happens through the headers stream):
-    /// - try to insert the block
-    /// - if it fails, check if the block is already in the db
-    /// - if it is, delete the block and associated data
-    /// - insert the block again
-    fn save_block(&self, block: &Block) -> Result<Block, DbError> {
-        use diesel::result::DatabaseErrorKind;
-        use diesel::result::Error::DatabaseError;
-        match self.blocks.insert(block) {
-            Ok(inserted_block) => Ok(inserted_block),
-            Err(e) => match e.downcast_ref() {
-                Some(DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
-                    log::info!("Block already exists");
-                    let orphan = self
-                        .blocks
-                        .find_by_height(block.height, block.chain_id)
-                        .unwrap()
-                        .unwrap();
-                    self.delete_block_data(&orphan)?;
-                    self.blocks.insert(block)
+        };
+        let catch_up_on_resume = async {
+            loop {
+                self.pause.resumed().await;
+                match self.catch_up().await {
+                    Ok(behind) => log::info!("Caught up {} chains after resuming", behind),
+                    Err(e) => log::error!("Error indexing the blocks missed while paused: {}", e),
+                }
+            }
+        };
+        // Alongside the stream, so that new headers aren't held up by a failing node
+        let retry_periodically = async {
+            let mut interval = tokio::time::interval(PENDING_HEADERS_RETRY_INTERVAL);
+            loop {
+                interval.tick().await;
+                if self.pause.is_paused() {
+                    continue;
                 }
-                _ => Err(e),
+                self.retry_pending_headers(buffer).await;
+            }
+        };
+        tokio::select! {
+            result = process_stream => match result {
+                Ok(_) => {
+                    log::info!("Headers stream ended");
+                    Ok(StreamEnd::Stopped)
+                }
+                Err(_) => Err("Stream error".into()),
             },
+            _ = flush_periodically => Ok(StreamEnd::Stopped),
+            _ = retry_periodically => Ok(StreamEnd::Stopped),
+            _ = catch_up_on_resume => Ok(StreamEnd::Stopped),
+            // Dropping the stream closes the connection to the node
+            _ = watch_stream(last_event, self.stream_timeout) => Ok(StreamEnd::Stalled),
+            _ = shutdown_signal() => {
+                log::info!("Shutting down");
+                Ok(StreamEnd::Stopped)
+            }
         }
     }
 
-    fn delete_block_data(&self, block: &Block) -> Result<(), DbError> {
-        self.transfers
-            .delete_all_by_block(&block.hash, block.chain_id)?;
-        self.events.delete_all_by_block(&block.hash)?;
-        self.transactions.delete_all_by_block(&block.hash)?;
-        self.blocks.delete_by_hash(&block.hash, block.chain_id)?;
-        Ok(())
-    }
-
-    async fn fetch_transactions_results(
+    /// Indexes the block of a header from the stream, buffered when `stream_buffer_size` > 1.
+    async fn index_stream_header(
+        &self,
+        header: &BlockHeader,
+        chain_id: &ChainId,
+        buffer: &tokio::sync::Mutex<Vec<BlockData>>,
+    ) -> Result<(), Box<dyn Error>> {
+        let _in_flight = InFlight::new(&self.in_flight_headers, chain_id, header);
+        match self.stream_buffer_size > 1 {
+            true => self.buffer_header(header, chain_id, buffer).await,
+            false => {
+                self.process_header(header, chain_id).await?;
+                self.save_stream_cursors(vec![StreamCursor {
+                    chain_id: chain_id.0 as i64,
+                    height: header.height as i64,
+                    hash: header.hash.clone(),
+                }]);
+                Ok(())
+            }
+        }
+    }
+
+    /// Indexes the queued headers whose payload couldn't be fetched. They stay queued until
+    /// indexed, so that the stream cursors don't move past them, and those still failing or not
+    /// retried yet when cancelled are retried the next time.
+    async fn retry_pending_headers(&self, buffer: &tokio::sync::Mutex<Vec<BlockData>>) {
+        let headers = self.pending_headers.lock().unwrap().clone();
+        for (chain_id, header) in headers {
+            log::info!(
+                "Chain {}: retrying header at height {}",
+                chain_id.0,
+                header.height
+            );
+            match self.index_stream_header(&header, &chain_id, buffer).await {
+                Ok(_) => self
+                    .pending_headers
+                    .lock()
+                    .unwrap()
+                    .retain(|(pending_chain, pending)| {
+                        *pending_chain != chain_id || pending.hash != header.hash
+                    }),
+                Err(e) => log::error!(
+                    "Chain {}: error retrying header at height {}: {}",
+                    chain_id.0,
+                    header.height,
+                    e
+                ),
+            }
+        }
+    }
+
+    /// Indexes the blocks above the highest indexed block of every chain, up to the current cut.
+    /// Returns the number of chains which were behind.
+    async fn catch_up(&self) -> Result<usize, Box<dyn Error>> {
+        let mut behind = 0;
+        let cut = self.chainweb_client.get_cut().await?;
+        let mut chains = cut.hashes.iter().collect::<Vec<_>>();
+        chains.sort_by_key(|(chain, _)| chain.0);
+        for (chain, last_block_hash) in chains {
+            let max_block = match self
+                .blocks
+                .find_min_max_height_blocks(chain.0 as i64)
+                .map_err(|e| e as Box<dyn Error>)?
+            {
+                (_, Some(max_block)) => max_block,
+                // Nothing indexed yet on this chain, that's for the backfill
+                _ => continue,
+            };
+            if max_block.height >= last_block_hash.height as i64 {
+                continue;
+            }
+            log::info!(
+                "Chain {}: catching up from height {} to {}",
+                chain.0,
+                max_block.height,
+                last_block_hash.height
+            );
+            let bounds = Bounds {
+                lower: vec![Hash(max_block.hash)],
+                upper: vec![Hash(last_block_hash.hash.to_string())],
+            };
+            behind += 1;
+            self.index_chain(bounds, chain, false).await?;
+            self.save_stream_cursors(vec![StreamCursor {
+                chain_id: chain.0 as i64,
+                height: last_block_hash.height as i64,
+                hash: last_block_hash.hash.clone(),
+            }]);
+        }
+        Ok(behind)
+    }
+
+    /// Indexes the blocks above the last block processed by the stream on every chain, up to
+    /// the current cut. Chains without a cursor, never reached by the stream, are left to the
+    /// backfill. A chain failing to catch up doesn't stop the others.
+    /// Returns the number of chains which were behind.
+    async fn resume_from_cursors(&self) -> Result<usize, Box<dyn Error>> {
+        let cursors = self
+            .stream_cursors
+            .find_all()
+            .map_err(|e| e as Box<dyn Error>)?;
+        if cursors.is_empty() {
+            return Ok(0);
+        }
+        let mut behind = 0;
+        let cut = self.chainweb_client.get_cut().await?;
+        for cursor in cursors {
+            let chain = ChainId(cursor.chain_id as u16);
+            let last_block_hash = match cut.hashes.get(&chain) {
+                Some(last_block_hash) => last_block_hash,
+                None => continue,
+            };
+            if cursor.height >= last_block_hash.height as i64 {
+                continue;
+            }
+            log::info!(
+                "Chain {}: resuming the stream from height {} to {}",
+                chain.0,
+                cursor.height,
+                last_block_hash.height
+            );
+            // Blocks orphaned since are replaced from the fork point, the node returning the
+            // branch of the upper bound that's not an ancestor of the lower one
+            let bounds = Bounds {
+                lower: vec![Hash(cursor.hash)],
+                upper: vec![Hash(last_block_hash.hash.to_string())],
+            };
+            behind += 1;
+            match self.index_chain(bounds, &chain, false).await {
+                Ok(_) => self.save_stream_cursors(vec![StreamCursor {
+                    chain_id: cursor.chain_id,
+                    height: last_block_hash.height as i64,
+                    hash: last_block_hash.hash.clone(),
+                }]),
+                Err(e) => log::error!("Chain {}: error resuming the stream: {}", chain.0, e),
+            }
+        }
+        Ok(behind)
+    }
+
+    /// Moves the stream cursors to the given blocks. A failure is only logged, the cursors
+    /// being behind at worst, so that more blocks are indexed again on restart.
+    /// A cursor isn't moved past the headers of its chain pending or in flight, it's left at the
+    /// parent of the lowest of them instead, so that they're indexed again on restart.
+    fn save_stream_cursors(&self, cursors: Vec<StreamCursor>) {
+        let cursors = cursors
+            .into_iter()
+            .map(|cursor| self.cap_stream_cursor(cursor))
+            .collect::<Vec<StreamCursor>>();
+        if let Err(e) = self.stream_cursors.save(&cursors) {
+            log::error!("Error saving the stream cursors: {}", e);
+        }
+    }
+
+    fn cap_stream_cursor(&self, cursor: StreamCursor) -> StreamCursor {
+        let pending_headers = self.pending_headers.lock().unwrap();
+        let in_flight_headers = self.in_flight_headers.lock().unwrap();
+        let lowest = pending_headers
+            .iter()
+            .chain(in_flight_headers.iter())
+            .filter(|(chain_id, header)| {
+                chain_id.0 as i64 == cursor.chain_id
+                    && header.hash != cursor.hash
+                    && header.height as i64 <= cursor.height
+            })
+            .map(|(_, header)| header)
+            .min_by_key(|header| header.height);
+        match lowest {
+            Some(header) => StreamCursor {
+                chain_id: cursor.chain_id,
+                height: header.height as i64 - 1,
+                hash: header.parent.clone(),
+            },
+            None => cursor,
+        }
+    }
+
+    /// Fetches the block of the header and adds it to the buffer,
+    /// the buffer is flushed when it reaches `stream_buffer_size` blocks.
+    /// While the saves keep failing the buffer is capped to `MAX_BUFFERED_BLOCKS`, the headers
+    /// received meanwhile are queued for retry instead, which keeps the cursors below them.
+    async fn buffer_header(
+        &self,
+        header: &BlockHeader,
+        chain_id: &ChainId,
+        buffer: &tokio::sync::Mutex<Vec<BlockData>>,
+    ) -> Result<(), Box<dyn Error>> {
+        if buffer.lock().await.len() >= MAX_BUFFERED_BLOCKS.max(self.stream_buffer_size) {
+            log::error!(
+                "Chain {}: buffer full, height {} queued for retry",
+                chain_id.0,
+                header.height
+            );
+            self.queue_pending_header(chain_id, header);
+            return Err("Buffer full".into());
+        }
+        let data = self.fetch_block(header, chain_id).await?;
+        let buffered = {
+            let mut buffer = buffer.lock().await;
+            buffer.push(data);
+            buffer.len()
+        };
+        if buffered >= self.stream_buffer_size {
+            self.flush(buffer).await?;
+        }
+        Ok(())
+    }
+
+    /// Saves all the buffered blocks with their data in a single database transaction.
+    /// Nothing is saved while paused, the blocks are kept buffered until resumed. They're also
+    /// kept buffered when the save fails, to be saved with the next flush. If the indexer stops
+    /// meanwhile, they're indexed again from the stream cursors on restart.
+    async fn flush(
+        &self,
+        buffer: &tokio::sync::Mutex<Vec<BlockData>>,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.pause.is_paused() {
+            return Ok(());
+        }
+        let mut blocks = std::mem::take(&mut *buffer.lock().await);
+        if blocks.is_empty() {
+            return Ok(());
+        }
+        let before = Instant::now();
+        if let Err(e) = self.save_blocks_data(&mut blocks) {
+            // Nothing was saved, the blocks are put back in front of those buffered since
+            log::error!(
+                "Error saving {} buffered blocks, kept buffered, heights: {:?}",
+                blocks.len(),
+                blocks
+                    .iter()
+                    .map(|data| (data.block.chain_id, data.block.height))
+                    .collect::<Vec<(i64, i64)>>()
+            );
+            let mut buffer = buffer.lock().await;
+            blocks.append(&mut buffer);
+            *buffer = blocks;
+            return Err(e);
+        }
+        self.process_saved_guards(&blocks);
+        // The highest block of every chain, the last one collected by height overriding the others
+        let mut saved_blocks = blocks
+            .iter()
+            .map(|data| &data.block)
+            .collect::<Vec<&Block>>();
+        saved_blocks.sort_by_key(|block| block.height);
+        let cursors = saved_blocks
+            .into_iter()
+            .map(|block| {
+                let cursor = StreamCursor {
+                    chain_id: block.chain_id,
+                    height: block.height,
+                    hash: block.hash.clone(),
+                };
+                (block.chain_id, cursor)
+            })
+            .collect::<HashMap<i64, StreamCursor>>();
+        self.save_stream_cursors(cursors.into_values().collect());
+        log::info!(
+            "Saved {} buffered blocks in {:.2?}",
+            blocks.len(),
+            before.elapsed()
+        );
+        self.publish_blocks_data(&blocks).await;
+        Ok(())
+    }
+
+    /// Saves the blocks with their data in a single database transaction, replacing the blocks
+    /// already at their heights. Transactions are left out unless `index_transactions` is set.
+    fn save_blocks_data(&self, blocks: &mut [BlockData]) -> Result<(), DbError> {
+        let orphans =
+            self.with_indexed_transactions(blocks, |blocks| self.blocks.save_with_data(blocks))?;
+        self.record_orphans(&orphans);
+        Ok(())
+    }
+
+    /// Inserts the blocks with their data in a single database transaction, leaving the blocks
+    /// already indexed as they are, along with their data. Returns the inserted blocks.
+    fn insert_blocks_data(&self, mut blocks: Vec<BlockData>) -> Result<Vec<BlockData>, DbError> {
+        let (inserted, orphans) = self.with_indexed_transactions(&mut blocks, |blocks| {
+            self.blocks.insert_batch_with_data(blocks)
+        })?;
+        log::info!(
+            "Inserted {} blocks, skipped {} already indexed",
+            inserted.len(),
+            blocks.len() - inserted.len()
+        );
+        self.record_orphans(&orphans);
+        let inserted = inserted.into_iter().collect::<HashSet<String>>();
+        blocks.retain(|data| inserted.contains(&data.block.hash));
+        Ok(blocks)
+    }
+
+    /// Saves the blocks with `save`, their transactions being left out unless
+    /// `index_transactions` is set. They're given back to the blocks afterwards either way.
+    fn with_indexed_transactions<T>(
+        &self,
+        blocks: &mut [BlockData],
+        save: impl FnOnce(&[BlockData]) -> Result<T, DbError>,
+    ) -> Result<T, DbError> {
+        let unsaved_transactions = match self.index_transactions {
+            true => vec![],
+            false => blocks
+                .iter_mut()
+                .map(|data| std::mem::take(&mut data.transactions))
+                .collect::<Vec<Vec<Transaction>>>(),
+        };
+        let saved = {
+            // Replacements lock the replaced block in the database, which serializes them across
+            // processes (eg. the stream and a backfill). Within this process they're serialized
+            // before reaching the database, so concurrent headers don't wait on each other's locks.
+            let _lock = SAVE_BLOCK_LOCK
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            save(blocks)
+        };
+        // Guards are derived from the transactions even when these aren't stored
+        blocks
+            .iter_mut()
+            .zip(unsaved_transactions)
+            .for_each(|(data, transactions)| data.transactions = transactions);
+        saved
+    }
+
+    /// Updates the guards of the accounts from the transactions of the saved blocks.
+    /// Errors are only logged, the blocks being already saved their cursors still have to be
+    /// saved and the blocks published.
+    fn process_saved_guards(&self, blocks: &[BlockData]) {
+        let transactions = blocks
+            .iter()
+            .flat_map(|data| data.transactions.iter().cloned())
+            .collect::<Vec<Transaction>>();
+        if let Err(e) = guards::process_guards(&transactions, &self.account_guards) {
+            log::error!("Error updating the account guards: {}", e);
+        }
+    }
+
+    /// Publishes the saved blocks with their events and transfers to the sinks.
+    async fn publish_blocks_data(&self, blocks: &[BlockData]) {
+        // The data is only copied into a batch when there are sinks to publish it to
+        if self.sinks.is_empty() {
+            return;
+        }
+        let events = blocks
+            .iter()
+            .flat_map(|data| data.events.iter().cloned())
+            .collect::<Vec<Event>>();
+        let transfers = blocks
+            .iter()
+            .flat_map(|data| data.transfers.iter().cloned())
+            .collect::<Vec<Transfer>>();
+        let blocks = blocks
+            .iter()
+            .map(|data| data.block.clone())
+            .collect::<Vec<Block>>();
+        self.sinks
+            .publish(&Batch {
+                blocks: &blocks,
+                events: &events,
+                transfers: &transfers,
+            })
+            .await;
+    }
+
+    /// Logs the blocks replaced by another block at the same height and counts them per chain,
+    /// frequent reorgs hinting at node issues.
+    fn record_orphans(&self, orphans: &[Orphan]) {
+        for orphan in orphans {
+            log::info!(
+                "Chain {}: block {} at height {} orphaned",
+                orphan.chain_id,
+                orphan.hash,
+                orphan.height
+            );
+            self.metrics.record_orphan(orphan.chain_id as u16);
+        }
+    }
+
+    async fn fetch_transactions_results(
         &self,
         request_keys: &[String],
         chain: &ChainId,
@@ -456,6 +1635,96 @@ fn get_signed_txs_from_payload(payload: &BlockPayload) -> HashMap<String, Signed
         .collect::<HashMap<String, SignedTransaction>>()
 }
 
+/// Payload hashes of the headers, without duplicates: blocks without transactions often share
+/// the same payload, which only needs to be fetched once.
+fn unique_payload_hashes(headers: &[BlockHeader]) -> Vec<&str> {
+    let mut seen = HashSet::new();
+    headers
+        .iter()
+        .map(|header| header.payload_hash.as_str())
+        .filter(|payload_hash| seen.insert(*payload_hash))
+        .collect()
+}
+
+/// Builds the block of every header, payloads shared by several headers being used for each of them.
+fn build_blocks(
+    headers: &[BlockHeader],
+    payloads: &[BlockPayload],
+) -> Result<Vec<Block>, Box<dyn Error>> {
+    let payloads_by_hash = payloads
+        .iter()
+        .map(|e| (e.payload_hash.as_str(), e))
+        .collect::<HashMap<&str, &BlockPayload>>();
+    headers
+        .iter()
+        .map(
+            |header| match payloads_by_hash.get(header.payload_hash.as_str()) {
+                Some(payload) => Ok(build_block(header, payload)),
+                None => Err(format!(
+                    "Missing payload {} of block {}",
+                    header.payload_hash, header.hash
+                )
+                .into()),
+            },
+        )
+        .collect()
+}
+
+/// Groups the transactions, events and transfers with the block they belong to. Those of
+/// other blocks, which aren't expected among the results, are logged and left out.
+fn group_by_block(
+    blocks: Vec<Block>,
+    transactions: Vec<Transaction>,
+    events: Vec<Event>,
+    transfers: Vec<Transfer>,
+) -> Vec<BlockData> {
+    let positions = blocks
+        .iter()
+        .enumerate()
+        .map(|(position, block)| (block.hash.clone(), position))
+        .collect::<HashMap<String, usize>>();
+    let mut blocks = blocks
+        .into_iter()
+        .map(|block| BlockData {
+            block,
+            transactions: vec![],
+            events: vec![],
+            transfers: vec![],
+        })
+        .collect::<Vec<BlockData>>();
+    for transaction in transactions {
+        match positions.get(&transaction.block) {
+            Some(&position) => blocks[position].transactions.push(transaction),
+            None => log::error!(
+                "Transaction {} of block {} outside of the batch",
+                transaction.request_key,
+                transaction.block
+            ),
+        }
+    }
+    for event in events {
+        match positions.get(&event.block) {
+            Some(&position) => blocks[position].events.push(event),
+            None => log::error!(
+                "Event {} of block {} outside of the batch",
+                event.request_key,
+                event.block
+            ),
+        }
+    }
+    for transfer in transfers {
+        match positions.get(&transfer.block) {
+            Some(&position) => blocks[position].transfers.push(transfer),
+            None => log::error!(
+                "Transfer {} of block {} outside of the batch",
+                transfer.request_key,
+                transfer.block
+            ),
+        }
+    }
+    blocks
+}
+
 fn get_signed_txs_from_payloads(payloads: &[BlockPayload]) -> HashMap<String, SignedTransaction> {
     payloads
         .iter()
@@ -465,10 +1734,25 @@ fn get_signed_txs_from_payloads(payloads: &[BlockPayload]) -> HashMap<String, Si
         .collect::<HashMap<String, SignedTransaction>>()
 }
 
+/// The header target is a base64url encoded 256-bit unsigned integer in little-endian order.
+fn decode_target(target: &str) -> Option<BigInt> {
+    let bytes = base64_url::decode(target).ok()?;
+    Some(BigInt::from_bytes_le(Sign::Plus, &bytes))
+}
+
+/// Difficulty is derived from the target as 2^256 / target.
+fn difficulty(target: &BigInt) -> Option<BigInt> {
+    if target.is_zero() {
+        return None;
+    }
+    Some((BigInt::from(1) << 256) / target)
+}
+
 fn build_block(header: &BlockHeader, block_payload: &BlockPayload) -> Block {
     let miner_data =
         serde_json::from_slice::<Value>(&base64_url::decode(&block_payload.miner_data).unwrap())
             .unwrap();
+    let target = decode_target(&header.target);
     Block {
         chain_id: header.chain_id.0 as i64,
         hash: header.hash.clone(),
@@ -483,7 +1767,11 @@ fn build_block(header: &BlockHeader, block_payload: &BlockPayload) -> Block {
         payload: block_payload.payload_hash.clone(),
         pow_hash: "".to_string(),
         predicate: miner_data["predicate"].to_string(),
-        target: bigdecimal::BigDecimal::from(1),
+        target: target
+            .clone()
+            .map(BigDecimal::from)
+            .unwrap_or(BigDecimal::from(1)),
+        difficulty: target.as_ref().and_then(difficulty).map(BigDecimal::from),
     }
 }
 
@@ -491,12 +1779,20 @@ fn get_transactions_from_payload(
     signed_txs: &HashMap<String, SignedTransaction>,
     tx_results: &[PactTransactionResult],
     chain_id: &ChainId,
+    verify_sigs: bool,
+    store_raw_cmd: bool,
+    gas_stations: &[String],
 ) -> Vec<Transaction> {
     tx_results
         .iter()
         .map(|pact_result| {
             let signed_tx = signed_txs.get(&pact_result.request_key).unwrap();
-            build_transaction(signed_tx, pact_result, chain_id)
+            let transaction = build_transaction(signed_tx, pact_result, chain_id, gas_stations);
+            Transaction {
+                signatures_valid: verify_sigs.then(|| verify_signatures(signed_tx)),
+                raw_cmd: store_raw_cmd.then(|| signed_tx.cmd.clone()),
+                ..transaction
+            }
         })
         .collect()
 }
@@ -505,6 +1801,7 @@ fn build_transaction(
     signed_tx: &SignedTransaction,
     pact_result: &PactTransactionResult,
     chain: &ChainId,
+    gas_stations: &[String],
 ) -> Transaction {
     let continuation = pact_result.continuation.clone();
     let command = serde_json::from_str::<Command>(&signed_tx.cmd);
@@ -516,6 +1813,7 @@ fn build_transaction(
         }
     }
     let command = command.unwrap();
+    let is_gas_station = is_gas_station(&command, gas_stations);
     let (code, data, proof) = match command.payload {
         Payload {
             exec: Some(value),
@@ -527,21 +1825,34 @@ fn build_transaction(
         } => (None, Some(value.data), Some(value.proof)),
         _ => (None, None, None),
     };
+    // The status tells which of the data and the error of the result applies
+    let (status, good_result, bad_result) = match pact_result.result.status {
+        Status::Success => ("success", pact_result.result.data.clone(), None),
+        Status::Failure => ("failure", None, pact_result.result.error.clone()),
+    };
+    let (error_type, error_message) = match &bad_result {
+        Some(error) => parse_error(error),
+        None => (None, None),
+    };
 
     return Transaction {
-        bad_result: pact_result.result.error.clone(),
+        bad_result,
         block: pact_result.metadata.block_hash.clone(),
         chain_id: chain.0 as i64,
         creation_time: NaiveDateTime::from_timestamp_micros(pact_result.metadata.block_time)
             .unwrap(),
         code,
-        data,
+        data: data.clone(),
         continuation: pact_result.continuation.clone(),
+        error_message,
+        error_type,
         gas: pact_result.gas,
         gas_price: command.meta.gas_price,
         gas_limit: command.meta.gas_limit,
-        good_result: pact_result.result.data.clone(),
+        good_result,
         height: pact_result.metadata.block_height,
+        is_gas_station,
+        keysets: data.as_ref().and_then(extract_keysets),
         logs: if pact_result.logs.is_empty() {
             None
         } else {
@@ -554,26 +1865,139 @@ fn build_transaction(
             .clone()
             .map(|e| e["pactId"].as_str().unwrap().to_string()),
         proof: proof.flatten(),
+        raw_cmd: None,
         request_key: pact_result.request_key.to_string(),
         rollback: continuation
             .clone()
             .map(|e| e["stepHasRollback"].as_bool().unwrap()),
         sender: command.meta.sender,
+        signatures_valid: None,
+        signers: command
+            .signers
+            .into_iter()
+            .map(|signer| signer.public_key)
+            .collect(),
+        status: status.to_string(),
         step: continuation.map(|e| e["step"].as_i64().unwrap()),
         ttl: command.meta.ttl as i64,
         tx_id: pact_result.tx_id,
     };
 }
 
+/// A transaction is considered paid by a gas station when its sender is a known gas station,
+/// or when its signers are only scoped to `coin.GAS` and the sender isn't the `k:` account of
+/// one of them: the sender then only pays for gas and isn't the account acting in the transaction.
+fn is_gas_station(command: &Command, gas_stations: &[String]) -> bool {
+    let only_gas = |signer: &Signer| match &signer.clist {
+        Some(clist) => !clist.is_empty() && clist.iter().all(|cap| cap.name == "coin.GAS"),
+        None => false,
+    };
+    let signs_for_sender = |signer: &Signer| {
+        command
+            .meta
+            .sender
+            .strip_prefix("k:")
+            .is_some_and(|key| key == signer.public_key)
+    };
+    gas_stations.contains(&command.meta.sender)
+        || (!command.signers.is_empty()
+            && command.signers.iter().all(only_gas)
+            && !command.signers.iter().any(signs_for_sender))
+}
+
+/// Extracts the type (eg. `TxFailure`, `GasError`) and the message of a Pact error,
+/// as returned in the `error` of a failed transaction result.
+fn parse_error(error: &serde_json::Value) -> (Option<String>, Option<String>) {
+    let field = |name: &str| {
+        error
+            .get(name)
+            .and_then(|value| value.as_str())
+            .filter(|value| !value.is_empty())
+            .map(|value| value.to_string())
+    };
+    (field("type"), field("message"))
+}
+
+/// Scans the transaction data for keyset shaped objects (with `keys` and `pred`)
+/// and returns them as a list of `{"name", "keys", "pred"}` objects, where `name` is the
+/// key the keyset was found under. Returns `None` when the data contains no keysets.
+fn extract_keysets(data: &serde_json::Value) -> Option<serde_json::Value> {
+    fn walk(name: Option<&str>, value: &serde_json::Value, keysets: &mut Vec<serde_json::Value>) {
+        match value {
+            serde_json::Value::Object(object) => match (object.get("keys"), object.get("pred")) {
+                (
+                    Some(keys @ serde_json::Value::Array(_)),
+                    Some(pred @ serde_json::Value::String(_)),
+                ) => {
+                    keysets.push(serde_json::json!({ "name": name, "keys": keys, "pred": pred }));
+                }
+                _ => object
+                    .iter()
+                    .for_each(|(key, value)| walk(Some(key), value, keysets)),
+            },
+            serde_json::Value::Array(values) => {
+                values.iter().for_each(|value| walk(name, value, keysets))
+            }
+            _ => {}
+        }
+    }
+    let mut keysets = vec![];
+    walk(None, data, &mut keysets);
+    match keysets.is_empty() {
+        true => None,
+        false => Some(serde_json::Value::Array(keysets)),
+    }
+}
+
+/// Reads the comma separated `INDEXER_GAS_STATIONS` accounts, defaults to `kadena-xchain-gas`.
+pub fn gas_stations_from_env() -> Vec<String> {
+    std::env::var("INDEXER_GAS_STATIONS")
+        .unwrap_or_else(|_| "kadena-xchain-gas".to_string())
+        .split(',')
+        .map(|account| account.trim().to_string())
+        .filter(|account| !account.is_empty())
+        .collect()
+}
+
+/// Reads the comma separated `INDEXED_MODULES` allowlist, eg. `coin,marmalade-v2`.
+/// Returns `None` when the variable is missing or empty, meaning all modules are indexed.
+pub fn indexed_modules_from_env() -> Option<Vec<String>> {
+    let modules = std::env::var("INDEXED_MODULES")
+        .ok()?
+        .split(',')
+        .map(|module| module.trim().to_string())
+        .filter(|module| !module.is_empty())
+        .collect::<Vec<String>>();
+    match modules.is_empty() {
+        true => None,
+        false => Some(modules),
+    }
+}
+
+/// A module is indexed if it's in the allowlist, either by its full name (`marmalade-v2.ledger`)
+/// or by its namespace (`marmalade-v2`).
+fn is_indexed_module(module: &str, indexed_modules: Option<&[String]>) -> bool {
+    match indexed_modules {
+        None => true,
+        Some(indexed_modules) => indexed_modules.iter().any(|indexed| {
+            module == indexed
+                || module
+                    .strip_prefix(indexed.as_str())
+                    .is_some_and(|rest| rest.starts_with('.'))
+        }),
+    }
+}
+
 fn get_events_from_txs(
     tx_results: &[PactTransactionResult],
     signed_txs_by_hash: &HashMap<String, SignedTransaction>,
+    indexed_modules: Option<&[String]>,
 ) -> Vec<Event> {
     tx_results
         .iter()
         .flat_map(|pact_result| {
             let signed_tx = signed_txs_by_hash.get(&pact_result.request_key).unwrap();
-            build_events(signed_tx, pact_result)
+            build_events(signed_tx, pact_result, indexed_modules)
         })
         .collect()
 }
@@ -581,15 +2005,16 @@ fn get_events_from_txs(
 fn build_events(
     signed_tx: &SignedTransaction,
     pact_result: &PactTransactionResult,
+    indexed_modules: Option<&[String]>,
 ) -> Vec<crate::models::Event> {
     let command = serde_json::from_str::<Command>(&signed_tx.cmd).unwrap();
     let mut events = vec![];
     if pact_result.events.is_some() {
         for (i, event) in pact_result.events.as_ref().unwrap().iter().enumerate() {
-            let module = match &event.module.namespace {
-                Some(namespace) => format!("{}.{}", namespace, event.module.name),
-                None => event.module.name.to_string(),
-            };
+            let module = event.module.qualified_name();
+            if !is_indexed_module(&module, indexed_modules) {
+                continue;
+            }
             let event = crate::models::Event {
                 block: pact_result.metadata.block_hash.clone(),
                 chain_id: command.meta.chain_id.parse().unwrap(),
@@ -616,30 +2041,176 @@ fn build_events(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::store::memory::{
+        MemoryBlocks, MemoryCursors, MemoryEvents, MemoryGuards, MemoryTransactions,
+        MemoryTransfers,
+    };
     use crate::{
-        chainweb_client::{BlockPayload, Sig},
+        chainweb_client::{BlockHash, BlockPayload, Sig, MAINNET_CHAINS},
         db,
     };
+    use chrono::Utc;
     use serial_test::serial;
 
+    /// Indexer storing in the database with the settings of the tests, which override them with
+    /// the struct update syntax.
+    fn make_indexer<'a>(client: &'a ChainwebClient, pool: &DbPool) -> Indexer<'a> {
+        make_indexer_with(
+            client,
+            BlocksRepository { pool: pool.clone() },
+            EventsRepository { pool: pool.clone() },
+            TransactionsRepository { pool: pool.clone() },
+            TransfersRepository { pool: pool.clone() },
+            AccountGuardsRepository { pool: pool.clone() },
+            StreamCursorsRepository { pool: pool.clone() },
+        )
+    }
+
+    /// Same as [`make_indexer`] with other stores.
+    fn make_indexer_with<'a, B, E, T, F, G, C>(
+        client: &'a ChainwebClient,
+        blocks: B,
+        events: E,
+        transactions: T,
+        transfers: F,
+        account_guards: G,
+        stream_cursors: C,
+    ) -> Indexer<'a, B, E, T, F, G, C> {
+        Indexer {
+            chainweb_client: client,
+            blocks,
+            events,
+            transactions,
+            transfers,
+            account_guards,
+            stream_cursors,
+            indexed_modules: None,
+            chain_retries: 0,
+            payload_retries: 0,
+            verify_signatures: false,
+            store_raw_cmd: false,
+            index_transactions: true,
+            gas_stations: vec![],
+            stream_concurrency: 1,
+            stream_buffer_size: 1,
+            stream_flush_interval: Duration::from_secs(5),
+            progress_interval: Duration::from_secs(60),
+            stream_timeout: Duration::from_secs(120),
+            poll_interval: Duration::from_secs(0),
+            payload_budget: MemoryBudget::new(64, 1),
+            indexing_progress: Arc::new(IndexingProgress::default()),
+            metrics: Arc::new(IndexerMetrics::default()),
+            pause: Arc::new(Pause::default()),
+            pending_headers: Mutex::new(vec![]),
+            in_flight_headers: Mutex::new(vec![]),
+            genesis_heights: None,
+            max_backfill_span: None,
+            sinks: Sinks::default(),
+        }
+    }
+
+    /// Indexer storing in memory, with the given blocks and stream cursors.
+    fn make_memory_indexer(
+        client: &ChainwebClient,
+        blocks: MemoryBlocks,
+        stream_cursors: MemoryCursors,
+    ) -> Indexer<
+        '_,
+        MemoryBlocks,
+        MemoryEvents,
+        MemoryTransactions,
+        MemoryTransfers,
+        MemoryGuards,
+        MemoryCursors,
+    > {
+        make_indexer_with(
+            client,
+            blocks,
+            MemoryEvents::default(),
+            MemoryTransactions::default(),
+            MemoryTransfers::default(),
+            MemoryGuards::default(),
+            stream_cursors,
+        )
+    }
+
+    fn make_block(chain_id: i64, height: i64, hash: &str) -> Block {
+        Block {
+            chain_id,
+            hash: hash.to_string(),
+            height,
+            parent: "parent".to_string(),
+            weight: BigDecimal::from(0),
+            creation_time: Utc::now().naive_utc(),
+            epoch: Utc::now().naive_utc(),
+            flags: BigDecimal::from(0),
+            miner: "miner".to_string(),
+            nonce: BigDecimal::from(0),
+            payload: "payload".to_string(),
+            pow_hash: "".to_string(),
+            predicate: "predicate".to_string(),
+            target: BigDecimal::from(1),
+            difficulty: None,
+        }
+    }
+
+    fn make_transaction(block: &Block, request_key: &str) -> Transaction {
+        Transaction {
+            bad_result: None,
+            block: block.hash.clone(),
+            chain_id: block.chain_id,
+            code: None,
+            continuation: None,
+            creation_time: block.creation_time,
+            data: None,
+            error_message: None,
+            error_type: None,
+            gas: 0,
+            gas_limit: 0,
+            gas_price: 0.0,
+            good_result: None,
+            height: block.height,
+            is_gas_station: false,
+            keysets: None,
+            logs: None,
+            metadata: None,
+            nonce: "nonce".to_string(),
+            num_events: None,
+            pact_id: None,
+            proof: None,
+            raw_cmd: None,
+            request_key: request_key.to_string(),
+            rollback: None,
+            sender: "alice".to_string(),
+            signatures_valid: None,
+            signers: vec![],
+            status: "success".to_string(),
+            step: None,
+            ttl: 0,
+            tx_id: None,
+        }
+    }
+
+    fn block_data(block: Block) -> BlockData {
+        BlockData {
+            block,
+            transactions: vec![],
+            events: vec![],
+            transfers: vec![],
+        }
+    }
+
     #[test]
     #[serial]
-    fn test_save_block() {
+    fn test_save_blocks_data_replaces_orphans() {
         dotenvy::from_filename(".env.test").ok();
         let pool = db::initialize_db_pool();
         let client = ChainwebClient::new();
         let blocks = BlocksRepository { pool: pool.clone() };
         let events = EventsRepository { pool: pool.clone() };
         let transactions = TransactionsRepository { pool: pool.clone() };
-        let transfers = TransfersRepository { pool: pool.clone() };
 
-        let indexer = Indexer {
-            chainweb_client: &client,
-            blocks: blocks.clone(),
-            events: events.clone(),
-            transactions: transactions.clone(),
-            transfers: transfers.clone(),
-        };
+        let indexer = make_indexer(&client, &pool);
 
         let orphan_header = BlockHeader {
             creation_time: 1688902875826238,
@@ -669,7 +2240,7 @@ mod tests {
         let chain_id = orphan_header.chain_id.0 as i64;
         let hash = orphan_header.hash.clone();
         let block = build_block(&orphan_header, &payload);
-        indexer.save_block(&block).unwrap();
+        indexer.save_blocks_data(&mut [block_data(block)]).unwrap();
         let block = indexer
             .blocks
             .find_by_hash(&orphan_header.hash, chain_id)
@@ -680,16 +2251,1593 @@ mod tests {
             ..orphan_header
         };
         let block = build_block(&header, &payload);
-        indexer.save_block(&block).unwrap();
+        indexer.save_blocks_data(&mut [block_data(block)]).unwrap();
         let block = indexer.blocks.find_by_hash(&"new_hash", chain_id).unwrap();
         assert!(block.is_some());
         let orphan_block = indexer.blocks.find_by_hash(&hash, chain_id).unwrap();
         assert!(orphan_block.is_none());
+        assert_eq!(indexer.metrics.orphaned_blocks(14), 1);
+        // Saving the same block again orphans nothing
+        indexer
+            .save_blocks_data(&mut [block_data(block.unwrap())])
+            .unwrap();
+        assert_eq!(indexer.metrics.orphaned_blocks(14), 1);
         transactions.delete_all().unwrap();
         events.delete_all().unwrap();
         blocks.delete_all().unwrap();
     }
 
+    #[test]
+    #[serial]
+    fn test_save_blocks_data_refreshes_events_on_reprocess() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let client = ChainwebClient::new();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let events = EventsRepository { pool: pool.clone() };
+        let indexer = make_indexer(&client, &pool);
+        let block = make_block(0, 1, "hash");
+        let stale_event = Event {
+            block: block.hash.clone(),
+            chain_id: 0,
+            height: 1,
+            idx: 0,
+            module: "coin".to_string(),
+            module_hash: "module-hash".to_string(),
+            name: "TRANSFER".to_string(),
+            params: serde_json::json!(["alice", "bob", 1.0]),
+            param_text: "[\"alice\",\"bob\",1.0]".to_string(),
+            qual_name: "coin.TRANSFER".to_string(),
+            request_key: "request-key".to_string(),
+            pact_id: None,
+        };
+        indexer
+            .save_blocks_data(&mut [block_data(block.clone())])
+            .unwrap();
+        events.insert_batch(&[stale_event]).unwrap();
+
+        indexer
+            .save_blocks_data(&mut [block_data(block.clone())])
+            .unwrap();
+        assert!(blocks.find_by_hash(&block.hash, 0).unwrap().is_some());
+        assert!(events.find_all().unwrap().rows.is_empty());
+
+        events.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_flush_saves_all_buffered_blocks() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let client = ChainwebClient::new();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let events = EventsRepository { pool: pool.clone() };
+        let transactions = TransactionsRepository { pool: pool.clone() };
+        let transfers = TransfersRepository { pool: pool.clone() };
+        let indexer = Indexer {
+            stream_buffer_size: 10,
+            ..make_indexer(&client, &pool)
+        };
+        let make_event = |block: &Block| Event {
+            block: block.hash.clone(),
+            chain_id: 0,
+            height: block.height,
+            idx: 0,
+            module: "coin".to_string(),
+            module_hash: "module-hash".to_string(),
+            name: "TRANSFER".to_string(),
+            params: serde_json::json!(["alice", "bob", 1.0]),
+            param_text: "[\"alice\",\"bob\",1.0]".to_string(),
+            qual_name: "coin.TRANSFER".to_string(),
+            request_key: format!("request-key-{}", block.hash),
+            pact_id: None,
+        };
+        let orphan = make_block(0, 2, "orphan");
+        blocks.insert(&orphan).unwrap();
+        events.insert_batch(&[make_event(&orphan)]).unwrap();
+
+        let buffer = tokio::sync::Mutex::new(
+            [make_block(0, 1, "hash-1"), make_block(0, 2, "hash-2")]
+                .into_iter()
+                .map(|block| {
+                    let events = vec![make_event(&block)];
+                    BlockData {
+                        transfers: projection::project_transfers(
+                            &events,
+                            std::slice::from_ref(&block),
+                        ),
+                        block,
+                        transactions: vec![],
+                        events,
+                    }
+                })
+                .collect::<Vec<BlockData>>(),
+        );
+        // Kept buffered while paused
+        indexer.pause.toggle();
+        indexer.flush(&buffer).await.unwrap();
+        assert_eq!(buffer.lock().await.len(), 2);
+        assert_eq!(blocks.count(0).unwrap(), 1);
+        indexer.pause.toggle();
+        indexer.flush(&buffer).await.unwrap();
+
+        assert!(buffer.lock().await.is_empty());
+        assert_eq!(blocks.count(0).unwrap(), 2);
+        assert_eq!(
+            indexer.stream_cursors.find_all().unwrap(),
+            vec![StreamCursor {
+                chain_id: 0,
+                height: 2,
+                hash: "hash-2".to_string(),
+            }]
+        );
+        assert!(blocks.find_by_hash("orphan", 0).unwrap().is_none());
+        let saved_events = events.find_all().unwrap().rows;
+        assert_eq!(saved_events.len(), 2);
+        assert!(saved_events.iter().all(|event| event.block != "orphan"));
+        assert_eq!(
+            transfers
+                .calculate_balance("bob", "coin")
+                .unwrap()
+                .get(&0)
+                .cloned(),
+            Some(BigDecimal::from(2))
+        );
+
+        indexer.stream_cursors.delete_all().unwrap();
+        transfers.delete_all().unwrap();
+        events.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_flush_without_transactions() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let client = ChainwebClient::new();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let events = EventsRepository { pool: pool.clone() };
+        let transactions = TransactionsRepository { pool: pool.clone() };
+        let transfers = TransfersRepository { pool: pool.clone() };
+        let indexer = Indexer {
+            index_transactions: false,
+            stream_buffer_size: 10,
+            ..make_indexer(&client, &pool)
+        };
+        let block = make_block(0, 1, "hash-1");
+        let transaction = Transaction {
+            num_events: Some(1),
+            ..make_transaction(&block, "request-key")
+        };
+        let event = Event {
+            block: block.hash.clone(),
+            chain_id: 0,
+            height: block.height,
+            idx: 0,
+            module: "coin".to_string(),
+            module_hash: "module-hash".to_string(),
+            name: "TRANSFER".to_string(),
+            params: serde_json::json!(["alice", "bob", 1.0]),
+            param_text: "[\"alice\",\"bob\",1.0]".to_string(),
+            qual_name: "coin.TRANSFER".to_string(),
+            request_key: "request-key".to_string(),
+            pact_id: None,
+        };
+        let buffer = tokio::sync::Mutex::new(vec![BlockData {
+            transfers: projection::project_transfers(
+                std::slice::from_ref(&event),
+                std::slice::from_ref(&block),
+            ),
+            block,
+            transactions: vec![transaction],
+            events: vec![event],
+        }]);
+        // Kept buffered with its transactions when the save fails, here as the event's block
+        // doesn't exist
+        buffer.lock().await[0].events[0].block = "unknown".to_string();
+        assert!(indexer.flush(&buffer).await.is_err());
+        {
+            let mut buffered = buffer.lock().await;
+            assert_eq!(buffered.len(), 1);
+            assert_eq!(buffered[0].transactions.len(), 1);
+            buffered[0].events[0].block = buffered[0].block.hash.clone();
+        }
+        indexer.flush(&buffer).await.unwrap();
+
+        assert!(transactions
+            .find_by_request_key(&vec!["request-key".to_string()])
+            .unwrap()
+            .rows
+            .is_empty());
+        assert_eq!(events.find_all().unwrap().rows.len(), 1);
+        assert_eq!(
+            transfers
+                .calculate_balance("bob", "coin")
+                .unwrap()
+                .get(&0)
+                .cloned(),
+            Some(BigDecimal::from(1))
+        );
+
+        indexer.stream_cursors.delete_all().unwrap();
+        transfers.delete_all().unwrap();
+        events.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    fn test_build_transaction_parses_error_details() {
+        let signed_tx = SignedTransaction {
+            cmd: String::from("{\"networkId\":\"mainnet01\",\"payload\":{\"exec\":{\"data\":{},\"code\":\"(coin.transfer \\\"alice\\\" \\\"bob\\\" 100.0)\"}},\"signers\":[{\"pubKey\":\"alice-key\"}],\"meta\":{\"creationTime\":1687691365,\"ttl\":28800,\"gasLimit\":1000,\"chainId\":\"0\",\"gasPrice\":0.000001,\"sender\":\"alice\"},\"nonce\":\"nonce\"}"),
+            hash: String::from("request-key"),
+            sigs: vec![],
+        };
+        let pact_result = serde_json::from_value::<PactTransactionResult>(serde_json::json!({
+            "continuation": null,
+            "events": [],
+            "gas": 1000,
+            "logs": "logs",
+            "metaData": {
+                "blockHash": "hash",
+                "blockHeight": 1,
+                "blockTime": 1687691365000000i64,
+                "prevBlockHash": "prev-block-hash"
+            },
+            "reqKey": "request-key",
+            "result": {
+                "error": {
+                    "callStack": ["<interactive>:0:0: (transfer \"alice\" \"bob\" 100.0)"],
+                    "type": "TxFailure",
+                    "message": "Insufficient funds",
+                    "info": ""
+                },
+                "status": "failure"
+            },
+            "txId": null
+        }))
+        .unwrap();
+
+        let transaction = build_transaction(&signed_tx, &pact_result, &ChainId(0), &[]);
+        assert_eq!(transaction.error_type, Some("TxFailure".to_string()));
+        assert_eq!(
+            transaction.error_message,
+            Some("Insufficient funds".to_string())
+        );
+        assert!(transaction.bad_result.is_some());
+        assert_eq!(transaction.signers, vec!["alice-key".to_string()]);
+
+        assert_eq!(
+            parse_error(&serde_json::json!({"type": "GasError", "message": ""})),
+            (Some("GasError".to_string()), None)
+        );
+        assert_eq!(parse_error(&serde_json::json!("error")), (None, None));
+    }
+
+    #[test]
+    fn test_build_transaction_sets_result_matching_status() {
+        let signed_tx = SignedTransaction {
+            cmd: String::from("{\"networkId\":\"mainnet01\",\"payload\":{\"exec\":{\"data\":{},\"code\":\"(free.app.play)\"}},\"signers\":[],\"meta\":{\"creationTime\":1687691365,\"ttl\":28800,\"gasLimit\":1000,\"chainId\":\"0\",\"gasPrice\":0.000001,\"sender\":\"alice\"},\"nonce\":\"nonce\"}"),
+            hash: String::from("request-key"),
+            sigs: vec![],
+        };
+        let pact_result = |result: serde_json::Value| {
+            serde_json::from_value::<PactTransactionResult>(serde_json::json!({
+                "continuation": null,
+                "events": [],
+                "gas": 1000,
+                "logs": "logs",
+                "metaData": {
+                    "blockHash": "hash",
+                    "blockHeight": 1,
+                    "blockTime": 1687691365000000i64,
+                    "prevBlockHash": "prev-block-hash"
+                },
+                "reqKey": "request-key",
+                "result": result,
+                "txId": null
+            }))
+            .unwrap()
+        };
+
+        let success = build_transaction(
+            &signed_tx,
+            &pact_result(serde_json::json!({
+                "data": "Write succeeded",
+                "error": {"type": "TxFailure", "message": "ignored"},
+                "status": "success"
+            })),
+            &ChainId(0),
+            &[],
+        );
+        assert_eq!(success.status, "success");
+        assert_eq!(
+            success.good_result,
+            Some(serde_json::json!("Write succeeded"))
+        );
+        assert_eq!(success.bad_result, None);
+        assert_eq!(success.error_type, None);
+
+        let failure = build_transaction(
+            &signed_tx,
+            &pact_result(serde_json::json!({
+                "data": "ignored",
+                "error": {"type": "TxFailure", "message": "Insufficient funds"},
+                "status": "failure"
+            })),
+            &ChainId(0),
+            &[],
+        );
+        assert_eq!(failure.status, "failure");
+        assert_eq!(failure.good_result, None);
+        assert!(failure.bad_result.is_some());
+        assert_eq!(failure.error_type, Some("TxFailure".to_string()));
+    }
+
+    #[test]
+    fn test_is_gas_station() {
+        let command = |sender: &str, signers: &str| {
+            serde_json::from_str::<Command>(&format!(
+                "{{\"payload\":{{\"exec\":{{\"data\":{{}},\"code\":\"(free.app.play)\"}}}},\"signers\":{},\"meta\":{{\"creationTime\":1687691365,\"ttl\":28800,\"gasLimit\":1000,\"chainId\":\"0\",\"gasPrice\":0.000001,\"sender\":\"{}\"}},\"nonce\":\"nonce\"}}",
+                signers, sender
+            ))
+            .unwrap()
+        };
+        let gas_stations = vec!["kadena-xchain-gas".to_string()];
+        let gas_only =
+            "[{\"pubKey\":\"alice-key\",\"clist\":[{\"name\":\"coin.GAS\",\"args\":[]}]}]";
+
+        // Known gas stations
+        assert!(is_gas_station(
+            &command("kadena-xchain-gas", "[]"),
+            &gas_stations
+        ));
+        assert!(!is_gas_station(&command("kadena-xchain-gas", "[]"), &[]));
+        // Signers only scoped to coin.GAS, paid by someone else
+        assert!(is_gas_station(
+            &command("free.gas-payer", gas_only),
+            &gas_stations
+        ));
+        // Paid by the signer itself
+        assert!(!is_gas_station(
+            &command("k:alice-key", gas_only),
+            &gas_stations
+        ));
+        assert!(!is_gas_station(
+            &command(
+                "free.gas-payer",
+                "[{\"pubKey\":\"alice-key\",\"clist\":[{\"name\":\"coin.GAS\",\"args\":[]},{\"name\":\"coin.TRANSFER\",\"args\":[\"alice\",\"bob\",1.0]}]}]"
+            ),
+            &gas_stations
+        ));
+        assert!(!is_gas_station(
+            &command("free.gas-payer", "[{\"pubKey\":\"alice-key\"}]"),
+            &gas_stations
+        ));
+        assert!(!is_gas_station(&command("alice", "[]"), &gas_stations));
+    }
+
+    #[test]
+    #[serial]
+    fn test_build_transaction_stores_unquoted_pact_id() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let transactions = TransactionsRepository { pool: pool.clone() };
+        let signed_tx = SignedTransaction {
+            cmd: String::from("{\"networkId\":\"mainnet01\",\"payload\":{\"exec\":{\"data\":{},\"code\":\"(coin.transfer-crosschain \\\"alice\\\" \\\"alice\\\" (read-keyset \\\"ks\\\") \\\"1\\\" 1.0)\"}},\"signers\":[],\"meta\":{\"creationTime\":1687691365,\"ttl\":28800,\"gasLimit\":1000,\"chainId\":\"0\",\"gasPrice\":0.000001,\"sender\":\"alice\"},\"nonce\":\"nonce\"}"),
+            hash: String::from("request-key"),
+            sigs: vec![],
+        };
+        let pact_result = serde_json::from_value::<PactTransactionResult>(serde_json::json!({
+            "continuation": {
+                "pactId": "pact-id",
+                "step": 0,
+                "stepCount": 2,
+                "stepHasRollback": false,
+                "executed": null,
+                "continuation": {"args": [], "def": "coin.transfer-crosschain"},
+                "yield": null
+            },
+            "events": [],
+            "gas": 700,
+            "logs": "logs",
+            "metaData": {
+                "blockHash": "hash",
+                "blockHeight": 1,
+                "blockTime": 1687691365000000i64,
+                "prevBlockHash": "prev-block-hash"
+            },
+            "reqKey": "request-key",
+            "result": {"data": "Write succeeded", "status": "success"},
+            "txId": 1
+        }))
+        .unwrap();
+
+        let transaction = build_transaction(&signed_tx, &pact_result, &ChainId(0), &[]);
+        assert_eq!(transaction.pact_id, Some("pact-id".to_string()));
+
+        blocks.insert(&make_block(0, 1, "hash")).unwrap();
+        transactions.insert(&transaction).unwrap();
+        let found = transactions
+            .find_by_pact_id(&vec!["pact-id".to_string()])
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].request_key, "request-key");
+
+        transactions.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_raw_cmd_round_trips() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let transactions = TransactionsRepository { pool: pool.clone() };
+        let cmd = String::from("{\"networkId\":\"mainnet01\",\"payload\":{\"exec\":{\"data\":{\"ks\":{\"keys\":[\"alice-key\"],\"pred\":\"keys-all\"}},\"code\":\"(free.app.play)\"}},\"signers\":[{\"pubKey\":\"alice-key\"}],\"meta\":{\"creationTime\":1687691365,\"ttl\":28800,\"gasLimit\":1000,\"chainId\":\"0\",\"gasPrice\":0.000001,\"sender\":\"alice\"},\"nonce\":\"nonce\"}");
+        let signed_txs = HashMap::from([(
+            "request-key".to_string(),
+            SignedTransaction {
+                cmd: cmd.clone(),
+                hash: String::from("request-key"),
+                sigs: vec![],
+            },
+        )]);
+        let pact_result = || {
+            serde_json::from_value::<PactTransactionResult>(serde_json::json!({
+                "continuation": null,
+                "events": [],
+                "gas": 700,
+                "logs": "logs",
+                "metaData": {
+                    "blockHash": "hash",
+                    "blockHeight": 1,
+                    "blockTime": 1687691365000000i64,
+                    "prevBlockHash": "prev-block-hash"
+                },
+                "reqKey": "request-key",
+                "result": {"data": "Write succeeded", "status": "success"},
+                "txId": 1
+            }))
+            .unwrap()
+        };
+
+        let without_raw_cmd = get_transactions_from_payload(
+            &signed_txs,
+            &[pact_result()],
+            &ChainId(0),
+            false,
+            false,
+            &[],
+        );
+        assert_eq!(without_raw_cmd[0].raw_cmd, None);
+
+        blocks.insert(&make_block(0, 1, "hash")).unwrap();
+        let txs = get_transactions_from_payload(
+            &signed_txs,
+            &[pact_result()],
+            &ChainId(0),
+            false,
+            true,
+            &[],
+        );
+        transactions.insert_batch(&txs).unwrap();
+        let found = transactions
+            .find_by_request_key(&vec!["request-key".to_string()])
+            .unwrap()
+            .rows;
+        assert_eq!(found.len(), 1);
+        let raw_cmd = found[0].raw_cmd.clone().unwrap();
+        assert_eq!(raw_cmd, cmd);
+        let command = serde_json::from_str::<Command>(&raw_cmd).unwrap();
+        assert_eq!(command.meta.sender, "alice");
+        assert_eq!(command.signers[0].public_key, "alice-key");
+
+        transactions.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    fn test_backfill_summary_failed_chains() {
+        let summary = BackfillSummary::new(vec![
+            (ChainId(2), Err("node error".to_string())),
+            (ChainId(0), Ok(())),
+            (ChainId(2), Ok(())),
+            (ChainId(1), Err("node error".to_string())),
+            (ChainId(2), Err("node error".to_string())),
+        ]);
+        assert_eq!(summary.failed_chains(), vec![&ChainId(1), &ChainId(2)]);
+    }
+
+    /// Starts a node with all the mainnet chains, whose header branch endpoint fails on chain 1
+    /// as a proxy in front of the node would, and has no block to index on the other chains.
+    async fn start_node_failing_on_chain_1() -> String {
+        use actix_web::{web, App, HttpResponse, HttpServer};
+
+        async fn cut() -> HttpResponse {
+            let hashes = (0..MAINNET_CHAINS)
+                .map(|chain| {
+                    (
+                        chain.to_string(),
+                        serde_json::json!({ "height": 100, "hash": format!("hash-{}", chain) }),
+                    )
+                })
+                .collect::<serde_json::Map<String, Value>>();
+            HttpResponse::Ok().json(serde_json::json!({
+                "height": 2000,
+                "weight": "weight",
+                "hashes": hashes,
+                "instance": "mainnet01",
+                "id": "id"
+            }))
+        }
+
+        async fn header_branch(chain: web::Path<u16>) -> HttpResponse {
+            match chain.into_inner() {
+                1 => HttpResponse::BadGateway().body("<html>502 Bad Gateway</html>"),
+                _ => HttpResponse::Ok()
+                    .json(serde_json::json!({ "items": [], "limit": 50, "next": null })),
+            }
+        }
+
+        let server = HttpServer::new(|| {
+            App::new()
+                .route("/chainweb/0.0/mainnet01/cut", web::get().to(cut))
+                .route(
+                    "/chainweb/0.0/mainnet01/chain/{chain}/header/branch",
+                    web::post().to(header_branch),
+                )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let address = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+        format!("http://{}", address)
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_backfill_reports_failed_chains() {
+        dotenvy::from_filename(".env.test").ok();
+        let node_host = std::env::var("CHAINWEB_NODE_HOST").unwrap();
+        std::env::set_var("CHAINWEB_NODE_HOST", start_node_failing_on_chain_1().await);
+        let client = ChainwebClient::new();
+        std::env::set_var("CHAINWEB_NODE_HOST", node_host);
+        let pool = db::initialize_db_pool();
+        let indexer = make_indexer(&client, &pool);
+
+        let summary = indexer.backfill(None, None, false).await.unwrap();
+        assert_eq!(summary.outcomes.len(), MAINNET_CHAINS as usize);
+        assert_eq!(summary.failed_chains(), vec![&ChainId(1)]);
+        let error = summary.outcomes[1].1.as_ref().unwrap_err();
+        assert!(error.contains("502 Bad Gateway"), "{}", error);
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_resume_from_cursors() {
+        dotenvy::from_filename(".env.test").ok();
+        let node_host = std::env::var("CHAINWEB_NODE_HOST").unwrap();
+        std::env::set_var("CHAINWEB_NODE_HOST", start_node_failing_on_chain_1().await);
+        let client = ChainwebClient::new();
+        std::env::set_var("CHAINWEB_NODE_HOST", node_host);
+        let pool = db::initialize_db_pool();
+        let stream_cursors = StreamCursorsRepository { pool: pool.clone() };
+        let indexer = Indexer {
+            stream_cursors: stream_cursors.clone(),
+            ..make_indexer(&client, &pool)
+        };
+        let cursor = |chain_id, height, hash: &str| StreamCursor {
+            chain_id,
+            height,
+            hash: hash.to_string(),
+        };
+        assert_eq!(indexer.resume_from_cursors().await.unwrap(), 0);
+        stream_cursors
+            .save(&[
+                cursor(0, 90, "hash-0-90"),
+                cursor(1, 90, "hash-1-90"),
+                cursor(2, 100, "hash-2"),
+            ])
+            .unwrap();
+
+        // Chains 0 and 1 are behind the cut, at height 100, only chain 0 catches up
+        assert_eq!(indexer.resume_from_cursors().await.unwrap(), 2);
+        assert_eq!(
+            stream_cursors.find_all().unwrap(),
+            vec![
+                cursor(0, 100, "hash-0"),
+                cursor(1, 90, "hash-1-90"),
+                cursor(2, 100, "hash-2"),
+            ]
+        );
+
+        stream_cursors.delete_all().unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_backfill_skips_indexed_heights() {
+        dotenvy::from_filename(".env.test").ok();
+        // Nothing listens on this port, any request to the node would fail
+        let node_host = std::env::var("CHAINWEB_NODE_HOST").unwrap();
+        std::env::set_var("CHAINWEB_NODE_HOST", "http://127.0.0.1:1");
+        let client = ChainwebClient::new();
+        std::env::set_var("CHAINWEB_NODE_HOST", node_host);
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let indexer = make_indexer(&client, &pool);
+        blocks
+            .insert_batch(
+                &(0..=10)
+                    .map(|height| Block {
+                        parent: format!("hash-{}", height - 1),
+                        ..make_block(0, height, &format!("hash-{}", height))
+                    })
+                    .collect::<Vec<Block>>(),
+            )
+            .unwrap();
+        let cut = Cut {
+            height: 10,
+            weight: "weight".to_string(),
+            hashes: HashMap::from([(
+                ChainId(0),
+                BlockHash {
+                    height: 10,
+                    hash: "hash-10".to_string(),
+                },
+            )]),
+            instance: "mainnet01".to_string(),
+            id: "id".to_string(),
+        };
+
+        let bounds = indexer
+            .get_bounds_between_heights(&cut, 2, Some(8))
+            .await
+            .unwrap();
+        assert!(bounds.is_empty());
+        let bounds = indexer
+            .get_bounds_between_heights(&cut, 0, None)
+            .await
+            .unwrap();
+        assert!(bounds.is_empty());
+        // A missing block needs the node
+        blocks.delete_one(5, 0).unwrap();
+        assert!(indexer
+            .get_bounds_between_heights(&cut, 0, None)
+            .await
+            .is_err());
+
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_backfill_span_is_capped() {
+        dotenvy::from_filename(".env.test").ok();
+        let client = ChainwebClient::new();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let indexer = Indexer {
+            max_backfill_span: Some(5),
+            ..make_indexer(&client, &pool)
+        };
+        blocks
+            .insert_batch(
+                &(0..=10)
+                    .map(|height| Block {
+                        parent: format!("hash-{}", height - 1),
+                        ..make_block(0, height, &format!("hash-{}", height))
+                    })
+                    .collect::<Vec<Block>>(),
+            )
+            .unwrap();
+        let cut = Cut {
+            height: 20,
+            weight: "weight".to_string(),
+            hashes: HashMap::from([(
+                ChainId(0),
+                BlockHash {
+                    height: 20,
+                    hash: "hash-20".to_string(),
+                },
+            )]),
+            instance: "mainnet01".to_string(),
+            id: "id".to_string(),
+        };
+
+        // Heights 11 to 20 are missing
+        assert_eq!(indexer.backfill_span(&cut, None, None).unwrap(), 10);
+        assert!(indexer.check_backfill_span(&cut, None, None).is_err());
+        assert!(indexer.check_backfill_span(&cut, Some(15), None).is_err());
+        assert_eq!(indexer.backfill_span(&cut, Some(16), None).unwrap(), 5);
+        assert!(indexer.check_backfill_span(&cut, Some(16), None).is_ok());
+        // Indexed heights aren't counted
+        assert_eq!(indexer.backfill_span(&cut, Some(0), Some(12)).unwrap(), 2);
+        assert!(indexer.check_backfill_span(&cut, Some(0), Some(12)).is_ok());
+        let unlimited = Indexer {
+            max_backfill_span: None,
+            ..indexer
+        };
+        assert!(unlimited.check_backfill_span(&cut, None, None).is_ok());
+
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    fn test_backfill_span_with_in_memory_stores() {
+        dotenvy::from_filename(".env.test").ok();
+        let client = ChainwebClient::new();
+        let indexer = Indexer {
+            max_backfill_span: Some(5),
+            ..make_memory_indexer(
+                &client,
+                MemoryBlocks::new(
+                    (0..=10)
+                        .map(|height| Block {
+                            parent: format!("hash-{}", height - 1),
+                            ..make_block(0, height, &format!("hash-{}", height))
+                        })
+                        .collect(),
+                ),
+                MemoryCursors::default(),
+            )
+        };
+        let cut = Cut {
+            height: 20,
+            weight: "weight".to_string(),
+            hashes: HashMap::from([(
+                ChainId(0),
+                BlockHash {
+                    height: 20,
+                    hash: "hash-20".to_string(),
+                },
+            )]),
+            instance: "mainnet01".to_string(),
+            id: "id".to_string(),
+        };
+
+        assert_eq!(indexer.backfill_span(&cut, None, None).unwrap(), 10);
+        assert!(indexer.check_backfill_span(&cut, None, None).is_err());
+        assert_eq!(indexer.backfill_span(&cut, Some(0), Some(12)).unwrap(), 2);
+        assert_eq!(
+            indexer.get_all_bounds(&cut),
+            vec![(
+                ChainId(0),
+                Bounds {
+                    lower: vec![Hash("hash-10".to_string())],
+                    upper: vec![Hash("hash-20".to_string())],
+                }
+            )]
+        );
+
+        // A block replacing another one at the same height orphans it
+        let replaced = indexer.blocks.blocks.lock().unwrap()[10].clone();
+        let (_, orphans) = indexer
+            .blocks
+            .replace(&Block {
+                hash: "new-hash-10".to_string(),
+                ..replaced
+            })
+            .unwrap();
+        assert_eq!(
+            orphans
+                .iter()
+                .map(|orphan| &orphan.hash)
+                .collect::<Vec<_>>(),
+            vec!["hash-10"]
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_backfill_stops_at_genesis_height() {
+        dotenvy::from_filename(".env.test").ok();
+        // Nothing listens on this port, any request to the node would fail
+        let node_host = std::env::var("CHAINWEB_NODE_HOST").unwrap();
+        std::env::set_var("CHAINWEB_NODE_HOST", "http://127.0.0.1:1");
+        let client = ChainwebClient::new();
+        std::env::set_var("CHAINWEB_NODE_HOST", node_host);
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let mut indexer = make_indexer(&client, &pool);
+        // Chain 10 of mainnet is indexed from its first block
+        blocks
+            .insert_batch(
+                &(852054..=852060)
+                    .map(|height| Block {
+                        parent: format!("hash-{}", height - 1),
+                        ..make_block(10, height, &format!("hash-{}", height))
+                    })
+                    .collect::<Vec<Block>>(),
+            )
+            .unwrap();
+        let cut = Cut {
+            height: 852060,
+            weight: "weight".to_string(),
+            hashes: HashMap::from([(
+                ChainId(10),
+                BlockHash {
+                    height: 852060,
+                    hash: "hash-852060".to_string(),
+                },
+            )]),
+            instance: "mainnet01".to_string(),
+            id: "id".to_string(),
+        };
+
+        // Nothing is missing between height 0 and the first block
+        let bounds = indexer
+            .get_bounds_between_heights(&cut, 0, None)
+            .await
+            .unwrap();
+        assert!(bounds.is_empty());
+        let bounds = indexer.get_all_bounds(&cut);
+        assert_eq!(
+            bounds,
+            vec![(
+                ChainId(10),
+                Bounds {
+                    lower: vec![Hash("hash-852060".to_string())],
+                    upper: vec![Hash("hash-852060".to_string())],
+                }
+            )]
+        );
+
+        // Without a known genesis height the heights below the first block are backfilled
+        indexer.genesis_heights = Some(GenesisHeights::default());
+        assert_eq!(indexer.get_all_bounds(&cut).len(), 2);
+        assert!(indexer
+            .get_bounds_between_heights(&cut, 0, None)
+            .await
+            .is_err());
+
+        blocks.delete_all().unwrap();
+    }
+
+    /// Starts a node whose payload batch endpoint returns no payload on every other request,
+    /// starting with the first one. It serves the headers of chain 0 up to height 2, the block
+    /// at height `h` having hash `hash-{h}`.
+    async fn start_flaky_node() -> String {
+        use actix_web::{web, App, HttpResponse, HttpServer};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        async fn cut() -> HttpResponse {
+            HttpResponse::Ok().json(serde_json::json!({
+                "height": 2,
+                "weight": "weight",
+                "hashes": { "0": { "height": 2, "hash": "hash-2" } },
+                "instance": "mainnet01",
+                "id": "id"
+            }))
+        }
+
+        /// Headers above the lower bound up to the upper bound, the highest first.
+        async fn header_branch(bounds: web::Json<serde_json::Value>) -> HttpResponse {
+            let height_of = |bound: &str, default: u64| {
+                bounds[bound][0]
+                    .as_str()
+                    .and_then(|hash| hash.strip_prefix("hash-"))
+                    .and_then(|height| height.parse::<u64>().ok())
+                    .unwrap_or(default)
+            };
+            let items = (height_of("lower", 0) + 1..=height_of("upper", 2))
+                .rev()
+                .map(|height| {
+                    serde_json::json!({
+                        "creationTime": 1688902875826238u64,
+                        "parent": format!("hash-{}", height - 1),
+                        "height": height,
+                        "hash": format!("hash-{}", height),
+                        "chainId": 0,
+                        "payloadHash": format!("payload-{}", height),
+                        "weight": "2CiW41EoGzYIeAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA",
+                        "featureFlags": 0,
+                        "epochStart": 1688901280684376u64,
+                        "adjacents": {},
+                        "chainwebVersion": "mainnet01",
+                        "target": "hvD3dR8UooHyvbpvuIKyu0eALPNztocLHAAAAAAAAAA",
+                        "nonce": "0"
+                    })
+                })
+                .collect::<Vec<serde_json::Value>>();
+            HttpResponse::Ok()
+                .json(serde_json::json!({ "items": items, "limit": 50, "next": null }))
+        }
+
+        async fn payload_batch(
+            requests: web::Data<AtomicUsize>,
+            payload_hashes: web::Json<Vec<String>>,
+        ) -> HttpResponse {
+            if requests.fetch_add(1, Ordering::SeqCst) % 2 == 0 {
+                return HttpResponse::Ok().json(serde_json::json!([]));
+            }
+            HttpResponse::Ok().json(
+                payload_hashes
+                    .iter()
+                    .map(|payload_hash| {
+                        serde_json::json!({
+                            "minerData": base64_url::encode("{\"account\":\"miner\"}"),
+                            "outputsHash": "outputs-hash",
+                            "payloadHash": payload_hash,
+                            "transactions": [],
+                            "transactionsHash": "transactions-hash"
+                        })
+                    })
+                    .collect::<Vec<serde_json::Value>>(),
+            )
+        }
+
+        let requests = web::Data::new(AtomicUsize::new(0));
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(requests.clone())
+                .route(
+                    "/chainweb/0.0/mainnet01/chain/{chain}/payload/batch",
+                    web::post().to(payload_batch),
+                )
+                .route("/chainweb/0.0/mainnet01/cut", web::get().to(cut))
+                .route(
+                    "/chainweb/0.0/mainnet01/chain/{chain}/header/branch",
+                    web::post().to(header_branch),
+                )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let address = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+        format!("http://{}", address)
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_fetch_payload_retries_and_queues_failed_headers() {
+        dotenvy::from_filename(".env.test").ok();
+        let node_host = std::env::var("CHAINWEB_NODE_HOST").unwrap();
+        std::env::set_var("CHAINWEB_NODE_HOST", start_flaky_node().await);
+        let client = ChainwebClient::new();
+        std::env::set_var("CHAINWEB_NODE_HOST", node_host);
+        let pool = db::initialize_db_pool();
+        let mut indexer = Indexer {
+            payload_retries: 1,
+            stream_buffer_size: 2,
+            ..make_indexer(&client, &pool)
+        };
+        let header = BlockHeader {
+            creation_time: 1688902875826238,
+            parent: "hash-0".to_string(),
+            height: 1,
+            hash: "hash-1".to_string(),
+            chain_id: ChainId(0),
+            payload_hash: "payload-hash".to_string(),
+            weight: "2CiW41EoGzYIeAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA".to_string(),
+            epoch_start: 1688901280684376,
+            feature_flags: BigDecimal::from(0),
+            adjacents: HashMap::new(),
+            chainweb_version: "mainnet01".to_string(),
+            target: "hvD3dR8UooHyvbpvuIKyu0eALPNztocLHAAAAAAAAAA".to_string(),
+            nonce: "0".to_string(),
+        };
+
+        // The first fetch fails, the retry succeeds
+        let block = indexer
+            .fetch_block(&header, &ChainId(0))
+            .await
+            .unwrap()
+            .block;
+        assert_eq!(block.hash, "hash-1");
+        assert!(indexer.pending_headers.lock().unwrap().is_empty());
+
+        // Without retries the header is queued
+        indexer.payload_retries = 0;
+        assert!(indexer.fetch_block(&header, &ChainId(0)).await.is_err());
+        assert_eq!(
+            indexer
+                .pending_headers
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(chain, header)| (chain.0, header.height))
+                .collect::<Vec<(u16, u64)>>(),
+            vec![(0, 1)]
+        );
+
+        // And indexed when retried
+        let buffer = tokio::sync::Mutex::new(Vec::<BlockData>::new());
+        indexer.retry_pending_headers(&buffer).await;
+        assert!(indexer.pending_headers.lock().unwrap().is_empty());
+        assert_eq!(
+            buffer
+                .lock()
+                .await
+                .iter()
+                .map(|data| data.block.hash.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["hash-1"]
+        );
+
+        // While the buffer is full the headers are queued for retry instead
+        {
+            let mut buffered = buffer.lock().await;
+            let data = buffered[0].clone();
+            buffered.resize(MAX_BUFFERED_BLOCKS, data);
+        }
+        assert!(indexer
+            .buffer_header(&header, &ChainId(0), &buffer)
+            .await
+            .is_err());
+        assert_eq!(buffer.lock().await.len(), MAX_BUFFERED_BLOCKS);
+        assert_eq!(indexer.pending_headers.lock().unwrap().len(), 1);
+        indexer.pending_headers.lock().unwrap().clear();
+        buffer.lock().await.truncate(1);
+
+        // Headers being retried when the stream stops stay queued
+        indexer.queue_pending_header(&ChainId(0), &header);
+        let retry = indexer.retry_pending_headers(&buffer);
+        assert!(tokio::time::timeout(Duration::ZERO, retry).await.is_err());
+        assert_eq!(indexer.pending_headers.lock().unwrap().len(), 1);
+        assert!(indexer.in_flight_headers.lock().unwrap().is_empty());
+
+        // The queue is capped
+        indexer
+            .pending_headers
+            .lock()
+            .unwrap()
+            .resize(MAX_PENDING_HEADERS, (ChainId(0), header.clone()));
+        indexer.queue_pending_header(&ChainId(1), &header);
+        assert_eq!(
+            indexer.pending_headers.lock().unwrap().len(),
+            MAX_PENDING_HEADERS
+        );
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_stream_cursor_stays_below_failed_headers() {
+        dotenvy::from_filename(".env.test").ok();
+        let node_host = std::env::var("CHAINWEB_NODE_HOST").unwrap();
+        std::env::set_var("CHAINWEB_NODE_HOST", start_flaky_node().await);
+        let client = ChainwebClient::new();
+        std::env::set_var("CHAINWEB_NODE_HOST", node_host);
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let stream_cursors = StreamCursorsRepository { pool: pool.clone() };
+        let indexer = make_indexer(&client, &pool);
+        let header = |height: u64| BlockHeader {
+            creation_time: 1688902875826238,
+            parent: format!("hash-{}", height - 1),
+            height,
+            hash: format!("hash-{}", height),
+            chain_id: ChainId(0),
+            payload_hash: format!("payload-{}", height),
+            weight: "2CiW41EoGzYIeAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA".to_string(),
+            epoch_start: 1688901280684376,
+            feature_flags: BigDecimal::from(0),
+            adjacents: HashMap::new(),
+            chainweb_version: "mainnet01".to_string(),
+            target: "hvD3dR8UooHyvbpvuIKyu0eALPNztocLHAAAAAAAAAA".to_string(),
+            nonce: "0".to_string(),
+        };
+        let cursor = |height: i64| StreamCursor {
+            chain_id: 0,
+            height,
+            hash: format!("hash-{}", height),
+        };
+        let buffer = tokio::sync::Mutex::new(Vec::<BlockData>::new());
+
+        // The first payload request fails, the header at height 1 is queued while the next
+        // one is indexed, the cursor being left at the parent of the queued header
+        assert!(indexer
+            .index_stream_header(&header(1), &ChainId(0), &buffer)
+            .await
+            .is_err());
+        indexer
+            .index_stream_header(&header(2), &ChainId(0), &buffer)
+            .await
+            .unwrap();
+        assert_eq!(blocks.count(0).unwrap(), 1);
+        assert_eq!(indexer.pending_headers.lock().unwrap().len(), 1);
+        assert_eq!(stream_cursors.find_all().unwrap(), vec![cursor(0)]);
+
+        // Restarted before the header was retried, its block is indexed from the cursor
+        let restarted = Indexer {
+            // The node fails every other payload request
+            chain_retries: 1,
+            ..make_indexer(&client, &pool)
+        };
+        assert_eq!(restarted.resume_from_cursors().await.unwrap(), 1);
+        assert_eq!(blocks.count(0).unwrap(), 2);
+        assert_eq!(stream_cursors.find_all().unwrap(), vec![cursor(2)]);
+
+        stream_cursors.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_process_headers_with_in_memory_stores() {
+        dotenvy::from_filename(".env.test").ok();
+        let node_host = std::env::var("CHAINWEB_NODE_HOST").unwrap();
+        std::env::set_var("CHAINWEB_NODE_HOST", start_flaky_node().await);
+        let client = ChainwebClient::new();
+        std::env::set_var("CHAINWEB_NODE_HOST", node_host);
+        let header = |hash: &str, height: u64| BlockHeader {
+            creation_time: 1688902875826238,
+            parent: format!("hash-{}", height - 1),
+            height,
+            hash: hash.to_string(),
+            chain_id: ChainId(0),
+            payload_hash: format!("payload-{}", height),
+            weight: "2CiW41EoGzYIeAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA".to_string(),
+            epoch_start: 1688901280684376,
+            feature_flags: BigDecimal::from(0),
+            adjacents: HashMap::new(),
+            chainweb_version: "mainnet01".to_string(),
+            target: "hvD3dR8UooHyvbpvuIKyu0eALPNztocLHAAAAAAAAAA".to_string(),
+            nonce: "0".to_string(),
+        };
+        let payload = BlockPayload {
+            miner_data: base64_url::encode("{\"account\":\"miner\"}"),
+            outputs_hash: "outputs-hash".to_string(),
+            payload_hash: "payload-2".to_string(),
+            transactions: vec![],
+            transactions_hash: "transactions-hash".to_string(),
+        };
+        let indexer = Indexer {
+            // The node fails every other payload request, starting with the first one
+            payload_retries: 1,
+            ..make_memory_indexer(
+                &client,
+                MemoryBlocks::new(vec![build_block(&header("orphan-2", 2), &payload)]),
+                MemoryCursors::default(),
+            )
+        };
+        let stored_hashes = || {
+            let mut hashes = indexer
+                .blocks
+                .blocks
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|block| block.hash.clone())
+                .collect::<Vec<String>>();
+            hashes.sort();
+            hashes
+        };
+        let headers = vec![header("hash-1", 1), header("hash-2", 2)];
+
+        assert!(indexer
+            .process_headers(headers.clone(), &ChainId(0), false)
+            .await
+            .is_err());
+        assert_eq!(stored_hashes(), vec!["orphan-2"]);
+        indexer
+            .process_headers(headers, &ChainId(0), false)
+            .await
+            .unwrap();
+        assert_eq!(stored_hashes(), vec!["hash-1", "hash-2"]);
+        assert_eq!(indexer.metrics.orphaned_blocks(0), 1);
+
+        // A header from the stream moves the cursor of its chain, its payload being fetched
+        // on the retry
+        let buffer = tokio::sync::Mutex::new(Vec::<BlockData>::new());
+        indexer
+            .index_stream_header(&header("hash-3", 3), &ChainId(0), &buffer)
+            .await
+            .unwrap();
+        assert_eq!(stored_hashes(), vec!["hash-1", "hash-2", "hash-3"]);
+        assert_eq!(
+            indexer.stream_cursors.find_all().unwrap(),
+            vec![StreamCursor {
+                chain_id: 0,
+                height: 3,
+                hash: "hash-3".to_string(),
+            }]
+        );
+        assert!(indexer.account_guards.guards.lock().unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_process_header_of_empty_block() {
+        dotenvy::from_filename(".env.test").ok();
+        let node_host = std::env::var("CHAINWEB_NODE_HOST").unwrap();
+        std::env::set_var("CHAINWEB_NODE_HOST", start_flaky_node().await);
+        let client = ChainwebClient::new();
+        std::env::set_var("CHAINWEB_NODE_HOST", node_host);
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let events = EventsRepository { pool: pool.clone() };
+        let transactions = TransactionsRepository { pool: pool.clone() };
+        let indexer = Indexer {
+            // The node fails every other payload request, starting with the first one
+            payload_retries: 1,
+            ..make_indexer(&client, &pool)
+        };
+        let header = BlockHeader {
+            creation_time: 1688902875826238,
+            parent: "hash-0".to_string(),
+            height: 1,
+            hash: "hash-1".to_string(),
+            chain_id: ChainId(0),
+            payload_hash: "payload-hash".to_string(),
+            weight: "2CiW41EoGzYIeAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA".to_string(),
+            epoch_start: 1688901280684376,
+            feature_flags: BigDecimal::from(0),
+            adjacents: HashMap::new(),
+            chainweb_version: "mainnet01".to_string(),
+            target: "hvD3dR8UooHyvbpvuIKyu0eALPNztocLHAAAAAAAAAA".to_string(),
+            nonce: "0".to_string(),
+        };
+
+        indexer.process_header(&header, &ChainId(0)).await.unwrap();
+
+        assert_eq!(blocks.count(0).unwrap(), 1);
+        assert!(transactions.find_all().unwrap().rows.is_empty());
+        assert!(events.find_all().unwrap().rows.is_empty());
+
+        blocks.delete_all().unwrap();
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_process_headers_at_the_same_height_concurrently() {
+        dotenvy::from_filename(".env.test").ok();
+        let node_host = std::env::var("CHAINWEB_NODE_HOST").unwrap();
+        std::env::set_var("CHAINWEB_NODE_HOST", start_flaky_node().await);
+        let client = ChainwebClient::new();
+        std::env::set_var("CHAINWEB_NODE_HOST", node_host);
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let indexer = Indexer {
+            // Enough for both payloads, the node failing every other request
+            payload_retries: 3,
+            ..make_indexer(&client, &pool)
+        };
+        let header = |hash: &str| BlockHeader {
+            creation_time: 1688902875826238,
+            parent: "hash-0".to_string(),
+            height: 1,
+            hash: hash.to_string(),
+            chain_id: ChainId(0),
+            payload_hash: format!("payload-{}", hash),
+            weight: "2CiW41EoGzYIeAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA".to_string(),
+            epoch_start: 1688901280684376,
+            feature_flags: BigDecimal::from(0),
+            adjacents: HashMap::new(),
+            chainweb_version: "mainnet01".to_string(),
+            target: "hvD3dR8UooHyvbpvuIKyu0eALPNztocLHAAAAAAAAAA".to_string(),
+            nonce: "0".to_string(),
+        };
+        let (first, second) = (header("hash-1"), header("other-hash-1"));
+
+        let (first_result, second_result) = tokio::join!(
+            indexer.process_header(&first, &ChainId(0)),
+            indexer.process_header(&second, &ChainId(0))
+        );
+        first_result.unwrap();
+        second_result.unwrap();
+
+        // The last one saved replaced the other
+        assert_eq!(blocks.count(0).unwrap(), 1);
+        let saved = blocks.find_by_height(1, 0).unwrap().unwrap();
+        assert!(saved.hash == "hash-1" || saved.hash == "other-hash-1");
+        assert_eq!(indexer.metrics.orphaned_blocks(0), 1);
+
+        blocks.delete_all().unwrap();
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_process_headers_keeps_indexed_blocks_and_replaces_orphans() {
+        dotenvy::from_filename(".env.test").ok();
+        let node_host = std::env::var("CHAINWEB_NODE_HOST").unwrap();
+        std::env::set_var("CHAINWEB_NODE_HOST", start_flaky_node().await);
+        let client = ChainwebClient::new();
+        std::env::set_var("CHAINWEB_NODE_HOST", node_host);
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let events = EventsRepository { pool: pool.clone() };
+        let indexer = make_indexer(&client, &pool);
+        let header = |hash: &str, height: u64| BlockHeader {
+            creation_time: 1688902875826238,
+            parent: format!("hash-{}", height - 1),
+            height,
+            hash: hash.to_string(),
+            chain_id: ChainId(0),
+            payload_hash: format!("payload-{}", hash),
+            weight: "2CiW41EoGzYIeAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA".to_string(),
+            epoch_start: 1688901280684376,
+            feature_flags: BigDecimal::from(0),
+            adjacents: HashMap::new(),
+            chainweb_version: "mainnet01".to_string(),
+            target: "hvD3dR8UooHyvbpvuIKyu0eALPNztocLHAAAAAAAAAA".to_string(),
+            nonce: "0".to_string(),
+        };
+        let saved = |hash: &str, height: i64| BlockData {
+            events: vec![Event {
+                block: hash.to_string(),
+                chain_id: 0,
+                height,
+                idx: 0,
+                module: "coin".to_string(),
+                module_hash: "module-hash".to_string(),
+                name: "TRANSFER".to_string(),
+                params: serde_json::json!(["alice", "bob", 1.0]),
+                param_text: "[\"alice\",\"bob\",1.0]".to_string(),
+                qual_name: "coin.TRANSFER".to_string(),
+                request_key: "request-key".to_string(),
+                pact_id: None,
+            }],
+            ..block_data(Block {
+                parent: format!("hash-{}", height - 1),
+                payload: format!("payload-{}", hash),
+                ..make_block(0, height, hash)
+            })
+        };
+        indexer
+            .save_blocks_data(&mut [saved("hash-1", 1), saved("orphan-2", 2)])
+            .unwrap();
+        let saved_events = || {
+            let mut saved_events = events
+                .find_all()
+                .unwrap()
+                .rows
+                .into_iter()
+                .map(|event| event.block)
+                .collect::<Vec<String>>();
+            saved_events.sort();
+            saved_events
+        };
+
+        // The node fails every other payload request, starting with the first one, which
+        // leaves the stored blocks as they were
+        let headers = vec![header("hash-1", 1), header("hash-2", 2)];
+        assert!(indexer
+            .process_headers(headers.clone(), &ChainId(0), false)
+            .await
+            .is_err());
+        assert_eq!(saved_events(), vec!["hash-1", "orphan-2"]);
+
+        // The indexed block keeps its events, the orphan is replaced along with its events
+        indexer
+            .process_headers(headers.clone(), &ChainId(0), false)
+            .await
+            .unwrap();
+        assert_eq!(blocks.count(0).unwrap(), 2);
+        assert_eq!(blocks.find_by_height(2, 0).unwrap().unwrap().hash, "hash-2");
+        assert_eq!(indexer.metrics.orphaned_blocks(0), 1);
+        assert_eq!(saved_events(), vec!["hash-1"]);
+
+        // Reprocessing refreshes the indexed block, its payload having no events
+        assert!(indexer
+            .process_headers(headers.clone(), &ChainId(0), true)
+            .await
+            .is_err());
+        indexer
+            .process_headers(headers, &ChainId(0), true)
+            .await
+            .unwrap();
+        assert_eq!(blocks.count(0).unwrap(), 2);
+        assert!(saved_events().is_empty());
+
+        events.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_watch_stream() {
+        let timeout = Duration::from_millis(100);
+        let last_event = Mutex::new(Instant::now());
+        let before = Instant::now();
+        watch_stream(&last_event, timeout).await;
+        assert!(before.elapsed() >= timeout);
+
+        // Events keep the stream alive
+        let last_event = Mutex::new(Instant::now());
+        let receive_events = async {
+            for _ in 0..10 {
+                tokio::time::sleep(Duration::from_millis(30)).await;
+                *last_event.lock().unwrap() = Instant::now();
+            }
+        };
+        tokio::select! {
+            _ = watch_stream(&last_event, timeout) => panic!("Stream considered stalled"),
+            _ = receive_events => {}
+        }
+        let before = Instant::now();
+        watch_stream(&last_event, timeout).await;
+        assert!(before.elapsed() < timeout * 2);
+    }
+
+    #[test]
+    fn test_backfill_progress() {
+        let mut progress = BackfillProgress::new(0);
+        progress.lower_height = Some(1000);
+        assert_eq!(progress.completed(), 0.0);
+        assert_eq!(progress.eta(Duration::from_secs(10)), None);
+        assert_eq!(progress.remaining(), None);
+
+        progress.update(2000, 1951);
+        progress.update(1951, 1750);
+        assert_eq!(progress.completed(), 0.25);
+        assert_eq!(progress.remaining(), Some(750));
+        assert_eq!(
+            progress.eta(Duration::from_secs(10)),
+            Some(Duration::from_secs(30))
+        );
+
+        progress.update(1002, 1000);
+        assert_eq!(progress.completed(), 1.0);
+        assert_eq!(progress.remaining(), Some(0));
+        assert_eq!(progress.eta(Duration::from_secs(40)), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_build_blocks_with_shared_payload() {
+        let make_header = |height: u64, payload_hash: &str| BlockHeader {
+            creation_time: 1688902875826238,
+            parent: format!("hash-{}", height - 1),
+            height,
+            hash: format!("hash-{}", height),
+            chain_id: ChainId(0),
+            payload_hash: payload_hash.to_string(),
+            weight: "2CiW41EoGzYIeAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA".to_string(),
+            epoch_start: 1688901280684376,
+            feature_flags: BigDecimal::from(0),
+            adjacents: HashMap::new(),
+            chainweb_version: "mainnet01".to_string(),
+            target: "hvD3dR8UooHyvbpvuIKyu0eALPNztocLHAAAAAAAAAA".to_string(),
+            nonce: "0".to_string(),
+        };
+        let headers = vec![
+            make_header(1, "empty-payload"),
+            make_header(2, "empty-payload"),
+            make_header(3, "other-payload"),
+        ];
+        assert_eq!(
+            unique_payload_hashes(&headers),
+            vec!["empty-payload", "other-payload"]
+        );
+
+        let make_payload = |payload_hash: &str| BlockPayload {
+            miner_data: base64_url::encode("{\"account\":\"miner\"}"),
+            outputs_hash: "outputs-hash".to_string(),
+            payload_hash: payload_hash.to_string(),
+            transactions: vec![],
+            transactions_hash: "transactions-hash".to_string(),
+        };
+        let payloads = vec![make_payload("empty-payload"), make_payload("other-payload")];
+        let blocks = build_blocks(&headers, &payloads).unwrap();
+        assert_eq!(
+            blocks
+                .iter()
+                .map(|block| (block.hash.as_str(), block.payload.as_str()))
+                .collect::<Vec<(&str, &str)>>(),
+            vec![
+                ("hash-1", "empty-payload"),
+                ("hash-2", "empty-payload"),
+                ("hash-3", "other-payload")
+            ]
+        );
+
+        assert!(build_blocks(&headers, &payloads[..1]).is_err());
+    }
+
+    #[test]
+    fn test_build_block_target_and_difficulty() {
+        let header = BlockHeader {
+            creation_time: 1688902875826238,
+            parent: "mZ3SiegRI9qBY43T3B7VQ82jY40tSgU2E9A7ZGPvXhI".to_string(),
+            height: 3882292,
+            hash: "_6S6n6dhjGw-vVHwIyq8Ulk8VNSlADLchRJCJg4vclM".to_string(),
+            chain_id: ChainId(14),
+            payload_hash: "yRHdjMjoqIeqm8K7WW1c4A77jxi8qP__4x_BjgZoFgE".to_string(),
+            weight: "2CiW41EoGzYIeAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA".to_string(),
+            epoch_start: 1688901280684376,
+            feature_flags: BigDecimal::from(0),
+            adjacents: HashMap::new(),
+            chainweb_version: "mainnet01".to_string(),
+            target: "hvD3dR8UooHyvbpvuIKyu0eALPNztocLHAAAAAAAAAA".to_string(),
+            nonce: "11077503293030185962".to_string(),
+        };
+        let payload = BlockPayload {
+            miner_data: "eyJhY2NvdW50IjoiazplN2Y3MTMwZjM1OWZiMWY4Yzg3ODczYmY4NThhMGU5Y2JjM2MxMDU5ZjYyYWU3MTVlYzcyZTc2MGIwNTVlOWYzIiwicHJlZGljYXRlIjoia2V5cy1hbGwiLCJwdWJsaWMta2V5cyI6WyJlN2Y3MTMwZjM1OWZiMWY4Yzg3ODczYmY4NThhMGU5Y2JjM2MxMDU5ZjYyYWU3MTVlYzcyZTc2MGIwNTVlOWYzIl19".to_string(),
+            outputs_hash: "WrjWEw4Gj-60kcBPY3HZKTT9Gyoh0ZnAjFrL65Fc3GU".to_string(),
+            payload_hash: "yRHdjMjoqIeqm8K7WW1c4A77jxi8qP__4x_BjgZoFgE".to_string(),
+            transactions: vec![],
+            transactions_hash: "9yNSeh7rTW_j1ziKYyubdYUCefnO5K63d5RfPkHQXiM".to_string()
+        };
+        let block = build_block(&header, &payload);
+        assert_eq!(
+            block.target,
+            BigDecimal::from_str("176041566500890765743079240203025667126204291545320436265094")
+                .unwrap()
+        );
+        assert_eq!(
+            block.difficulty,
+            Some(BigDecimal::from_str("657754253946213836").unwrap())
+        );
+    }
+
+    #[test]
+    fn test_build_events_skips_modules_not_in_allowlist() {
+        let signed_tx = SignedTransaction {
+            cmd: String::from("{\"networkId\":\"mainnet01\",\"payload\":{\"exec\":{\"data\":{},\"code\":\"(coin.transfer \\\"alice\\\" \\\"bob\\\" 1.0)\"}},\"signers\":[],\"meta\":{\"creationTime\":1687691365,\"ttl\":28800,\"gasLimit\":1000,\"chainId\":\"0\",\"gasPrice\":0.000001,\"sender\":\"alice\"},\"nonce\":\"nonce\"}"),
+            hash: String::from("request-key"),
+            sigs: vec![],
+        };
+        let pact_result = serde_json::from_value::<PactTransactionResult>(serde_json::json!({
+            "continuation": null,
+            "events": [
+                {
+                    "module": {"name": "coin", "namespace": null},
+                    "moduleHash": "coin-hash",
+                    "name": "TRANSFER",
+                    "params": ["alice", "bob", 1.0]
+                },
+                {
+                    "module": {"name": "radio02", "namespace": "free"},
+                    "moduleHash": "radio-hash",
+                    "name": "SENT",
+                    "params": ["alice"]
+                },
+                {
+                    "module": {"name": "ledger", "namespace": "marmalade-v2"},
+                    "moduleHash": "ledger-hash",
+                    "name": "TRANSFER",
+                    "params": ["token", "alice", "bob", 1]
+                }
+            ],
+            "gas": 700,
+            "logs": "logs",
+            "metaData": {
+                "blockHash": "block-hash",
+                "blockHeight": 1,
+                "blockTime": 1687691365000000i64,
+                "prevBlockHash": "prev-block-hash"
+            },
+            "reqKey": "request-key",
+            "result": {"data": "Write succeeded", "status": "success"},
+            "txId": 1
+        }))
+        .unwrap();
+
+        let events = build_events(&signed_tx, &pact_result, None);
+        assert_eq!(events.len(), 3);
+
+        let allowlist = vec!["coin".to_string(), "marmalade-v2".to_string()];
+        let events = build_events(&signed_tx, &pact_result, Some(&allowlist));
+        assert_eq!(
+            events
+                .iter()
+                .map(|e| (e.qual_name.as_str(), e.idx))
+                .collect::<Vec<_>>(),
+            vec![("coin.TRANSFER", 0), ("marmalade-v2.ledger.TRANSFER", 2)]
+        );
+    }
+
+    #[test]
+    fn test_extract_keysets_from_transfer_create_data() {
+        let command = serde_json::from_str::<Command>("{\"networkId\":\"mainnet01\",\"payload\":{\"exec\":{\"data\":{\"ks\":{\"pred\":\"keys-all\",\"keys\":[\"f3b4a2bd7d3b5a6ab4cd1a0d2f1a8b2b0d5a1f7bbf4e3c5b6a7d8e9f0a1b2c3d\"]}},\"code\":\"(coin.transfer-create \\\"alice\\\" \\\"k:f3b4a2bd7d3b5a6ab4cd1a0d2f1a8b2b0d5a1f7bbf4e3c5b6a7d8e9f0a1b2c3d\\\" (read-keyset \\\"ks\\\") 1.0)\"}},\"signers\":[],\"meta\":{\"creationTime\":1687691365,\"ttl\":28800,\"gasLimit\":1000,\"chainId\":\"0\",\"gasPrice\":0.000001,\"sender\":\"alice\"},\"nonce\":\"nonce\"}").unwrap();
+        let data = command.payload.exec.unwrap().data;
+        assert_eq!(
+            extract_keysets(&data),
+            Some(serde_json::json!([{
+                "name": "ks",
+                "keys": ["f3b4a2bd7d3b5a6ab4cd1a0d2f1a8b2b0d5a1f7bbf4e3c5b6a7d8e9f0a1b2c3d"],
+                "pred": "keys-all"
+            }]))
+        );
+        assert_eq!(extract_keysets(&serde_json::json!({"amount": 1.0})), None);
+    }
+
     #[test]
     fn test_get_signed_txs_from_payloads() {
         let payload = BlockPayload {
@@ -1,8 +1,23 @@
+pub mod budget;
 pub mod chainweb_client;
 pub mod db;
 pub mod gaps;
+pub mod genesis;
+pub mod guards;
 pub mod indexer;
+pub mod logging;
+pub mod maintenance;
+pub mod metrics;
 pub mod models;
+pub mod pagination;
+pub mod pause;
+pub mod progress;
+pub mod projection;
+pub mod repair;
 pub mod repository;
 mod schema;
+pub mod sink;
+pub mod store;
+pub mod tokens;
 pub mod transfers;
+pub mod verify;
@@ -0,0 +1,146 @@
+use std::env;
+use std::future::Future;
+use std::io::Write;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Runs the future with the given request id, added to all the log lines it writes.
+pub async fn with_request_id<F: Future>(request_id: String, future: F) -> F::Output {
+    REQUEST_ID.scope(request_id, future).await
+}
+
+/// Id of the request being handled, if any.
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.try_with(|request_id| request_id.clone()).ok()
+}
+
+/// Runs `f` on the blocking pool like `actix_web::web::block`, with the id of the request being
+/// handled, which isn't passed on to the threads of the blocking pool otherwise.
+pub async fn block_with_request_id<F, R>(f: F) -> Result<R, actix_web::error::BlockingError>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let request_id = request_id();
+    actix_web::web::block(move || match request_id {
+        Some(request_id) => REQUEST_ID.sync_scope(request_id, f),
+        None => f(),
+    })
+    .await
+}
+
+/// Initializes the logger, defaulting to the `info` level. Logs are printed in the default
+/// human-readable format unless `LOG_FORMAT=json` is set, in which case each record is
+/// printed as a single line JSON object. Lines written while handling a request carry its id.
+pub fn init() {
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::new().default_filter_or("info"));
+    if env::var("LOG_FORMAT").map(|format| format.eq_ignore_ascii_case("json")) == Ok(true) {
+        builder.format(|buf, record| {
+            writeln!(
+                buf,
+                "{}",
+                json_record(
+                    record.level(),
+                    record.target(),
+                    &record.args().to_string(),
+                    request_id().as_deref()
+                )
+            )
+        });
+    } else {
+        builder.format(|buf, record| {
+            write!(
+                buf,
+                "[{} {:<5} {}] ",
+                buf.timestamp(),
+                buf.default_styled_level(record.level()),
+                record.target()
+            )?;
+            if let Some(request_id) = request_id() {
+                write!(buf, "[{}] ", request_id)?;
+            }
+            writeln!(buf, "{}", record.args())
+        });
+    }
+    builder.init();
+}
+
+fn json_record(
+    level: log::Level,
+    target: &str,
+    message: &str,
+    request_id: Option<&str>,
+) -> serde_json::Value {
+    let mut record = serde_json::json!({
+        "timestamp": chrono::Utc::now().to_rfc3339(),
+        "level": level.to_string(),
+        "target": target,
+        "message": message,
+    });
+    if let Some(request_id) = request_id {
+        record["request_id"] = serde_json::json!(request_id);
+    }
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_record() {
+        let record = json_record(
+            log::Level::Warn,
+            "bento::indexer",
+            "Chain \"0\" is missing",
+            None,
+        );
+        let line = record.to_string();
+        assert!(!line.contains('\n'));
+        let parsed = serde_json::from_str::<serde_json::Value>(&line).unwrap();
+        assert_eq!(parsed["level"], "WARN");
+        assert_eq!(parsed["target"], "bento::indexer");
+        assert_eq!(parsed["message"], "Chain \"0\" is missing");
+        assert!(parsed["timestamp"].is_string());
+        assert!(parsed.get("request_id").is_none());
+
+        let record = json_record(log::Level::Info, "api", "message", Some("request-id"));
+        assert_eq!(record["request_id"], "request-id");
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        assert_eq!(request_id(), None);
+        let in_request = with_request_id("request-id".to_string(), async {
+            tokio::task::yield_now().await;
+            request_id()
+        })
+        .await;
+        assert_eq!(in_request, Some("request-id".to_string()));
+        assert_eq!(request_id(), None);
+    }
+
+    #[actix_web::test]
+    async fn test_block_with_request_id() {
+        // The lines logged on the blocking pool carry the id
+        let in_block = with_request_id(
+            "request-id".to_string(),
+            block_with_request_id(|| {
+                log::info!("On the blocking pool");
+                request_id()
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(in_block, Some("request-id".to_string()));
+        let without_id =
+            with_request_id("request-id".to_string(), actix_web::web::block(request_id))
+                .await
+                .unwrap();
+        assert_eq!(without_id, None);
+        assert_eq!(block_with_request_id(request_id).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,164 @@
+use diesel::sql_types::{BigInt, Text};
+use diesel::RunQueryDsl;
+use std::env;
+use std::fmt;
+use std::time::Duration;
+
+use crate::db::{DbError, DbPool};
+
+/// Tables maintained, the events growing the fastest. Deleting orphaned blocks and
+/// reprocessing leave dead rows in all of them, which Postgres only reclaims when vacuuming.
+const TABLES: [&str; 4] = ["events", "transactions", "transfers", "blocks"];
+
+/// Share of dead rows above which vacuuming a table is advised, the scale factor
+/// of the autovacuum of Postgres by default.
+const DEAD_ROWS_THRESHOLD: f64 = 0.2;
+
+/// Statistics of a table as last collected by Postgres.
+#[derive(diesel::QueryableByName, Debug, Clone, PartialEq)]
+pub struct TableStats {
+    #[diesel(sql_type = BigInt)]
+    pub live_rows: i64,
+    /// Rows deleted or updated, whose space isn't reclaimed until the table is vacuumed.
+    #[diesel(sql_type = BigInt)]
+    pub dead_rows: i64,
+    /// Size of the table with its indexes.
+    #[diesel(sql_type = BigInt)]
+    pub total_bytes: i64,
+}
+
+impl TableStats {
+    pub fn dead_ratio(&self) -> f64 {
+        match self.live_rows + self.dead_rows {
+            0 => 0.0,
+            rows => self.dead_rows as f64 / rows as f64,
+        }
+    }
+
+    pub fn needs_vacuum(&self) -> bool {
+        self.dead_ratio() > DEAD_ROWS_THRESHOLD
+    }
+}
+
+impl fmt::Display for TableStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} live rows, {} dead rows ({:.1}%), {} MB",
+            self.live_rows,
+            self.dead_rows,
+            self.dead_ratio() * 100.0,
+            self.total_bytes / (1024 * 1024)
+        )
+    }
+}
+
+pub fn table_stats(pool: &DbPool, table: &str) -> Result<TableStats, DbError> {
+    let mut conn = pool.get()?;
+    let stats = diesel::sql_query(
+        "SELECT n_live_tup AS live_rows, n_dead_tup AS dead_rows, \
+         pg_total_relation_size(relid) AS total_bytes FROM pg_stat_user_tables \
+         WHERE schemaname = current_schema() AND relname = $1",
+    )
+    .bind::<Text, _>(table)
+    .get_result::<TableStats>(&mut conn)?;
+    Ok(stats)
+}
+
+/// Analyzes the table, so that the planner keeps up with its growth, or vacuums and analyzes
+/// it when `vacuum` is set. Otherwise, a warning advises to vacuum the table when it has too many
+/// dead rows. The statistics are logged before and after, those after a vacuum may take a few
+/// seconds to be updated by Postgres.
+pub fn maintain_table(pool: &DbPool, table: &str, vacuum: bool) -> Result<TableStats, DbError> {
+    let before = table_stats(pool, table)?;
+    log::info!("Table {} before maintenance: {}", table, before);
+    let mut conn = pool.get()?;
+    let statement = match vacuum {
+        true => format!("VACUUM (ANALYZE) {}", table),
+        false => format!("ANALYZE {}", table),
+    };
+    diesel::sql_query(&statement).execute(&mut conn)?;
+    let after = table_stats(pool, table)?;
+    log::info!("Table {} after {}: {}", table, statement, after);
+    if !vacuum && after.needs_vacuum() {
+        log::warn!(
+            "Table {} has {:.1}% dead rows, consider vacuuming it or setting INDEXER_MAINTENANCE_VACUUM=true",
+            table,
+            after.dead_ratio() * 100.0
+        );
+    }
+    Ok(after)
+}
+
+/// Maintains the tables growing the most every `INDEXER_MAINTENANCE_INTERVAL` seconds, disabled
+/// by default. Tables are analyzed, and also vacuumed when `INDEXER_MAINTENANCE_VACUUM` is set
+/// as vacuuming slows down the other queries while it runs.
+pub fn spawn_table_maintenance(pool: DbPool) {
+    let interval = env::var("INDEXER_MAINTENANCE_INTERVAL")
+        .unwrap_or_else(|_| "0".to_string())
+        .parse::<u64>()
+        .expect("Invalid INDEXER_MAINTENANCE_INTERVAL");
+    let vacuum = env::var("INDEXER_MAINTENANCE_VACUUM")
+        .unwrap_or_else(|_| "false".to_string())
+        .parse::<bool>()
+        .expect("Invalid INDEXER_MAINTENANCE_VACUUM");
+    if interval == 0 {
+        return;
+    }
+    tokio::spawn(async move {
+        let period = Duration::from_secs(interval);
+        // The first run waits for a whole interval rather than slowing down the start
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop {
+            interval.tick().await;
+            let pool = pool.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                for table in TABLES {
+                    if let Err(e) = maintain_table(&pool, table, vacuum) {
+                        log::error!("Error maintaining table {}: {:#?}", table, e);
+                    }
+                }
+            })
+            .await;
+            if let Err(e) = result {
+                log::error!("Table maintenance panicked: {:#?}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use serial_test::serial;
+
+    #[test]
+    fn test_needs_vacuum() {
+        let stats = |live_rows, dead_rows| TableStats {
+            live_rows,
+            dead_rows,
+            total_bytes: 3 * 1024 * 1024,
+        };
+        assert!(!stats(0, 0).needs_vacuum());
+        assert!(!stats(80, 20).needs_vacuum());
+        assert!(stats(70, 30).needs_vacuum());
+        assert!(stats(0, 1).needs_vacuum());
+        assert_eq!(
+            stats(70, 30).to_string(),
+            "70 live rows, 30 dead rows (30.0%), 3 MB"
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_maintain_table() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        for table in TABLES {
+            assert!(maintain_table(&pool, table, false).is_ok());
+        }
+        assert!(maintain_table(&pool, "events", true).is_ok());
+        assert!(table_stats(&pool, "unknown").is_err());
+    }
+}
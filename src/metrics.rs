@@ -0,0 +1,69 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+/// Counters of the indexer, served in the Prometheus text format by `GET /metrics`.
+#[derive(Default)]
+pub struct IndexerMetrics {
+    /// Blocks replaced by another block at the same height, per chain.
+    orphaned_blocks: Mutex<BTreeMap<u16, u64>>,
+}
+
+impl IndexerMetrics {
+    pub fn record_orphan(&self, chain: u16) {
+        *self
+            .orphaned_blocks
+            .lock()
+            .unwrap()
+            .entry(chain)
+            .or_default() += 1;
+    }
+
+    pub fn orphaned_blocks(&self, chain: u16) -> u64 {
+        self.orphaned_blocks
+            .lock()
+            .unwrap()
+            .get(&chain)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Renders the counters in the Prometheus text format, ordered by chain.
+    pub fn render(&self) -> String {
+        let mut output = String::from(
+            "# HELP bento_orphaned_blocks_total Blocks replaced by another block at the same height.\n\
+             # TYPE bento_orphaned_blocks_total counter\n",
+        );
+        for (chain, count) in self.orphaned_blocks.lock().unwrap().iter() {
+            writeln!(
+                output,
+                "bento_orphaned_blocks_total{{chain=\"{}\"}} {}",
+                chain, count
+            )
+            .unwrap();
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_metrics() {
+        let metrics = IndexerMetrics::default();
+        metrics.record_orphan(12);
+        metrics.record_orphan(3);
+        metrics.record_orphan(12);
+        assert_eq!(metrics.orphaned_blocks(12), 2);
+        assert_eq!(metrics.orphaned_blocks(0), 0);
+        assert_eq!(
+            metrics.render(),
+            "# HELP bento_orphaned_blocks_total Blocks replaced by another block at the same height.\n\
+             # TYPE bento_orphaned_blocks_total counter\n\
+             bento_orphaned_blocks_total{chain=\"3\"} 1\n\
+             bento_orphaned_blocks_total{chain=\"12\"} 2\n"
+        );
+    }
+}
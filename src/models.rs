@@ -6,26 +6,34 @@ use serde::Serialize;
 #[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize)]
 #[diesel(table_name = crate::schema::blocks)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct Block {
     pub chain_id: i64,
     pub creation_time: NaiveDateTime,
     pub epoch: NaiveDateTime,
+    #[cfg_attr(feature = "openapi", schema(value_type = String))]
     pub flags: BigDecimal,
     pub hash: String,
     pub height: i64,
     pub miner: String,
+    #[cfg_attr(feature = "openapi", schema(value_type = String))]
     pub nonce: BigDecimal,
     pub parent: String,
     pub payload: String,
     pub pow_hash: String,
     pub predicate: String,
+    #[cfg_attr(feature = "openapi", schema(value_type = String))]
     pub target: BigDecimal,
+    #[cfg_attr(feature = "openapi", schema(value_type = String))]
     pub weight: BigDecimal,
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<String>))]
+    pub difficulty: Option<BigDecimal>,
 }
 
-#[derive(Queryable, Selectable, Insertable, Debug, Clone, AsChangeset)]
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, PartialEq, AsChangeset, Serialize)]
 #[diesel(table_name = crate::schema::events)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct Event {
     pub block: String,
     pub chain_id: i64,
@@ -45,6 +53,7 @@ pub struct Event {
 #[diesel(table_name = crate::schema::transactions)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 #[derive(Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct Transaction {
     pub bad_result: Option<serde_json::Value>,
     pub block: String,
@@ -53,20 +62,32 @@ pub struct Transaction {
     pub continuation: Option<serde_json::Value>,
     pub creation_time: NaiveDateTime,
     pub data: Option<serde_json::Value>,
+    pub error_message: Option<String>,
+    pub error_type: Option<String>,
     pub gas: i64,
     pub gas_limit: i64,
     pub gas_price: f64,
     pub good_result: Option<serde_json::Value>,
     pub height: i64,
+    /// Whether the transaction is paid by a gas station, its sender not being the actual actor.
+    pub is_gas_station: bool,
+    pub keysets: Option<serde_json::Value>,
     pub logs: Option<String>,
     pub metadata: Option<serde_json::Value>,
     pub nonce: String,
     pub num_events: Option<i64>,
     pub pact_id: Option<String>,
     pub proof: Option<String>,
+    /// Signed command the transaction was built from, only stored with `INDEXER_STORE_RAW_CMD`.
+    pub raw_cmd: Option<String>,
     pub request_key: String,
     pub rollback: Option<bool>,
     pub sender: String,
+    pub signatures_valid: Option<bool>,
+    /// Public keys of the signers of the transaction.
+    pub signers: Vec<String>,
+    /// `success` or `failure`, only the matching one of `good_result` and `bad_result` is set.
+    pub status: String,
     pub step: Option<i64>,
     pub ttl: i64,
     pub tx_id: Option<i64>,
@@ -77,8 +98,15 @@ pub struct Transaction {
 #[diesel(table_name = crate::schema::transfers)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 #[derive(Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct Transfer {
+    #[cfg_attr(feature = "openapi", schema(value_type = String))]
     pub amount: BigDecimal,
+    /// Amount the sender authorized in the `TRANSFER` capability of their signature, unknown
+    /// for unscoped signatures and transfers first derived from the stored events, eg. by the
+    /// `transfers` command, which doesn't have the signed commands.
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<String>))]
+    pub authorized_amount: Option<BigDecimal>,
     pub block: String,
     pub chain_id: i64,
     pub creation_time: NaiveDateTime,
@@ -89,5 +117,40 @@ pub struct Transfer {
     pub module_name: String,
     pub pact_id: Option<String>,
     pub request_key: String,
+    /// The sender is also the receiver, eg. when consolidating funds from other chains.
+    pub self_transfer: bool,
     pub to_account: String,
 }
+
+impl Transfer {
+    /// More was transferred than the amount authorized by the sender. Less is fine, a managed
+    /// `TRANSFER` capability authorizing up to its amount over one or more transfers.
+    pub fn exceeds_authorized_amount(&self) -> bool {
+        self.authorized_amount
+            .as_ref()
+            .is_some_and(|authorized| self.amount > *authorized)
+    }
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, PartialEq, Eq, Serialize)]
+#[diesel(table_name = crate::schema::account_guards)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct AccountGuard {
+    pub account: String,
+    pub chain_id: i64,
+    pub module: String,
+    pub guard: serde_json::Value,
+    pub height: i64,
+    pub request_key: String,
+}
+
+/// The last block of a chain processed by the headers stream.
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, PartialEq, Eq)]
+#[diesel(table_name = crate::schema::stream_cursors)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct StreamCursor {
+    pub chain_id: i64,
+    pub height: i64,
+    pub hash: String,
+}
@@ -0,0 +1,75 @@
+use crate::models::{Event, Transaction};
+
+/// Position of the last item of a page, used to fetch the next page without an OFFSET.
+/// Items are ordered by `(height, request_key, idx)` descending, the request key breaking
+/// the ties between events of different transactions at the same height and index.
+/// Transactions, ordered by `(height, request_key)`, have no index and are given 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cursor {
+    pub height: i64,
+    pub idx: i64,
+    pub request_key: String,
+}
+
+impl Cursor {
+    /// Encodes the cursor as an opaque base64url string.
+    pub fn encode(&self) -> String {
+        base64_url::encode(&format!(
+            "{}:{}:{}",
+            self.height, self.idx, self.request_key
+        ))
+    }
+
+    /// Decodes a cursor previously returned by `encode`, `None` if it's not a valid cursor.
+    pub fn decode(cursor: &str) -> Option<Cursor> {
+        let decoded = String::from_utf8(base64_url::decode(cursor).ok()?).ok()?;
+        let mut parts = decoded.splitn(3, ':');
+        let height = parts.next()?.parse::<i64>().ok()?;
+        let idx = parts.next()?.parse::<i64>().ok()?;
+        let request_key = parts.next()?.to_string();
+        Some(Cursor {
+            height,
+            idx,
+            request_key,
+        })
+    }
+}
+
+impl From<&Event> for Cursor {
+    fn from(event: &Event) -> Self {
+        Cursor {
+            height: event.height,
+            idx: event.idx,
+            request_key: event.request_key.clone(),
+        }
+    }
+}
+
+impl From<&Transaction> for Cursor {
+    fn from(transaction: &Transaction) -> Self {
+        Cursor {
+            height: transaction.height,
+            idx: 0,
+            request_key: transaction.request_key.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor_round_trip() {
+        let cursor = Cursor {
+            height: 4305010,
+            idx: 3,
+            request_key: "y3aWL72-3wAy7vL9wcegGXnstH0lHi-q-cfxkhD5JCw".to_string(),
+        };
+        assert_eq!(Cursor::decode(&cursor.encode()), Some(cursor));
+
+        assert_eq!(Cursor::decode("not a cursor"), None);
+        assert_eq!(Cursor::decode(&base64_url::encode("1:2")), None);
+        assert_eq!(Cursor::decode(&base64_url::encode("a:2:key")), None);
+    }
+}
@@ -0,0 +1,118 @@
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Paused state of the indexer, eg. for a maintenance window of the database or the node.
+/// While paused, chains being backfilled wait before their next batch and the headers from
+/// the stream are dropped, the blocks missed meanwhile being caught up once resumed.
+pub struct Pause {
+    paused: watch::Sender<bool>,
+}
+
+impl Default for Pause {
+    fn default() -> Self {
+        Pause {
+            paused: watch::channel(false).0,
+        }
+    }
+}
+
+impl Pause {
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Pauses the indexer if it's running, resumes it otherwise. Returns whether it's paused.
+    pub fn toggle(&self) -> bool {
+        let mut paused = false;
+        self.paused.send_modify(|state| {
+            *state = !*state;
+            paused = *state;
+        });
+        match paused {
+            true => log::info!("Indexer paused, send SIGUSR1 again to resume"),
+            false => log::info!("Indexer resumed"),
+        }
+        paused
+    }
+
+    /// Returns once the indexer isn't paused, right away if it isn't.
+    pub async fn wait_while_paused(&self) {
+        let mut receiver = self.paused.subscribe();
+        // The sender lives as long as self, so waiting can't fail
+        let _ = receiver.wait_for(|paused| !paused).await;
+    }
+
+    /// Returns the next time the indexer is resumed, after being paused.
+    pub async fn resumed(&self) {
+        let mut receiver = self.paused.subscribe();
+        let _ = receiver.wait_for(|paused| *paused).await;
+        let _ = receiver.wait_for(|paused| !paused).await;
+    }
+}
+
+/// Toggles the paused state every time the process receives SIGUSR1, eg. `kill -USR1 <pid>`.
+#[cfg(unix)]
+pub fn toggle_on_sigusr1(pause: Arc<Pause>) -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut signals = signal(SignalKind::user_defined1())?;
+    tokio::spawn(async move {
+        while signals.recv().await.is_some() {
+            pause.toggle();
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn toggle_on_sigusr1(_pause: Arc<Pause>) -> std::io::Result<()> {
+    log::warn!("Pausing the indexer with SIGUSR1 is only supported on Unix");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_wait_while_paused() {
+        let pause = Arc::new(Pause::default());
+        assert!(!pause.is_paused());
+        pause.wait_while_paused().await;
+
+        assert!(pause.toggle());
+        assert!(pause.is_paused());
+        let waiting = tokio::spawn({
+            let pause = pause.clone();
+            async move { pause.wait_while_paused().await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        assert!(!pause.toggle());
+        tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resumed() {
+        let pause = Arc::new(Pause::default());
+        let resumed = tokio::spawn({
+            let pause = pause.clone();
+            async move { pause.resumed().await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!resumed.is_finished());
+
+        pause.toggle();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!resumed.is_finished());
+        pause.toggle();
+        tokio::time::timeout(Duration::from_secs(1), resumed)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,192 @@
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Period over which the indexing rate is computed.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Indexing rate of the chains being indexed, shared with the progress endpoint of the indexer.
+#[derive(Default)]
+pub struct IndexingProgress {
+    chains: Mutex<HashMap<u16, ChainState>>,
+}
+
+struct ChainState {
+    started: Instant,
+    /// Time and number of blocks of the batches indexed within the rate window.
+    batches: VecDeque<(Instant, u64)>,
+    height: Option<u64>,
+    remaining_blocks: Option<u64>,
+}
+
+impl ChainState {
+    fn new(started: Instant) -> Self {
+        ChainState {
+            started,
+            batches: VecDeque::new(),
+            height: None,
+            remaining_blocks: None,
+        }
+    }
+}
+
+/// Progress of a chain as returned by `GET /indexer/progress`.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ChainProgress {
+    pub chain_id: u16,
+    /// Height of the latest indexed batch.
+    pub height: Option<u64>,
+    /// Blocks indexed per second over the last minute.
+    pub blocks_per_second: f64,
+    /// Blocks left to index, unknown until the backfill progress is first logged.
+    pub remaining_blocks: Option<u64>,
+    /// Estimated seconds left at the current rate.
+    pub eta_seconds: Option<u64>,
+}
+
+impl IndexingProgress {
+    /// Starts tracking the indexing of a chain, resetting its previous progress.
+    pub fn start(&self, chain: u16) {
+        self.start_at(chain, Instant::now());
+    }
+
+    /// Records an indexed batch of blocks, with the height it reached and the number of blocks
+    /// left to index if known.
+    pub fn record(&self, chain: u16, blocks: u64, height: u64, remaining_blocks: Option<u64>) {
+        self.record_at(chain, blocks, height, remaining_blocks, Instant::now());
+    }
+
+    /// Marks the indexing of a chain as complete.
+    pub fn finish(&self, chain: u16) {
+        if let Some(state) = self.chains.lock().unwrap().get_mut(&chain) {
+            state.remaining_blocks = Some(0);
+        }
+    }
+
+    /// Progress of every tracked chain, ordered by chain.
+    pub fn snapshot(&self) -> Vec<ChainProgress> {
+        self.snapshot_at(Instant::now())
+    }
+
+    fn start_at(&self, chain: u16, now: Instant) {
+        self.chains
+            .lock()
+            .unwrap()
+            .insert(chain, ChainState::new(now));
+    }
+
+    fn record_at(
+        &self,
+        chain: u16,
+        blocks: u64,
+        height: u64,
+        remaining_blocks: Option<u64>,
+        now: Instant,
+    ) {
+        let mut chains = self.chains.lock().unwrap();
+        let state = chains.entry(chain).or_insert_with(|| ChainState::new(now));
+        state.batches.push_back((now, blocks));
+        state.height = Some(height);
+        state.remaining_blocks = remaining_blocks;
+        while let Some((time, _)) = state.batches.front() {
+            match now.duration_since(*time) > RATE_WINDOW {
+                true => state.batches.pop_front(),
+                false => break,
+            };
+        }
+    }
+
+    fn snapshot_at(&self, now: Instant) -> Vec<ChainProgress> {
+        let chains = self.chains.lock().unwrap();
+        let mut snapshot = chains
+            .iter()
+            .map(|(chain, state)| {
+                let blocks = state
+                    .batches
+                    .iter()
+                    .filter(|(time, _)| now.duration_since(*time) <= RATE_WINDOW)
+                    .map(|(_, blocks)| blocks)
+                    .sum::<u64>();
+                // Chains started less than a minute ago are measured since they started
+                let period = now.duration_since(state.started).min(RATE_WINDOW);
+                let blocks_per_second = match period.is_zero() {
+                    true => 0.0,
+                    false => blocks as f64 / period.as_secs_f64(),
+                };
+                let eta_seconds = match state.remaining_blocks {
+                    Some(0) => Some(0),
+                    Some(remaining) if blocks_per_second > 0.0 => {
+                        Some((remaining as f64 / blocks_per_second).ceil() as u64)
+                    }
+                    _ => None,
+                };
+                ChainProgress {
+                    chain_id: *chain,
+                    height: state.height,
+                    blocks_per_second,
+                    remaining_blocks: state.remaining_blocks,
+                    eta_seconds,
+                }
+            })
+            .collect::<Vec<ChainProgress>>();
+        snapshot.sort_by_key(|progress| progress.chain_id);
+        snapshot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_indexing_progress() {
+        let progress = IndexingProgress::default();
+        let start = Instant::now();
+        progress.start_at(1, start);
+        progress.start_at(0, start);
+        assert_eq!(
+            progress.snapshot_at(start),
+            vec![
+                ChainProgress {
+                    chain_id: 0,
+                    height: None,
+                    blocks_per_second: 0.0,
+                    remaining_blocks: None,
+                    eta_seconds: None,
+                },
+                ChainProgress {
+                    chain_id: 1,
+                    height: None,
+                    blocks_per_second: 0.0,
+                    remaining_blocks: None,
+                    eta_seconds: None,
+                }
+            ]
+        );
+
+        // Measured since the start during the first minute
+        progress.record_at(0, 100, 1900, None, start + Duration::from_secs(10));
+        progress.record_at(0, 100, 1800, Some(1800), start + Duration::from_secs(20));
+        let chain = &progress.snapshot_at(start + Duration::from_secs(20))[0];
+        assert_eq!(chain.height, Some(1800));
+        assert_eq!(chain.blocks_per_second, 10.0);
+        assert_eq!(chain.eta_seconds, Some(180));
+
+        // Then over the last minute only
+        progress.record_at(0, 300, 1500, Some(1500), start + Duration::from_secs(85));
+        let chain = &progress.snapshot_at(start + Duration::from_secs(85))[0];
+        assert_eq!(chain.blocks_per_second, 5.0);
+        assert_eq!(chain.eta_seconds, Some(300));
+        let chain = &progress.snapshot_at(start + Duration::from_secs(200))[0];
+        assert_eq!(chain.blocks_per_second, 0.0);
+        assert_eq!(chain.eta_seconds, None);
+
+        progress.finish(0);
+        assert_eq!(progress.snapshot()[0].eta_seconds, Some(0));
+
+        // Restarting a chain resets its progress
+        progress.start(0);
+        assert_eq!(progress.snapshot()[0].height, None);
+    }
+}
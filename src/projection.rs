@@ -0,0 +1,874 @@
+use crate::chainweb_client::{Command, SignedTransaction, Signer};
+use crate::models::{Block, Event, Transfer};
+use bigdecimal::BigDecimal;
+use chrono::NaiveDateTime;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+/// Derives the transfers of the balance transfer events of the given blocks, without
+/// touching the database, so that any source of events can be projected.
+/// `MINT` and `BURN` events are transfers from and to the empty account, like the `TRANSFER`
+/// events of `coin` creating and destroying coins, unless their transaction already has that
+/// transfer. Events of blocks which aren't given and events whose params don't match their
+/// transfer layout are skipped. Transfers are returned in the order of their events.
+pub fn project_transfers(events: &[Event], blocks: &[Block]) -> Vec<Transfer> {
+    let blocks_by_hash = blocks
+        .iter()
+        .map(|block| (block.hash.as_str(), block))
+        .collect::<HashMap<&str, &Block>>();
+    let projected = events
+        .iter()
+        .filter(|event| is_balance_transfer(event) || is_supply_change(event))
+        .filter_map(|event| match blocks_by_hash.get(event.block.as_str()) {
+            Some(block) => match is_balance_transfer(event) {
+                true => make_transfer(event, block).map(|transfer| (false, transfer)),
+                false => make_supply_transfer(event, block).map(|transfer| (true, transfer)),
+            },
+            None => {
+                log::warn!(
+                    "Skipping {} event {} of tx {}, block {} not found",
+                    event.qual_name,
+                    event.idx,
+                    event.request_key,
+                    event.block
+                );
+                None
+            }
+        })
+        .collect::<Vec<(bool, Transfer)>>();
+    let real_transfers = projected
+        .iter()
+        .filter(|(supply_change, _)| !supply_change)
+        .map(|(_, transfer)| transfer_key(transfer))
+        .collect::<HashSet<TransferKey>>();
+    projected
+        .iter()
+        .filter(|(supply_change, transfer)| {
+            !supply_change || !real_transfers.contains(&transfer_key(transfer))
+        })
+        .map(|(_, transfer)| transfer.clone())
+        .collect::<Vec<Transfer>>()
+}
+
+/// What a supply change and the `TRANSFER` of its transaction have in common:
+/// request key, module, sender, receiver and amount.
+type TransferKey<'a> = (&'a str, &'a str, &'a str, &'a str, &'a BigDecimal);
+
+fn transfer_key(transfer: &Transfer) -> TransferKey<'_> {
+    (
+        &transfer.request_key,
+        &transfer.module_name,
+        &transfer.from_account,
+        &transfer.to_account,
+        &transfer.amount,
+    )
+}
+
+/// Sets the amount authorized by the sender of every transfer, from the `TRANSFER` capability
+/// of its module in the clist of the signers of its transaction. Transfers of more than the
+/// authorized amount are logged.
+pub fn authorize_transfers(
+    transfers: &mut [Transfer],
+    signed_txs: &HashMap<String, SignedTransaction>,
+) {
+    let mut signers_by_request_key: HashMap<String, Vec<Signer>> = HashMap::new();
+    for transfer in transfers.iter_mut() {
+        let signers = signers_by_request_key
+            .entry(transfer.request_key.clone())
+            .or_insert_with(|| {
+                signed_txs
+                    .get(&transfer.request_key)
+                    .and_then(|signed_tx| serde_json::from_str::<Command>(&signed_tx.cmd).ok())
+                    .map(|command| command.signers)
+                    .unwrap_or_default()
+            });
+        transfer.authorized_amount = authorized_amount(signers, transfer);
+        if transfer.exceeds_authorized_amount() {
+            log::warn!(
+                "Transfer {} of tx {} in block {} moved {} {} from {} to {}, {} authorized",
+                transfer.idx,
+                transfer.request_key,
+                transfer.block,
+                transfer.amount,
+                transfer.module_name,
+                transfer.from_account,
+                transfer.to_account,
+                transfer.authorized_amount.as_ref().unwrap()
+            );
+        }
+    }
+}
+
+/// Amount of the first `TRANSFER` capability of the module of the transfer, with the same
+/// sender and receiver, in the clist of the signers. Its args follow the layout of the events.
+fn authorized_amount(signers: &[Signer], transfer: &Transfer) -> Option<BigDecimal> {
+    let name = format!("{}.TRANSFER", transfer.module_name);
+    let layout = transfer_layout(&transfer.module_name);
+    signers
+        .iter()
+        .flat_map(|signer| signer.clist.iter().flatten())
+        .filter(|capability| capability.name == name)
+        .find(|capability| {
+            capability
+                .args
+                .get(layout.sender)
+                .and_then(|arg| arg.as_str())
+                == Some(transfer.from_account.as_str())
+                && capability
+                    .args
+                    .get(layout.receiver)
+                    .and_then(|arg| arg.as_str())
+                    == Some(transfer.to_account.as_str())
+        })
+        .and_then(|capability| capability.args.get(layout.amount))
+        .map(parse_pact_decimal)
+}
+
+fn is_balance_transfer(event: &Event) -> bool {
+    event.name == "TRANSFER"
+}
+
+/// Tokens minted or burned, changing the supply of their module.
+fn is_supply_change(event: &Event) -> bool {
+    event.name == "MINT" || event.name == "BURN"
+}
+
+/// Positions of the sender, receiver and amount in the params of a `TRANSFER` event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TransferLayout {
+    sender: usize,
+    receiver: usize,
+    amount: usize,
+}
+
+/// Layout of `fungible-v2` tokens, eg. `coin`: `[sender, receiver, amount]`.
+const FUNGIBLE_LAYOUT: TransferLayout = TransferLayout {
+    sender: 0,
+    receiver: 1,
+    amount: 2,
+};
+
+/// Layout of poly-fungible tokens, eg. the marmalade ledger: `[token-id, sender, receiver, amount]`.
+const POLY_FUNGIBLE_LAYOUT: TransferLayout = TransferLayout {
+    sender: 1,
+    receiver: 2,
+    amount: 3,
+};
+
+/// Modules whose `TRANSFER` events don't follow the fungible layout.
+const KNOWN_TRANSFER_LAYOUTS: [(&str, TransferLayout); 2] = [
+    ("marmalade.ledger", POLY_FUNGIBLE_LAYOUT),
+    ("marmalade-v2.ledger", POLY_FUNGIBLE_LAYOUT),
+];
+
+fn transfer_layout(module: &str) -> TransferLayout {
+    KNOWN_TRANSFER_LAYOUTS
+        .iter()
+        .find(|(known_module, _)| *known_module == module)
+        .map(|(_, layout)| *layout)
+        .unwrap_or(FUNGIBLE_LAYOUT)
+}
+
+/// Positions of the account and amount in the params of a `MINT` or `BURN` event, and their
+/// number: `[account, amount]`, preceded by the token id for poly-fungible tokens.
+fn supply_change_layout(module: &str) -> (usize, usize, usize) {
+    let offset = transfer_layout(module).sender;
+    (offset, offset + 1, offset + 2)
+}
+
+/// Largest number of digits the decimal point of a parsed decimal is moved by, eg. 8 for `1e-8`.
+/// Values such as `1e-1000000000` are rejected rather than expanded into a huge number of digits.
+const MAX_DECIMAL_EXPONENT: i64 = 1000;
+
+/// Parses a Pact decimal as encoded in JSON: a number, `{"decimal": "1.5"}`, `{"int": 1}`
+/// or a string, in scientific notation or not (eg. `1e-8`, `1.0E-8`).
+/// Anything that isn't a valid number is parsed as 0.
+pub fn parse_pact_decimal(value: &serde_json::Value) -> BigDecimal {
+    try_parse_pact_decimal(value).unwrap_or_else(|| BigDecimal::from(0))
+}
+
+fn try_parse_pact_decimal(value: &serde_json::Value) -> Option<BigDecimal> {
+    match value {
+        // Small and large floats are written in scientific notation, eg. 1e-8
+        serde_json::Value::Number(number) => parse_decimal_str(&number.to_string()),
+        serde_json::Value::String(string) => parse_decimal_str(string),
+        serde_json::Value::Object(object) => object
+            .get("decimal")
+            .or_else(|| object.get("int"))
+            .and_then(try_parse_pact_decimal),
+        _ => None,
+    }
+}
+
+fn parse_decimal_str(string: &str) -> Option<BigDecimal> {
+    let decimal = BigDecimal::from_str(string.trim()).ok()?;
+    let (_, exponent) = decimal.as_bigint_and_exponent();
+    (exponent.abs() <= MAX_DECIMAL_EXPONENT).then_some(decimal)
+}
+
+/// Builds the transfer of a `TRANSFER` event, `None` if the event params don't match
+/// the expected layout (eg. a malformed event with fewer params).
+fn make_transfer(event: &Event, block: &Block) -> Option<Transfer> {
+    let layout = transfer_layout(&event.module);
+    let (sender, receiver, amount) = match (
+        event.params.get(layout.sender).and_then(|p| p.as_str()),
+        event.params.get(layout.receiver).and_then(|p| p.as_str()),
+        event.params.get(layout.amount),
+    ) {
+        (Some(sender), Some(receiver), Some(amount)) => (
+            sender.to_string(),
+            receiver.to_string(),
+            parse_pact_decimal(amount),
+        ),
+        _ => {
+            log::warn!(
+                "Skipping {} event {} of tx {} in block {}, unexpected params: {}",
+                event.qual_name,
+                event.idx,
+                event.request_key,
+                event.block,
+                event.params
+            );
+            return None;
+        }
+    };
+    Some(build_transfer(event, block, sender, receiver, amount))
+}
+
+/// Builds the transfer of a `MINT` event from the empty account, or of a `BURN` event to it.
+/// `None` if the event params don't match the expected layout, as other modules may emit
+/// events with the same names which aren't about tokens.
+fn make_supply_transfer(event: &Event, block: &Block) -> Option<Transfer> {
+    let (account, amount, count) = supply_change_layout(&event.module);
+    let params = event
+        .params
+        .as_array()
+        .filter(|params| params.len() == count);
+    let account_and_amount = params.and_then(|params| {
+        let account = params[account].as_str()?;
+        let amount = try_parse_pact_decimal(&params[amount])?;
+        Some((account.to_string(), amount))
+    });
+    let (account, amount) = match account_and_amount {
+        Some(account_and_amount) => account_and_amount,
+        None => {
+            log::warn!(
+                "Skipping {} event {} of tx {} in block {}, unexpected params: {}",
+                event.qual_name,
+                event.idx,
+                event.request_key,
+                event.block,
+                event.params
+            );
+            return None;
+        }
+    };
+    let (sender, receiver) = match event.name.as_str() {
+        "MINT" => (String::new(), account),
+        _ => (account, String::new()),
+    };
+    Some(build_transfer(event, block, sender, receiver, amount))
+}
+
+fn build_transfer(
+    event: &Event,
+    block: &Block,
+    sender: String,
+    receiver: String,
+    amount: BigDecimal,
+) -> Transfer {
+    Transfer {
+        amount,
+        authorized_amount: None,
+        block: event.block.clone(),
+        chain_id: event.chain_id,
+        creation_time: NaiveDateTime::from_timestamp_millis(block.creation_time.timestamp_millis())
+            .unwrap(),
+        self_transfer: sender == receiver,
+        from_account: sender,
+        height: event.height,
+        idx: event.idx,
+        module_hash: event.module_hash.clone(),
+        module_name: event.module.clone(),
+        request_key: event.request_key.clone(),
+        to_account: receiver,
+        pact_id: event.pact_id.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn make_block(chain_id: i64, height: i64, hash: String) -> Block {
+        Block {
+            chain_id,
+            hash,
+            height,
+            parent: "parent".to_string(),
+            weight: BigDecimal::from(0),
+            creation_time: Utc::now().naive_utc(),
+            epoch: Utc::now().naive_utc(),
+            flags: BigDecimal::from(0),
+            miner: "miner".to_string(),
+            nonce: BigDecimal::from(0),
+            payload: "payload".to_string(),
+            pow_hash: "".to_string(),
+            predicate: "predicate".to_string(),
+            target: BigDecimal::from(1),
+            difficulty: None,
+        }
+    }
+
+    #[test]
+    fn test_make_transfer() {
+        let event = Event {
+            block: "block-hash".to_string(),
+            chain_id: 0,
+            height: 0,
+            idx: 0,
+            module: "coin".to_string(),
+            module_hash: "module-hash".to_string(),
+            name: "TRANSFER".to_string(),
+            params: serde_json::json!(["bob", "alice", 100.12324354665567]),
+            param_text: "param-text".to_string(),
+            qual_name: "coin.TRANSFER".to_string(),
+            request_key: "request-key".to_string(),
+            pact_id: None,
+        };
+        let block = make_block(0, 0, "hash".to_string());
+        let transfer = make_transfer(&event, &block).unwrap();
+        assert_eq!(
+            transfer,
+            Transfer {
+                amount: BigDecimal::from_str("100.12324354665567").unwrap(),
+                authorized_amount: None,
+                block: "block-hash".to_string(),
+                chain_id: 0,
+                creation_time: NaiveDateTime::from_timestamp_millis(
+                    block.creation_time.timestamp_millis()
+                )
+                .unwrap(),
+                from_account: "bob".to_string(),
+                height: 0,
+                idx: 0,
+                module_hash: "module-hash".to_string(),
+                module_name: "coin".to_string(),
+                request_key: "request-key".to_string(),
+                self_transfer: false,
+                to_account: "alice".to_string(),
+                pact_id: None
+            }
+        );
+
+        let no_sender_event = Event {
+            params: serde_json::json!(["", "alice", 10]),
+            ..event.clone()
+        };
+        let transfer = make_transfer(&no_sender_event, &block).unwrap();
+        assert_eq!(
+            transfer,
+            Transfer {
+                amount: BigDecimal::from_str("10").unwrap(),
+                authorized_amount: None,
+                block: "block-hash".to_string(),
+                chain_id: 0,
+                creation_time: NaiveDateTime::from_timestamp_millis(
+                    block.creation_time.timestamp_millis()
+                )
+                .unwrap(),
+                from_account: "".to_string(),
+                height: 0,
+                idx: 0,
+                module_hash: "module-hash".to_string(),
+                module_name: "coin".to_string(),
+                request_key: "request-key".to_string(),
+                self_transfer: false,
+                to_account: "alice".to_string(),
+                pact_id: None
+            }
+        );
+        let no_receiver_event = Event {
+            params: serde_json::json!(["bob", "", 10]),
+            ..event
+        };
+        let transfer = make_transfer(&no_receiver_event, &block).unwrap();
+        assert_eq!(
+            transfer,
+            Transfer {
+                amount: BigDecimal::from_str("10").unwrap(),
+                authorized_amount: None,
+                block: "block-hash".to_string(),
+                chain_id: 0,
+                creation_time: NaiveDateTime::from_timestamp_millis(
+                    block.creation_time.timestamp_millis()
+                )
+                .unwrap(),
+                from_account: "bob".to_string(),
+                height: 0,
+                idx: 0,
+                module_hash: "module-hash".to_string(),
+                module_name: "coin".to_string(),
+                request_key: "request-key".to_string(),
+                self_transfer: false,
+                to_account: "".to_string(),
+                pact_id: None
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_transfer_event_decimal() {
+        let event = Event {
+            block: "block-hash".to_string(),
+            chain_id: 0,
+            height: 0,
+            idx: 0,
+            module: "coin".to_string(),
+            module_hash: "module-hash".to_string(),
+            name: "TRANSFER".to_string(),
+            params: serde_json::json!(["bob", "alice", {"decimal": "22.230409400000000000000000"}]),
+            param_text: "param-text".to_string(),
+            qual_name: "coin.TRANSFER".to_string(),
+            request_key: "request-key".to_string(),
+            pact_id: None,
+        };
+        let block = make_block(0, 0, "hash".to_string());
+        let transfer = make_transfer(&event, &block).unwrap();
+        assert!(transfer.amount == BigDecimal::from_str("22.230409400000000000000000").unwrap());
+        let event = Event {
+            block: "block-hash".to_string(),
+            chain_id: 0,
+            height: 0,
+            idx: 0,
+            module: "coin".to_string(),
+            module_hash: "module-hash".to_string(),
+            name: "TRANSFER".to_string(),
+            params: serde_json::json!(["bob", "alice", {"int": 1}]),
+            param_text: "param-text".to_string(),
+            qual_name: "coin.TRANSFER".to_string(),
+            request_key: "request-key".to_string(),
+            pact_id: None,
+        };
+        let transfer = make_transfer(&event, &block).unwrap();
+        assert!(transfer.amount == BigDecimal::from(1));
+    }
+
+    #[test]
+    fn test_make_self_transfer() {
+        let event = Event {
+            block: "block-hash".to_string(),
+            chain_id: 0,
+            height: 0,
+            idx: 0,
+            module: "coin".to_string(),
+            module_hash: "module-hash".to_string(),
+            name: "TRANSFER".to_string(),
+            params: serde_json::json!(["bob", "bob", 1.5]),
+            param_text: "param-text".to_string(),
+            qual_name: "coin.TRANSFER".to_string(),
+            request_key: "request-key".to_string(),
+            pact_id: None,
+        };
+        let block = make_block(0, 0, "hash".to_string());
+        assert!(make_transfer(&event, &block).unwrap().self_transfer);
+        let event = Event {
+            params: serde_json::json!(["bob", "alice", 1.5]),
+            ..event
+        };
+        assert!(!make_transfer(&event, &block).unwrap().self_transfer);
+    }
+
+    #[test]
+    /// This test is to make sure that if the amount is not a number, we default to 0
+    fn test_make_transfer_when_event_has_string_as_amount() {
+        let event = Event {
+            block: "block-hash".to_string(),
+            chain_id: 0,
+            height: 0,
+            idx: 0,
+            module: "coin".to_string(),
+            module_hash: "module-hash".to_string(),
+            name: "TRANSFER".to_string(),
+            params: serde_json::json!(["bob", "alice", "wrong-amount"]),
+            param_text: "param-text".to_string(),
+            qual_name: "coin.TRANSFER".to_string(),
+            request_key: "request-key".to_string(),
+            pact_id: None,
+        };
+        let block = make_block(0, 0, "hash".to_string());
+        let transfer = make_transfer(&event, &block).unwrap();
+        assert!(transfer.amount == BigDecimal::from(0));
+    }
+
+    #[test]
+    fn test_is_balance_transfer() {
+        let event = Event {
+            block: "block-hash".to_string(),
+            chain_id: 0,
+            height: 0,
+            idx: 0,
+            module: "coin".to_string(),
+            module_hash: "module-hash".to_string(),
+            name: "TRANSFER".to_string(),
+            params: serde_json::json!(["bob", "alice", 100.1]),
+            param_text: "param-text".to_string(),
+            qual_name: "coin.TRANSFER".to_string(),
+            request_key: "request-key".to_string(),
+            pact_id: None,
+        };
+        assert!(is_balance_transfer(&event));
+        let event = Event {
+            name: "NOT_TRANSFER".to_string(),
+            ..event
+        };
+        assert!(is_balance_transfer(&event) == false);
+    }
+
+    #[test]
+    fn test_make_transfer_with_marmalade_ledger_layout() {
+        let event = Event {
+            block: "block-hash".to_string(),
+            chain_id: 0,
+            height: 0,
+            idx: 0,
+            module: "marmalade-v2.ledger".to_string(),
+            module_hash: "module-hash".to_string(),
+            name: "TRANSFER".to_string(),
+            params: serde_json::json!(["t:token-id", "bob", "alice", {"decimal": "0.5"}]),
+            param_text: "param-text".to_string(),
+            qual_name: "marmalade-v2.ledger.TRANSFER".to_string(),
+            request_key: "request-key".to_string(),
+            pact_id: None,
+        };
+        let block = make_block(0, 0, "hash".to_string());
+        let transfer = make_transfer(&event, &block).unwrap();
+        assert_eq!(transfer.from_account, "bob");
+        assert_eq!(transfer.to_account, "alice");
+        assert_eq!(transfer.amount, BigDecimal::from_str("0.5").unwrap());
+        assert_eq!(transfer.module_name, "marmalade-v2.ledger");
+
+        assert_eq!(transfer_layout("coin"), FUNGIBLE_LAYOUT);
+        assert_eq!(transfer_layout("free.my-token"), FUNGIBLE_LAYOUT);
+        assert_eq!(transfer_layout("marmalade.ledger"), POLY_FUNGIBLE_LAYOUT);
+    }
+
+    #[test]
+    fn test_make_transfer_with_missing_params() {
+        let event = Event {
+            block: "block-hash".to_string(),
+            chain_id: 0,
+            height: 0,
+            idx: 0,
+            module: "coin".to_string(),
+            module_hash: "module-hash".to_string(),
+            name: "TRANSFER".to_string(),
+            params: serde_json::json!(["bob", "alice"]),
+            param_text: "param-text".to_string(),
+            qual_name: "coin.TRANSFER".to_string(),
+            request_key: "request-key".to_string(),
+            pact_id: None,
+        };
+        let block = make_block(0, 0, "hash".to_string());
+        assert_eq!(make_transfer(&event, &block), None);
+
+        let no_params_event = Event {
+            params: serde_json::json!([]),
+            ..event.clone()
+        };
+        assert_eq!(make_transfer(&no_params_event, &block), None);
+
+        let not_an_array_event = Event {
+            params: serde_json::json!({"sender": "bob"}),
+            ..event.clone()
+        };
+        assert_eq!(make_transfer(&not_an_array_event, &block), None);
+
+        let non_string_sender_event = Event {
+            params: serde_json::json!([null, "alice", 1.0]),
+            ..event.clone()
+        };
+        assert_eq!(make_transfer(&non_string_sender_event, &block), None);
+
+        // Fungible-sized params are too short for the poly-fungible layout
+        let marmalade_event = Event {
+            module: "marmalade-v2.ledger".to_string(),
+            qual_name: "marmalade-v2.ledger.TRANSFER".to_string(),
+            params: serde_json::json!(["t:token-id", "bob", "alice"]),
+            ..event
+        };
+        assert_eq!(make_transfer(&marmalade_event, &block), None);
+    }
+
+    #[test]
+    fn test_parse_pact_decimal() {
+        let cases = [
+            (serde_json::json!(1.5), "1.5"),
+            (serde_json::json!(10), "10"),
+            (serde_json::json!(1e-8), "0.00000001"),
+            (
+                serde_json::json!({"decimal": "22.230409400000000000000000"}),
+                "22.2304094",
+            ),
+            (serde_json::json!({"int": 1}), "1"),
+            (
+                serde_json::json!({"int": "123456789012345678901234567890"}),
+                "123456789012345678901234567890",
+            ),
+            (serde_json::json!("0.5"), "0.5"),
+            (serde_json::json!(2.5e-8), "0.000000025"),
+            (serde_json::json!(1.5e21), "1500000000000000000000"),
+            (serde_json::json!("1e-8"), "0.00000001"),
+            (serde_json::json!("1.0E-8"), "0.00000001"),
+            (serde_json::json!("1.5e+3"), "1500"),
+            (serde_json::json!(" 2.5E2 "), "250"),
+            (serde_json::json!({"decimal": "1e-8"}), "0.00000001"),
+            (serde_json::json!("1e-100000"), "0"),
+            (serde_json::json!("e-8"), "0"),
+            (serde_json::json!("1e"), "0"),
+            (serde_json::json!("wrong-amount"), "0"),
+            (serde_json::json!({"decimal": "wrong-amount"}), "0"),
+            (serde_json::json!({"other": 1}), "0"),
+            (serde_json::json!(null), "0"),
+            (serde_json::json!([1]), "0"),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(
+                parse_pact_decimal(&value),
+                BigDecimal::from_str(expected).unwrap(),
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn test_project_transfers() {
+        let transfer_event = |block: &str, idx: i64, params: serde_json::Value| Event {
+            block: block.to_string(),
+            chain_id: 0,
+            height: 0,
+            idx,
+            module: "coin".to_string(),
+            module_hash: "module-hash".to_string(),
+            name: "TRANSFER".to_string(),
+            params,
+            param_text: "param-text".to_string(),
+            qual_name: "coin.TRANSFER".to_string(),
+            request_key: "request-key".to_string(),
+            pact_id: None,
+        };
+        let blocks = [
+            make_block(0, 0, "block-0".to_string()),
+            make_block(0, 1, "block-1".to_string()),
+        ];
+        let events = [
+            transfer_event("block-1", 0, serde_json::json!(["alice", "bob", 1.0])),
+            Event {
+                name: "TRANSFER_XCHAIN".to_string(),
+                ..transfer_event("block-1", 1, serde_json::json!(["alice", "bob", 2.0]))
+            },
+            transfer_event("block-0", 0, serde_json::json!(["bob", "carol", 3.0])),
+            // Malformed event
+            transfer_event("block-0", 1, serde_json::json!(["bob"])),
+            // Block not given
+            transfer_event("block-2", 0, serde_json::json!(["carol", "dave", 4.0])),
+        ];
+
+        let transfers = project_transfers(&events, &blocks);
+        assert_eq!(
+            transfers
+                .iter()
+                .map(|transfer| (
+                    transfer.block.as_str(),
+                    transfer.from_account.as_str(),
+                    transfer.to_account.as_str()
+                ))
+                .collect::<Vec<(&str, &str, &str)>>(),
+            vec![("block-1", "alice", "bob"), ("block-0", "bob", "carol")]
+        );
+        assert_eq!(
+            transfers[1].creation_time,
+            NaiveDateTime::from_timestamp_millis(blocks[0].creation_time.timestamp_millis())
+                .unwrap()
+        );
+
+        // Projections are replayable
+        assert_eq!(project_transfers(&events, &blocks), transfers);
+        assert!(project_transfers(&events, &[]).is_empty());
+        assert!(project_transfers(&[], &blocks).is_empty());
+    }
+
+    #[test]
+    fn test_project_mints_and_burns() {
+        let event = |module: &str, name: &str, idx: i64, params: serde_json::Value| Event {
+            block: "block-0".to_string(),
+            chain_id: 0,
+            height: 0,
+            idx,
+            module: module.to_string(),
+            module_hash: "module-hash".to_string(),
+            name: name.to_string(),
+            params,
+            param_text: "param-text".to_string(),
+            qual_name: format!("{}.{}", module, name),
+            request_key: format!("request-key-{}", idx),
+            pact_id: None,
+        };
+        let blocks = [make_block(0, 0, "block-0".to_string())];
+        let events = [
+            event("free.token", "MINT", 0, serde_json::json!(["alice", 10.0])),
+            event(
+                "free.token",
+                "BURN",
+                1,
+                serde_json::json!(["alice", {"decimal": "2.5"}]),
+            ),
+            event(
+                "marmalade-v2.ledger",
+                "MINT",
+                2,
+                serde_json::json!(["t:token", "bob", 1]),
+            ),
+            // Not about tokens
+            event(
+                "free.game",
+                "MINT",
+                3,
+                serde_json::json!(["sword", "alice"]),
+            ),
+            event("free.game", "BURN", 4, serde_json::json!(["alice"])),
+        ];
+
+        let transfers = project_transfers(&events, &blocks);
+        assert_eq!(
+            transfers
+                .iter()
+                .map(|transfer| (
+                    transfer.module_name.as_str(),
+                    transfer.from_account.as_str(),
+                    transfer.to_account.as_str(),
+                    transfer.amount.clone()
+                ))
+                .collect::<Vec<(&str, &str, &str, BigDecimal)>>(),
+            vec![
+                ("free.token", "", "alice", BigDecimal::from(10)),
+                (
+                    "free.token",
+                    "alice",
+                    "",
+                    BigDecimal::from_str("2.5").unwrap()
+                ),
+                ("marmalade-v2.ledger", "", "bob", BigDecimal::from(1)),
+            ]
+        );
+        // Minted minus burned
+        let supply = transfers
+            .iter()
+            .filter(|transfer| transfer.module_name == "free.token")
+            .fold(BigDecimal::from(0), |supply, transfer| {
+                match transfer.from_account.is_empty() {
+                    true => supply + &transfer.amount,
+                    false => supply - &transfer.amount,
+                }
+            });
+        assert_eq!(supply, BigDecimal::from_str("7.5").unwrap());
+
+        // Mints also emitted as a transfer from the empty account are only counted once
+        let events = [
+            event("free.token", "MINT", 0, serde_json::json!(["alice", 10.0])),
+            Event {
+                request_key: "request-key-0".to_string(),
+                ..event(
+                    "free.token",
+                    "TRANSFER",
+                    1,
+                    serde_json::json!(["", "alice", 10.0]),
+                )
+            },
+        ];
+        let transfers = project_transfers(&events, &blocks);
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].idx, 1);
+    }
+
+    #[test]
+    fn test_authorize_transfers() {
+        let transfer = |request_key: &str, idx: i64, to: &str, amount: &str| Transfer {
+            amount: BigDecimal::from_str(amount).unwrap(),
+            authorized_amount: None,
+            block: "block-hash".to_string(),
+            chain_id: 0,
+            creation_time: Utc::now().naive_utc(),
+            from_account: "alice".to_string(),
+            height: 0,
+            idx,
+            module_hash: "module-hash".to_string(),
+            module_name: "coin".to_string(),
+            pact_id: None,
+            request_key: request_key.to_string(),
+            self_transfer: false,
+            to_account: to.to_string(),
+        };
+        let signed_tx = |request_key: &str, clist: serde_json::Value| {
+            let cmd = serde_json::json!({
+                "meta": {"chainId": "0", "creationTime": 1688039944, "gasLimit": 8000, "gasPrice": 0.00000001, "sender": "alice", "ttl": 28800},
+                "networkId": "mainnet01",
+                "nonce": "nonce",
+                "payload": {"exec": {"code": "(coin.transfer \"alice\" \"bob\" 230.9)", "data": null}},
+                "signers": [{"pubKey": "alice-key", "clist": clist}]
+            });
+            (
+                request_key.to_string(),
+                SignedTransaction {
+                    cmd: cmd.to_string(),
+                    hash: request_key.to_string(),
+                    sigs: vec![],
+                },
+            )
+        };
+        let signed_txs = HashMap::from([
+            signed_tx(
+                "matching",
+                serde_json::json!([
+                    {"name": "coin.GAS", "args": []},
+                    {"name": "coin.TRANSFER", "args": ["alice", "bob", 230.9]}
+                ]),
+            ),
+            signed_tx(
+                "mismatched",
+                serde_json::json!([
+                    {"name": "coin.TRANSFER", "args": ["alice", "carol", {"decimal": "1.5"}]},
+                    {"name": "coin.TRANSFER", "args": ["alice", "bob", {"decimal": "10.0"}]}
+                ]),
+            ),
+            signed_tx("unscoped", serde_json::json!(null)),
+        ]);
+        let mut transfers = vec![
+            transfer("matching", 0, "bob", "230.9"),
+            transfer("mismatched", 0, "bob", "12.5"),
+            transfer("mismatched", 1, "carol", "1.2"),
+            transfer("unscoped", 0, "bob", "1.0"),
+            transfer("unknown", 0, "bob", "1.0"),
+        ];
+        authorize_transfers(&mut transfers, &signed_txs);
+        assert_eq!(
+            transfers
+                .iter()
+                .map(|transfer| (
+                    transfer.authorized_amount.clone(),
+                    transfer.exceeds_authorized_amount()
+                ))
+                .collect::<Vec<(Option<BigDecimal>, bool)>>(),
+            vec![
+                (Some(BigDecimal::from_str("230.9").unwrap()), false),
+                (Some(BigDecimal::from_str("10.0").unwrap()), true),
+                // Less than authorized, the managed capability allowing it
+                (Some(BigDecimal::from_str("1.5").unwrap()), false),
+                (None, false),
+                (None, false),
+            ]
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use crate::chainweb_client::{tx_result::PactTransactionResult, ChainId, ChainwebClient};
+use crate::models::Event;
+use crate::repository::EventsRepository;
+use futures::StreamExt;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::time::Instant;
+
+/// Re-derives the module hash and qualified name of the stored events which were indexed
+/// before these were fixed, on every chain or only the given one, from the given height.
+/// Events already repaired are skipped, so an interrupted run can simply be started again,
+/// from the last height logged to skip the heights already done.
+pub async fn repair_events(
+    chainweb_client: &ChainwebClient,
+    events_repository: &EventsRepository,
+    chain_id: Option<i64>,
+    from_height: i64,
+    batch_size: i64,
+) -> Result<(), Box<dyn Error>> {
+    let chains = match chain_id {
+        Some(chain_id) => vec![chain_id],
+        None => {
+            let cut = chainweb_client.get_complete_cut().await?;
+            let mut chains = cut
+                .hashes
+                .keys()
+                .map(|chain| chain.0 as i64)
+                .collect::<Vec<i64>>();
+            chains.sort();
+            chains
+        }
+    };
+    for chain_id in chains {
+        repair_chain_events(
+            chainweb_client,
+            events_repository,
+            chain_id,
+            from_height,
+            batch_size,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+async fn repair_chain_events(
+    chainweb_client: &ChainwebClient,
+    events_repository: &EventsRepository,
+    chain_id: i64,
+    from_height: i64,
+    batch_size: i64,
+) -> Result<(), Box<dyn Error>> {
+    let max_height = events_repository
+        .find_max_height(chain_id)
+        .map_err(|e| e as Box<dyn Error>)?;
+    log::info!(
+        "Chain {}: repairing events between heights {} and {}",
+        chain_id,
+        from_height,
+        max_height
+    );
+    let mut repaired = 0;
+    let mut min_height = from_height;
+    while min_height <= max_height {
+        let upper_height = (min_height + batch_size - 1).min(max_height);
+        let before = Instant::now();
+        let events = events_repository
+            .find_to_repair(chain_id, min_height, upper_height)
+            .map_err(|e| e as Box<dyn Error>)?;
+        if !events.is_empty() {
+            // Module hashes can only be found in the transaction results,
+            // qualified names can be derived from the stored module and name
+            let request_keys = events
+                .iter()
+                .filter(|event| event.module_hash.is_empty())
+                .map(|event| event.request_key.clone())
+                .collect::<HashSet<String>>();
+            let results =
+                fetch_results(chainweb_client, &ChainId(chain_id as u16), request_keys).await;
+            let fixed = events
+                .iter()
+                .filter_map(|event| repair_event(event, results.get(&event.request_key)))
+                .collect::<Vec<Event>>();
+            repaired += events_repository
+                .update_derived_fields(&fixed)
+                .map_err(|e| e as Box<dyn Error>)?;
+            log::info!(
+                "Chain {}: repaired {} of {} events up to height {} in {}ms, {} repaired so far",
+                chain_id,
+                fixed.len(),
+                events.len(),
+                upper_height,
+                before.elapsed().as_millis(),
+                repaired
+            );
+        }
+        min_height = upper_height + 1;
+    }
+    log::info!("Chain {}: repaired {} events", chain_id, repaired);
+    Ok(())
+}
+
+/// Fetches the results of the given transactions, one per request like the indexer does.
+/// Transactions whose result can't be fetched are left out.
+async fn fetch_results(
+    chainweb_client: &ChainwebClient,
+    chain: &ChainId,
+    request_keys: HashSet<String>,
+) -> HashMap<String, PactTransactionResult> {
+    futures::stream::iter(request_keys)
+        .map(|request_key| async move {
+            match chainweb_client
+                .poll(&vec![request_key.clone()], chain)
+                .await
+            {
+                Ok(results) => results,
+                Err(e) => {
+                    log::warn!("Could not fetch the result of tx {}: {}", request_key, e);
+                    HashMap::new()
+                }
+            }
+        })
+        .buffer_unordered(10)
+        .collect::<Vec<HashMap<String, PactTransactionResult>>>()
+        .await
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+/// Returns the event with its module, module hash and qualified name derived again, from the
+/// result of its transaction when given, otherwise from its stored module and name when its
+/// module hash is known. `None` if the event can't be repaired or doesn't need to be.
+fn repair_event(event: &Event, result: Option<&PactTransactionResult>) -> Option<Event> {
+    // Results of another block mean the stored event is on an orphaned block
+    let source = result
+        .filter(|result| result.metadata.block_hash == event.block)
+        .and_then(|result| result.events.as_ref()?.get(event.idx as usize))
+        .filter(|source| source.name == event.name);
+    let repaired = match source {
+        Some(source) => {
+            let module = source.module.qualified_name();
+            Event {
+                qual_name: format!("{}.{}", module, event.name),
+                module,
+                module_hash: source.module_hash.clone(),
+                ..event.clone()
+            }
+        }
+        None if !event.module_hash.is_empty() => Event {
+            qual_name: format!("{}.{}", event.module, event.name),
+            ..event.clone()
+        },
+        None => {
+            log::warn!(
+                "Could not repair event {} of tx {} in block {}, its result wasn't found",
+                event.idx,
+                event.request_key,
+                event.block
+            );
+            return None;
+        }
+    };
+    match repaired == *event {
+        true => None,
+        false => Some(repaired),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_event(module: &str, module_hash: &str, qual_name: &str) -> Event {
+        Event {
+            block: "block-hash".to_string(),
+            chain_id: 0,
+            height: 0,
+            idx: 1,
+            module: module.to_string(),
+            module_hash: module_hash.to_string(),
+            name: "TRANSFER".to_string(),
+            params: serde_json::json!(["t:token-id", "bob", "alice", 1.0]),
+            param_text: "param-text".to_string(),
+            qual_name: qual_name.to_string(),
+            request_key: "request-key".to_string(),
+            pact_id: None,
+        }
+    }
+
+    fn make_result(block_hash: &str) -> PactTransactionResult {
+        serde_json::from_value(serde_json::json!({
+            "continuation": null,
+            "events": [
+                {
+                    "module": {"name": "coin", "namespace": null},
+                    "moduleHash": "coin-hash",
+                    "name": "TRANSFER",
+                    "params": ["bob", "", 0.01]
+                },
+                {
+                    "module": {"name": "ledger", "namespace": "marmalade-v2"},
+                    "moduleHash": "ledger-hash",
+                    "name": "TRANSFER",
+                    "params": ["t:token-id", "bob", "alice", 1.0]
+                }
+            ],
+            "gas": 1000,
+            "logs": "logs",
+            "metaData": {
+                "blockHash": block_hash,
+                "blockHeight": 0,
+                "blockTime": 1687691365000000i64,
+                "prevBlockHash": "prev-block-hash"
+            },
+            "reqKey": "request-key",
+            "result": {"data": "Write succeeded", "status": "success"},
+            "txId": 1
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_repair_event() {
+        let fixed = make_event(
+            "marmalade-v2.ledger",
+            "ledger-hash",
+            "marmalade-v2.ledger.TRANSFER",
+        );
+        let result = make_result("block-hash");
+
+        // From the transaction result
+        let broken = make_event("marmalade-v2.ledger", "", "ledger.TRANSFER");
+        assert_eq!(repair_event(&broken, Some(&result)), Some(fixed.clone()));
+        // From the stored data
+        let broken = make_event("marmalade-v2.ledger", "ledger-hash", "ledger.TRANSFER");
+        assert_eq!(repair_event(&broken, None), Some(fixed.clone()));
+        assert_eq!(repair_event(&fixed, Some(&result)), None);
+
+        // Results of another block are ignored
+        let broken = make_event("marmalade-v2.ledger", "", "ledger.TRANSFER");
+        assert_eq!(repair_event(&broken, Some(&make_result("orphan"))), None);
+        assert_eq!(repair_event(&broken, None), None);
+    }
+}
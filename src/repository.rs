@@ -1,13 +1,140 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::vec;
 
-use crate::db::DbError;
+use crate::db::{max_rows, DbError};
 
 use super::db::DbPool;
 use super::models::*;
+use super::pagination::Cursor;
 use bigdecimal::BigDecimal;
+use chrono::NaiveDateTime;
 use diesel::dsl::sum;
 use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Outcome of a batch insert ignoring conflicts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchInsert {
+    pub inserted: usize,
+    /// Rows which were already stored
+    pub skipped: usize,
+}
+
+/// Conditions on the params of events, all of which must hold.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ParamsMatch {
+    /// Values found among the params, at any position (`params @> [values]`).
+    #[serde(default)]
+    pub contains: Vec<serde_json::Value>,
+    /// Values of the params at the given positions, eg. `{"0": "alice"}` for the sender
+    /// of a transfer (`params -> 0 = "alice"`).
+    #[serde(default)]
+    pub equals: BTreeMap<i32, serde_json::Value>,
+}
+
+/// Rows of a list query, at most [`max_rows`] of them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capped<T> {
+    pub rows: T,
+    /// More rows matched the query than the cap, the extra ones were left out.
+    pub truncated: bool,
+}
+
+/// Balances of an account per module and chain.
+pub type AllBalances = HashMap<String, HashMap<i64, BigDecimal>>;
+
+/// A module with its number of events and the chains it appears on.
+pub type ModuleStats = (String, i64, Vec<i64>);
+
+/// Keeps at most `max_rows` rows. Queries load one more row than the cap to know
+/// whether they were truncated.
+fn cap<T>(mut rows: Vec<T>, max_rows: i64) -> Capped<Vec<T>> {
+    let truncated = rows.len() as i64 > max_rows;
+    rows.truncate(max_rows as usize);
+    Capped { rows, truncated }
+}
+
+/// A transfer of an account's statement, with the resulting change and balance of the account.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LedgerEntry {
+    #[serde(flatten)]
+    pub transfer: Transfer,
+    /// Positive for incoming and negative for outgoing transfers, 0 for transfers to itself.
+    pub change: BigDecimal,
+    pub balance: BigDecimal,
+}
+
+/// Gas figures of the transactions of a chain, see [`TransactionsRepository::gas_stats`].
+#[derive(Debug, Clone, PartialEq, Serialize, QueryableByName)]
+pub struct GasStats {
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub chain_id: i64,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub transactions: i64,
+    #[diesel(sql_type = diesel::sql_types::Double)]
+    pub avg_gas_price: f64,
+    #[diesel(sql_type = diesel::sql_types::Double)]
+    pub median_gas_price: f64,
+    #[diesel(sql_type = diesel::sql_types::Double)]
+    pub avg_gas_used: f64,
+    #[diesel(sql_type = diesel::sql_types::Double)]
+    pub median_gas_used: f64,
+    /// Share of the gas limit actually used, averaged over the transactions.
+    #[diesel(sql_type = diesel::sql_types::Double)]
+    pub avg_gas_limit_usage: f64,
+}
+
+/// A block with the data derived from it, saved together by [`BlocksRepository::save_with_data`].
+#[derive(Debug, Clone)]
+pub struct BlockData {
+    pub block: Block,
+    pub transactions: Vec<Transaction>,
+    pub events: Vec<Event>,
+    pub transfers: Vec<Transfer>,
+}
+
+/// A block replaced by another block at the same height, eg. after a reorg.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Orphan {
+    pub chain_id: i64,
+    pub height: i64,
+    pub hash: String,
+}
+
+/// A row of [`EventsRepository::list_modules`].
+#[derive(QueryableByName)]
+struct ModuleCount {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    module: String,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    count: i64,
+    #[diesel(sql_type = diesel::sql_types::Array<diesel::sql_types::BigInt>)]
+    chains: Vec<i64>,
+}
+
+/// Heights (inclusive) of a range found by [`BlocksRepository::find_missing_ranges`].
+#[derive(QueryableByName)]
+struct HeightRange {
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    lower_height: i64,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    upper_height: i64,
+}
+
+/// The orphans among the blocks found at the height of the given block, that is all of them
+/// but the block itself.
+fn orphans_of(block: &Block, hashes: &[String]) -> Vec<Orphan> {
+    hashes
+        .iter()
+        .filter(|hash| **hash != block.hash)
+        .map(|hash| Orphan {
+            chain_id: block.chain_id,
+            height: block.height,
+            hash: hash.clone(),
+        })
+        .collect()
+}
 
 #[derive(Clone)]
 pub struct BlocksRepository {
@@ -15,9 +142,46 @@ pub struct BlocksRepository {
 }
 
 impl BlocksRepository {
-    pub fn find_by_hashes(&self, hashes: &[String]) -> Result<Vec<Block>, diesel::result::Error> {
+    /// Passes all the blocks to `process` in chunks of at most `chunk_size` blocks, fetched one
+    /// after the other by primary key so that the table is never loaded whole.
+    /// Returns the number of blocks processed.
+    pub fn find_all_streamed(
+        &self,
+        chunk_size: i64,
+        mut process: impl FnMut(Vec<Block>) -> Result<(), DbError>,
+    ) -> Result<usize, DbError> {
+        use crate::schema::blocks::dsl::{blocks as blocks_table, hash as hash_column};
+        let mut processed = 0;
+        let mut last_hash: Option<String> = None;
+        loop {
+            let mut query = blocks_table.into_boxed();
+            if let Some(last_hash) = &last_hash {
+                query = query.filter(hash_column.gt(last_hash));
+            }
+            let mut conn = self.pool.get()?;
+            let chunk = query
+                .select(Block::as_select())
+                .order(hash_column.asc())
+                .limit(chunk_size)
+                .load::<Block>(&mut conn)?;
+            drop(conn);
+            let last = match chunk.last() {
+                Some(last) => last.hash.clone(),
+                None => return Ok(processed),
+            };
+            let complete = (chunk.len() as i64) < chunk_size;
+            processed += chunk.len();
+            process(chunk)?;
+            if complete {
+                return Ok(processed);
+            }
+            last_hash = Some(last);
+        }
+    }
+
+    pub fn find_by_hashes(&self, hashes: &[String]) -> Result<Vec<Block>, DbError> {
         use crate::schema::blocks::dsl::{blocks, hash};
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         let results = blocks
             .filter(hash.eq_any(hashes))
             .select(Block::as_select())
@@ -25,15 +189,11 @@ impl BlocksRepository {
         Ok(results)
     }
 
-    pub fn find_by_hash(
-        &self,
-        hash: &str,
-        chain_id: i64,
-    ) -> Result<Option<Block>, diesel::result::Error> {
+    pub fn find_by_hash(&self, hash: &str, chain_id: i64) -> Result<Option<Block>, DbError> {
         use crate::schema::blocks::dsl::{
             blocks as blocks_table, chain_id as chain_id_column, hash as hash_column,
         };
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         let result = blocks_table
             .filter(hash_column.eq(hash))
             .filter(chain_id_column.eq(chain_id))
@@ -47,7 +207,7 @@ impl BlocksRepository {
         use crate::schema::blocks::dsl::{
             blocks as blocks_table, chain_id as chain_id_column, height as height_column,
         };
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         let result = blocks_table
             .filter(height_column.eq(height))
             .filter(chain_id_column.eq(chain_id))
@@ -66,7 +226,7 @@ impl BlocksRepository {
         use crate::schema::blocks::dsl::{
             blocks as blocks_table, chain_id as chain_id_column, height as height_column,
         };
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         let results = blocks_table
             .filter(height_column.ge(min_height))
             .filter(height_column.le(max_height))
@@ -77,6 +237,41 @@ impl BlocksRepository {
         Ok(results)
     }
 
+    /// Returns the ranges of heights (inclusive) between the given heights
+    /// with no indexed block, in ascending order.
+    pub fn find_missing_ranges(
+        &self,
+        chain_id: i64,
+        min_height: i64,
+        max_height: i64,
+    ) -> Result<Vec<(i64, i64)>, DbError> {
+        use diesel::sql_types::BigInt;
+        let mut conn = self.pool.get()?;
+        // Every indexed height is followed by a gap up to the next one, the height below the
+        // range and the one above it bounding the first and last gaps
+        let ranges = diesel::sql_query(
+            "SELECT lower_height, upper_height FROM ( \
+               SELECT height + 1 AS lower_height, \
+                 lead(height, 1, $3 + 1) OVER (ORDER BY height) - 1 AS upper_height \
+               FROM ( \
+                 SELECT $2 - 1 AS height \
+                 UNION ALL \
+                 SELECT height FROM blocks WHERE chain_id = $1 AND height BETWEEN $2 AND $3 \
+               ) AS heights \
+             ) AS gaps \
+             WHERE lower_height <= upper_height \
+             ORDER BY lower_height",
+        )
+        .bind::<BigInt, _>(chain_id)
+        .bind::<BigInt, _>(min_height)
+        .bind::<BigInt, _>(max_height)
+        .load::<HeightRange>(&mut conn)?;
+        Ok(ranges
+            .into_iter()
+            .map(|range| (range.lower_height, range.upper_height))
+            .collect())
+    }
+
     pub fn find_min_max_height_blocks(
         &self,
         chain_id: i64,
@@ -84,25 +279,61 @@ impl BlocksRepository {
         use crate::schema::blocks::dsl::{
             blocks as blocks_table, chain_id as chain_id_column, height,
         };
-        let mut conn = self.pool.get().unwrap();
-        let query = blocks_table.filter(chain_id_column.eq(chain_id));
-        let min_block = query
+        let mut conn = self.pool.get()?;
+        let min_block = blocks_table
+            .filter(chain_id_column.eq(chain_id))
             .order_by(height.asc())
             .select(Block::as_select())
             .first::<Block>(&mut conn)
             .optional()?;
-        let max_block = query
-            .order_by(height.desc())
+        let max_block = Self::latest_block_query(chain_id)
             .select(Block::as_select())
             .first::<Block>(&mut conn)
             .optional()?;
         Ok((min_block, max_block))
     }
 
+    /// Returns the creation time of the highest indexed block of the chain.
+    pub fn latest_block_time(&self, chain_id: i64) -> Result<Option<NaiveDateTime>, DbError> {
+        use crate::schema::blocks::dsl::creation_time;
+        let mut conn = self.pool.get()?;
+        let result = Self::latest_block_query(chain_id)
+            .select(creation_time)
+            .first::<NaiveDateTime>(&mut conn)
+            .optional()?;
+        Ok(result)
+    }
+
+    /// Highest indexed block of the chain.
+    fn latest_block_query(
+        chain_id: i64,
+    ) -> crate::schema::blocks::BoxedQuery<'static, diesel::pg::Pg> {
+        use crate::schema::blocks::dsl::{
+            blocks as blocks_table, chain_id as chain_id_column, height,
+        };
+        blocks_table
+            .filter(chain_id_column.eq(chain_id))
+            .order_by(height.desc())
+            .limit(1)
+            .into_boxed()
+    }
+
+    /// Returns the highest indexed block of every chain, in a single query.
+    pub fn latest_per_chain(&self) -> Result<Vec<Block>, DbError> {
+        use crate::schema::blocks::dsl::{blocks as blocks_table, chain_id, height};
+        let mut conn = self.pool.get()?;
+        let result = blocks_table
+            .distinct_on(chain_id)
+            .order_by((chain_id, height.desc()))
+            .select(Block::as_select())
+            .load::<Block>(&mut conn)?;
+        Ok(result)
+    }
+
     pub fn count(&self, chain_id: i64) -> Result<i64, DbError> {
         use crate::schema::blocks::dsl::{blocks, chain_id as chain_id_col, height};
         use diesel::dsl::count;
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         let count = blocks
             .select(count(height))
             .filter(chain_id_col.eq(chain_id))
@@ -112,7 +343,7 @@ impl BlocksRepository {
 
     pub fn insert(&self, block: &Block) -> Result<Block, DbError> {
         use crate::schema::blocks::dsl::*;
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         let new_block = diesel::insert_into(blocks)
             .values(block)
             .returning(Block::as_returning())
@@ -120,20 +351,175 @@ impl BlocksRepository {
         Ok(new_block)
     }
 
-    pub fn insert_batch(&self, blocks: &[Block]) -> Result<Vec<Block>, DbError> {
+    /// Inserts the blocks which are not indexed yet, the others are skipped.
+    pub fn insert_batch(&self, blocks: &[Block]) -> Result<BatchInsert, DbError> {
         use crate::schema::blocks::dsl::blocks as blocks_table;
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         let inserted = diesel::insert_into(blocks_table)
             .values(blocks)
             .on_conflict_do_nothing()
-            .returning(Block::as_returning())
-            .get_results(&mut conn)?;
-        Ok(inserted)
+            .execute(&mut conn)?;
+        Ok(BatchInsert {
+            inserted,
+            skipped: blocks.len() - inserted,
+        })
     }
 
-    pub fn delete_all(&self) -> Result<usize, diesel::result::Error> {
+    /// Inserts the blocks, refreshing the fields of those which were already stored, eg. to fix
+    /// blocks indexed with wrong data when reprocessing them. Returns the number of rows written.
+    pub fn insert_batch_upsert(&self, blocks: &[Block]) -> Result<usize, DbError> {
+        use crate::schema::blocks::dsl::{
+            blocks as blocks_table, chain_id, creation_time, difficulty, epoch, flags, hash,
+            height, miner, nonce, parent, payload, pow_hash, predicate, target, weight,
+        };
+        use diesel::upsert::excluded;
+        let mut conn = self.pool.get()?;
+        let upserted = diesel::insert_into(blocks_table)
+            .values(blocks)
+            .on_conflict(hash)
+            .do_update()
+            .set((
+                chain_id.eq(excluded(chain_id)),
+                creation_time.eq(excluded(creation_time)),
+                epoch.eq(excluded(epoch)),
+                flags.eq(excluded(flags)),
+                height.eq(excluded(height)),
+                miner.eq(excluded(miner)),
+                nonce.eq(excluded(nonce)),
+                parent.eq(excluded(parent)),
+                payload.eq(excluded(payload)),
+                pow_hash.eq(excluded(pow_hash)),
+                predicate.eq(excluded(predicate)),
+                target.eq(excluded(target)),
+                weight.eq(excluded(weight)),
+                difficulty.eq(excluded(difficulty)),
+            ))
+            .execute(&mut conn)?;
+        Ok(upserted)
+    }
+
+    /// Saves the blocks with their transactions, events and transfers in a single database
+    /// transaction. Orphans at the same height as a block are deleted with their data first,
+    /// and the data of blocks which were already saved is replaced. Returns the orphans.
+    /// Like [`BlocksRepository::replace`], the transaction is retried when another block was
+    /// saved concurrently at the height of one of the blocks.
+    pub fn save_with_data(&self, blocks_data: &[BlockData]) -> Result<Vec<Orphan>, DbError> {
+        let (_, orphans) = self.save_blocks_with_data(blocks_data, false)?;
+        Ok(orphans)
+    }
+
+    /// Saves the blocks with their data like [`BlocksRepository::save_with_data`], but leaves the
+    /// blocks which were already saved as they are, along with their data. Orphans at the height
+    /// of a new block are still replaced. Returns the hashes of the inserted blocks and the orphans.
+    pub fn insert_batch_with_data(
+        &self,
+        blocks_data: &[BlockData],
+    ) -> Result<(Vec<String>, Vec<Orphan>), DbError> {
+        self.save_blocks_with_data(blocks_data, true)
+    }
+
+    fn save_blocks_with_data(
+        &self,
+        blocks_data: &[BlockData],
+        keep_saved: bool,
+    ) -> Result<(Vec<String>, Vec<Orphan>), DbError> {
+        use crate::schema::{blocks, events, transactions, transfers};
+        use diesel::result::{DatabaseErrorKind, Error::DatabaseError};
+        let mut conn = self.pool.get()?;
+        loop {
+            let result = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+                let mut inserted = vec![];
+                let mut orphans = vec![];
+                for data in blocks_data {
+                    let block = &data.block;
+                    let hashes = blocks::table
+                        .filter(blocks::height.eq(block.height))
+                        .filter(blocks::chain_id.eq(block.chain_id))
+                        .select(blocks::hash)
+                        .for_update()
+                        .load::<String>(conn)?;
+                    if keep_saved && hashes.contains(&block.hash) {
+                        continue;
+                    }
+                    delete_blocks_with_data(conn, &hashes, block.chain_id)?;
+                    orphans.extend(orphans_of(block, &hashes));
+                    diesel::insert_into(blocks::table)
+                        .values(block)
+                        .execute(conn)?;
+                    // Number of parameters in one SQL query is limited to 65535
+                    for chunk in data.transactions.chunks(1000) {
+                        diesel::insert_into(transactions::table)
+                            .values(chunk)
+                            .on_conflict_do_nothing()
+                            .execute(conn)?;
+                    }
+                    for chunk in data.events.chunks(1000) {
+                        diesel::insert_into(events::table)
+                            .values(chunk)
+                            .on_conflict_do_nothing()
+                            .execute(conn)?;
+                    }
+                    for chunk in data.transfers.chunks(1000) {
+                        diesel::insert_into(transfers::table)
+                            .values(chunk)
+                            .on_conflict_do_nothing()
+                            .execute(conn)?;
+                    }
+                    inserted.push(block.hash.clone());
+                }
+                Ok((inserted, orphans))
+            });
+            match result {
+                Err(DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                    log::info!("Blocks saved concurrently, retrying")
+                }
+                result => return Ok(result?),
+            }
+        }
+    }
+
+    /// Replaces the blocks at the height of the given block, eg. an orphan, then inserts it.
+    /// The replaced blocks are deleted with their transfers, events and transactions in the same
+    /// database transaction, after being locked with `SELECT ... FOR UPDATE`, so concurrent
+    /// replacements of the same block, even from different processes, run one after the other.
+    /// A replacement which didn't see the block inserted by a concurrent one fails on the unique
+    /// height index, and is retried to replace that block in turn. Every such failure means
+    /// another block was saved, so the retries end once the concurrent saves are done.
+    /// Returns the inserted block with the orphans it replaced.
+    pub fn replace(&self, block: &Block) -> Result<(Block, Vec<Orphan>), DbError> {
+        use crate::schema::blocks;
+        use diesel::result::{DatabaseErrorKind, Error::DatabaseError};
+        let mut conn = self.pool.get()?;
+        loop {
+            let result = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+                let hashes = blocks::table
+                    .filter(blocks::height.eq(block.height))
+                    .filter(blocks::chain_id.eq(block.chain_id))
+                    .select(blocks::hash)
+                    .for_update()
+                    .load::<String>(conn)?;
+                delete_blocks_with_data(conn, &hashes, block.chain_id)?;
+                let inserted = diesel::insert_into(blocks::table)
+                    .values(block)
+                    .returning(Block::as_returning())
+                    .get_result(conn)?;
+                Ok((inserted, orphans_of(block, &hashes)))
+            });
+            match result {
+                Err(DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                    log::info!(
+                        "Block at height {} saved concurrently, retrying",
+                        block.height
+                    )
+                }
+                result => return Ok(result?),
+            }
+        }
+    }
+
+    pub fn delete_all(&self) -> Result<usize, DbError> {
         use crate::schema::blocks::dsl::*;
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         let deleted = diesel::delete(blocks).execute(&mut conn)?;
         Ok(deleted)
     }
@@ -143,7 +529,7 @@ impl BlocksRepository {
         use crate::schema::blocks::dsl::{
             blocks as blocks_table, chain_id as chain_id_col, height as height_col,
         };
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         let deleted = diesel::delete(
             blocks_table
                 .filter(height_col.eq(height))
@@ -157,7 +543,7 @@ impl BlocksRepository {
         use crate::schema::blocks::dsl::{
             blocks as blocks_table, chain_id as chain_id_col, hash as hash_col,
         };
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         let deleted = diesel::delete(
             blocks_table
                 .filter(hash_col.eq(hash))
@@ -168,6 +554,57 @@ impl BlocksRepository {
     }
 }
 
+/// Deletes the blocks with the given hashes, with their transfers, events and transactions,
+/// and the account guards set by their transactions.
+fn delete_blocks_with_data(
+    conn: &mut PgConnection,
+    hashes: &[String],
+    chain_id: i64,
+) -> QueryResult<()> {
+    use crate::schema::{account_guards, blocks, events, transactions, transfers};
+    if hashes.is_empty() {
+        return Ok(());
+    }
+    // The transactions aren't stored when not indexed, their events are
+    let mut request_keys = transactions::table
+        .filter(transactions::block.eq_any(hashes))
+        .select(transactions::request_key)
+        .load::<String>(conn)?;
+    request_keys.extend(
+        events::table
+            .filter(events::block.eq_any(hashes))
+            .select(events::request_key)
+            .distinct()
+            .load::<String>(conn)?,
+    );
+    let heights = blocks::table
+        .filter(blocks::hash.eq_any(hashes))
+        .filter(blocks::chain_id.eq(chain_id))
+        .select(blocks::height)
+        .load::<i64>(conn)?;
+    // At the heights of the blocks, as the same transaction may be in a block at another height
+    diesel::delete(account_guards::table)
+        .filter(account_guards::request_key.eq_any(&request_keys))
+        .filter(account_guards::chain_id.eq(chain_id))
+        .filter(account_guards::height.eq_any(&heights))
+        .execute(conn)?;
+    diesel::delete(transfers::table)
+        .filter(transfers::block.eq_any(hashes))
+        .filter(transfers::chain_id.eq(chain_id))
+        .execute(conn)?;
+    diesel::delete(events::table)
+        .filter(events::block.eq_any(hashes))
+        .execute(conn)?;
+    diesel::delete(transactions::table)
+        .filter(transactions::block.eq_any(hashes))
+        .execute(conn)?;
+    diesel::delete(blocks::table)
+        .filter(blocks::hash.eq_any(hashes))
+        .filter(blocks::chain_id.eq(chain_id))
+        .execute(conn)?;
+    Ok(())
+}
+
 #[derive(Clone)]
 pub struct EventsRepository {
     pub pool: DbPool,
@@ -175,16 +612,69 @@ pub struct EventsRepository {
 
 impl EventsRepository {
     #[allow(dead_code)]
-    pub fn find_all(&self) -> Result<Vec<Event>, DbError> {
+    pub fn find_all(&self) -> Result<Capped<Vec<Event>>, DbError> {
         use crate::schema::events::dsl::*;
-        let mut conn = self.pool.get().unwrap();
-        let results = events.select(Event::as_select()).load::<Event>(&mut conn)?;
-        Ok(results)
+        let mut conn = self.pool.get()?;
+        let max_rows = max_rows();
+        let results = events
+            .select(Event::as_select())
+            .limit(max_rows + 1)
+            .load::<Event>(&mut conn)?;
+        Ok(cap(results, max_rows))
+    }
+
+    /// Passes all the events to `process` in chunks of at most `chunk_size` events, fetched one
+    /// after the other by primary key so that the table is never loaded whole.
+    /// Returns the number of events processed.
+    pub fn find_all_streamed(
+        &self,
+        chunk_size: i64,
+        mut process: impl FnMut(Vec<Event>) -> Result<(), DbError>,
+    ) -> Result<usize, DbError> {
+        use crate::schema::events::dsl::{
+            block as block_col, events, idx as idx_col, request_key as request_key_col,
+        };
+        use diesel::sql_types::{BigInt, Bool, Text};
+        let mut processed = 0;
+        let mut last_key: Option<(String, i64, String)> = None;
+        loop {
+            let mut query = events.into_boxed();
+            if let Some((block, idx, request_key)) = &last_key {
+                // A row comparison, unlike the equivalent ORs, is a range of the primary key
+                query = query.filter(
+                    diesel::dsl::sql::<Bool>("(block, idx, request_key) > (")
+                        .bind::<Text, _>(block.clone())
+                        .sql(", ")
+                        .bind::<BigInt, _>(*idx)
+                        .sql(", ")
+                        .bind::<Text, _>(request_key.clone())
+                        .sql(")"),
+                );
+            }
+            let mut conn = self.pool.get()?;
+            let chunk = query
+                .select(Event::as_select())
+                .order((block_col.asc(), idx_col.asc(), request_key_col.asc()))
+                .limit(chunk_size)
+                .load::<Event>(&mut conn)?;
+            drop(conn);
+            let last = match chunk.last() {
+                Some(last) => (last.block.clone(), last.idx, last.request_key.clone()),
+                None => return Ok(processed),
+            };
+            let complete = (chunk.len() as i64) < chunk_size;
+            processed += chunk.len();
+            process(chunk)?;
+            if complete {
+                return Ok(processed);
+            }
+            last_key = Some(last);
+        }
     }
 
     pub fn find_max_height(&self, chain_id: i64) -> Result<i64, DbError> {
         use crate::schema::events::dsl::{chain_id as chain_id_col, events, height as height_col};
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         let max_height = events
             .filter(chain_id_col.eq(chain_id))
             .select(diesel::dsl::max(height_col))
@@ -199,7 +689,7 @@ impl EventsRepository {
         chain_id: i64,
     ) -> Result<Vec<Event>, DbError> {
         use crate::schema::events::dsl::{chain_id as chain_id_col, events, height as height_col};
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         let results = events
             .filter(chain_id_col.eq(chain_id))
             .filter(height_col.ge(min_height))
@@ -210,10 +700,234 @@ impl EventsRepository {
         Ok(results)
     }
 
+    /// Finds the events between the given heights (inclusive) whose module hash is missing or whose
+    /// qualified name doesn't match their module and name, eg. indexed before these were fixed.
+    pub fn find_to_repair(
+        &self,
+        chain_id: i64,
+        min_height: i64,
+        max_height: i64,
+    ) -> Result<Vec<Event>, DbError> {
+        use crate::schema::events::dsl::{
+            chain_id as chain_id_col, events, height as height_col, idx as idx_col,
+            module as module_col, module_hash as module_hash_col, name as name_col,
+            qual_name as qual_name_col,
+        };
+        let mut conn = self.pool.get()?;
+        let results = events
+            .filter(chain_id_col.eq(chain_id))
+            .filter(height_col.ge(min_height))
+            .filter(height_col.le(max_height))
+            .filter(
+                module_hash_col
+                    .eq("")
+                    .or(qual_name_col.ne(module_col.concat(".").concat(name_col))),
+            )
+            .select(Event::as_select())
+            .order((height_col.asc(), idx_col.asc()))
+            .load::<Event>(&mut conn)?;
+        Ok(results)
+    }
+
+    /// Updates the module, module hash and qualified name of the given events
+    /// in a single database transaction.
+    pub fn update_derived_fields(&self, repaired: &[Event]) -> Result<usize, DbError> {
+        use crate::schema::events::dsl::{
+            block as block_col, events, idx as idx_col, module as module_col,
+            module_hash as module_hash_col, qual_name as qual_name_col,
+            request_key as request_key_col,
+        };
+        let mut conn = self.pool.get()?;
+        let updated = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            let mut updated = 0;
+            for event in repaired {
+                updated += diesel::update(
+                    events
+                        .filter(block_col.eq(&event.block))
+                        .filter(idx_col.eq(event.idx))
+                        .filter(request_key_col.eq(&event.request_key)),
+                )
+                .set((
+                    module_col.eq(&event.module),
+                    module_hash_col.eq(&event.module_hash),
+                    qual_name_col.eq(&event.qual_name),
+                ))
+                .execute(conn)?;
+            }
+            Ok(updated)
+        })?;
+        Ok(updated)
+    }
+
+    /// Lists every module that emitted events, optionally since the given height, with its
+    /// number of events and the chains it appears on. Ordered by number of events, descending.
+    pub fn list_modules(
+        &self,
+        min_height: Option<i64>,
+    ) -> Result<Capped<Vec<ModuleStats>>, DbError> {
+        self.list_modules_capped(min_height, max_rows())
+    }
+
+    /// Same as [`EventsRepository::list_modules`], capped at `max_rows` modules.
+    fn list_modules_capped(
+        &self,
+        min_height: Option<i64>,
+        max_rows: i64,
+    ) -> Result<Capped<Vec<ModuleStats>>, DbError> {
+        use diesel::sql_types::BigInt;
+        let mut conn = self.pool.get()?;
+        // Counted per module before capping, so that the modules kept are the top ones
+        let modules = diesel::sql_query(
+            "SELECT module, count(*) AS count, \
+               array_agg(DISTINCT chain_id ORDER BY chain_id) AS chains \
+             FROM events \
+             WHERE height >= $1 \
+             GROUP BY module \
+             ORDER BY count DESC, module \
+             LIMIT $2",
+        )
+        .bind::<BigInt, _>(min_height.unwrap_or(0))
+        .bind::<BigInt, _>(max_rows + 1)
+        .load::<ModuleCount>(&mut conn)?;
+        let Capped {
+            rows: modules,
+            truncated,
+        } = cap(modules, max_rows);
+        Ok(Capped {
+            rows: modules
+                .into_iter()
+                .map(|module| (module.module, module.count, module.chains))
+                .collect(),
+            truncated,
+        })
+    }
+
+    pub fn find_by_request_keys(&self, request_keys: &[String]) -> Result<Vec<Event>, DbError> {
+        use crate::schema::events::dsl::{
+            events, height as height_col, idx as idx_col, request_key as request_key_col,
+        };
+        let mut conn = self.pool.get()?;
+        let results = events
+            .filter(request_key_col.eq_any(request_keys))
+            .select(Event::as_select())
+            .order((height_col.asc(), idx_col.asc()))
+            .load::<Event>(&mut conn)?;
+        Ok(results)
+    }
+
+    /// Finds the most recent events with the given module and name
+    /// whose params contain the given value, eg. an account taking part in a transfer.
+    pub fn find_by_module_name_and_param(
+        &self,
+        module: &str,
+        name: &str,
+        param_value: &serde_json::Value,
+        limit: i64,
+        after: Option<&Cursor>,
+    ) -> Result<Vec<Event>, DbError> {
+        let params = ParamsMatch {
+            contains: vec![param_value.clone()],
+            ..Default::default()
+        };
+        self.search(module, name, &params, limit, after)
+    }
+
+    /// Finds the most recent events with the given module and name whose params match.
+    /// The values compared by position are also looked up by containment, so that the
+    /// GIN index on params narrows down the events before their positions are checked.
+    pub fn search(
+        &self,
+        module: &str,
+        name: &str,
+        params: &ParamsMatch,
+        limit: i64,
+        after: Option<&Cursor>,
+    ) -> Result<Vec<Event>, DbError> {
+        let mut conn = self.pool.get()?;
+        let results = Self::search_query(module, name, params, limit, after)
+            .select(Event::as_select())
+            .load::<Event>(&mut conn)?;
+        Ok(results)
+    }
+
+    /// Events matching the filters of [`EventsRepository::search`], most recent first.
+    fn search_query<'a>(
+        module: &'a str,
+        name: &'a str,
+        params: &ParamsMatch,
+        limit: i64,
+        after: Option<&'a Cursor>,
+    ) -> crate::schema::events::BoxedQuery<'a, diesel::pg::Pg> {
+        use crate::schema::events::dsl::{
+            events, height as height_col, idx as idx_col, module as module_col, name as name_col,
+            params as params_col, request_key as request_key_col,
+        };
+        use diesel::sql_types::{Bool, Integer, Jsonb};
+        let mut query = events
+            .filter(module_col.eq(module))
+            .filter(name_col.eq(name))
+            .into_boxed();
+        let contained = params
+            .contains
+            .iter()
+            .chain(params.equals.values())
+            .cloned()
+            .collect::<Vec<_>>();
+        if !contained.is_empty() {
+            query = query.filter(params_col.contains(serde_json::Value::Array(contained)));
+        }
+        for (position, value) in &params.equals {
+            query = query.filter(
+                diesel::dsl::sql::<Bool>("params -> ")
+                    .bind::<Integer, _>(*position)
+                    .sql(" = ")
+                    .bind::<Jsonb, _>(value.clone()),
+            );
+        }
+        if let Some(cursor) = after {
+            query = query.filter(
+                height_col
+                    .lt(cursor.height)
+                    .or(height_col
+                        .eq(cursor.height)
+                        .and(request_key_col.lt(&cursor.request_key)))
+                    .or(height_col
+                        .eq(cursor.height)
+                        .and(request_key_col.eq(&cursor.request_key))
+                        .and(idx_col.lt(cursor.idx))),
+            );
+        }
+        query
+            .order((height_col.desc(), request_key_col.desc(), idx_col.desc()))
+            .limit(limit)
+    }
+
+    /// Finds the most recent events of all chains. Chains are not at the same height,
+    /// so events are ordered by the creation time of their block rather than by height.
+    pub fn find_latest(&self, limit: i64) -> Result<Vec<Event>, DbError> {
+        use crate::schema::blocks::dsl::{blocks, creation_time};
+        use crate::schema::events::dsl::{
+            block as block_col, events, idx as idx_col, request_key as request_key_col,
+        };
+        let mut conn = self.pool.get()?;
+        let results = events
+            .inner_join(blocks)
+            .select(Event::as_select())
+            .order((
+                creation_time.desc(),
+                block_col.desc(),
+                request_key_col.desc(),
+                idx_col.desc(),
+            ))
+            .limit(limit)
+            .load::<Event>(&mut conn)?;
+        Ok(results)
+    }
+
     #[allow(dead_code)]
     pub fn insert(&self, event: &Event) -> Result<Event, DbError> {
         use crate::schema::events::dsl::*;
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         let new_event = diesel::insert_into(events)
             .values(event)
             .on_conflict((block, idx, request_key))
@@ -224,10 +938,10 @@ impl EventsRepository {
         Ok(new_event)
     }
 
-    pub fn insert_batch(&self, events: &[Event]) -> Result<usize, diesel::result::Error> {
+    pub fn insert_batch(&self, events: &[Event]) -> Result<usize, DbError> {
         use crate::schema::events::dsl::events as events_table;
         let mut inserted = 0;
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         for chunk in events.chunks(1000) {
             inserted += diesel::insert_into(events_table)
                 .values(chunk)
@@ -240,7 +954,7 @@ impl EventsRepository {
     #[allow(dead_code)]
     pub fn delete_all(&self) -> Result<usize, DbError> {
         use crate::schema::events::dsl::*;
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         let deleted = diesel::delete(events).execute(&mut conn)?;
         Ok(deleted)
     }
@@ -250,7 +964,7 @@ impl EventsRepository {
         use crate::schema::events::dsl::{
             block as block_col, events, idx as idx_col, request_key as request_key_col,
         };
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         let deleted = diesel::delete(
             events
                 .filter(block_col.eq(block))
@@ -263,7 +977,7 @@ impl EventsRepository {
 
     pub fn delete_all_by_block(&self, hash: &str) -> Result<usize, DbError> {
         use crate::schema::events::dsl::{block as block_col, events};
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         let deleted = diesel::delete(events.filter(block_col.eq(hash))).execute(&mut conn)?;
         Ok(deleted)
     }
@@ -276,38 +990,175 @@ pub struct TransactionsRepository {
 
 impl TransactionsRepository {
     #[allow(dead_code)]
-    pub fn find_all(&self) -> Result<Vec<Transaction>, DbError> {
+    pub fn find_all(&self) -> Result<Capped<Vec<Transaction>>, DbError> {
         use crate::schema::transactions::dsl::*;
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
+        let max_rows = max_rows();
         let results = transactions
             .select(Transaction::as_select())
+            .limit(max_rows + 1)
             .load::<Transaction>(&mut conn)?;
-        Ok(results)
+        Ok(cap(results, max_rows))
     }
 
     #[allow(dead_code)]
     pub fn find_by_request_key(
         &self,
         request_keys: &Vec<String>,
-    ) -> Result<Vec<Transaction>, DbError> {
+    ) -> Result<Capped<Vec<Transaction>>, DbError> {
         use crate::schema::transactions::dsl::{
             request_key as request_key_column, transactions as transactions_table,
         };
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
+        let max_rows = max_rows();
         let result = transactions_table
             .filter(request_key_column.eq_any(request_keys))
+            .select(Transaction::as_select())
+            .limit(max_rows + 1)
+            .load(&mut conn)?;
+        Ok(cap(result, max_rows))
+    }
+
+    /// Counts the transactions sent by the account.
+    pub fn count_by_sender(&self, account: &str) -> Result<i64, DbError> {
+        let mut conn = self.pool.get()?;
+        let count = Self::count_by_sender_query(account).get_result(&mut conn)?;
+        Ok(count)
+    }
+
+    fn count_by_sender_query(
+        account: &str,
+    ) -> crate::schema::transactions::BoxedQuery<'_, diesel::pg::Pg, diesel::sql_types::BigInt>
+    {
+        use crate::schema::transactions::dsl::{sender, transactions};
+        transactions.filter(sender.eq(account)).count().into_boxed()
+    }
+
+    /// Finds the most recent transactions sent by the account, whose code contains the given
+    /// text, and with the given status (`success` or `failure`), for the filters which are set.
+    /// At most `limit` transactions are returned, after the given cursor if any.
+    pub fn search(
+        &self,
+        sender: Option<String>,
+        code: Option<String>,
+        status: Option<String>,
+        limit: i64,
+        after: Option<&Cursor>,
+    ) -> Result<Vec<Transaction>, DbError> {
+        use crate::schema::transactions::dsl::{
+            code as code_column, sender as sender_column, status as status_column,
+            transactions as transactions_table,
+        };
+        let mut conn = self.pool.get()?;
+        let mut query = transactions_table.into_boxed();
+        if let Some(sender) = sender {
+            query = query.filter(sender_column.eq(sender));
+        }
+        if let Some(code) = code {
+            // Matched literally, LIKE wildcards included
+            let pattern = code
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            query = query.filter(code_column.like(format!("%{}%", pattern)));
+        }
+        if let Some(status) = status {
+            query = query.filter(status_column.eq(status));
+        }
+        let result = Self::page(query, limit, after)
+            .select(Transaction::as_select())
+            .load(&mut conn)?;
+        Ok(result)
+    }
+
+    /// Finds the most recent transactions signed with the given public key.
+    /// At most `limit` transactions are returned, after the given cursor if any.
+    pub fn find_by_signer(
+        &self,
+        public_key: &str,
+        limit: i64,
+        after: Option<&Cursor>,
+    ) -> Result<Vec<Transaction>, DbError> {
+        use crate::schema::transactions::dsl::{
+            signers as signers_column, transactions as transactions_table,
+        };
+        let mut conn = self.pool.get()?;
+        let query = transactions_table
+            .filter(signers_column.contains(vec![public_key]))
+            .into_boxed();
+        let result = Self::page(query, limit, after)
             .select(Transaction::as_select())
             .load(&mut conn)?;
         Ok(result)
     }
 
+    /// A page of the transactions of the query, ordered by `(height, request_key)` descending,
+    /// starting after the cursor if any.
+    fn page<'a>(
+        query: crate::schema::transactions::BoxedQuery<'a, diesel::pg::Pg>,
+        limit: i64,
+        after: Option<&'a Cursor>,
+    ) -> crate::schema::transactions::BoxedQuery<'a, diesel::pg::Pg> {
+        use crate::schema::transactions::dsl::{
+            height as height_column, request_key as request_key_column,
+        };
+        let mut query = query;
+        if let Some(cursor) = after {
+            query = query.filter(
+                height_column.lt(cursor.height).or(height_column
+                    .eq(cursor.height)
+                    .and(request_key_column.lt(&cursor.request_key))),
+            );
+        }
+        query
+            .order((height_column.desc(), request_key_column.desc()))
+            .limit(limit)
+    }
+
+    /// Aggregates the gas prices and the gas used by the transactions between the given heights
+    /// (inclusive), per chain, of the given chain only if set. Chains without transactions in the
+    /// range are left out, so an empty range returns no figures.
+    pub fn gas_stats(
+        &self,
+        chain_id: Option<i64>,
+        min_height: i64,
+        max_height: i64,
+    ) -> Result<Vec<GasStats>, DbError> {
+        use diesel::sql_types::BigInt;
+        let mut conn = self.pool.get()?;
+        // percentile_cont has no counterpart in the query builder
+        let mut query = diesel::sql_query(
+            "SELECT chain_id, count(*) AS transactions, \
+             avg(gas_price) AS avg_gas_price, \
+             percentile_cont(0.5) WITHIN GROUP (ORDER BY gas_price) AS median_gas_price, \
+             avg(gas)::float8 AS avg_gas_used, \
+             percentile_cont(0.5) WITHIN GROUP (ORDER BY gas::float8) AS median_gas_used, \
+             coalesce(avg(gas::float8 / nullif(gas_limit, 0)), 0) AS avg_gas_limit_usage \
+             FROM transactions WHERE height >= ",
+        )
+        .into_boxed::<diesel::pg::Pg>()
+        .bind::<BigInt, _>(min_height)
+        .sql(" AND height <= ")
+        .bind::<BigInt, _>(max_height);
+        if let Some(chain_id) = chain_id {
+            query = query.sql(" AND chain_id = ").bind::<BigInt, _>(chain_id);
+        }
+        let stats = query
+            .sql(" GROUP BY chain_id ORDER BY chain_id")
+            .load::<GasStats>(&mut conn)?;
+        Ok(stats)
+    }
+
     #[allow(dead_code)]
     pub fn find_all_related(
         &self,
         request_keys: &Vec<String>,
-    ) -> Result<HashMap<String, Vec<Transaction>>, DbError> {
+    ) -> Result<Capped<HashMap<String, Vec<Transaction>>>, DbError> {
         match self.find_by_request_key(request_keys) {
-            Ok(transactions) => {
+            Ok(Capped {
+                rows: transactions,
+                truncated,
+            }) => {
                 //TODO: Optimize this to avoid multiple queries
                 let mut result = HashMap::new();
                 for tx in transactions.iter() {
@@ -322,29 +1173,41 @@ impl TransactionsRepository {
                         result.insert(tx.request_key.clone(), vec![tx.clone()]);
                     }
                 }
-                Ok(result)
+                Ok(Capped {
+                    rows: result,
+                    truncated,
+                })
             }
             Err(err) => Err(err),
         }
     }
 
-    #[allow(dead_code)]
+    /// Finds the transactions executing the steps of the given defpacts, ordered by step.
     pub fn find_by_pact_id(&self, pact_ids: &Vec<String>) -> Result<Vec<Transaction>, DbError> {
-        use crate::schema::transactions::dsl::{
-            pact_id as pact_id_column, transactions as transactions_table,
-        };
-        let mut conn = self.pool.get().unwrap();
-        let result = transactions_table
-            .filter(pact_id_column.eq_any(pact_ids))
+        let mut conn = self.pool.get()?;
+        let result = Self::find_by_pact_id_query(pact_ids)
             .select(Transaction::as_select())
             .load(&mut conn)?;
         Ok(result)
     }
 
+    fn find_by_pact_id_query(
+        pact_ids: &[String],
+    ) -> crate::schema::transactions::BoxedQuery<'_, diesel::pg::Pg> {
+        use crate::schema::transactions::dsl::{
+            height as height_column, pact_id as pact_id_column, step as step_column,
+            transactions as transactions_table,
+        };
+        transactions_table
+            .filter(pact_id_column.eq_any(pact_ids))
+            .order((step_column.asc(), height_column.asc()))
+            .into_boxed()
+    }
+
     #[allow(dead_code)]
     pub fn insert(&self, transaction: &Transaction) -> Result<Transaction, DbError> {
         use crate::schema::transactions::dsl::*;
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         let transaction = diesel::insert_into(transactions)
             .values(transaction)
             .returning(Transaction::as_returning())
@@ -354,7 +1217,7 @@ impl TransactionsRepository {
 
     pub fn insert_batch(&self, transactions: &[Transaction]) -> Result<usize, DbError> {
         use crate::schema::transactions::dsl::transactions as transactions_table;
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         let mut inserted = 0;
         for chunk in transactions.chunks(1000) {
             inserted += diesel::insert_into(transactions_table)
@@ -368,14 +1231,14 @@ impl TransactionsRepository {
     #[allow(dead_code)]
     pub fn delete_all(&self) -> Result<usize, DbError> {
         use crate::schema::transactions::dsl::*;
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         let deleted = diesel::delete(transactions).execute(&mut conn)?;
         Ok(deleted)
     }
 
     pub fn delete_all_by_block(&self, hash: &str) -> Result<usize, DbError> {
         use crate::schema::transactions::dsl::{block as block_col, transactions};
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         let deleted = diesel::delete(transactions.filter(block_col.eq(hash))).execute(&mut conn)?;
         Ok(deleted)
     }
@@ -385,7 +1248,7 @@ impl TransactionsRepository {
         use crate::schema::transactions::dsl::{
             block as block_column, request_key as request_key_column, transactions,
         };
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         let deleted = diesel::delete(
             transactions
                 .filter(block_column.eq(block))
@@ -402,6 +1265,11 @@ pub struct TransfersRepository {
 }
 
 impl TransfersRepository {
+    /// Calculates the balance of the account on every chain as the sum of incoming
+    /// minus the sum of outgoing transfers. Amounts are stored in an unconstrained `numeric`
+    /// column and summed by Postgres, then subtracted as `BigDecimal`s, so balances keep
+    /// the full precision of the transferred amounts (eg. 12 decimals for `coin`)
+    /// without any rounding, however large the amounts are.
     pub fn calculate_balance(
         &self,
         account: &str,
@@ -411,7 +1279,7 @@ impl TransfersRepository {
             amount as amount_col, chain_id as chain_id_col, from_account,
             module_name as module_name_col, to_account, transfers,
         };
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         let outgoing_amounts_per_chain = transfers
             .filter(from_account.eq(account))
             .filter(module_name_col.eq(module))
@@ -445,20 +1313,44 @@ impl TransfersRepository {
         Ok(balance)
     }
 
-    pub fn calculate_all_balances(
-        &self,
+    /// Sums the amounts the account sent, or received when not `outgoing`, per chain and module,
+    /// only those of the given modules if any.
+    fn sum_amounts_by_module(
+        conn: &mut PgConnection,
         account: &str,
-    ) -> Result<HashMap<String, HashMap<i64, BigDecimal>>, DbError> {
+        outgoing: bool,
+        modules: Option<&[String]>,
+    ) -> Result<Vec<(i64, Option<BigDecimal>, String)>, DbError> {
         use crate::schema::transfers::dsl::{
             amount as amount_col, chain_id as chain_id_col, from_account,
             module_name as module_name_col, to_account, transfers,
         };
-        let mut conn = self.pool.get().unwrap();
-        let outgoing_amounts: Vec<(i64, Option<BigDecimal>, String)> = transfers
-            .filter(from_account.eq(account))
+        let mut query = transfers
             .group_by((chain_id_col, module_name_col))
             .select((chain_id_col, sum(amount_col), module_name_col))
-            .load::<(i64, Option<BigDecimal>, String)>(&mut conn)?;
+            .order((module_name_col.asc(), chain_id_col.asc()))
+            .into_boxed();
+        query = match outgoing {
+            true => query.filter(from_account.eq(account)),
+            false => query.filter(to_account.eq(account)),
+        };
+        if let Some(modules) = modules {
+            query = query.filter(module_name_col.eq_any(modules.to_vec()));
+        }
+        Ok(query.load::<(i64, Option<BigDecimal>, String)>(conn)?)
+    }
+
+    /// Same as [`TransfersRepository::calculate_balance`] for every module the account
+    /// received transfers from, only the given modules if any, with the same precision
+    /// guarantees.
+    /// The sums aren't capped, as a subset of them would be wrong balances.
+    pub fn calculate_all_balances(
+        &self,
+        account: &str,
+        modules: Option<&[String]>,
+    ) -> Result<AllBalances, DbError> {
+        let mut conn = self.pool.get()?;
+        let outgoing_amounts = Self::sum_amounts_by_module(&mut conn, account, true, modules)?;
         let mut outgoing_amounts_by_module: HashMap<String, HashMap<i64, BigDecimal>> =
             HashMap::new();
         outgoing_amounts
@@ -477,11 +1369,7 @@ impl TransfersRepository {
             "outgoing_amounts_by_module: {:?}",
             outgoing_amounts_by_module
         );
-        let incoming_amounts = transfers
-            .filter(to_account.eq(account))
-            .group_by((chain_id_col, module_name_col))
-            .select((chain_id_col, sum(amount_col), module_name_col))
-            .load::<(i64, Option<BigDecimal>, String)>(&mut conn)?;
+        let incoming_amounts = Self::sum_amounts_by_module(&mut conn, account, false, modules)?;
         let mut incoming_amounts_by_module: HashMap<String, HashMap<i64, BigDecimal>> =
             HashMap::new();
         incoming_amounts
@@ -518,51 +1406,340 @@ impl TransfersRepository {
         Ok(balances_by_module)
     }
 
-    pub fn find(
+    /// Calculates the balance of the account in the module as of the given height, summing
+    /// the incoming minus the outgoing transfers of all chains up to that height (inclusive).
+    /// An account without any transfer up to that height has a balance of 0.
+    pub fn balance_at_height(
         &self,
-        from: Option<String>,
-        to: Option<String>,
-        min_height: Option<i64>,
-    ) -> Result<Vec<Transfer>, DbError> {
+        account: &str,
+        module: &str,
+        height: i64,
+    ) -> Result<BigDecimal, DbError> {
         use crate::schema::transfers::dsl::{
-            from_account as from_account_col, height as height_col, to_account as to_account_col,
-            transfers,
+            amount as amount_col, from_account, height as height_col,
+            module_name as module_name_col, to_account, transfers,
         };
-        let mut conn = self.pool.get().unwrap();
-        let mut query = transfers.into_boxed();
-        if let Some(from) = from {
-            query = query.filter(from_account_col.eq(from));
-        }
+        let mut conn = self.pool.get()?;
+        let outgoing_amount = transfers
+            .filter(from_account.eq(account))
+            .filter(module_name_col.eq(module))
+            .filter(height_col.le(height))
+            .select(sum(amount_col))
+            .first::<Option<BigDecimal>>(&mut conn)?;
+        let incoming_amount = transfers
+            .filter(to_account.eq(account))
+            .filter(module_name_col.eq(module))
+            .filter(height_col.le(height))
+            .select(sum(amount_col))
+            .first::<Option<BigDecimal>>(&mut conn)?;
+        Ok(incoming_amount.unwrap_or_default() - outgoing_amount.unwrap_or_default())
+    }
+
+    /// Returns the transfers of the account in the module in chronological order, ie. by height
+    /// then by chain and position in the block, with the running balance of the account
+    /// over all chains after each of them. When truncated, only the earliest transfers are returned.
+    pub fn ledger(&self, account: &str, module: &str) -> Result<Capped<Vec<LedgerEntry>>, DbError> {
+        self.ledger_capped(account, module, max_rows())
+    }
+
+    /// Same as [`TransfersRepository::ledger`], capped at `max_rows` transfers.
+    fn ledger_capped(
+        &self,
+        account: &str,
+        module: &str,
+        max_rows: i64,
+    ) -> Result<Capped<Vec<LedgerEntry>>, DbError> {
+        use crate::schema::transfers::dsl::{
+            chain_id as chain_id_col, from_account, height as height_col, idx as idx_col,
+            module_name as module_name_col, request_key as request_key_col, to_account, transfers,
+        };
+        let mut conn = self.pool.get()?;
+        let account_transfers = cap(
+            transfers
+                .filter(from_account.eq(account).or(to_account.eq(account)))
+                .filter(module_name_col.eq(module))
+                .order((
+                    height_col.asc(),
+                    chain_id_col.asc(),
+                    idx_col.asc(),
+                    request_key_col.asc(),
+                ))
+                .select(Transfer::as_select())
+                .limit(max_rows + 1)
+                .load::<Transfer>(&mut conn)?,
+            max_rows,
+        );
+        let mut balance = BigDecimal::from(0);
+        let entries = account_transfers
+            .rows
+            .into_iter()
+            .map(|transfer| {
+                let mut change = BigDecimal::from(0);
+                if transfer.to_account == account {
+                    change += &transfer.amount;
+                }
+                if transfer.from_account == account {
+                    change -= &transfer.amount;
+                }
+                balance += &change;
+                LedgerEntry {
+                    transfer,
+                    change,
+                    balance: balance.clone(),
+                }
+            })
+            .collect();
+        Ok(Capped {
+            rows: entries,
+            truncated: account_transfers.truncated,
+        })
+    }
+
+    /// Returns the height and creation time of the earliest transfer
+    /// in which the account was either the sender or the receiver.
+    pub fn first_seen(&self, account: &str) -> Result<Option<(i64, NaiveDateTime)>, DbError> {
+        use crate::schema::transfers::dsl::{
+            creation_time as creation_time_col, from_account, height as height_col, to_account,
+            transfers,
+        };
+        let mut conn = self.pool.get()?;
+        let min_height = transfers
+            .filter(from_account.eq(account).or(to_account.eq(account)))
+            .select(diesel::dsl::min(height_col))
+            .first::<Option<i64>>(&mut conn)?;
+        let min_height = match min_height {
+            Some(min_height) => min_height,
+            None => return Ok(None),
+        };
+        let creation_time = transfers
+            .filter(from_account.eq(account).or(to_account.eq(account)))
+            .filter(height_col.eq(min_height))
+            .select(diesel::dsl::min(creation_time_col))
+            .first::<Option<NaiveDateTime>>(&mut conn)?;
+        Ok(creation_time.map(|creation_time| (min_height, creation_time)))
+    }
+
+    /// Returns the height and creation time of the latest transfer
+    /// in which the account was either the sender or the receiver.
+    pub fn last_seen(&self, account: &str) -> Result<Option<(i64, NaiveDateTime)>, DbError> {
+        use crate::schema::transfers::dsl::{
+            creation_time as creation_time_col, from_account, height as height_col, to_account,
+            transfers,
+        };
+        let mut conn = self.pool.get()?;
+        let max_height = transfers
+            .filter(from_account.eq(account).or(to_account.eq(account)))
+            .select(diesel::dsl::max(height_col))
+            .first::<Option<i64>>(&mut conn)?;
+        let max_height = match max_height {
+            Some(max_height) => max_height,
+            None => return Ok(None),
+        };
+        let creation_time = transfers
+            .filter(from_account.eq(account).or(to_account.eq(account)))
+            .filter(height_col.eq(max_height))
+            .select(diesel::dsl::max(creation_time_col))
+            .first::<Option<NaiveDateTime>>(&mut conn)?;
+        Ok(creation_time.map(|creation_time| (max_height, creation_time)))
+    }
+
+    /// Counts the transfers sent and received by the account, in all modules.
+    pub fn count_by_account(&self, account: &str) -> Result<(i64, i64), DbError> {
+        use crate::schema::transfers::dsl::{from_account, to_account, transfers};
+        let mut conn = self.pool.get()?;
+        let sent = transfers
+            .filter(from_account.eq(account))
+            .count()
+            .get_result(&mut conn)?;
+        let received = transfers
+            .filter(to_account.eq(account))
+            .count()
+            .get_result(&mut conn)?;
+        Ok((sent, received))
+    }
+
+    /// Sums the amounts transferred for the given module between the given heights (inclusive).
+    /// Returns the volume per chain and the total volume.
+    /// Self-transfers are only counted when `exclude_self_transfers` is false.
+    pub fn volume(
+        &self,
+        module: &str,
+        min_height: Option<i64>,
+        max_height: Option<i64>,
+        exclude_self_transfers: bool,
+    ) -> Result<(HashMap<i64, BigDecimal>, BigDecimal), DbError> {
+        use crate::schema::transfers::dsl::{
+            amount as amount_col, chain_id as chain_id_col, height as height_col,
+            module_name as module_name_col, self_transfer, transfers,
+        };
+        let mut conn = self.pool.get()?;
+        // Boxed queries can't be grouped, so the filter is always applied
+        let self_transfer_values = match exclude_self_transfers {
+            true => vec![false],
+            false => vec![false, true],
+        };
+        let volume_per_chain = transfers
+            .filter(module_name_col.eq(module))
+            .filter(height_col.ge(min_height.unwrap_or(0)))
+            .filter(height_col.le(max_height.unwrap_or(i64::MAX)))
+            .filter(self_transfer.eq_any(self_transfer_values))
+            .group_by(chain_id_col)
+            .select((chain_id_col, sum(amount_col)))
+            .load::<(i64, Option<BigDecimal>)>(&mut conn)?
+            .into_iter()
+            .map(|(chain, volume)| (chain, volume.unwrap_or(BigDecimal::from(0))))
+            .collect::<HashMap<i64, BigDecimal>>();
+        let total = volume_per_chain
+            .values()
+            .fold(BigDecimal::from(0), |acc, volume| acc + volume);
+        Ok((volume_per_chain, total))
+    }
+
+    /// Calculates the supply of the given module as the sum of the amounts minted, transferred
+    /// from the empty account, minus the sum of the amounts burned, transferred to it.
+    /// Returns the supply per chain and the total supply, which is also right for tokens moved
+    /// between chains, as they're burned on one chain and minted on the other.
+    pub fn supply(&self, module: &str) -> Result<(HashMap<i64, BigDecimal>, BigDecimal), DbError> {
+        use crate::schema::transfers::dsl::{
+            amount as amount_col, chain_id as chain_id_col, from_account,
+            module_name as module_name_col, to_account, transfers,
+        };
+        let mut conn = self.pool.get()?;
+        let minted = transfers
+            .filter(module_name_col.eq(module))
+            .filter(from_account.eq(""))
+            .group_by(chain_id_col)
+            .select((chain_id_col, sum(amount_col)))
+            .load::<(i64, Option<BigDecimal>)>(&mut conn)?;
+        let burned = transfers
+            .filter(module_name_col.eq(module))
+            .filter(to_account.eq(""))
+            .group_by(chain_id_col)
+            .select((chain_id_col, sum(amount_col)))
+            .load::<(i64, Option<BigDecimal>)>(&mut conn)?;
+        let mut supply_per_chain: HashMap<i64, BigDecimal> = HashMap::new();
+        for (chain, amount) in minted {
+            *supply_per_chain.entry(chain).or_default() += amount.unwrap_or_default();
+        }
+        for (chain, amount) in burned {
+            *supply_per_chain.entry(chain).or_default() -= amount.unwrap_or_default();
+        }
+        let total = supply_per_chain
+            .values()
+            .fold(BigDecimal::from(0), |acc, supply| acc + supply);
+        Ok((supply_per_chain, total))
+    }
+
+    /// Transfers matching the filters of [`TransfersRepository::find`] and
+    /// [`TransfersRepository::count`].
+    fn filtered(
+        from: Option<String>,
+        to: Option<String>,
+        min_height: Option<i64>,
+        exclude_self_transfers: bool,
+    ) -> crate::schema::transfers::BoxedQuery<'static, diesel::pg::Pg> {
+        use crate::schema::transfers::dsl::{
+            from_account as from_account_col, height as height_col, self_transfer,
+            to_account as to_account_col, transfers,
+        };
+        let mut query = transfers.into_boxed();
+        if let Some(from) = from {
+            query = query.filter(from_account_col.eq(from));
+        }
         if let Some(to) = to {
             query = query.filter(to_account_col.eq(to));
         }
         if let Some(min_height) = min_height {
             query = query.filter(height_col.ge(min_height));
         }
-        let results: Vec<Transfer> = query
-            .select(Transfer::as_select())
-            .load::<Transfer>(&mut conn)?;
-        Ok(results)
+        if exclude_self_transfers {
+            query = query.filter(self_transfer.eq(false));
+        }
+        query
+    }
+
+    pub fn find(
+        &self,
+        from: Option<String>,
+        to: Option<String>,
+        min_height: Option<i64>,
+        exclude_self_transfers: bool,
+    ) -> Result<Capped<Vec<Transfer>>, DbError> {
+        self.find_capped(from, to, min_height, exclude_self_transfers, max_rows())
+    }
+
+    /// Same as [`TransfersRepository::find`], most recent transfers first, capped at `max_rows`.
+    fn find_capped(
+        &self,
+        from: Option<String>,
+        to: Option<String>,
+        min_height: Option<i64>,
+        exclude_self_transfers: bool,
+        max_rows: i64,
+    ) -> Result<Capped<Vec<Transfer>>, DbError> {
+        let mut conn = self.pool.get()?;
+        let results: Vec<Transfer> =
+            Self::find_query(from, to, min_height, exclude_self_transfers, max_rows + 1)
+                .select(Transfer::as_select())
+                .load::<Transfer>(&mut conn)?;
+        Ok(cap(results, max_rows))
+    }
+
+    /// Most recent transfers matching the filters, up to `limit`.
+    fn find_query(
+        from: Option<String>,
+        to: Option<String>,
+        min_height: Option<i64>,
+        exclude_self_transfers: bool,
+        limit: i64,
+    ) -> crate::schema::transfers::BoxedQuery<'static, diesel::pg::Pg> {
+        use crate::schema::transfers::dsl::{chain_id, height, idx};
+        Self::filtered(from, to, min_height, exclude_self_transfers)
+            .order((height.desc(), chain_id.asc(), idx.asc()))
+            .limit(limit)
+    }
+
+    /// Counts all the transfers [`TransfersRepository::find`] would return, regardless of the cap.
+    pub fn count(
+        &self,
+        from: Option<String>,
+        to: Option<String>,
+        min_height: Option<i64>,
+        exclude_self_transfers: bool,
+    ) -> Result<i64, DbError> {
+        let mut conn = self.pool.get()?;
+        let count = Self::filtered(from, to, min_height, exclude_self_transfers)
+            .count()
+            .get_result(&mut conn)?;
+        Ok(count)
     }
 
     pub fn find_received(
         &self,
         to_account: &str,
         min_height: Option<i64>,
-    ) -> Result<HashMap<String, Vec<Transfer>>, DbError> {
+    ) -> Result<Capped<HashMap<String, Vec<Transfer>>>, DbError> {
         use crate::schema::blocks::dsl::blocks;
         use crate::schema::transfers::dsl::{
             height as height_col, to_account as to_account_col, transfers,
         };
         use itertools::Itertools;
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         let min_height = min_height.unwrap_or(0);
-        let received_transfers: Vec<(Transfer, Block)> = transfers
-            .inner_join(blocks)
-            .filter(to_account_col.eq(to_account))
-            .filter(height_col.ge(min_height))
-            .select((Transfer::as_select(), Block::as_select()))
-            .load::<(Transfer, Block)>(&mut conn)?;
+        let max_rows = max_rows();
+        let Capped {
+            rows: received_transfers,
+            truncated,
+        } = cap(
+            transfers
+                .inner_join(blocks)
+                .filter(to_account_col.eq(to_account))
+                .filter(height_col.ge(min_height))
+                .select((Transfer::as_select(), Block::as_select()))
+                .limit(max_rows + 1)
+                .load::<(Transfer, Block)>(&mut conn)?,
+            max_rows,
+        );
         let multi_step_transfers_pact_ids = received_transfers
             .iter()
             .filter_map(|t| t.0.pact_id.clone())
@@ -582,22 +1759,53 @@ impl TransfersRepository {
         for (request_key, transfers_list) in &multi_step_transfers {
             simple_transfers.insert(request_key, transfers_list.cloned().collect_vec());
         }
-        Ok(simple_transfers)
+        Ok(Capped {
+            rows: simple_transfers,
+            truncated,
+        })
     }
 
-    pub fn find_by_pact_id(&self, ids: Vec<String>) -> Result<Vec<Transfer>, DbError> {
-        use crate::schema::transfers::dsl::{pact_id as pact_id_col, transfers};
-        let mut conn = self.pool.get().unwrap();
+    pub fn find_by_request_keys(&self, request_keys: &[String]) -> Result<Vec<Transfer>, DbError> {
+        use crate::schema::transfers::dsl::{
+            height as height_col, idx as idx_col, request_key as request_key_col, transfers,
+        };
+        let mut conn = self.pool.get()?;
         let results = transfers
-            .filter(pact_id_col.eq_any(ids))
+            .filter(request_key_col.eq_any(request_keys))
+            .select(Transfer::as_select())
+            .order((height_col.asc(), idx_col.asc()))
+            .load(&mut conn)?;
+        Ok(results)
+    }
+
+    pub fn find_by_blocks(&self, hashes: &[String]) -> Result<Vec<Transfer>, DbError> {
+        use crate::schema::transfers::dsl::{block as block_col, transfers};
+        let mut conn = self.pool.get()?;
+        let results = transfers
+            .filter(block_col.eq_any(hashes))
+            .select(Transfer::as_select())
+            .load(&mut conn)?;
+        Ok(results)
+    }
+
+    pub fn find_by_pact_id(&self, ids: Vec<String>) -> Result<Vec<Transfer>, DbError> {
+        let mut conn = self.pool.get()?;
+        let results = Self::find_by_pact_id_query(ids)
             .select(Transfer::as_select())
             .load(&mut conn)?;
         Ok(results)
     }
 
+    fn find_by_pact_id_query(
+        ids: Vec<String>,
+    ) -> crate::schema::transfers::BoxedQuery<'static, diesel::pg::Pg> {
+        use crate::schema::transfers::dsl::{pact_id as pact_id_col, transfers};
+        transfers.filter(pact_id_col.eq_any(ids)).into_boxed()
+    }
+
     pub fn insert(&self, transfer: &Transfer) -> Result<Transfer, DbError> {
         use crate::schema::transfers::dsl::*;
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         let new_transfer = diesel::insert_into(transfers)
             .values(transfer)
             .on_conflict_do_nothing()
@@ -608,7 +1816,7 @@ impl TransfersRepository {
 
     pub fn insert_batch(&self, transfers: &Vec<Transfer>) -> Result<Vec<Transfer>, DbError> {
         use crate::schema::transfers::dsl::transfers as transfers_table;
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         let inserted = diesel::insert_into(transfers_table)
             .values(transfers)
             .on_conflict_do_nothing()
@@ -619,7 +1827,7 @@ impl TransfersRepository {
 
     pub fn delete_all(&self) -> Result<usize, DbError> {
         use crate::schema::transfers::dsl::*;
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         let deleted = diesel::delete(transfers).execute(&mut conn)?;
         Ok(deleted)
     }
@@ -628,7 +1836,7 @@ impl TransfersRepository {
         use crate::schema::transfers::dsl::{
             block as block_col, chain_id as chain_id_col, transfers,
         };
-        let mut conn = self.pool.get().unwrap();
+        let mut conn = self.pool.get()?;
         let deleted = diesel::delete(
             transfers
                 .filter(block_col.eq(block))
@@ -638,3 +1846,1666 @@ impl TransfersRepository {
         Ok(deleted)
     }
 }
+
+#[derive(Clone)]
+pub struct AccountGuardsRepository {
+    pub pool: DbPool,
+}
+
+impl AccountGuardsRepository {
+    pub fn find_by_account(&self, account: &str) -> Result<Capped<Vec<AccountGuard>>, DbError> {
+        use crate::schema::account_guards::dsl::{
+            account as account_col, account_guards, chain_id as chain_id_col, module as module_col,
+        };
+        let mut conn = self.pool.get()?;
+        let max_rows = max_rows();
+        let results = account_guards
+            .filter(account_col.eq(account))
+            .select(AccountGuard::as_select())
+            .order((module_col.asc(), chain_id_col.asc()))
+            .limit(max_rows + 1)
+            .load(&mut conn)?;
+        Ok(cap(results, max_rows))
+    }
+
+    /// Inserts the given guards, replacing the existing guard of an account
+    /// only when the new one was set at the same or a greater height.
+    pub fn upsert_batch(&self, guards: &[AccountGuard]) -> Result<usize, DbError> {
+        use crate::schema::account_guards::dsl::{
+            account, account_guards, chain_id, guard, height, module, request_key,
+        };
+        use diesel::query_dsl::methods::FilterDsl;
+        use diesel::upsert::excluded;
+        let mut conn = self.pool.get()?;
+        let mut upserted = 0;
+        for account_guard in guards {
+            upserted += diesel::insert_into(account_guards)
+                .values(account_guard)
+                .on_conflict((account, chain_id, module))
+                .do_update()
+                .set((
+                    guard.eq(excluded(guard)),
+                    height.eq(excluded(height)),
+                    request_key.eq(excluded(request_key)),
+                ))
+                .filter(height.le(excluded(height)))
+                .execute(&mut conn)?;
+        }
+        Ok(upserted)
+    }
+
+    #[allow(dead_code)]
+    pub fn delete_all(&self) -> Result<usize, DbError> {
+        use crate::schema::account_guards::dsl::account_guards;
+        let mut conn = self.pool.get()?;
+        let deleted = diesel::delete(account_guards).execute(&mut conn)?;
+        Ok(deleted)
+    }
+}
+
+#[derive(Clone)]
+pub struct StreamCursorsRepository {
+    pub pool: DbPool,
+}
+
+impl StreamCursorsRepository {
+    pub fn find_all(&self) -> Result<Vec<StreamCursor>, DbError> {
+        use crate::schema::stream_cursors::dsl::{chain_id, stream_cursors};
+        let mut conn = self.pool.get()?;
+        let cursors = stream_cursors
+            .select(StreamCursor::as_select())
+            .order(chain_id.asc())
+            .load(&mut conn)?;
+        Ok(cursors)
+    }
+
+    /// Saves the cursor of every chain, replacing the existing cursor of a chain only when
+    /// the new one is at the same or a greater height, as headers may be processed out of order.
+    pub fn save(&self, cursors: &[StreamCursor]) -> Result<usize, DbError> {
+        use crate::schema::stream_cursors::dsl::{chain_id, hash, height, stream_cursors};
+        use diesel::query_dsl::methods::FilterDsl;
+        use diesel::upsert::excluded;
+        let mut conn = self.pool.get()?;
+        let mut saved = 0;
+        for cursor in cursors {
+            saved += diesel::insert_into(stream_cursors)
+                .values(cursor)
+                .on_conflict(chain_id)
+                .do_update()
+                .set((height.eq(excluded(height)), hash.eq(excluded(hash))))
+                .filter(height.le(excluded(height)))
+                .execute(&mut conn)?;
+        }
+        Ok(saved)
+    }
+
+    #[allow(dead_code)]
+    pub fn delete_all(&self) -> Result<usize, DbError> {
+        use crate::schema::stream_cursors::dsl::stream_cursors;
+        let mut conn = self.pool.get()?;
+        let deleted = diesel::delete(stream_cursors).execute(&mut conn)?;
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use chrono::Utc;
+    use diesel::pg::Pg;
+    use diesel::query_builder::QueryFragment;
+    use serial_test::serial;
+    use std::collections::HashSet;
+
+    fn make_block(chain_id: i64, height: i64, hash: &str) -> Block {
+        Block {
+            chain_id,
+            hash: hash.to_string(),
+            height,
+            parent: "parent".to_string(),
+            weight: BigDecimal::from(0),
+            creation_time: Utc::now().naive_utc(),
+            epoch: Utc::now().naive_utc(),
+            flags: BigDecimal::from(0),
+            miner: "miner".to_string(),
+            nonce: BigDecimal::from(0),
+            payload: "payload".to_string(),
+            pow_hash: "".to_string(),
+            predicate: "predicate".to_string(),
+            target: BigDecimal::from(1),
+            difficulty: None,
+        }
+    }
+
+    fn make_event(
+        block: &str,
+        idx: i64,
+        module: &str,
+        name: &str,
+        params: serde_json::Value,
+    ) -> Event {
+        Event {
+            block: block.to_string(),
+            chain_id: 0,
+            height: 0,
+            idx,
+            module: module.to_string(),
+            module_hash: "module-hash".to_string(),
+            name: name.to_string(),
+            params: params.clone(),
+            param_text: params.to_string(),
+            qual_name: format!("{}.{}", module, name),
+            request_key: "request-key".to_string(),
+            pact_id: None,
+        }
+    }
+
+    fn make_transfer(
+        block: &str,
+        chain_id: i64,
+        height: i64,
+        idx: i64,
+        from: &str,
+        to: &str,
+        amount: &str,
+    ) -> Transfer {
+        Transfer {
+            amount: amount.parse().unwrap(),
+            authorized_amount: None,
+            block: block.to_string(),
+            chain_id,
+            creation_time: Utc::now().naive_utc(),
+            from_account: from.to_string(),
+            height,
+            idx,
+            module_hash: "module-hash".to_string(),
+            module_name: "coin".to_string(),
+            pact_id: None,
+            request_key: format!("request-key-{}-{}", height, idx),
+            self_transfer: from == to,
+            to_account: to.to_string(),
+        }
+    }
+
+    fn make_transaction(height: i64, request_key: &str, sender: &str) -> Transaction {
+        Transaction {
+            bad_result: None,
+            block: format!("block-0-{}", height),
+            chain_id: 0,
+            code: None,
+            continuation: None,
+            creation_time: Utc::now().naive_utc(),
+            data: None,
+            error_message: None,
+            error_type: None,
+            gas: 0,
+            gas_limit: 0,
+            gas_price: 0.0,
+            good_result: None,
+            height,
+            is_gas_station: false,
+            keysets: None,
+            logs: None,
+            metadata: None,
+            nonce: "nonce".to_string(),
+            num_events: None,
+            pact_id: None,
+            proof: None,
+            raw_cmd: None,
+            request_key: request_key.to_string(),
+            rollback: None,
+            sender: sender.to_string(),
+            signatures_valid: None,
+            signers: vec![],
+            status: "success".to_string(),
+            step: None,
+            ttl: 0,
+            tx_id: None,
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_account_activity_counts() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let transactions = TransactionsRepository { pool: pool.clone() };
+        let transfers = TransfersRepository { pool: pool.clone() };
+        blocks
+            .insert_batch(&[
+                make_block(0, 1, "block-0-1"),
+                make_block(0, 2, "block-0-2"),
+                make_block(0, 3, "block-0-3"),
+            ])
+            .unwrap();
+        transactions
+            .insert_batch(&[
+                make_transaction(1, "request-key-1", "alice"),
+                make_transaction(2, "request-key-2", "alice"),
+                make_transaction(3, "request-key-3", "bob"),
+            ])
+            .unwrap();
+        transfers
+            .insert_batch(&vec![
+                make_transfer("block-0-1", 0, 1, 0, "alice", "bob", "1.0"),
+                make_transfer("block-0-2", 0, 2, 0, "alice", "carol", "2.0"),
+                make_transfer("block-0-3", 0, 3, 0, "bob", "alice", "0.5"),
+            ])
+            .unwrap();
+
+        assert_eq!(transactions.count_by_sender("alice").unwrap(), 2);
+        assert_eq!(transactions.count_by_sender("carol").unwrap(), 0);
+        assert_eq!(transfers.count_by_account("alice").unwrap(), (2, 1));
+        assert_eq!(transfers.count_by_account("carol").unwrap(), (0, 1));
+        assert_eq!(transfers.first_seen("alice").unwrap().unwrap().0, 1);
+        assert_eq!(transfers.last_seen("alice").unwrap().unwrap().0, 3);
+        assert_eq!(transfers.last_seen("carol").unwrap().unwrap().0, 2);
+        assert_eq!(transfers.last_seen("dave").unwrap(), None);
+
+        transfers.delete_all().unwrap();
+        transactions.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_find_by_signer() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let transactions = TransactionsRepository { pool: pool.clone() };
+        blocks
+            .insert_batch(&[make_block(0, 1, "block-0-1"), make_block(0, 2, "block-0-2")])
+            .unwrap();
+        transactions
+            .insert_batch(&[
+                Transaction {
+                    signers: vec!["alice-key".to_string()],
+                    ..make_transaction(1, "request-key-1", "alice")
+                },
+                // Gas paid by a gas station, signed by both
+                Transaction {
+                    signers: vec!["gas-station-key".to_string(), "alice-key".to_string()],
+                    ..make_transaction(2, "request-key-2", "gas-station")
+                },
+            ])
+            .unwrap();
+
+        let signed = transactions.find_by_signer("alice-key", 100, None).unwrap();
+        assert_eq!(
+            signed
+                .iter()
+                .map(|transaction| transaction.request_key.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["request-key-2", "request-key-1"]
+        );
+        assert_eq!(
+            transactions
+                .find_by_signer("gas-station-key", 100, None)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(transactions
+            .find_by_signer("bob-key", 100, None)
+            .unwrap()
+            .is_empty());
+
+        // Paginated with the cursor of the last transaction of the previous page
+        let first_page = transactions.find_by_signer("alice-key", 1, None).unwrap();
+        assert_eq!(first_page[0].request_key, "request-key-2");
+        let cursor = Cursor::from(&first_page[0]);
+        let second_page = transactions
+            .find_by_signer("alice-key", 1, Some(&cursor))
+            .unwrap();
+        assert_eq!(second_page[0].request_key, "request-key-1");
+        let cursor = Cursor::from(&second_page[0]);
+        assert!(transactions
+            .find_by_signer("alice-key", 1, Some(&cursor))
+            .unwrap()
+            .is_empty());
+
+        transactions.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_gas_stats() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let transactions = TransactionsRepository { pool: pool.clone() };
+        blocks
+            .insert_batch(&[
+                make_block(0, 1, "block-0-1"),
+                make_block(0, 2, "block-0-2"),
+                make_block(1, 2, "block-1-2"),
+            ])
+            .unwrap();
+        let with_gas = |height, request_key, gas, gas_limit, gas_price| Transaction {
+            gas,
+            gas_limit,
+            gas_price,
+            ..make_transaction(height, request_key, "alice")
+        };
+        transactions
+            .insert_batch(&[
+                with_gas(1, "request-key-1", 100, 1000, 0.00000001),
+                with_gas(2, "request-key-2", 200, 1000, 0.00000002),
+                with_gas(2, "request-key-3", 600, 1000, 0.00000006),
+                Transaction {
+                    block: "block-1-2".to_string(),
+                    chain_id: 1,
+                    ..with_gas(2, "request-key-4", 500, 0, 0.00000001)
+                },
+            ])
+            .unwrap();
+
+        let stats = transactions.gas_stats(None, 0, 2).unwrap();
+        assert_eq!(
+            stats
+                .iter()
+                .map(|stats| (stats.chain_id, stats.transactions))
+                .collect::<Vec<(i64, i64)>>(),
+            vec![(0, 3), (1, 1)]
+        );
+        assert_eq!(stats[0].avg_gas_used, 300.0);
+        assert_eq!(stats[0].median_gas_used, 200.0);
+        assert!((stats[0].avg_gas_price - 0.00000003).abs() < 1e-15);
+        assert!((stats[0].median_gas_price - 0.00000002).abs() < 1e-15);
+        assert!((stats[0].avg_gas_limit_usage - 0.3).abs() < 1e-9);
+        // A gas limit of 0 doesn't count towards the usage
+        assert_eq!(stats[1].avg_gas_limit_usage, 0.0);
+
+        let stats = transactions.gas_stats(Some(0), 2, 2).unwrap();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].transactions, 2);
+        assert_eq!(stats[0].median_gas_used, 400.0);
+        assert!(transactions.gas_stats(None, 3, 10).unwrap().is_empty());
+        assert!(transactions.gas_stats(Some(2), 0, 10).unwrap().is_empty());
+
+        transactions.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_balance_at_height() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let transfers = TransfersRepository { pool: pool.clone() };
+        blocks
+            .insert_batch(&[
+                make_block(0, 1, "block-0-1"),
+                make_block(0, 2, "block-0-2"),
+                make_block(1, 3, "block-1-3"),
+            ])
+            .unwrap();
+        transfers
+            .insert_batch(&vec![
+                make_transfer("block-0-1", 0, 1, 0, "alice", "bob", "10.0"),
+                make_transfer("block-0-2", 0, 2, 0, "bob", "carol", "2.5"),
+                make_transfer("block-1-3", 1, 3, 0, "alice", "bob", "1.25"),
+            ])
+            .unwrap();
+
+        let balance_at = |height| transfers.balance_at_height("bob", "coin", height).unwrap();
+        assert_eq!(balance_at(0), BigDecimal::from(0));
+        assert_eq!(balance_at(1), "10.0".parse::<BigDecimal>().unwrap());
+        assert_eq!(balance_at(2), "7.5".parse::<BigDecimal>().unwrap());
+        assert_eq!(balance_at(3), "8.75".parse::<BigDecimal>().unwrap());
+        assert_eq!(
+            transfers.balance_at_height("dave", "coin", 3).unwrap(),
+            BigDecimal::from(0)
+        );
+
+        transfers.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_ledger_running_balance() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let transfers = TransfersRepository { pool: pool.clone() };
+        blocks
+            .insert_batch(&[
+                make_block(0, 1, "block-0-1"),
+                make_block(1, 1, "block-1-1"),
+                make_block(0, 2, "block-0-2"),
+            ])
+            .unwrap();
+        transfers
+            .insert_batch(&vec![
+                make_transfer("block-0-2", 0, 2, 0, "bob", "bob", "3.0"),
+                make_transfer("block-0-2", 0, 2, 1, "bob", "carol", "2.5"),
+                make_transfer("block-1-1", 1, 1, 0, "alice", "bob", "1.25"),
+                make_transfer("block-0-1", 0, 1, 0, "alice", "bob", "10.0"),
+                make_transfer("block-0-1", 0, 1, 1, "alice", "carol", "4.0"),
+            ])
+            .unwrap();
+
+        let ledger = transfers.ledger("bob", "coin").unwrap().rows;
+        let rows = ledger
+            .iter()
+            .map(|entry| {
+                (
+                    entry.transfer.height,
+                    entry.transfer.chain_id,
+                    entry.change.to_string(),
+                    entry.balance.to_string(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            vec![
+                (1, 0, "10.0".to_string(), "10.0".to_string()),
+                (1, 1, "1.25".to_string(), "11.25".to_string()),
+                (2, 0, "0.0".to_string(), "11.25".to_string()),
+                (2, 0, "-2.5".to_string(), "8.75".to_string()),
+            ]
+        );
+        assert!(transfers
+            .ledger("bob", "free.token")
+            .unwrap()
+            .rows
+            .is_empty());
+
+        transfers.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_count_transfers() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let transfers = TransfersRepository { pool: pool.clone() };
+        blocks
+            .insert_batch(&[make_block(0, 1, "block-0-1"), make_block(0, 2, "block-0-2")])
+            .unwrap();
+        transfers
+            .insert_batch(&vec![
+                make_transfer("block-0-1", 0, 1, 0, "alice", "bob", "1.0"),
+                make_transfer("block-0-1", 0, 1, 1, "alice", "carol", "2.0"),
+                make_transfer("block-0-2", 0, 2, 0, "alice", "bob", "3.0"),
+                make_transfer("block-0-2", 0, 2, 1, "bob", "alice", "4.0"),
+            ])
+            .unwrap();
+
+        // Not capped like the transfers found
+        let found = transfers
+            .find_capped(Some("alice".to_string()), None, None, false, 2)
+            .unwrap();
+        assert!(found.truncated);
+        assert_eq!(
+            transfers
+                .count(Some("alice".to_string()), None, None, false)
+                .unwrap(),
+            3
+        );
+        assert_eq!(
+            transfers
+                .count(
+                    Some("alice".to_string()),
+                    Some("bob".to_string()),
+                    None,
+                    false
+                )
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            transfers
+                .count(Some("alice".to_string()), None, Some(2), false)
+                .unwrap(),
+            1
+        );
+        assert_eq!(transfers.count(None, None, None, false).unwrap(), 4);
+        assert_eq!(
+            transfers
+                .count(Some("dave".to_string()), None, None, false)
+                .unwrap(),
+            0
+        );
+
+        transfers.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_list_queries_are_capped() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let transfers = TransfersRepository { pool: pool.clone() };
+        blocks
+            .insert_batch(&[make_block(0, 1, "block-0-1")])
+            .unwrap();
+        transfers
+            .insert_batch(&vec![
+                make_transfer("block-0-1", 0, 1, 0, "alice", "bob", "1.0"),
+                make_transfer("block-0-1", 0, 1, 1, "alice", "bob", "2.0"),
+                make_transfer("block-0-1", 0, 1, 2, "alice", "bob", "3.0"),
+            ])
+            .unwrap();
+
+        let found = transfers
+            .find_capped(Some("alice".to_string()), None, None, false, 2)
+            .unwrap();
+        let ledger = transfers.ledger_capped("bob", "coin", 2).unwrap();
+        let all_found = transfers
+            .find_capped(Some("alice".to_string()), None, None, false, 3)
+            .unwrap();
+
+        // The first transfers in order, the most recent ones then by index
+        assert_eq!(
+            found
+                .rows
+                .iter()
+                .map(|transfer| transfer.idx)
+                .collect::<Vec<i64>>(),
+            vec![0, 1]
+        );
+        assert!(found.truncated);
+        assert_eq!(
+            ledger
+                .rows
+                .iter()
+                .map(|entry| entry.balance.to_string())
+                .collect::<Vec<String>>(),
+            vec!["1.0", "3.0"]
+        );
+        assert!(ledger.truncated);
+        assert_eq!(all_found.rows.len(), 3);
+        assert!(!all_found.truncated);
+
+        transfers.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_balances_keep_full_precision() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let transfers = TransfersRepository { pool: pool.clone() };
+        blocks
+            .insert_batch(&[make_block(0, 1, "block-0-1")])
+            .unwrap();
+        transfers
+            .insert_batch(&vec![
+                make_transfer(
+                    "block-0-1",
+                    0,
+                    1,
+                    0,
+                    "alice",
+                    "bob",
+                    "123456789012345678901234567890.123456789012",
+                ),
+                make_transfer("block-0-1", 0, 1, 1, "alice", "bob", "0.000000000001"),
+                make_transfer("block-0-1", 0, 1, 2, "bob", "alice", "0.000000000003"),
+            ])
+            .unwrap();
+        let expected: BigDecimal = "123456789012345678901234567890.12345678901"
+            .parse()
+            .unwrap();
+
+        let balance = transfers.calculate_balance("bob", "coin").unwrap();
+        assert_eq!(balance.get(&0), Some(&expected));
+        let balances = transfers.calculate_all_balances("bob", None).unwrap();
+        assert_eq!(balances["coin"].get(&0), Some(&expected));
+        let balance = transfers.calculate_balance("alice", "coin").unwrap();
+        assert_eq!(balance.get(&0), Some(&-expected));
+
+        transfers.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_calculate_all_balances_of_modules() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let transfers = TransfersRepository { pool: pool.clone() };
+        blocks
+            .insert_batch(&[make_block(0, 1, "block-0-1")])
+            .unwrap();
+        let in_module = |idx, amount, module: &str| Transfer {
+            module_name: module.to_string(),
+            ..make_transfer("block-0-1", 0, 1, idx, "alice", "bob", amount)
+        };
+        transfers
+            .insert_batch(&vec![
+                in_module(0, "1.0", "coin"),
+                in_module(1, "2.0", "kdlaunch.token"),
+                in_module(2, "3.0", "free.other"),
+            ])
+            .unwrap();
+
+        let all = transfers.calculate_all_balances("bob", None).unwrap();
+        assert_eq!(all.len(), 3);
+        let modules = vec!["coin".to_string(), "kdlaunch.token".to_string()];
+        let some = transfers
+            .calculate_all_balances("bob", Some(&modules))
+            .unwrap();
+        let mut names = some.keys().cloned().collect::<Vec<String>>();
+        names.sort();
+        assert_eq!(names, modules);
+        assert_eq!(some["kdlaunch.token"][&0], BigDecimal::from(2));
+        assert!(transfers
+            .calculate_all_balances("bob", Some(&["unknown".to_string()]))
+            .unwrap()
+            .is_empty());
+
+        transfers.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_transfers_volume() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let transfers = TransfersRepository { pool: pool.clone() };
+        blocks
+            .insert_batch(&[
+                make_block(0, 1, "block-0-1"),
+                make_block(0, 2, "block-0-2"),
+                make_block(1, 2, "block-1-2"),
+            ])
+            .unwrap();
+        transfers
+            .insert_batch(&vec![
+                make_transfer("block-0-1", 0, 1, 0, "alice", "bob", "10.5"),
+                make_transfer("block-0-2", 0, 2, 0, "bob", "alice", "2.25"),
+                make_transfer("block-1-2", 1, 2, 0, "alice", "carol", "1"),
+            ])
+            .unwrap();
+
+        let (per_chain, total) = transfers.volume("coin", None, None, false).unwrap();
+        assert_eq!(per_chain.get(&0), Some(&"12.75".parse().unwrap()));
+        assert_eq!(per_chain.get(&1), Some(&BigDecimal::from(1)));
+        assert_eq!(total, "13.75".parse().unwrap());
+
+        let (per_chain, total) = transfers.volume("coin", Some(2), Some(2), false).unwrap();
+        assert_eq!(per_chain.get(&0), Some(&"2.25".parse().unwrap()));
+        assert_eq!(total, "3.25".parse().unwrap());
+
+        let (per_chain, total) = transfers.volume("coin", Some(10), None, false).unwrap();
+        assert!(per_chain.is_empty());
+        assert_eq!(total, BigDecimal::from(0));
+
+        transfers
+            .insert_batch(&vec![make_transfer(
+                "block-0-2",
+                0,
+                2,
+                1,
+                "bob",
+                "bob",
+                "5",
+            )])
+            .unwrap();
+        let (_, total) = transfers.volume("coin", None, None, false).unwrap();
+        assert_eq!(total, "18.75".parse().unwrap());
+        let (_, total) = transfers.volume("coin", None, None, true).unwrap();
+        assert_eq!(total, "13.75".parse().unwrap());
+
+        transfers.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_transfers_supply() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let transfers = TransfersRepository { pool: pool.clone() };
+        blocks
+            .insert_batch(&[make_block(0, 1, "block-0-1"), make_block(1, 1, "block-1-1")])
+            .unwrap();
+        transfers
+            .insert_batch(&vec![
+                // Minted
+                make_transfer("block-0-1", 0, 1, 0, "", "alice", "10"),
+                make_transfer("block-0-1", 0, 1, 1, "alice", "bob", "4"),
+                // Burned
+                make_transfer("block-0-1", 0, 1, 2, "bob", "", "1.5"),
+                // Moved to chain 1
+                make_transfer("block-0-1", 0, 1, 3, "alice", "", "2"),
+                make_transfer("block-1-1", 1, 1, 0, "", "alice", "2"),
+            ])
+            .unwrap();
+
+        let (per_chain, total) = transfers.supply("coin").unwrap();
+        assert_eq!(per_chain.get(&0), Some(&"6.5".parse().unwrap()));
+        assert_eq!(per_chain.get(&1), Some(&BigDecimal::from(2)));
+        assert_eq!(total, "8.5".parse().unwrap());
+        let (per_chain, total) = transfers.supply("free.token").unwrap();
+        assert!(per_chain.is_empty());
+        assert_eq!(total, BigDecimal::from(0));
+
+        transfers.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_find_events_and_transfers_by_request_keys() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let events = EventsRepository { pool: pool.clone() };
+        let transfers = TransfersRepository { pool: pool.clone() };
+        blocks
+            .insert_batch(&[make_block(0, 1, "block-0-1")])
+            .unwrap();
+        let transfer = make_transfer("block-0-1", 0, 1, 0, "alice", "bob", "1");
+        let event = Event {
+            request_key: transfer.request_key.clone(),
+            ..make_event(
+                "block-0-1",
+                0,
+                "coin",
+                "TRANSFER",
+                serde_json::json!(["alice", "bob", 1]),
+            )
+        };
+        let other_event = Event {
+            request_key: "other-request-key".to_string(),
+            ..make_event(
+                "block-0-1",
+                1,
+                "coin",
+                "TRANSFER",
+                serde_json::json!(["bob", "alice", 1]),
+            )
+        };
+        events.insert_batch(&[event.clone(), other_event]).unwrap();
+        transfers.insert_batch(&vec![transfer.clone()]).unwrap();
+
+        let request_keys = vec![transfer.request_key.clone()];
+        let found = events.find_by_request_keys(&request_keys).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].request_key, event.request_key);
+        assert_eq!(found[0].idx, event.idx);
+        let found = transfers.find_by_request_keys(&request_keys).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].request_key, transfer.request_key);
+        assert!(events
+            .find_by_request_keys(&["unknown".to_string()])
+            .unwrap()
+            .is_empty());
+
+        transfers.delete_all().unwrap();
+        events.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_list_modules() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let events = EventsRepository { pool: pool.clone() };
+        blocks
+            .insert_batch(&[make_block(0, 1, "block-0-1"), make_block(1, 5, "block-1-5")])
+            .unwrap();
+        events
+            .insert_batch(&[
+                make_event("block-0-1", 0, "coin", "TRANSFER", serde_json::json!([])),
+                make_event("block-0-1", 1, "coin", "TRANSFER", serde_json::json!([])),
+                make_event("block-0-1", 2, "free.radio02", "ADD", serde_json::json!([])),
+                make_event(
+                    "block-0-1",
+                    3,
+                    "arkade.token",
+                    "MINT",
+                    serde_json::json!([]),
+                ),
+                Event {
+                    chain_id: 1,
+                    height: 5,
+                    ..make_event("block-1-5", 0, "coin", "TRANSFER", serde_json::json!([]))
+                },
+            ])
+            .unwrap();
+
+        assert_eq!(
+            events.list_modules(None).unwrap().rows,
+            vec![
+                ("coin".to_string(), 3, vec![0, 1]),
+                ("arkade.token".to_string(), 1, vec![0]),
+                ("free.radio02".to_string(), 1, vec![0]),
+            ]
+        );
+        assert_eq!(
+            events.list_modules(Some(5)).unwrap().rows,
+            vec![("coin".to_string(), 1, vec![1])]
+        );
+        // The modules kept are those with the most events, whatever their names, with all their
+        // events and chains
+        let capped = events.list_modules_capped(None, 1).unwrap();
+        assert!(capped.truncated);
+        assert_eq!(capped.rows, vec![("coin".to_string(), 3, vec![0, 1])]);
+
+        events.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    fn test_exhausted_pool_returns_an_error() {
+        use diesel::r2d2::{ConnectionManager, Pool};
+        use std::time::Duration;
+        dotenvy::from_filename(".env.test").ok();
+        let pool = Pool::builder()
+            .max_size(1)
+            .connection_timeout(Duration::from_millis(100))
+            .build(ConnectionManager::new(db::database_url()))
+            .unwrap();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let events = EventsRepository { pool: pool.clone() };
+        let _conn = pool.get().unwrap();
+        assert!(blocks.count(0).is_err());
+        assert!(blocks.find_by_hash("hash", 0).is_err());
+        assert!(events.find_max_height(0).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_find_missing_ranges() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        blocks
+            .insert_batch(&[
+                make_block(0, 3, "block-3"),
+                make_block(0, 4, "block-4"),
+                make_block(0, 7, "block-7"),
+                make_block(1, 5, "block-1-5"),
+            ])
+            .unwrap();
+        assert_eq!(
+            blocks.find_missing_ranges(0, 1, 9).unwrap(),
+            vec![(1, 2), (5, 6), (8, 9)]
+        );
+        assert_eq!(blocks.find_missing_ranges(0, 3, 7).unwrap(), vec![(5, 6)]);
+        assert!(blocks.find_missing_ranges(0, 3, 4).unwrap().is_empty());
+        assert_eq!(blocks.find_missing_ranges(1, 0, 4).unwrap(), vec![(0, 4)]);
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_latest_per_chain() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        assert!(blocks.latest_per_chain().unwrap().is_empty());
+        blocks
+            .insert_batch(&[
+                make_block(0, 2, "block-0-2"),
+                make_block(0, 3, "block-0-3"),
+                make_block(0, 1, "block-0-1"),
+                make_block(1, 1, "block-1-1"),
+                make_block(1, 5, "block-1-5"),
+            ])
+            .unwrap();
+        assert_eq!(
+            blocks
+                .latest_per_chain()
+                .unwrap()
+                .iter()
+                .map(|block| (block.chain_id, block.height, block.hash.as_str()))
+                .collect::<Vec<(i64, i64, &str)>>(),
+            vec![(0, 3, "block-0-3"), (1, 5, "block-1-5")]
+        );
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_blocks_insert_batch_reports_skipped_blocks() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        assert_eq!(
+            blocks
+                .insert_batch(&[make_block(0, 1, "block-1"), make_block(0, 2, "block-2")])
+                .unwrap(),
+            BatchInsert {
+                inserted: 2,
+                skipped: 0
+            }
+        );
+        assert_eq!(
+            blocks
+                .insert_batch(&[
+                    make_block(0, 2, "block-2"),
+                    make_block(0, 3, "block-3"),
+                    make_block(0, 4, "block-4")
+                ])
+                .unwrap(),
+            BatchInsert {
+                inserted: 2,
+                skipped: 1
+            }
+        );
+        assert_eq!(blocks.count(0).unwrap(), 4);
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_blocks_insert_batch_with_data_keeps_saved_blocks() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let events = EventsRepository { pool: pool.clone() };
+        let transactions = TransactionsRepository { pool: pool.clone() };
+        let account_guards = AccountGuardsRepository { pool: pool.clone() };
+        let block_data = |block: Block, event_name: &str| BlockData {
+            events: vec![make_event(
+                &block.hash,
+                0,
+                "coin",
+                event_name,
+                serde_json::json!([]),
+            )],
+            block,
+            transactions: vec![],
+            transfers: vec![],
+        };
+        let with_transaction = |data: BlockData, request_key: &str| BlockData {
+            transactions: vec![Transaction {
+                block: data.block.hash.clone(),
+                ..make_transaction(data.block.height, request_key, "alice")
+            }],
+            ..data
+        };
+        let make_guard = |account: &str, height: i64, request_key: &str| AccountGuard {
+            account: account.to_string(),
+            chain_id: 0,
+            module: "coin".to_string(),
+            guard: serde_json::json!({"keys": [account], "pred": "keys-all"}),
+            height,
+            request_key: request_key.to_string(),
+        };
+        blocks
+            .save_with_data(&[
+                with_transaction(
+                    block_data(make_block(0, 1, "block-1"), "SAVED"),
+                    "request-key-1",
+                ),
+                with_transaction(
+                    block_data(make_block(0, 2, "orphan-2"), "SAVED"),
+                    "request-key-orphan",
+                ),
+            ])
+            .unwrap();
+        account_guards
+            .upsert_batch(&[
+                make_guard("alice", 1, "request-key-1"),
+                make_guard("bob", 2, "request-key-orphan"),
+            ])
+            .unwrap();
+
+        let (inserted, orphans) = blocks
+            .insert_batch_with_data(&[
+                block_data(make_block(0, 1, "block-1"), "REPROCESSED"),
+                block_data(make_block(0, 2, "block-2"), "REPROCESSED"),
+                block_data(make_block(0, 3, "block-3"), "REPROCESSED"),
+            ])
+            .unwrap();
+        assert_eq!(inserted, vec!["block-2".to_string(), "block-3".to_string()]);
+        assert_eq!(
+            orphans,
+            vec![Orphan {
+                chain_id: 0,
+                height: 2,
+                hash: "orphan-2".to_string()
+            }]
+        );
+        // The saved block keeps its events, the orphan's are replaced by those of the new block
+        let mut saved_events = events
+            .find_all()
+            .unwrap()
+            .rows
+            .into_iter()
+            .map(|event| (event.block, event.name))
+            .collect::<Vec<(String, String)>>();
+        saved_events.sort();
+        assert_eq!(
+            saved_events,
+            vec![
+                ("block-1".to_string(), "SAVED".to_string()),
+                ("block-2".to_string(), "REPROCESSED".to_string()),
+                ("block-3".to_string(), "REPROCESSED".to_string()),
+            ]
+        );
+        // Along with the guards set by its transactions
+        assert_eq!(
+            account_guards.find_by_account("alice").unwrap().rows.len(),
+            1
+        );
+        assert!(account_guards
+            .find_by_account("bob")
+            .unwrap()
+            .rows
+            .is_empty());
+        assert!(transactions
+            .find_by_request_key(&vec!["request-key-orphan".to_string()])
+            .unwrap()
+            .rows
+            .is_empty());
+        account_guards.delete_all().unwrap();
+        transactions.delete_all().unwrap();
+        events.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_blocks_insert_batch_upsert_refreshes_blocks() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        blocks.insert_batch(&[make_block(0, 1, "block-1")]).unwrap();
+        let fixed = Block {
+            pow_hash: "fixed-pow-hash".to_string(),
+            target: BigDecimal::from(42),
+            ..make_block(0, 1, "block-1")
+        };
+
+        // Stored blocks are left as they are when ignoring conflicts
+        blocks.insert_batch(std::slice::from_ref(&fixed)).unwrap();
+        let stored = blocks.find_by_hash("block-1", 0).unwrap().unwrap();
+        assert_eq!(stored.pow_hash, make_block(0, 1, "block-1").pow_hash);
+
+        assert_eq!(
+            blocks
+                .insert_batch_upsert(&[fixed.clone(), make_block(0, 2, "block-2")])
+                .unwrap(),
+            2
+        );
+        let stored = blocks.find_by_hash("block-1", 0).unwrap().unwrap();
+        assert_eq!(stored.pow_hash, "fixed-pow-hash");
+        assert_eq!(stored.target, BigDecimal::from(42));
+        assert_eq!(blocks.count(0).unwrap(), 2);
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_concurrent_block_replacements() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let transactions = TransactionsRepository { pool: pool.clone() };
+        blocks.insert(&make_block(0, 1, "block-0-1")).unwrap();
+        transactions
+            .insert(&make_transaction(1, "request-key-1", "alice"))
+            .unwrap();
+
+        let handles = (0..4)
+            .map(|i| {
+                let blocks = blocks.clone();
+                std::thread::spawn(move || blocks.replace(&make_block(0, 1, &format!("new-{}", i))))
+            })
+            .collect::<Vec<_>>();
+        let orphans = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap().unwrap().1)
+            .collect::<Vec<Vec<Orphan>>>();
+
+        // The replacements ran one after the other, the last one being kept
+        // and every other block being orphaned once
+        assert!(orphans.iter().all(|orphans| orphans.len() == 1));
+        let mut orphaned = orphans
+            .concat()
+            .into_iter()
+            .map(|orphan| orphan.hash)
+            .collect::<Vec<String>>();
+        orphaned.sort();
+        assert_eq!(orphaned[0], "block-0-1");
+        assert_eq!(orphaned.len(), 4);
+        assert_eq!(blocks.count(0).unwrap(), 1);
+        let block = blocks.find_by_height(1, 0).unwrap().unwrap();
+        assert!(block.hash.starts_with("new-"));
+        assert!(transactions.find_all().unwrap().rows.is_empty());
+
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_find_events_by_module_name_and_param() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let events = EventsRepository { pool: pool.clone() };
+        blocks.insert(&make_block(0, 0, "block-0")).unwrap();
+        events
+            .insert_batch(&[
+                make_event(
+                    "block-0",
+                    0,
+                    "marmalade-v2.ledger",
+                    "TRANSFER",
+                    serde_json::json!(["token-1", "alice", "bob", 1]),
+                ),
+                make_event(
+                    "block-0",
+                    1,
+                    "marmalade-v2.ledger",
+                    "TRANSFER",
+                    serde_json::json!(["token-2", "bob", "carol", 1]),
+                ),
+                make_event(
+                    "block-0",
+                    2,
+                    "coin",
+                    "TRANSFER",
+                    serde_json::json!(["alice", "bob", 1.5]),
+                ),
+            ])
+            .unwrap();
+
+        let alice_events = events
+            .find_by_module_name_and_param(
+                "marmalade-v2.ledger",
+                "TRANSFER",
+                &serde_json::json!("alice"),
+                100,
+                None,
+            )
+            .unwrap();
+        assert_eq!(alice_events.len(), 1);
+        assert_eq!(alice_events[0].idx, 0);
+
+        let bob_events = events
+            .find_by_module_name_and_param(
+                "marmalade-v2.ledger",
+                "TRANSFER",
+                &serde_json::json!("bob"),
+                100,
+                None,
+            )
+            .unwrap();
+        assert_eq!(
+            bob_events.iter().map(|e| e.idx).collect::<Vec<i64>>(),
+            vec![1, 0]
+        );
+
+        events.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_search_events_by_params() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let events = EventsRepository { pool: pool.clone() };
+        blocks.insert(&make_block(0, 0, "block-0")).unwrap();
+        events
+            .insert_batch(&[
+                make_event(
+                    "block-0",
+                    0,
+                    "coin",
+                    "TRANSFER",
+                    serde_json::json!(["alice", "bob", 1.5]),
+                ),
+                make_event(
+                    "block-0",
+                    1,
+                    "coin",
+                    "TRANSFER",
+                    serde_json::json!(["bob", "alice", 2]),
+                ),
+                make_event(
+                    "block-0",
+                    2,
+                    "coin",
+                    "TRANSFER",
+                    serde_json::json!(["bob", "carol", 2.0]),
+                ),
+            ])
+            .unwrap();
+        let search = |params: serde_json::Value| {
+            let params = serde_json::from_value::<ParamsMatch>(params).unwrap();
+            events
+                .search("coin", "TRANSFER", &params, 100, None)
+                .unwrap()
+                .iter()
+                .map(|e| e.idx)
+                .collect::<Vec<i64>>()
+        };
+
+        assert_eq!(search(serde_json::json!({})), vec![2, 1, 0]);
+        assert_eq!(
+            search(serde_json::json!({"contains": ["alice"]})),
+            vec![1, 0]
+        );
+        assert_eq!(
+            search(serde_json::json!({"contains": ["alice", "bob"]})),
+            vec![1, 0]
+        );
+        assert_eq!(search(serde_json::json!({"contains": [2]})), vec![2, 1]);
+        assert_eq!(
+            search(serde_json::json!({"equals": {"0": "alice"}})),
+            vec![0]
+        );
+        assert_eq!(
+            search(serde_json::json!({"equals": {"1": "alice"}})),
+            vec![1]
+        );
+        assert_eq!(
+            search(serde_json::json!({"equals": {"0": "bob", "2": 2}})),
+            vec![2, 1]
+        );
+        assert_eq!(
+            search(serde_json::json!({"contains": ["carol"], "equals": {"0": "bob"}})),
+            vec![2]
+        );
+        assert!(search(serde_json::json!({"equals": {"3": "alice"}})).is_empty());
+        assert!(serde_json::from_value::<ParamsMatch>(serde_json::json!({"any": []})).is_err());
+
+        events.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_find_all_streamed() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let events = EventsRepository { pool: pool.clone() };
+        let all_blocks = (0..250)
+            .map(|height| make_block(0, height, &format!("block-{}", height)))
+            .collect::<Vec<Block>>();
+        blocks.insert_batch(&all_blocks).unwrap();
+        // Events of different transactions share the same block and index
+        let all_events = (0..2500)
+            .map(|i| Event {
+                request_key: format!("rk-{}", i % 3),
+                ..make_event(
+                    &format!("block-{}", i % 5),
+                    i / 15,
+                    "coin",
+                    "TRANSFER",
+                    serde_json::json!([]),
+                )
+            })
+            .collect::<Vec<Event>>();
+        events.insert_batch(&all_events).unwrap();
+
+        let mut chunk_sizes = vec![];
+        let mut hashes = HashSet::new();
+        let processed = blocks
+            .find_all_streamed(100, |chunk| {
+                chunk_sizes.push(chunk.len());
+                hashes.extend(chunk.into_iter().map(|block| block.hash));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(processed, 250);
+        assert_eq!(chunk_sizes, vec![100, 100, 50]);
+        assert_eq!(hashes.len(), 250);
+
+        let mut chunks = 0;
+        let mut keys = HashSet::new();
+        let processed = events
+            .find_all_streamed(500, |chunk| {
+                chunks += 1;
+                keys.extend(
+                    chunk
+                        .into_iter()
+                        .map(|event| (event.block, event.idx, event.request_key)),
+                );
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(processed, 2500);
+        // The last chunk is full, the next one is empty
+        assert_eq!(chunks, 5);
+        assert_eq!(keys.len(), 2500);
+
+        // Errors of the processing stop the streaming
+        let mut chunks = 0;
+        let result = events.find_all_streamed(500, |_| {
+            chunks += 1;
+            Err("processing failed".into())
+        });
+        assert!(result.is_err());
+        assert_eq!(chunks, 1);
+
+        events.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_find_events_by_module_name_and_param_with_cursor() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let events = EventsRepository { pool: pool.clone() };
+        blocks.insert(&make_block(0, 1, "block-1")).unwrap();
+        blocks.insert(&make_block(0, 2, "block-2")).unwrap();
+        let params = serde_json::json!(["alice", "bob", 1.0]);
+        // Events of different transactions share the same height and index
+        let all_events = [
+            (1, "rk-a", 0),
+            (1, "rk-a", 1),
+            (1, "rk-b", 0),
+            (2, "rk-a", 0),
+            (2, "rk-c", 0),
+        ]
+        .iter()
+        .map(|(height, request_key, idx)| Event {
+            height: *height,
+            request_key: request_key.to_string(),
+            ..make_event(
+                &format!("block-{}", height),
+                *idx,
+                "coin",
+                "TRANSFER",
+                params.clone(),
+            )
+        })
+        .collect::<Vec<Event>>();
+        events.insert_batch(&all_events).unwrap();
+
+        let mut pages = vec![];
+        let mut cursor = None;
+        loop {
+            let page = events
+                .find_by_module_name_and_param(
+                    "coin",
+                    "TRANSFER",
+                    &serde_json::json!("alice"),
+                    2,
+                    cursor.as_ref(),
+                )
+                .unwrap();
+            if page.is_empty() {
+                break;
+            }
+            // The cursor goes through its encoded form as it would between API calls
+            cursor = Cursor::decode(&Cursor::from(page.last().unwrap()).encode());
+            pages.push(
+                page.iter()
+                    .map(|e| (e.height, e.request_key.clone(), e.idx))
+                    .collect::<Vec<(i64, String, i64)>>(),
+            );
+        }
+        let key = |height: i64, request_key: &str, idx: i64| (height, request_key.to_string(), idx);
+        assert_eq!(
+            pages,
+            vec![
+                vec![key(2, "rk-c", 0), key(2, "rk-a", 0)],
+                vec![key(1, "rk-b", 0), key(1, "rk-a", 1)],
+                vec![key(1, "rk-a", 0)],
+            ]
+        );
+
+        events.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_find_and_repair_events() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let events = EventsRepository { pool: pool.clone() };
+        blocks.insert_batch(&[make_block(0, 0, "block-0")]).unwrap();
+        let fixed = make_event(
+            "block-0",
+            0,
+            "marmalade-v2.ledger",
+            "TRANSFER",
+            serde_json::json!([]),
+        );
+        events
+            .insert_batch(&[
+                fixed.clone(),
+                Event {
+                    qual_name: "ledger.TRANSFER".to_string(),
+                    ..make_event(
+                        "block-0",
+                        1,
+                        "marmalade-v2.ledger",
+                        "TRANSFER",
+                        serde_json::json!([]),
+                    )
+                },
+                Event {
+                    module_hash: "".to_string(),
+                    ..make_event("block-0", 2, "coin", "TRANSFER", serde_json::json!([]))
+                },
+            ])
+            .unwrap();
+
+        let broken = events.find_to_repair(0, 0, 10).unwrap();
+        assert_eq!(
+            broken.iter().map(|event| event.idx).collect::<Vec<i64>>(),
+            vec![1, 2]
+        );
+        assert!(events.find_to_repair(0, 1, 10).unwrap().is_empty());
+        assert!(events.find_to_repair(1, 0, 10).unwrap().is_empty());
+
+        let repaired = broken
+            .into_iter()
+            .map(|event| Event {
+                module_hash: "module-hash".to_string(),
+                qual_name: format!("{}.{}", event.module, event.name),
+                ..event
+            })
+            .collect::<Vec<Event>>();
+        assert_eq!(events.update_derived_fields(&repaired).unwrap(), 2);
+        assert!(events.find_to_repair(0, 0, 10).unwrap().is_empty());
+        let stored = events
+            .find_by_request_keys(&["request-key".to_string()])
+            .unwrap();
+        assert_eq!(stored[0], fixed);
+        assert_eq!(stored[1].qual_name, "marmalade-v2.ledger.TRANSFER");
+
+        events.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_find_latest_events() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        let events = EventsRepository { pool: pool.clone() };
+        let at = |secs: i64| NaiveDateTime::from_timestamp_opt(1700000000 + secs, 0).unwrap();
+        // Chain 1 is behind chain 0, its block at height 5 is more recent than the one at 10
+        blocks
+            .insert_batch(&[
+                Block {
+                    creation_time: at(0),
+                    ..make_block(0, 10, "block-0-10")
+                },
+                Block {
+                    creation_time: at(30),
+                    ..make_block(1, 5, "block-1-5")
+                },
+                Block {
+                    creation_time: at(60),
+                    ..make_block(0, 11, "block-0-11")
+                },
+            ])
+            .unwrap();
+        events
+            .insert_batch(&[
+                make_event("block-0-10", 0, "coin", "TRANSFER", serde_json::json!([])),
+                make_event("block-1-5", 0, "coin", "TRANSFER", serde_json::json!([])),
+                make_event("block-1-5", 1, "coin", "TRANSFER", serde_json::json!([])),
+                make_event("block-0-11", 0, "coin", "TRANSFER", serde_json::json!([])),
+            ])
+            .unwrap();
+
+        let latest = events
+            .find_latest(3)
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.block, e.idx))
+            .collect::<Vec<(String, i64)>>();
+        assert_eq!(
+            latest,
+            vec![
+                ("block-0-11".to_string(), 0),
+                ("block-1-5".to_string(), 1),
+                ("block-1-5".to_string(), 0),
+            ]
+        );
+
+        events.delete_all().unwrap();
+        blocks.delete_all().unwrap();
+    }
+
+    /// Plan of the query, with sequential scans disabled so that the indexes are used
+    /// whenever they can be, however few rows the test tables hold.
+    /// Plans the query for any bind values (generic plans need Postgres 16), with sequential
+    /// scans disabled so that the plan shows whether an index can be used on a small table.
+    fn explain<Q: QueryFragment<Pg>>(conn: &mut PgConnection, query: &Q) -> String {
+        let query = diesel::debug_query::<Pg, _>(query).to_string();
+        let sql = query.split(" -- binds: ").next().unwrap();
+        diesel::sql_query("SET enable_seqscan = off")
+            .execute(conn)
+            .unwrap();
+        let plan =
+            diesel::dsl::sql::<diesel::sql_types::Text>(&format!("EXPLAIN (GENERIC_PLAN) {}", sql))
+                .load::<String>(conn)
+                .unwrap()
+                .join("\n");
+        diesel::sql_query("RESET enable_seqscan")
+            .execute(conn)
+            .unwrap();
+        format!("{}\n{}", sql, plan)
+    }
+
+    #[test]
+    #[serial]
+    fn test_queries_use_indexes() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let mut conn = pool.get().unwrap();
+        let alice = || Some("alice".to_string());
+        let pact_ids = vec!["pact-id".to_string()];
+        // events_qualname_height_idx isn't checked, no query filters by qualified name yet
+        let plans = [
+            (
+                explain(
+                    &mut conn,
+                    &EventsRepository::search_query(
+                        "coin",
+                        "MINT",
+                        &ParamsMatch::default(),
+                        100,
+                        None,
+                    ),
+                ),
+                "events_module_name_height_idx",
+            ),
+            (
+                explain(
+                    &mut conn,
+                    &TransactionsRepository::count_by_sender_query("alice"),
+                ),
+                "transactions_sender_height_idx",
+            ),
+            (
+                explain(
+                    &mut conn,
+                    &TransactionsRepository::find_by_pact_id_query(&pact_ids),
+                ),
+                "transactions_pactid_index",
+            ),
+            (
+                explain(
+                    &mut conn,
+                    &TransfersRepository::find_query(alice(), None, None, false, 100),
+                ),
+                "transfers_from_acct_height_idx",
+            ),
+            (
+                explain(
+                    &mut conn,
+                    &TransfersRepository::find_query(None, alice(), None, false, 100),
+                ),
+                "transfers_to_acct_height_idx_idx",
+            ),
+            (
+                explain(
+                    &mut conn,
+                    &TransfersRepository::find_by_pact_id_query(pact_ids.clone()),
+                ),
+                "transfers_pactid_idx",
+            ),
+            (
+                explain(&mut conn, &BlocksRepository::latest_block_query(0)),
+                "blocks_chainid_height_idx",
+            ),
+        ];
+        for (plan, index) in plans {
+            assert!(plan.contains(index), "{} not used by:\n{}", index, plan);
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_save_stream_cursors() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let stream_cursors = StreamCursorsRepository { pool: pool.clone() };
+        let cursor = |chain_id, height, hash: &str| StreamCursor {
+            chain_id,
+            height,
+            hash: hash.to_string(),
+        };
+        stream_cursors
+            .save(&[cursor(0, 10, "block-0-10"), cursor(1, 5, "block-1-5")])
+            .unwrap();
+        // A header processed out of order doesn't move the cursor back
+        assert_eq!(
+            stream_cursors.save(&[cursor(0, 9, "block-0-9")]).unwrap(),
+            0
+        );
+        // A block replaced at the same height does
+        stream_cursors
+            .save(&[cursor(0, 10, "other-block-0-10"), cursor(1, 6, "block-1-6")])
+            .unwrap();
+        assert_eq!(
+            stream_cursors.find_all().unwrap(),
+            vec![cursor(0, 10, "other-block-0-10"), cursor(1, 6, "block-1-6")]
+        );
+
+        stream_cursors.delete_all().unwrap();
+    }
+}
@@ -1,5 +1,16 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    account_guards (account, chain_id, module) {
+        account -> Varchar,
+        chain_id -> Int8,
+        module -> Varchar,
+        guard -> Jsonb,
+        height -> Int8,
+        request_key -> Varchar,
+    }
+}
+
 diesel::table! {
     blocks (hash) {
         chain_id -> Int8,
@@ -16,6 +27,7 @@ diesel::table! {
         predicate -> Varchar,
         target -> Numeric,
         weight -> Numeric,
+        difficulty -> Nullable<Numeric>,
     }
 }
 
@@ -36,6 +48,14 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    stream_cursors (chain_id) {
+        chain_id -> Int8,
+        height -> Int8,
+        hash -> Varchar,
+    }
+}
+
 diesel::table! {
     transactions (block, request_key) {
         bad_result -> Nullable<Jsonb>,
@@ -62,6 +82,14 @@ diesel::table! {
         step -> Nullable<Int8>,
         ttl -> Int8,
         tx_id -> Nullable<Int8>,
+        keysets -> Nullable<Jsonb>,
+        signatures_valid -> Nullable<Bool>,
+        error_type -> Nullable<Varchar>,
+        error_message -> Nullable<Varchar>,
+        signers -> Array<Text>,
+        is_gas_station -> Bool,
+        status -> Varchar,
+        raw_cmd -> Nullable<Text>,
     }
 }
 
@@ -79,6 +107,8 @@ diesel::table! {
         to_account -> Varchar,
         pact_id -> Nullable<Varchar>,
         creation_time -> Timestamptz,
+        self_transfer -> Bool,
+        authorized_amount -> Nullable<Numeric>,
     }
 }
 
@@ -86,4 +116,11 @@ diesel::joinable!(events -> blocks (block));
 diesel::joinable!(transactions -> blocks (block));
 diesel::joinable!(transfers -> blocks (block));
 
-diesel::allow_tables_to_appear_in_same_query!(blocks, events, transactions, transfers,);
+diesel::allow_tables_to_appear_in_same_query!(
+    account_guards,
+    blocks,
+    events,
+    stream_cursors,
+    transactions,
+    transfers,
+);
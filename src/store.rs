@@ -0,0 +1,470 @@
+use crate::db::DbError;
+use crate::models::{AccountGuard, Block, Event, StreamCursor, Transaction, Transfer};
+use crate::repository::{
+    AccountGuardsRepository, BatchInsert, BlockData, BlocksRepository, EventsRepository, Orphan,
+    StreamCursorsRepository, TransactionsRepository, TransfersRepository,
+};
+
+/// Storage of the blocks used by the indexer, the repository in production.
+pub trait BlockStore: Send + Sync {
+    fn insert(&self, block: &Block) -> Result<Block, DbError>;
+    fn insert_batch(&self, blocks: &[Block]) -> Result<BatchInsert, DbError>;
+    fn insert_batch_upsert(&self, blocks: &[Block]) -> Result<usize, DbError>;
+    fn replace(&self, block: &Block) -> Result<(Block, Vec<Orphan>), DbError>;
+    fn save_with_data(&self, blocks_data: &[BlockData]) -> Result<Vec<Orphan>, DbError>;
+    fn insert_batch_with_data(
+        &self,
+        blocks_data: &[BlockData],
+    ) -> Result<(Vec<String>, Vec<Orphan>), DbError>;
+    fn find_min_max_height_blocks(
+        &self,
+        chain_id: i64,
+    ) -> Result<(Option<Block>, Option<Block>), DbError>;
+    fn find_missing_ranges(
+        &self,
+        chain_id: i64,
+        min_height: i64,
+        max_height: i64,
+    ) -> Result<Vec<(i64, i64)>, DbError>;
+}
+
+/// Storage of the events used by the indexer, the repository in production.
+pub trait EventStore: Send + Sync {
+    fn insert_batch(&self, events: &[Event]) -> Result<usize, DbError>;
+    fn delete_all_by_block(&self, hash: &str) -> Result<usize, DbError>;
+}
+
+/// Storage of the transactions used by the indexer, the repository in production.
+pub trait TransactionStore: Send + Sync {
+    fn insert_batch(&self, transactions: &[Transaction]) -> Result<usize, DbError>;
+    fn delete_all_by_block(&self, hash: &str) -> Result<usize, DbError>;
+}
+
+/// Storage of the transfers used by the indexer, the repository in production.
+pub trait TransferStore: Send + Sync {
+    fn insert_batch(&self, transfers: &[Transfer]) -> Result<usize, DbError>;
+    fn delete_all_by_block(&self, block: &str, chain_id: i64) -> Result<usize, DbError>;
+}
+
+/// Storage of the account guards used by the indexer, the repository in production.
+pub trait GuardStore: Send + Sync {
+    fn upsert_batch(&self, guards: &[AccountGuard]) -> Result<usize, DbError>;
+}
+
+/// Storage of the stream cursors used by the indexer, the repository in production.
+pub trait CursorStore: Send + Sync {
+    fn find_all(&self) -> Result<Vec<StreamCursor>, DbError>;
+    fn save(&self, cursors: &[StreamCursor]) -> Result<usize, DbError>;
+}
+
+impl BlockStore for BlocksRepository {
+    fn insert(&self, block: &Block) -> Result<Block, DbError> {
+        BlocksRepository::insert(self, block)
+    }
+
+    fn insert_batch(&self, blocks: &[Block]) -> Result<BatchInsert, DbError> {
+        BlocksRepository::insert_batch(self, blocks)
+    }
+
+    fn insert_batch_upsert(&self, blocks: &[Block]) -> Result<usize, DbError> {
+        BlocksRepository::insert_batch_upsert(self, blocks)
+    }
+
+    fn replace(&self, block: &Block) -> Result<(Block, Vec<Orphan>), DbError> {
+        BlocksRepository::replace(self, block)
+    }
+
+    fn save_with_data(&self, blocks_data: &[BlockData]) -> Result<Vec<Orphan>, DbError> {
+        BlocksRepository::save_with_data(self, blocks_data)
+    }
+
+    fn insert_batch_with_data(
+        &self,
+        blocks_data: &[BlockData],
+    ) -> Result<(Vec<String>, Vec<Orphan>), DbError> {
+        BlocksRepository::insert_batch_with_data(self, blocks_data)
+    }
+
+    fn find_min_max_height_blocks(
+        &self,
+        chain_id: i64,
+    ) -> Result<(Option<Block>, Option<Block>), DbError> {
+        BlocksRepository::find_min_max_height_blocks(self, chain_id)
+    }
+
+    fn find_missing_ranges(
+        &self,
+        chain_id: i64,
+        min_height: i64,
+        max_height: i64,
+    ) -> Result<Vec<(i64, i64)>, DbError> {
+        BlocksRepository::find_missing_ranges(self, chain_id, min_height, max_height)
+    }
+}
+
+impl EventStore for EventsRepository {
+    fn insert_batch(&self, events: &[Event]) -> Result<usize, DbError> {
+        EventsRepository::insert_batch(self, events)
+    }
+
+    fn delete_all_by_block(&self, hash: &str) -> Result<usize, DbError> {
+        EventsRepository::delete_all_by_block(self, hash)
+    }
+}
+
+impl TransactionStore for TransactionsRepository {
+    fn insert_batch(&self, transactions: &[Transaction]) -> Result<usize, DbError> {
+        TransactionsRepository::insert_batch(self, transactions)
+    }
+
+    fn delete_all_by_block(&self, hash: &str) -> Result<usize, DbError> {
+        TransactionsRepository::delete_all_by_block(self, hash)
+    }
+}
+
+impl TransferStore for TransfersRepository {
+    fn insert_batch(&self, transfers: &[Transfer]) -> Result<usize, DbError> {
+        TransfersRepository::insert_batch(self, &transfers.to_vec()).map(|inserted| inserted.len())
+    }
+
+    fn delete_all_by_block(&self, block: &str, chain_id: i64) -> Result<usize, DbError> {
+        TransfersRepository::delete_all_by_block(self, block, chain_id)
+    }
+}
+
+impl GuardStore for AccountGuardsRepository {
+    fn upsert_batch(&self, guards: &[AccountGuard]) -> Result<usize, DbError> {
+        AccountGuardsRepository::upsert_batch(self, guards)
+    }
+}
+
+impl CursorStore for StreamCursorsRepository {
+    fn find_all(&self) -> Result<Vec<StreamCursor>, DbError> {
+        StreamCursorsRepository::find_all(self)
+    }
+
+    fn save(&self, cursors: &[StreamCursor]) -> Result<usize, DbError> {
+        StreamCursorsRepository::save(self, cursors)
+    }
+}
+
+/// In-memory stores, to test the indexer without a database.
+#[cfg(test)]
+pub mod memory {
+    use super::*;
+    use diesel::result::{DatabaseErrorKind, Error::DatabaseError};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    pub struct MemoryBlocks {
+        pub blocks: Mutex<Vec<Block>>,
+    }
+
+    impl MemoryBlocks {
+        pub fn new(blocks: Vec<Block>) -> Self {
+            MemoryBlocks {
+                blocks: Mutex::new(blocks),
+            }
+        }
+
+        /// Removes the blocks at the height of the block, returning those other than itself.
+        fn remove_at_height_of(blocks: &mut Vec<Block>, block: &Block) -> Vec<Orphan> {
+            let (removed, kept) = std::mem::take(blocks).into_iter().partition(|stored| {
+                stored.chain_id == block.chain_id && stored.height == block.height
+            });
+            *blocks = kept;
+            removed
+                .into_iter()
+                .filter(|orphan: &Block| orphan.hash != block.hash)
+                .map(|orphan| Orphan {
+                    chain_id: orphan.chain_id,
+                    height: orphan.height,
+                    hash: orphan.hash,
+                })
+                .collect()
+        }
+    }
+
+    impl BlockStore for MemoryBlocks {
+        /// Fails like the unique index of the database when a block is stored at this height.
+        fn insert(&self, block: &Block) -> Result<Block, DbError> {
+            let mut blocks = self.blocks.lock().unwrap();
+            if blocks.iter().any(|stored| {
+                stored.hash == block.hash
+                    || (stored.chain_id == block.chain_id && stored.height == block.height)
+            }) {
+                return Err(Box::new(DatabaseError(
+                    DatabaseErrorKind::UniqueViolation,
+                    Box::new(format!("Block at height {} already stored", block.height)),
+                )));
+            }
+            blocks.push(block.clone());
+            Ok(block.clone())
+        }
+
+        fn insert_batch(&self, blocks: &[Block]) -> Result<BatchInsert, DbError> {
+            let mut stored = self.blocks.lock().unwrap();
+            let mut inserted = 0;
+            for block in blocks {
+                if !stored.iter().any(|stored| stored.hash == block.hash) {
+                    stored.push(block.clone());
+                    inserted += 1;
+                }
+            }
+            Ok(BatchInsert {
+                inserted,
+                skipped: blocks.len() - inserted,
+            })
+        }
+
+        fn insert_batch_upsert(&self, blocks: &[Block]) -> Result<usize, DbError> {
+            let mut stored = self.blocks.lock().unwrap();
+            stored.retain(|stored| !blocks.iter().any(|block| block.hash == stored.hash));
+            stored.extend_from_slice(blocks);
+            Ok(blocks.len())
+        }
+
+        fn replace(&self, block: &Block) -> Result<(Block, Vec<Orphan>), DbError> {
+            let mut blocks = self.blocks.lock().unwrap();
+            let orphans = Self::remove_at_height_of(&mut blocks, block);
+            blocks.push(block.clone());
+            Ok((block.clone(), orphans))
+        }
+
+        /// Only the blocks are kept, their data is left out.
+        fn save_with_data(&self, blocks_data: &[BlockData]) -> Result<Vec<Orphan>, DbError> {
+            let mut blocks = self.blocks.lock().unwrap();
+            let mut orphans = vec![];
+            for data in blocks_data {
+                orphans.extend(Self::remove_at_height_of(&mut blocks, &data.block));
+                blocks.push(data.block.clone());
+            }
+            Ok(orphans)
+        }
+
+        /// Only the blocks are kept, their data is left out.
+        fn insert_batch_with_data(
+            &self,
+            blocks_data: &[BlockData],
+        ) -> Result<(Vec<String>, Vec<Orphan>), DbError> {
+            let mut blocks = self.blocks.lock().unwrap();
+            let mut inserted = vec![];
+            let mut orphans = vec![];
+            for data in blocks_data {
+                if blocks.iter().any(|stored| stored.hash == data.block.hash) {
+                    continue;
+                }
+                orphans.extend(Self::remove_at_height_of(&mut blocks, &data.block));
+                blocks.push(data.block.clone());
+                inserted.push(data.block.hash.clone());
+            }
+            Ok((inserted, orphans))
+        }
+
+        fn find_min_max_height_blocks(
+            &self,
+            chain_id: i64,
+        ) -> Result<(Option<Block>, Option<Block>), DbError> {
+            let blocks = self.blocks.lock().unwrap();
+            let chain = blocks.iter().filter(|block| block.chain_id == chain_id);
+            Ok((
+                chain.clone().min_by_key(|block| block.height).cloned(),
+                chain.max_by_key(|block| block.height).cloned(),
+            ))
+        }
+
+        fn find_missing_ranges(
+            &self,
+            chain_id: i64,
+            min_height: i64,
+            max_height: i64,
+        ) -> Result<Vec<(i64, i64)>, DbError> {
+            let mut heights = self
+                .blocks
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|block| block.chain_id == chain_id)
+                .map(|block| block.height)
+                .filter(|height| (min_height..=max_height).contains(height))
+                .collect::<Vec<i64>>();
+            heights.sort();
+            heights.dedup();
+            let mut ranges = vec![];
+            let mut next_height = min_height;
+            for height in heights.into_iter().chain(std::iter::once(max_height + 1)) {
+                if height > next_height {
+                    ranges.push((next_height, height - 1));
+                }
+                next_height = height + 1;
+            }
+            Ok(ranges)
+        }
+    }
+
+    #[derive(Default)]
+    pub struct MemoryEvents {
+        pub events: Mutex<Vec<Event>>,
+    }
+
+    impl EventStore for MemoryEvents {
+        fn insert_batch(&self, events: &[Event]) -> Result<usize, DbError> {
+            let mut stored = self.events.lock().unwrap();
+            let mut inserted = 0;
+            for event in events {
+                if !stored.iter().any(|stored| {
+                    stored.block == event.block
+                        && stored.idx == event.idx
+                        && stored.request_key == event.request_key
+                }) {
+                    stored.push(event.clone());
+                    inserted += 1;
+                }
+            }
+            Ok(inserted)
+        }
+
+        fn delete_all_by_block(&self, hash: &str) -> Result<usize, DbError> {
+            let mut events = self.events.lock().unwrap();
+            let before = events.len();
+            events.retain(|event| event.block != hash);
+            Ok(before - events.len())
+        }
+    }
+
+    #[derive(Default)]
+    pub struct MemoryTransactions {
+        pub transactions: Mutex<Vec<Transaction>>,
+    }
+
+    impl TransactionStore for MemoryTransactions {
+        fn insert_batch(&self, transactions: &[Transaction]) -> Result<usize, DbError> {
+            let mut stored = self.transactions.lock().unwrap();
+            let mut inserted = 0;
+            for transaction in transactions {
+                if !stored.iter().any(|stored| {
+                    stored.block == transaction.block
+                        && stored.request_key == transaction.request_key
+                }) {
+                    stored.push(transaction.clone());
+                    inserted += 1;
+                }
+            }
+            Ok(inserted)
+        }
+
+        fn delete_all_by_block(&self, hash: &str) -> Result<usize, DbError> {
+            let mut transactions = self.transactions.lock().unwrap();
+            let before = transactions.len();
+            transactions.retain(|transaction| transaction.block != hash);
+            Ok(before - transactions.len())
+        }
+    }
+
+    #[derive(Default)]
+    pub struct MemoryTransfers {
+        pub transfers: Mutex<Vec<Transfer>>,
+    }
+
+    impl TransferStore for MemoryTransfers {
+        fn insert_batch(&self, transfers: &[Transfer]) -> Result<usize, DbError> {
+            let mut stored = self.transfers.lock().unwrap();
+            let mut inserted = 0;
+            for transfer in transfers {
+                if !stored.iter().any(|stored| {
+                    stored.block == transfer.block
+                        && stored.chain_id == transfer.chain_id
+                        && stored.idx == transfer.idx
+                        && stored.module_hash == transfer.module_hash
+                        && stored.request_key == transfer.request_key
+                }) {
+                    stored.push(transfer.clone());
+                    inserted += 1;
+                }
+            }
+            Ok(inserted)
+        }
+
+        fn delete_all_by_block(&self, block: &str, chain_id: i64) -> Result<usize, DbError> {
+            let mut transfers = self.transfers.lock().unwrap();
+            let before = transfers.len();
+            transfers.retain(|transfer| transfer.block != block || transfer.chain_id != chain_id);
+            Ok(before - transfers.len())
+        }
+    }
+
+    #[derive(Default)]
+    pub struct MemoryGuards {
+        pub guards: Mutex<Vec<AccountGuard>>,
+    }
+
+    impl GuardStore for MemoryGuards {
+        /// Replaces the guard of an account only with one set at the same or a greater height.
+        fn upsert_batch(&self, guards: &[AccountGuard]) -> Result<usize, DbError> {
+            let mut stored = self.guards.lock().unwrap();
+            let mut upserted = 0;
+            for guard in guards {
+                match stored.iter_mut().find(|stored| {
+                    stored.account == guard.account
+                        && stored.chain_id == guard.chain_id
+                        && stored.module == guard.module
+                }) {
+                    Some(stored) if stored.height > guard.height => {}
+                    Some(stored) => {
+                        *stored = guard.clone();
+                        upserted += 1;
+                    }
+                    None => {
+                        stored.push(guard.clone());
+                        upserted += 1;
+                    }
+                }
+            }
+            Ok(upserted)
+        }
+    }
+
+    #[derive(Default)]
+    pub struct MemoryCursors {
+        pub cursors: Mutex<Vec<StreamCursor>>,
+    }
+
+    impl MemoryCursors {
+        pub fn new(cursors: Vec<StreamCursor>) -> Self {
+            MemoryCursors {
+                cursors: Mutex::new(cursors),
+            }
+        }
+    }
+
+    impl CursorStore for MemoryCursors {
+        fn find_all(&self) -> Result<Vec<StreamCursor>, DbError> {
+            let mut cursors = self.cursors.lock().unwrap().clone();
+            cursors.sort_by_key(|cursor| cursor.chain_id);
+            Ok(cursors)
+        }
+
+        /// Replaces the cursor of a chain only with one at the same or a greater height.
+        fn save(&self, cursors: &[StreamCursor]) -> Result<usize, DbError> {
+            let mut stored = self.cursors.lock().unwrap();
+            let mut saved = 0;
+            for cursor in cursors {
+                match stored
+                    .iter_mut()
+                    .find(|stored| stored.chain_id == cursor.chain_id)
+                {
+                    Some(stored) if stored.height > cursor.height => {}
+                    Some(stored) => {
+                        *stored = cursor.clone();
+                        saved += 1;
+                    }
+                    None => {
+                        stored.push(cursor.clone());
+                        saved += 1;
+                    }
+                }
+            }
+            Ok(saved)
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use bigdecimal::{BigDecimal, RoundingMode};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Number of decimals of fungible tokens, by module name. Pact amounts are stored as they were
+/// transferred, which may have fewer (or, for computed amounts, more) decimals than the token
+/// uses. Amounts of registered tokens can also be displayed at the precision of their token.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TokenDecimals {
+    decimals: HashMap<String, i64>,
+}
+
+impl TokenDecimals {
+    /// Parses a comma separated list of `module:decimals`, eg. `coin:12,free.token:8`.
+    pub fn parse(config: &str) -> Result<Self, String> {
+        let mut decimals = HashMap::new();
+        for token in config.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            let (module, value) = token
+                .rsplit_once(':')
+                .ok_or_else(|| format!("Missing decimals for {}", token))?;
+            let value = value
+                .trim()
+                .parse::<u32>()
+                .map_err(|_| format!("Invalid decimals for {}", module))?;
+            decimals.insert(module.trim().to_string(), value as i64);
+        }
+        Ok(TokenDecimals { decimals })
+    }
+
+    /// Reads the registry from `TOKEN_DECIMALS`, empty when the variable is not set.
+    pub fn from_env() -> Self {
+        match std::env::var("TOKEN_DECIMALS") {
+            Ok(config) => TokenDecimals::parse(&config).expect("Invalid TOKEN_DECIMALS"),
+            Err(_) => TokenDecimals::default(),
+        }
+    }
+
+    /// Returns the amount with exactly the number of decimals of the token, rounded toward
+    /// negative infinity so it's never more than the actual amount, negative amounts included.
+    /// `None` if the module is not registered.
+    pub fn scale(&self, module: &str, amount: &BigDecimal) -> Option<BigDecimal> {
+        self.decimals
+            .get(module)
+            .map(|decimals| amount.with_scale_round(*decimals, RoundingMode::Floor))
+    }
+
+    /// Raw amount when the module is not registered,
+    /// otherwise both the raw and the scaled amounts, eg. `{"raw": "1.5", "scaled": "1.500000"}`.
+    pub fn to_json(&self, module: &str, amount: &BigDecimal) -> Value {
+        match self.scale(module, amount) {
+            Some(scaled) => serde_json::json!({ "raw": amount, "scaled": scaled }),
+            None => serde_json::json!(amount),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_decimals() {
+        let decimals = TokenDecimals::parse("coin:12, free.token : 8").unwrap();
+        let amount = "1.5".parse::<BigDecimal>().unwrap();
+        assert_eq!(
+            decimals.scale("coin", &amount).unwrap().to_string(),
+            "1.500000000000"
+        );
+        let amount = "0.1234567890129".parse::<BigDecimal>().unwrap();
+        assert_eq!(
+            decimals.scale("coin", &amount).unwrap().to_string(),
+            "0.123456789012"
+        );
+        assert_eq!(
+            decimals.to_json("coin", &amount),
+            serde_json::json!({"raw": "0.1234567890129", "scaled": "0.123456789012"})
+        );
+        assert_eq!(
+            decimals.scale("free.token", &amount).unwrap().to_string(),
+            "0.12345678"
+        );
+        // Negative amounts, eg. balances of accounts debited by reprocessed transfers
+        let amount = "-0.1234567890129".parse::<BigDecimal>().unwrap();
+        assert_eq!(
+            decimals.scale("free.token", &amount).unwrap().to_string(),
+            "-0.12345679"
+        );
+
+        // Amounts of unknown tokens stay raw
+        assert_eq!(decimals.scale("free.other", &amount), None);
+        assert_eq!(
+            decimals.to_json("free.other", &amount),
+            serde_json::json!("0.1234567890129")
+        );
+
+        assert_eq!(TokenDecimals::parse(""), Ok(TokenDecimals::default()));
+        assert!(TokenDecimals::parse("coin").is_err());
+        assert!(TokenDecimals::parse("coin:twelve").is_err());
+    }
+}
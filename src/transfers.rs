@@ -1,11 +1,10 @@
 use crate::chainweb_client::ChainwebClient;
 use crate::db::DbError;
 use crate::models::{Block, Event, Transfer};
+use crate::projection::project_transfers;
 use crate::repository::{BlocksRepository, EventsRepository, TransfersRepository};
 use bigdecimal::BigDecimal;
-use chrono::NaiveDateTime;
 use std::collections::HashMap;
-use std::str::FromStr;
 use std::time::Instant;
 
 pub async fn backfill(
@@ -15,7 +14,7 @@ pub async fn backfill(
     events_repository: &EventsRepository,
     transfers_repository: &TransfersRepository,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let cut = chainweb_client.get_cut().await.unwrap();
+    let cut = chainweb_client.get_complete_cut().await?;
     cut.hashes.iter().for_each(|e| {
         let chain_id = e.0 .0;
         log::info!("Backfilling transfers on chain {}...", chain_id);
@@ -82,82 +81,91 @@ pub fn backfill_chain(
     Ok(())
 }
 
-fn is_balance_transfer(event: &Event) -> bool {
-    event.name == "TRANSFER"
+/// Deletes the transfers of the blocks between the given heights (inclusive)
+/// and derives them again from the indexed events.
+/// The amounts authorized by the senders come from the signed commands, which the events don't
+/// have, so the authorizations of the transfers being replaced are kept.
+pub fn reprocess_range(
+    chain_id: i64,
+    min_height: i64,
+    max_height: i64,
+    events_repository: &EventsRepository,
+    blocks_repository: &BlocksRepository,
+    transfers_repository: &TransfersRepository,
+) -> Result<(), DbError> {
+    let blocks = blocks_repository.find_by_range(min_height, max_height, chain_id)?;
+    let hashes = blocks
+        .iter()
+        .map(|block| block.hash.clone())
+        .collect::<Vec<String>>();
+    let authorized_amounts = transfers_repository
+        .find_by_blocks(&hashes)?
+        .into_iter()
+        .filter_map(|transfer| {
+            let authorized_amount = transfer.authorized_amount.clone()?;
+            Some((transfer_key(&transfer), authorized_amount))
+        })
+        .collect::<HashMap<TransferKey, BigDecimal>>();
+    for block in blocks.iter() {
+        transfers_repository.delete_all_by_block(&block.hash, block.chain_id)?;
+    }
+    let events = events_repository.find_by_range(min_height, max_height, chain_id)?;
+    let mut transfers = project_transfers(&events, &blocks);
+    for transfer in transfers.iter_mut() {
+        transfer.authorized_amount = authorized_amounts.get(&transfer_key(transfer)).cloned();
+    }
+    insert_transfers(&transfers, transfers_repository)?;
+    log::info!(
+        "Reprocessed transfers of {} blocks between heights {} and {} on chain {}",
+        blocks.len(),
+        min_height,
+        max_height,
+        chain_id
+    );
+    Ok(())
+}
+
+/// Primary key of a transfer, on a single chain.
+type TransferKey = (String, i64, String, String);
+
+fn transfer_key(transfer: &Transfer) -> TransferKey {
+    (
+        transfer.block.clone(),
+        transfer.idx,
+        transfer.request_key.clone(),
+        transfer.module_hash.clone(),
+    )
 }
 
+/// Projects the transfers of the events and inserts them.
 pub fn process_transfers(
     events: &[Event],
     blocks: &[Block],
     repository: &TransfersRepository,
 ) -> Result<(), DbError> {
-    let blocks_by_hash = blocks
-        .iter()
-        .map(|block| (block.hash.to_string(), block))
-        .collect::<HashMap<String, &Block>>();
-    let transfers = events
-        .iter()
-        .filter(|event| is_balance_transfer(event))
-        .map(|event| make_transfer(event, blocks_by_hash[&event.block]))
-        .collect::<Vec<Transfer>>();
+    insert_transfers(&project_transfers(events, blocks), repository)
+}
+
+/// Inserts the transfers in chunks.
+pub fn insert_transfers(
+    transfers: &[Transfer],
+    repository: &TransfersRepository,
+) -> Result<(), DbError> {
     // Number of parameters in one SQL query is limited to 65535, so we need to split the inserts
     transfers.chunks(1000).for_each(|chunk| {
         repository.insert_batch(&chunk.to_vec()).unwrap();
     });
     Ok(())
 }
-
-fn make_transfer(event: &Event, block: &Block) -> Transfer {
-    let sender = event.params[0].as_str().unwrap().to_string();
-    let receiver = event.params[1].as_str().unwrap().to_string();
-    let amount = match event.params[2].is_number() {
-        true => BigDecimal::from_str(&event.params[2].to_string()).unwrap(),
-        false => match event.params[2].is_object() {
-            true => match &event.params[2].as_object().unwrap().get("decimal") {
-                Some(number) => {
-                    BigDecimal::from_str(number.as_str().unwrap()).unwrap_or(BigDecimal::from(0))
-                }
-                None => {
-                    let number = &event.params[2]
-                        .as_object()
-                        .unwrap()
-                        .get("int")
-                        .unwrap()
-                        .as_i64()
-                        .unwrap();
-                    BigDecimal::from(*number)
-                }
-            },
-            false => BigDecimal::from(0),
-        },
-    };
-
-    Transfer {
-        amount,
-        block: event.block.clone(),
-        chain_id: event.chain_id,
-        creation_time: NaiveDateTime::from_timestamp_millis(block.creation_time.timestamp_millis())
-            .unwrap(),
-        from_account: sender,
-        height: event.height,
-        idx: event.idx,
-        module_hash: event.module_hash.clone(),
-        module_name: event.module.clone(),
-        request_key: event.request_key.clone(),
-        to_account: receiver,
-        pact_id: event.pact_id.clone(),
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::db;
-    use crate::models::Block;
     use crate::repository::BlocksRepository;
     use bigdecimal::BigDecimal;
     use chrono::Utc;
     use serial_test::serial;
+    use std::str::FromStr;
 
     fn make_block(chain_id: i64, height: i64, hash: String) -> Block {
         Block {
@@ -175,6 +183,7 @@ mod tests {
             pow_hash: "".to_string(),
             predicate: "predicate".to_string(),
             target: BigDecimal::from(1),
+            difficulty: None,
         }
     }
 
@@ -270,12 +279,14 @@ mod tests {
         .unwrap();
 
         let bob_incoming_transfers = transfers_repository
-            .find(None, Some(String::from("bob")), None)
-            .unwrap();
+            .find(None, Some(String::from("bob")), None, false)
+            .unwrap()
+            .rows;
         assert!(bob_incoming_transfers.len() == 3);
         let alice_incoming_transfers = transfers_repository
-            .find(None, Some(String::from("alice")), None)
-            .unwrap();
+            .find(None, Some(String::from("alice")), None, false)
+            .unwrap()
+            .rows;
         assert!(alice_incoming_transfers.len() == 1);
 
         events_repository.delete_all().unwrap();
@@ -284,176 +295,70 @@ mod tests {
     }
 
     #[test]
-    fn test_make_transfer() {
-        let event = Event {
-            block: "block-hash".to_string(),
-            chain_id: 0,
-            height: 0,
-            idx: 0,
-            module: "coin".to_string(),
-            module_hash: "module-hash".to_string(),
-            name: "TRANSFER".to_string(),
-            params: serde_json::json!(["bob", "alice", 100.12324354665567]),
-            param_text: "param-text".to_string(),
-            qual_name: "coin.TRANSFER".to_string(),
-            request_key: "request-key".to_string(),
-            pact_id: None,
-        };
-        let block = make_block(0, 0, "hash".to_string());
-        let transfer = make_transfer(&event, &block);
-        assert_eq!(
-            transfer,
-            Transfer {
-                amount: BigDecimal::from_str("100.12324354665567").unwrap(),
-                block: "block-hash".to_string(),
-                chain_id: 0,
-                creation_time: NaiveDateTime::from_timestamp_millis(
-                    block.creation_time.timestamp_millis()
-                )
-                .unwrap(),
-                from_account: "bob".to_string(),
-                height: 0,
-                idx: 0,
-                module_hash: "module-hash".to_string(),
-                module_name: "coin".to_string(),
-                request_key: "request-key".to_string(),
-                to_account: "alice".to_string(),
-                pact_id: None
-            }
-        );
-
-        let no_sender_event = Event {
-            params: serde_json::json!(["", "alice", 10]),
-            ..event.clone()
-        };
-        let transfer = make_transfer(&no_sender_event, &block);
-        assert_eq!(
-            transfer,
-            Transfer {
-                amount: BigDecimal::from_str("10").unwrap(),
-                block: "block-hash".to_string(),
-                chain_id: 0,
-                creation_time: NaiveDateTime::from_timestamp_millis(
-                    block.creation_time.timestamp_millis()
-                )
-                .unwrap(),
-                from_account: "".to_string(),
-                height: 0,
-                idx: 0,
-                module_hash: "module-hash".to_string(),
-                module_name: "coin".to_string(),
-                request_key: "request-key".to_string(),
-                to_account: "alice".to_string(),
-                pact_id: None
-            }
-        );
-        let no_receiver_event = Event {
-            params: serde_json::json!(["bob", "", 10]),
-            ..event
-        };
-        let transfer = make_transfer(&no_receiver_event, &block);
-        assert_eq!(
-            transfer,
-            Transfer {
-                amount: BigDecimal::from_str("10").unwrap(),
-                block: "block-hash".to_string(),
-                chain_id: 0,
-                creation_time: NaiveDateTime::from_timestamp_millis(
-                    block.creation_time.timestamp_millis()
+    #[serial]
+    fn test_reprocess_range_is_idempotent() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks_repository = BlocksRepository { pool: pool.clone() };
+        let events_repository = EventsRepository { pool: pool.clone() };
+        let transfers_repository = TransfersRepository { pool: pool.clone() };
+        blocks_repository
+            .insert_batch(&[
+                make_block(0, 0, "block-0".to_string()),
+                make_block(0, 1, "block-1".to_string()),
+                make_block(0, 2, "block-2".to_string()),
+            ])
+            .unwrap();
+        let events = (0..3)
+            .map(|height| {
+                make_transfer_event(
+                    format!("block-{}", height),
+                    height,
+                    0,
+                    0,
+                    "alice".to_string(),
+                    "bob".to_string(),
+                    1.5,
                 )
-                .unwrap(),
-                from_account: "bob".to_string(),
-                height: 0,
-                idx: 0,
-                module_hash: "module-hash".to_string(),
-                module_name: "coin".to_string(),
-                request_key: "request-key".to_string(),
-                to_account: "".to_string(),
-                pact_id: None
-            }
-        );
-    }
-
-    #[test]
-    fn test_parse_transfer_event_decimal() {
-        let event = Event {
-            block: "block-hash".to_string(),
-            chain_id: 0,
-            height: 0,
-            idx: 0,
-            module: "coin".to_string(),
-            module_hash: "module-hash".to_string(),
-            name: "TRANSFER".to_string(),
-            params: serde_json::json!(["bob", "alice", {"decimal": "22.230409400000000000000000"}]),
-            param_text: "param-text".to_string(),
-            qual_name: "coin.TRANSFER".to_string(),
-            request_key: "request-key".to_string(),
-            pact_id: None,
-        };
-        let block = make_block(0, 0, "hash".to_string());
-        let transfer = make_transfer(&event, &block);
-        assert!(transfer.amount == BigDecimal::from_str("22.230409400000000000000000").unwrap());
-        let event = Event {
-            block: "block-hash".to_string(),
-            chain_id: 0,
-            height: 0,
-            idx: 0,
-            module: "coin".to_string(),
-            module_hash: "module-hash".to_string(),
-            name: "TRANSFER".to_string(),
-            params: serde_json::json!(["bob", "alice", {"int": 1}]),
-            param_text: "param-text".to_string(),
-            qual_name: "coin.TRANSFER".to_string(),
-            request_key: "request-key".to_string(),
-            pact_id: None,
-        };
-        let transfer = make_transfer(&event, &block);
-        assert!(transfer.amount == BigDecimal::from(1));
-    }
+            })
+            .collect::<Vec<Event>>();
+        events_repository.insert_batch(&events).unwrap();
+        let blocks = blocks_repository.find_by_range(0, 2, 0).unwrap();
+        // Authorized when indexed, from the signed commands
+        let mut transfers = project_transfers(&events, &blocks);
+        for transfer in transfers.iter_mut() {
+            transfer.authorized_amount = Some(BigDecimal::from(2));
+        }
+        insert_transfers(&transfers, &transfers_repository).unwrap();
 
-    #[test]
-    /// This test is to make sure that if the amount is not a number, we default to 0
-    fn test_make_transfer_when_event_has_string_as_amount() {
-        let event = Event {
-            block: "block-hash".to_string(),
-            chain_id: 0,
-            height: 0,
-            idx: 0,
-            module: "coin".to_string(),
-            module_hash: "module-hash".to_string(),
-            name: "TRANSFER".to_string(),
-            params: serde_json::json!(["bob", "alice", "wrong-amount"]),
-            param_text: "param-text".to_string(),
-            qual_name: "coin.TRANSFER".to_string(),
-            request_key: "request-key".to_string(),
-            pact_id: None,
-        };
-        let block = make_block(0, 0, "hash".to_string());
-        let transfer = make_transfer(&event, &block);
-        assert!(transfer.amount == BigDecimal::from(0));
-    }
+        for _ in 0..2 {
+            reprocess_range(
+                0,
+                1,
+                2,
+                &events_repository,
+                &blocks_repository,
+                &transfers_repository,
+            )
+            .unwrap();
+            let transfers = transfers_repository
+                .find(Some(String::from("alice")), None, None, false)
+                .unwrap()
+                .rows;
+            assert_eq!(transfers.len(), 3);
+            assert!(transfers
+                .iter()
+                .all(|transfer| transfer.authorized_amount == Some(BigDecimal::from(2))));
+            assert_eq!(
+                transfers_repository
+                    .calculate_balance("bob", "coin")
+                    .unwrap()[&0],
+                BigDecimal::from_str("4.5").unwrap()
+            );
+        }
 
-    #[test]
-    fn test_is_balance_transfer() {
-        let event = Event {
-            block: "block-hash".to_string(),
-            chain_id: 0,
-            height: 0,
-            idx: 0,
-            module: "coin".to_string(),
-            module_hash: "module-hash".to_string(),
-            name: "TRANSFER".to_string(),
-            params: serde_json::json!(["bob", "alice", 100.1]),
-            param_text: "param-text".to_string(),
-            qual_name: "coin.TRANSFER".to_string(),
-            request_key: "request-key".to_string(),
-            pact_id: None,
-        };
-        assert!(is_balance_transfer(&event));
-        let event = Event {
-            name: "NOT_TRANSFER".to_string(),
-            ..event
-        };
-        assert!(is_balance_transfer(&event) == false);
+        events_repository.delete_all().unwrap();
+        transfers_repository.delete_all().unwrap();
+        blocks_repository.delete_all().unwrap();
     }
 }
@@ -0,0 +1,234 @@
+use std::fmt::Display;
+
+use crate::chainweb_client::{Bounds, ChainId, ChainwebClient, Hash};
+use crate::db::DbError;
+use crate::repository::BlocksRepository;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Discrepancy {
+    /// The number of indexed blocks doesn't match the number expected
+    /// between the min and max indexed heights.
+    MissingBlocks {
+        chain: i64,
+        min_height: i64,
+        max_height: i64,
+        expected: i64,
+        actual: i64,
+    },
+    /// The indexed block hash at this height is not the one on the node's main branch.
+    HashMismatch {
+        chain: i64,
+        height: i64,
+        indexed: String,
+        node: String,
+    },
+}
+
+impl Display for Discrepancy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Discrepancy::MissingBlocks {
+                chain,
+                min_height,
+                max_height,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Chain {}: expected {} blocks between heights {} and {}, found {}",
+                chain, expected, min_height, max_height, actual
+            ),
+            Discrepancy::HashMismatch {
+                chain,
+                height,
+                indexed,
+                node,
+            } => write!(
+                f,
+                "Chain {}: block at height {} is {}, node has {}",
+                chain, height, indexed, node
+            ),
+        }
+    }
+}
+
+/// Verifies the indexed blocks of every chain in the current cut. The number of blocks
+/// is compared with the expected number given the min and max indexed heights and,
+/// if `spot_checks` is greater than 0, that many random heights are compared with the node.
+pub async fn verify(
+    chainweb_client: &ChainwebClient,
+    blocks_repo: &BlocksRepository,
+    spot_checks: usize,
+) -> Result<Vec<Discrepancy>, Box<dyn std::error::Error>> {
+    let cut = chainweb_client.get_complete_cut().await?;
+    let mut chains = cut.hashes.iter().collect::<Vec<_>>();
+    chains.sort_by_key(|(chain, _)| chain.0);
+    let mut discrepancies = vec![];
+    for (chain, last_block) in chains {
+        let chain_discrepancies = match verify_count(chain.0 as i64, blocks_repo)
+            .map_err(|e| e as Box<dyn std::error::Error>)?
+        {
+            Some(discrepancy) => vec![discrepancy],
+            None => vec![],
+        };
+        let spot_check_discrepancies = if spot_checks > 0 {
+            spot_check(
+                chain,
+                &last_block.hash,
+                spot_checks,
+                chainweb_client,
+                blocks_repo,
+            )
+            .await?
+        } else {
+            vec![]
+        };
+        if chain_discrepancies.is_empty() && spot_check_discrepancies.is_empty() {
+            log::info!("Chain {}: OK", chain);
+        }
+        for discrepancy in chain_discrepancies
+            .into_iter()
+            .chain(spot_check_discrepancies)
+        {
+            log::error!("{}", discrepancy);
+            discrepancies.push(discrepancy);
+        }
+    }
+    Ok(discrepancies)
+}
+
+/// Compares the number of indexed blocks with the expected number of blocks
+/// between the min and max indexed heights.
+pub fn verify_count(
+    chain: i64,
+    blocks_repo: &BlocksRepository,
+) -> Result<Option<Discrepancy>, DbError> {
+    let count = blocks_repo.count(chain)?;
+    match blocks_repo.find_min_max_height_blocks(chain)? {
+        (Some(min_block), Some(max_block)) => {
+            let expected = max_block.height - min_block.height + 1;
+            if expected == count {
+                return Ok(None);
+            }
+            Ok(Some(Discrepancy::MissingBlocks {
+                chain,
+                min_height: min_block.height,
+                max_height: max_block.height,
+                expected,
+                actual: count,
+            }))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Picks random indexed heights and compares the indexed block hashes
+/// with the ones on the node's current branch.
+async fn spot_check(
+    chain: &ChainId,
+    last_block_hash: &str,
+    spot_checks: usize,
+    chainweb_client: &ChainwebClient,
+    blocks_repo: &BlocksRepository,
+) -> Result<Vec<Discrepancy>, Box<dyn std::error::Error>> {
+    let (min_block, max_block) = match blocks_repo
+        .find_min_max_height_blocks(chain.0 as i64)
+        .map_err(|e| e as Box<dyn std::error::Error>)?
+    {
+        (Some(min_block), Some(max_block)) => (min_block, max_block),
+        _ => return Ok(vec![]),
+    };
+    // Offsets from the min height are sampled, without collecting all the heights
+    let span = (max_block.height - min_block.height + 1) as usize;
+    let heights = rand::seq::index::sample(&mut rand::thread_rng(), span, spot_checks.min(span))
+        .into_iter()
+        .map(|offset| min_block.height + offset as i64)
+        .collect::<Vec<i64>>();
+    let bounds = Bounds {
+        lower: vec![],
+        upper: vec![Hash(last_block_hash.to_string())],
+    };
+    let mut discrepancies = vec![];
+    for height in heights {
+        let indexed = match blocks_repo
+            .find_by_height(height, chain.0 as i64)
+            .map_err(|e| e as Box<dyn std::error::Error>)?
+        {
+            Some(block) => block,
+            // Missing blocks are already reported by the count check
+            None => continue,
+        };
+        let response = chainweb_client
+            .get_block_headers_branches(chain, &bounds, &None, None, Some(height as u64))
+            .await?;
+        match response.items.first() {
+            Some(header) if header.hash != indexed.hash => {
+                discrepancies.push(Discrepancy::HashMismatch {
+                    chain: chain.0 as i64,
+                    height,
+                    indexed: indexed.hash,
+                    node: header.hash.clone(),
+                })
+            }
+            _ => {}
+        }
+    }
+    Ok(discrepancies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use crate::models::Block;
+    use bigdecimal::BigDecimal;
+    use chrono::Utc;
+    use serial_test::serial;
+
+    fn make_block(chain_id: i64, height: i64) -> Block {
+        Block {
+            chain_id,
+            hash: format!("hash-{}", height),
+            height,
+            parent: "parent".to_string(),
+            weight: BigDecimal::from(0),
+            creation_time: Utc::now().naive_utc(),
+            epoch: Utc::now().naive_utc(),
+            flags: BigDecimal::from(0),
+            miner: "miner".to_string(),
+            nonce: BigDecimal::from(0),
+            payload: "payload".to_string(),
+            pow_hash: "".to_string(),
+            predicate: "predicate".to_string(),
+            target: BigDecimal::from(1),
+            difficulty: None,
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_verify_count() {
+        dotenvy::from_filename(".env.test").ok();
+        let pool = db::initialize_db_pool();
+        let blocks = BlocksRepository { pool: pool.clone() };
+        blocks
+            .insert_batch(&[make_block(0, 10), make_block(0, 11), make_block(0, 12)])
+            .unwrap();
+        assert_eq!(verify_count(0, &blocks).unwrap(), None);
+
+        blocks
+            .insert_batch(&[make_block(0, 15), make_block(0, 16)])
+            .unwrap();
+        assert_eq!(
+            verify_count(0, &blocks).unwrap(),
+            Some(Discrepancy::MissingBlocks {
+                chain: 0,
+                min_height: 10,
+                max_height: 16,
+                expected: 7,
+                actual: 5
+            })
+        );
+        blocks.delete_all().unwrap();
+    }
+}